@@ -0,0 +1,92 @@
+/* Link with libkillcode_overload (staticlib or cdylib). Call killcode_connect() once at startup. */
+
+#ifndef KILLCODE_OVERLOAD_H
+#define KILLCODE_OVERLOAD_H
+
+/* Generated from src/ffi.rs by cbindgen. Do not edit; run `KILLCODE_UPDATE_HEADER=1 cargo test`. */
+
+#include <stdint.h>
+
+// Connected to the stub
+#define KILLCODE_OK 0
+
+// The stub didn't set up a health channel
+#define KILLCODE_ERR_NOT_AVAILABLE -1
+
+// The health channel couldn't be opened or mapped
+#define KILLCODE_ERR_OPEN -2
+
+// The stub uses a different protocol version or layout
+#define KILLCODE_ERR_PROTOCOL -3
+
+// How the base ended (see `killcode_base_exit`)
+typedef struct KillcodeBaseExit {
+  // Exit code, 0 when a signal ended the base
+  int32_t code;
+  // Signal that ended the base, 0 if it exited (always 0 on Windows)
+  int32_t signal;
+  // Milliseconds from the base's start to its end
+  uint64_t runtime_ms;
+  // Peak resident set size in bytes, 0 if unknown
+  uint64_t peak_rss;
+  // Seconds the stub waits for the overload to exit before terminating it
+  uint32_t shutdown_window;
+} KillcodeBaseExit;
+
+// What the base and everything it started use (see `killcode_base_usage`)
+typedef struct KillcodeBaseUsage {
+  // When the stub took the sample, in seconds since boot
+  int64_t sampled_at;
+  // CPU time in milliseconds, including exited processes the tree waited
+  // for. Never decreases.
+  uint64_t cpu_ms;
+  // Resident set size in bytes
+  uint64_t rss;
+  uint32_t threads;
+  // Running processes the base started, at any depth
+  uint32_t children;
+} KillcodeBaseUsage;
+
+// Attach to the stub's health channel. Returns `KILLCODE_OK` or a
+// `KILLCODE_ERR_*` code. Calling it again after a success does nothing.
+int32_t killcode_connect(void);
+
+// The license check passed: restart the grace period and clear failures
+void killcode_report_success(void);
+
+// The license server couldn't be reached. Returns the number of
+// consecutive failures (0 when not connected).
+uint32_t killcode_report_failure(void);
+
+// Tell the stub the overload is still running. Any report counts; the stub
+// treats an overload that stays silent past its heartbeat timeout as hung.
+void killcode_heartbeat(void);
+
+// Ask the stub to kill the base
+void killcode_request_kill(void);
+
+// 1 when the stub asks the overload to run its kill method and exit
+int32_t killcode_should_terminate(void);
+
+// 1 when the stub or a payload failed an integrity check
+int32_t killcode_integrity_violation(void);
+
+// PID of the base, or 0 while it isn't running yet
+uint32_t killcode_base_pid(void);
+
+// 1 once the base ended, filling in `exit`. 0 while it runs; `exit` is
+// left alone then.
+//
+// # Safety
+// `exit` must be null or point to a writable `KillcodeBaseExit`.
+int32_t killcode_base_exit(struct KillcodeBaseExit *exit);
+
+// 1 once the stub sampled the base's resource use, filling in `usage`
+// with the latest sample. 0 before that (and on stubs that don't sample);
+// `usage` is left alone then.
+//
+// # Safety
+// `usage` must be null or point to a writable `KillcodeBaseUsage`.
+int32_t killcode_base_usage(struct KillcodeBaseUsage *usage);
+
+#endif  /* KILLCODE_OVERLOAD_H */
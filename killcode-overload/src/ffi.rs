@@ -0,0 +1,183 @@
+//! C API. `include/killcode_overload.h` is generated from this file.
+//!
+//! `killcode_connect` attaches once per process. Before it succeeds every
+//! other call does nothing and reports "no".
+
+use std::sync::OnceLock;
+
+use crate::{Error, Health};
+
+/// Connected to the stub
+pub const KILLCODE_OK: i32 = 0;
+/// The stub didn't set up a health channel
+pub const KILLCODE_ERR_NOT_AVAILABLE: i32 = -1;
+/// The health channel couldn't be opened or mapped
+pub const KILLCODE_ERR_OPEN: i32 = -2;
+/// The stub uses a different protocol version or layout
+pub const KILLCODE_ERR_PROTOCOL: i32 = -3;
+
+static HEALTH: OnceLock<Health> = OnceLock::new();
+
+/// Attach to the stub's health channel. Returns `KILLCODE_OK` or a
+/// `KILLCODE_ERR_*` code. Calling it again after a success does nothing.
+#[no_mangle]
+pub extern "C" fn killcode_connect() -> i32 {
+    if HEALTH.get().is_some() {
+        return KILLCODE_OK;
+    }
+    match Health::connect() {
+        Ok(health) => {
+            let _ = HEALTH.set(health);
+            KILLCODE_OK
+        }
+        Err(Error::NotAvailable) => KILLCODE_ERR_NOT_AVAILABLE,
+        Err(Error::Open(_)) => KILLCODE_ERR_OPEN,
+        Err(Error::Protocol(_)) => KILLCODE_ERR_PROTOCOL,
+    }
+}
+
+/// The license check passed: restart the grace period and clear failures
+#[no_mangle]
+pub extern "C" fn killcode_report_success() {
+    if let Some(health) = HEALTH.get() {
+        health.report_success();
+    }
+}
+
+/// The license server couldn't be reached. Returns the number of
+/// consecutive failures (0 when not connected).
+#[no_mangle]
+pub extern "C" fn killcode_report_failure() -> u32 {
+    HEALTH.get().map_or(0, Health::report_failure)
+}
+
+/// Tell the stub the overload is still running. Any report counts; the stub
+/// treats an overload that stays silent past its heartbeat timeout as hung.
+#[no_mangle]
+pub extern "C" fn killcode_heartbeat() {
+    if let Some(health) = HEALTH.get() {
+        health.heartbeat();
+    }
+}
+
+/// Ask the stub to kill the base
+#[no_mangle]
+pub extern "C" fn killcode_request_kill() {
+    if let Some(health) = HEALTH.get() {
+        health.request_kill();
+    }
+}
+
+/// 1 when the stub asks the overload to run its kill method and exit
+#[no_mangle]
+pub extern "C" fn killcode_should_terminate() -> i32 {
+    HEALTH.get().is_some_and(Health::should_terminate) as i32
+}
+
+/// 1 when the stub or a payload failed an integrity check
+#[no_mangle]
+pub extern "C" fn killcode_integrity_violation() -> i32 {
+    HEALTH.get().is_some_and(Health::integrity_violation) as i32
+}
+
+/// PID of the base, or 0 while it isn't running yet
+#[no_mangle]
+pub extern "C" fn killcode_base_pid() -> u32 {
+    HEALTH.get().and_then(Health::base_pid).unwrap_or(0)
+}
+
+/// How the base ended (see `killcode_base_exit`)
+#[repr(C)]
+pub struct KillcodeBaseExit {
+    /// Exit code, 0 when a signal ended the base
+    pub code: i32,
+    /// Signal that ended the base, 0 if it exited (always 0 on Windows)
+    pub signal: i32,
+    /// Milliseconds from the base's start to its end
+    pub runtime_ms: u64,
+    /// Peak resident set size in bytes, 0 if unknown
+    pub peak_rss: u64,
+    /// Seconds the stub waits for the overload to exit before terminating it
+    pub shutdown_window: u32,
+}
+
+/// 1 once the base ended, filling in `exit`. 0 while it runs; `exit` is
+/// left alone then.
+///
+/// # Safety
+/// `exit` must be null or point to a writable `KillcodeBaseExit`.
+#[no_mangle]
+pub unsafe extern "C" fn killcode_base_exit(exit: *mut KillcodeBaseExit) -> i32 {
+    let Some(base_exit) = HEALTH.get().and_then(Health::base_exit) else {
+        return 0;
+    };
+    if let Some(exit) = exit.as_mut() {
+        *exit = KillcodeBaseExit {
+            code: base_exit.code,
+            signal: base_exit.signal,
+            runtime_ms: base_exit.runtime_ms,
+            peak_rss: base_exit.peak_rss,
+            shutdown_window: base_exit.shutdown_window,
+        };
+    }
+    1
+}
+
+/// What the base and everything it started use (see `killcode_base_usage`)
+#[repr(C)]
+pub struct KillcodeBaseUsage {
+    /// When the stub took the sample, in seconds since boot
+    pub sampled_at: i64,
+    /// CPU time in milliseconds, including exited processes the tree waited
+    /// for. Never decreases.
+    pub cpu_ms: u64,
+    /// Resident set size in bytes
+    pub rss: u64,
+    pub threads: u32,
+    /// Running processes the base started, at any depth
+    pub children: u32,
+}
+
+/// 1 once the stub sampled the base's resource use, filling in `usage`
+/// with the latest sample. 0 before that (and on stubs that don't sample);
+/// `usage` is left alone then.
+///
+/// # Safety
+/// `usage` must be null or point to a writable `KillcodeBaseUsage`.
+#[no_mangle]
+pub unsafe extern "C" fn killcode_base_usage(usage: *mut KillcodeBaseUsage) -> i32 {
+    let Some(base_usage) = HEALTH.get().and_then(Health::base_usage) else {
+        return 0;
+    };
+    if let Some(usage) = usage.as_mut() {
+        *usage = KillcodeBaseUsage {
+            sampled_at: base_usage.sampled_at,
+            cpu_ms: base_usage.cpu_ms,
+            rss: base_usage.rss,
+            threads: base_usage.threads,
+            children: base_usage.children,
+        };
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    /// Regenerate the header with `KILLCODE_UPDATE_HEADER=1 cargo test`
+    #[test]
+    fn test_c_header_is_current() {
+        let crate_dir = env!("CARGO_MANIFEST_DIR");
+        let config = cbindgen::Config::from_file(Path::new(crate_dir).join("cbindgen.toml")).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::generate_with_config(crate_dir, config).unwrap().write(&mut generated);
+
+        let header = Path::new(crate_dir).join("include/killcode_overload.h");
+        if std::env::var_os("KILLCODE_UPDATE_HEADER").is_some() {
+            std::fs::write(&header, &generated).unwrap();
+        }
+        let current = std::fs::read(&header).unwrap_or_default();
+        assert!(current == generated, "include/killcode_overload.h is stale, see this test's doc comment");
+    }
+}
@@ -0,0 +1,564 @@
+//! Overload-side access to the KillCode stub's health channel.
+//!
+//! The stub shares a small memory region (or, where it can't create one, a
+//! socket) with the overload and watches it while the base runs. Overloads report the outcome of their license checks
+//! and watch for the stub asking them to run their kill method:
+//!
+//! ```no_run
+//! let health = killcode_overload::Health::connect()?;
+//! loop {
+//!     # let license_valid = true;
+//!     if license_valid {
+//!         health.report_success();
+//!     } else {
+//!         health.report_failure();
+//!     }
+//!     health.heartbeat();
+//!     if health.should_terminate() {
+//!         // run the kill method, then exit
+//!         break;
+//!     }
+//!     std::thread::sleep(std::time::Duration::from_secs(1));
+//! }
+//! # Ok::<(), killcode_overload::Error>(())
+//! ```
+//!
+//! Only the overload inherits the channel, and every report is signed with a
+//! per-launch key (see [`protocol`]), so the base can't report on its behalf.
+//!
+//! C overloads use the same calls through `include/killcode_overload.h`.
+
+use std::fmt;
+use std::ptr::NonNull;
+use std::sync::{Mutex, PoisonError};
+
+mod ffi;
+pub mod protocol;
+
+use protocol::{
+    BaseExit, BaseUsage, HealthStatus, ProtocolError, Report, StubState, HEALTH_CHANNEL_ENV, HEALTH_KEY_LEN, STATE_FRAME_LEN,
+    TRANSPORT_SHARED_MEMORY, TRANSPORT_STREAM,
+};
+
+/// Why the health channel can't be used
+#[derive(Debug)]
+pub enum Error {
+    /// The stub didn't set up a health channel (sync mode, or no monitoring)
+    NotAvailable,
+    /// The inherited channel or key couldn't be read or mapped
+    Open(std::io::Error),
+    /// The stub uses a different protocol
+    Protocol(ProtocolError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotAvailable => write!(f, "{} is not set", HEALTH_CHANNEL_ENV),
+            Error::Open(e) => write!(f, "cannot open the health channel: {}", e),
+            Error::Protocol(e) => write!(f, "incompatible health shared memory: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+/// The overload's handle on the stub's health channel
+pub struct Health {
+    channel: Channel,
+    key: [u8; HEALTH_KEY_LEN],
+    /// Last published report. Every change publishes a new one.
+    report: Mutex<Report>,
+}
+
+enum Channel {
+    Shared(NonNull<HealthStatus>),
+    Stream(Mutex<Stream>),
+}
+
+/// The overload's end of a stream channel
+struct Stream {
+    socket: sys::Stream,
+    /// Bytes of a state frame that is still incomplete
+    received: Vec<u8>,
+    /// The latest complete state frame
+    state: StubState,
+}
+
+// Every field of a shared region is accessed through atomics
+unsafe impl Send for Health {}
+unsafe impl Sync for Health {}
+
+impl Health {
+    /// Attach to the channel the stub passed down. Can only succeed once per
+    /// process: the descriptors are consumed and the variable is removed, so
+    /// nothing the overload starts inherits them.
+    pub fn connect() -> Result<Self, Error> {
+        let channel = std::env::var(HEALTH_CHANNEL_ENV).map_err(|_| Error::NotAvailable)?;
+        std::env::remove_var(HEALTH_CHANNEL_ENV);
+        let mut fields = channel.splitn(3, ':');
+        let (transport, channel, key) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(transport), Some(channel), Some(key)) => (transport, channel, key),
+            _ => return Err(Error::Open(std::io::ErrorKind::InvalidInput.into())),
+        };
+        let (channel, key) = channel
+            .parse()
+            .ok()
+            .zip(key.parse().ok())
+            .ok_or_else(|| Error::Open(std::io::ErrorKind::InvalidInput.into()))?;
+
+        sys::shield();
+        let key = sys::read_key(key).map_err(Error::Open)?;
+        let channel = match transport {
+            TRANSPORT_SHARED_MEMORY => {
+                let status = sys::map(channel)?;
+                let channel = Channel::Shared(status);
+                unsafe { status.as_ref() }.validate()?;
+                channel
+            }
+            TRANSPORT_STREAM => {
+                let mut socket = sys::Stream::inherit(channel).map_err(Error::Open)?;
+                // The stub wrote the first state frame before the overload started
+                let mut frame = [0u8; STATE_FRAME_LEN];
+                socket.read_first(&mut frame).map_err(Error::Open)?;
+                let state = StubState::from_frame(&frame)?;
+                let mut stream = Stream { socket, received: Vec::new(), state };
+                // Frames the stub sent to an overload that was restarted
+                // come before the current one
+                stream.receive();
+                Channel::Stream(Mutex::new(stream))
+            }
+            _ => return Err(Error::Open(std::io::ErrorKind::Unsupported.into())),
+        };
+        // A restarted overload continues the grace period of the one before
+        // (or of an earlier launch) and a hybrid-mode stub waits for a real
+        // success
+        let now = protocol::clock();
+        let accepted_success = match &channel {
+            Channel::Shared(status) => unsafe { status.as_ref() }.state().accepted_success,
+            Channel::Stream(stream) => stream.lock().unwrap_or_else(PoisonError::into_inner).state.accepted_success,
+        };
+        let last_success = match accepted_success {
+            protocol::NOT_VERIFIED => 0,
+            0 => now,
+            accepted => accepted,
+        };
+        Ok(Self {
+            channel,
+            key,
+            report: Mutex::new(Report { last_success, last_heartbeat: now, ..Default::default() }),
+        })
+    }
+
+    /// The stub's latest state
+    fn state(&self) -> StubState {
+        match &self.channel {
+            Channel::Shared(status) => unsafe { status.as_ref() }.state(),
+            Channel::Stream(stream) => stream.lock().unwrap_or_else(PoisonError::into_inner).receive(),
+        }
+    }
+
+    /// Change the report and publish it under the next counter. Every report
+    /// is also a heartbeat.
+    fn update(&self, change: impl FnOnce(&mut Report)) -> Report {
+        let mut report = self.report.lock().unwrap_or_else(PoisonError::into_inner);
+        change(&mut report);
+        report.counter += 1;
+        report.last_heartbeat = protocol::clock();
+        match &self.channel {
+            Channel::Shared(status) => unsafe { status.as_ref() }.publish(&report, &self.key),
+            Channel::Stream(stream) => {
+                let frame = report.to_frame(&report.mac(&self.key));
+                // A stub that is gone can't be told anything anymore
+                let _ = stream.lock().unwrap_or_else(PoisonError::into_inner).socket.send(&frame);
+            }
+        }
+        *report
+    }
+
+    /// The license check passed: restart the grace period and clear failures
+    pub fn report_success(&self) {
+        self.update(|report| {
+            report.last_success = protocol::clock();
+            report.consecutive_failures = 0;
+        });
+    }
+
+    /// The license server couldn't be reached. Returns the number of
+    /// consecutive failures, which the stub compares to its threshold.
+    pub fn report_failure(&self) -> u32 {
+        let report = self.update(|report| report.consecutive_failures = report.consecutive_failures.saturating_add(1));
+        report.consecutive_failures as u32
+    }
+
+    /// Tell the stub the overload is still running. Any report counts, so this
+    /// only matters while there is nothing else to report; the stub treats an
+    /// overload that stays silent past its heartbeat timeout as hung.
+    pub fn heartbeat(&self) {
+        self.update(|_| {});
+    }
+
+    /// Ask the stub to kill the base
+    pub fn request_kill(&self) {
+        self.update(|report| report.should_kill_base = true);
+    }
+
+    /// The stub asks the overload to run its kill method and exit
+    pub fn should_terminate(&self) -> bool {
+        self.state().parent_requests_kill
+    }
+
+    /// The stub or a payload failed an integrity check
+    pub fn integrity_violation(&self) -> bool {
+        self.state().integrity_violation
+    }
+
+    /// PID of the base, once the stub started it
+    pub fn base_pid(&self) -> Option<u32> {
+        let pid = self.state().base_pid;
+        (pid > 0).then_some(pid as u32)
+    }
+
+    /// How the base ended, once it did. The overload has
+    /// [`BaseExit::shutdown_window`] seconds to record it before the stub
+    /// terminates it.
+    pub fn base_exit(&self) -> Option<BaseExit> {
+        self.state().base_exit
+    }
+
+    /// What the base and everything it started use, as the stub last sampled
+    /// it. Only Linux stubs sample, and only when the merge enabled it.
+    pub fn base_usage(&self) -> Option<BaseUsage> {
+        self.state().base_usage
+    }
+}
+
+impl Drop for Health {
+    fn drop(&mut self) {
+        if let Channel::Shared(status) = self.channel {
+            unsafe { sys::unmap(status) };
+        }
+    }
+}
+
+impl Stream {
+    /// Read what the stub sent since the last call without blocking and
+    /// return the latest state
+    fn receive(&mut self) -> StubState {
+        let mut buffer = [0u8; 16 * STATE_FRAME_LEN];
+        loop {
+            let read = self.socket.receive(&mut buffer);
+            if read == 0 {
+                break;
+            }
+            self.received.extend_from_slice(&buffer[..read]);
+        }
+        let complete = self.received.len() / STATE_FRAME_LEN * STATE_FRAME_LEN;
+        if let Some(frame) = self.received[..complete].chunks_exact(STATE_FRAME_LEN).last() {
+            if let Ok(state) = StubState::from_frame(frame.try_into().unwrap()) {
+                self.state = state;
+            }
+        }
+        self.received.drain(..complete);
+        self.state
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+    use std::ptr::NonNull;
+
+    use crate::protocol::{HealthStatus, ProtocolError, HEALTH_KEY_LEN};
+    use crate::Error;
+
+    /// Keep processes of the same user (such as the base) out of
+    /// `/proc/<pid>/fd` and the overload's memory
+    pub fn shield() {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::prctl(libc::PR_SET_DUMPABLE, 0);
+        }
+    }
+
+    /// Take ownership of an inherited descriptor
+    fn inherited(fd: RawFd) -> io::Result<File> {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub fn read_key(fd: RawFd) -> io::Result<[u8; HEALTH_KEY_LEN]> {
+        let mut key = [0u8; HEALTH_KEY_LEN];
+        inherited(fd)?.read_exact(&mut key)?;
+        Ok(key)
+    }
+
+    pub fn map(fd: RawFd) -> Result<NonNull<HealthStatus>, Error> {
+        let region = inherited(fd).map_err(Error::Open)?;
+        // Mapping past the end of a smaller region would fault
+        let size = region.metadata().map_err(Error::Open)?.len();
+        if size < HealthStatus::SIZE as u64 {
+            return Err(ProtocolError::TooSmall(size as u32).into());
+        }
+        let address = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                HealthStatus::SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(Error::Open(io::Error::last_os_error()));
+        }
+        Ok(unsafe { NonNull::new_unchecked(address as *mut HealthStatus) })
+    }
+
+    pub unsafe fn unmap(status: NonNull<HealthStatus>) {
+        libc::munmap(status.as_ptr() as *mut libc::c_void, HealthStatus::SIZE);
+    }
+
+    /// The overload's end of the stub's socketpair
+    pub struct Stream(File);
+
+    impl Stream {
+        pub fn inherit(fd: RawFd) -> io::Result<Self> {
+            let socket = inherited(fd)?;
+            // A write after the stub is gone must fail, not kill the overload
+            #[cfg(target_os = "macos")]
+            unsafe {
+                let on: libc::c_int = 1;
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_NOSIGPIPE,
+                    &on as *const _ as *const libc::c_void,
+                    std::mem::size_of_val(&on) as libc::socklen_t,
+                );
+            }
+            Ok(Self(socket))
+        }
+
+        pub fn read_first(&mut self, frame: &mut [u8]) -> io::Result<()> {
+            self.0.read_exact(frame)
+        }
+
+        pub fn send(&self, mut bytes: &[u8]) -> io::Result<()> {
+            #[cfg(target_os = "linux")]
+            let flags = libc::MSG_NOSIGNAL;
+            #[cfg(not(target_os = "linux"))]
+            let flags = 0;
+            while !bytes.is_empty() {
+                let sent = unsafe { libc::send(self.0.as_raw_fd(), bytes.as_ptr().cast(), bytes.len(), flags) };
+                if sent < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(error);
+                }
+                bytes = &bytes[sent as usize..];
+            }
+            Ok(())
+        }
+
+        /// Whatever arrived, without waiting. 0 when nothing did.
+        pub fn receive(&mut self, buffer: &mut [u8]) -> usize {
+            let read = unsafe {
+                libc::recv(self.0.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), libc::MSG_DONTWAIT)
+            };
+            read.max(0) as usize
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
+    use std::ptr::{self, NonNull};
+
+    use windows_sys::Win32::Foundation::{CloseHandle, GetHandleInformation, HANDLE};
+    use windows_sys::Win32::System::Memory::{
+        MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS, MEMORY_MAPPED_VIEW_ADDRESS,
+    };
+    use windows_sys::Win32::System::Pipes::PeekNamedPipe;
+
+    use crate::protocol::{HealthStatus, HEALTH_KEY_LEN};
+    use crate::Error;
+
+    /// Windows keeps other processes out through the handle's access rights
+    pub fn shield() {}
+
+    fn check(handle: usize) -> io::Result<HANDLE> {
+        let mut flags = 0;
+        if unsafe { GetHandleInformation(handle as HANDLE, &mut flags) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(handle as HANDLE)
+    }
+
+    pub fn read_key(handle: usize) -> io::Result<[u8; HEALTH_KEY_LEN]> {
+        let mut key = [0u8; HEALTH_KEY_LEN];
+        let mut pipe = unsafe { File::from_raw_handle(check(handle)? as RawHandle) };
+        pipe.read_exact(&mut key)?;
+        Ok(key)
+    }
+
+    pub fn map(handle: usize) -> Result<NonNull<HealthStatus>, Error> {
+        let mapping = check(handle).map_err(Error::Open)?;
+        unsafe {
+            let view = MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, HealthStatus::SIZE);
+            let error = io::Error::last_os_error();
+            // The view keeps the mapping alive
+            CloseHandle(mapping);
+            NonNull::new(view.Value as *mut HealthStatus).ok_or(Error::Open(error))
+        }
+    }
+
+    pub unsafe fn unmap(status: NonNull<HealthStatus>) {
+        UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: status.as_ptr() as *mut _ });
+    }
+
+    /// The overload's end of the stub's named pipe
+    pub struct Stream(File);
+
+    impl Stream {
+        pub fn inherit(handle: usize) -> io::Result<Self> {
+            Ok(Self(unsafe { File::from_raw_handle(check(handle)? as RawHandle) }))
+        }
+
+        pub fn read_first(&mut self, frame: &mut [u8]) -> io::Result<()> {
+            self.0.read_exact(frame)
+        }
+
+        pub fn send(&self, bytes: &[u8]) -> io::Result<()> {
+            (&self.0).write_all(bytes)
+        }
+
+        /// Whatever arrived, without waiting. 0 when nothing did.
+        pub fn receive(&mut self, buffer: &mut [u8]) -> usize {
+            let mut available = 0;
+            let peeked = unsafe {
+                PeekNamedPipe(
+                    self.0.as_raw_handle() as HANDLE,
+                    ptr::null_mut(),
+                    0,
+                    ptr::null_mut(),
+                    &mut available,
+                    ptr::null_mut(),
+                )
+            };
+            if peeked == 0 || available == 0 {
+                return 0;
+            }
+            let wanted = buffer.len().min(available as usize);
+            self.0.read(&mut buffer[..wanted]).unwrap_or(0)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::fd::FromRawFd;
+
+    #[test]
+    fn test_connect_and_signed_reports() {
+        assert!(matches!(Health::connect(), Err(Error::NotAvailable)));
+
+        // What the stub hands down: an anonymous region and a pipe holding the key
+        let name = std::ffi::CString::new(format!("/killcode-overload-test{}", std::process::id())).unwrap();
+        let region = unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o600);
+            assert!(fd >= 0);
+            libc::shm_unlink(name.as_ptr());
+            libc::ftruncate(fd, HealthStatus::SIZE as libc::off_t);
+            fd
+        };
+        let stub = unsafe {
+            let address = libc::mmap(
+                std::ptr::null_mut(),
+                HealthStatus::SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                region,
+                0,
+            );
+            HealthStatus::init(address as *mut HealthStatus);
+            &*(address as *const HealthStatus)
+        };
+        let key = [42u8; HEALTH_KEY_LEN];
+        let mut fds = [0; 2];
+        unsafe { libc::pipe(fds.as_mut_ptr()) };
+        unsafe { std::fs::File::from_raw_fd(fds[1]) }.write_all(&key).unwrap();
+        std::env::set_var(HEALTH_CHANNEL_ENV, format!("shm:{}:{}", region, fds[0]));
+        stub.set_state(&StubState { accepted_success: protocol::NOT_VERIFIED, ..Default::default() });
+
+        let health = Health::connect().unwrap();
+        assert!(std::env::var_os(HEALTH_CHANNEL_ENV).is_none());
+
+        assert_eq!(health.report_failure(), 1);
+        assert_eq!(health.report_failure(), 2);
+        let (report, mac) = stub.read_report().unwrap();
+        assert_eq!((report.counter, report.consecutive_failures, report.last_success), (2, 2, 0));
+        assert!(report.verify(&key, &mac));
+
+        health.report_success();
+        let (report, mac) = stub.read_report().unwrap();
+        assert_eq!((report.counter, report.consecutive_failures), (3, 0));
+        assert!(report.last_success > 0 && report.verify(&key, &mac));
+
+        assert!(!health.should_terminate());
+        stub.set_state(&StubState { parent_requests_kill: true, ..Default::default() });
+        assert!(health.should_terminate());
+        assert_eq!(health.base_pid(), None);
+
+        // The same over a socketpair, as a restarted overload that finds the
+        // frames its predecessor left before the current one
+        let mut sockets = [0; 2];
+        unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, sockets.as_mut_ptr()) };
+        let mut stub = unsafe { std::fs::File::from_raw_fd(sockets[0]) };
+        stub.write_all(&StubState::default().to_frame()).unwrap();
+        stub.write_all(&StubState { accepted_success: 1234, ..Default::default() }.to_frame()).unwrap();
+        unsafe { libc::pipe(fds.as_mut_ptr()) };
+        unsafe { std::fs::File::from_raw_fd(fds[1]) }.write_all(&key).unwrap();
+        std::env::set_var(HEALTH_CHANNEL_ENV, format!("stream:{}:{}", sockets[1], fds[0]));
+
+        let health = Health::connect().unwrap();
+        assert_eq!(health.report_failure(), 1);
+        let mut frame = [0u8; protocol::REPORT_FRAME_LEN];
+        stub.read_exact(&mut frame).unwrap();
+        let (report, mac) = Report::from_frame(&frame);
+        assert_eq!((report.counter, report.consecutive_failures, report.last_success), (1, 1, 1234));
+        assert!(report.verify(&key, &mac));
+
+        assert_eq!(health.base_pid(), None);
+        stub.write_all(&StubState { base_pid: 42, ..Default::default() }.to_frame()).unwrap();
+        stub.write_all(&StubState { base_pid: 42, parent_requests_kill: true, ..Default::default() }.to_frame()).unwrap();
+        assert_eq!(health.base_pid(), Some(42));
+        assert!(health.should_terminate());
+        assert_eq!(health.base_exit(), None);
+        let exit = protocol::BaseExit { signal: 9, runtime_ms: 2000, shutdown_window: 5, ..Default::default() };
+        stub.write_all(&StubState { base_pid: 42, base_exit: Some(exit), ..Default::default() }.to_frame()).unwrap();
+        assert_eq!(health.base_exit(), Some(exit));
+        assert_eq!(health.base_usage(), None);
+        let usage = protocol::BaseUsage { sampled_at: 100, cpu_ms: 1500, rss: 1 << 20, threads: 3, children: 1 };
+        stub.write_all(&StubState { base_pid: 42, base_usage: Some(usage), ..Default::default() }.to_frame()).unwrap();
+        assert_eq!(health.base_usage(), Some(usage));
+    }
+}
@@ -0,0 +1,570 @@
+//! Layout of the health channel. The stub sets it up and hands it to the
+//! overload alone, as an inherited descriptor named in [`HEALTH_CHANNEL_ENV`],
+//! together with a per-launch key. The channel is either a shared memory
+//! region laid out as [`HealthStatus`], or a stream (a socketpair, or a named
+//! pipe on Windows) carrying fixed-size frames, for hosts where no region can
+//! be created.
+//!
+//! The overload's state travels as a [`Report`]: a counter and the values,
+//! authenticated with HMAC-SHA256 under that key. The stub only accepts a
+//! report whose MAC verifies and whose counter is higher than the last one it
+//! accepted, so a process that gets hold of the region can neither forge nor
+//! replay one. Reports are written under a sequence lock (odd while a write
+//! is in progress), so the stub never reads half of one.
+//!
+//! The fields the stub writes are independent flags, so relaxed ordering is
+//! enough for them. The magic is published last with release ordering: a
+//! reader that sees it also sees the rest of the header.
+//!
+//! Timestamps are seconds on the [`clock`] both sides share: time since boot,
+//! including suspend. Setting the system clock doesn't move it, so it can
+//! neither stretch nor cut short a grace period.
+//!
+//! Compatible changes only append fields and grow `size`. Anything else bumps
+//! [`HEALTH_VERSION`], which readers must match exactly.
+
+use std::fmt;
+use std::sync::atomic::{fence, AtomicI32, AtomicI64, AtomicU32, AtomicU64, Ordering};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// `"KCHS"` in little-endian byte order
+pub const HEALTH_MAGIC: u32 = u32::from_le_bytes(*b"KCHS");
+
+/// Current layout. Version 1 was the unversioned struct without a header.
+pub const HEALTH_VERSION: u16 = 2;
+
+/// Environment variable naming the transport and the inherited channel and
+/// key descriptors, as `<transport>:<channel>:<key>` (file descriptors on
+/// Unix, handle values on Windows)
+pub const HEALTH_CHANNEL_ENV: &str = "KILLCODE_HEALTH_FD";
+
+/// Transport of a channel that is a [`HealthStatus`] region
+pub const TRANSPORT_SHARED_MEMORY: &str = "shm";
+/// Transport of a channel that is a stream of frames
+pub const TRANSPORT_STREAM: &str = "stream";
+
+/// A report followed by its MAC, as the overload writes it to a stream
+pub const REPORT_FRAME_LEN: usize = 64;
+/// A [`StubState`], as the stub writes it to a stream whenever it changes.
+/// The first one is written before the overload starts.
+pub const STATE_FRAME_LEN: usize = 88;
+
+/// Length of the per-launch report key, read once from the key descriptor
+pub const HEALTH_KEY_LEN: usize = 32;
+
+#[repr(C)]
+pub struct HealthHeader {
+    /// [`HEALTH_MAGIC`] once the region is initialized
+    pub magic: AtomicU32,
+    pub version: u16,
+    pub header_size: u16,
+    /// Size of the whole region written by the stub
+    pub size: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+pub struct HealthStatus {
+    pub header: HealthHeader,
+
+    // Written by the overload (see `Report`)
+    /// Sequence lock, odd while a report is being written
+    pub report_sequence: AtomicU64,
+    pub report_counter: AtomicU64,
+    /// Time of the overload's last successful check
+    pub last_success: AtomicI64,
+    /// Time of the overload's last report of any kind
+    pub last_heartbeat: AtomicI64,
+    /// Network failures since the last success
+    pub consecutive_failures: AtomicI32,
+    /// Set by the overload: kill the base
+    pub should_kill_base: AtomicI32,
+    pub report_mac: [AtomicU64; 4],
+
+    // Written by the stub
+    /// Set by the stub: the overload should run its kill method and exit
+    pub parent_requests_kill: AtomicI32,
+    /// PID of the base process
+    pub base_pid: AtomicI32,
+    /// Set by the stub when an integrity check fails
+    pub integrity_violation: AtomicI32,
+    /// Set by the stub once the base ended, after the fields below (see [`BaseExit`])
+    pub base_exited: AtomicI32,
+    /// Time of the last success the stub accepted from a previous overload
+    /// or launch, 0 for a fresh grace period (see [`StubState`])
+    pub accepted_success: AtomicI64,
+    pub exit_code: AtomicI32,
+    pub exit_signal: AtomicI32,
+    pub base_runtime_ms: AtomicU64,
+    pub base_peak_rss: AtomicU64,
+    pub shutdown_window: AtomicU32,
+    pub reserved: AtomicU32,
+    /// Sequence lock over the usage below, odd while the stub writes it and
+    /// 0 until the first sample (see [`BaseUsage`])
+    pub usage_sequence: AtomicU64,
+    pub usage_sampled_at: AtomicI64,
+    pub base_cpu_ms: AtomicU64,
+    pub base_rss: AtomicU64,
+    pub base_threads: AtomicU32,
+    pub base_children: AtomicU32,
+}
+
+/// Seconds since boot, counting time spent suspended. Every timestamp on the
+/// channel is given on this clock.
+pub fn clock() -> i64 {
+    #[cfg(unix)]
+    {
+        // macOS's monotonic clock keeps counting while the system sleeps
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let id = libc::CLOCK_BOOTTIME;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let id = libc::CLOCK_MONOTONIC;
+        let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(id, &mut now) };
+        // time_t is 32 bits on some targets
+        #[allow(clippy::unnecessary_cast)]
+        let seconds = now.tv_sec as i64;
+        seconds
+    }
+    #[cfg(windows)]
+    {
+        (unsafe { windows_sys::Win32::System::SystemInformation::GetTickCount64() } / 1000) as i64
+    }
+}
+
+/// The overload's state as one authenticated unit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Increases with every report. 0 means the overload hasn't reported yet.
+    pub counter: u64,
+    pub last_success: i64,
+    /// When the overload last reported. The stub's heartbeat timeout runs from here.
+    pub last_heartbeat: i64,
+    pub consecutive_failures: i32,
+    pub should_kill_base: bool,
+}
+
+impl Report {
+    /// HMAC-SHA256 over the counter and the values
+    pub fn mac(&self, key: &[u8; HEALTH_KEY_LEN]) -> [u8; 32] {
+        self.hmac(key).finalize().into_bytes().into()
+    }
+
+    /// Check a MAC in constant time
+    pub fn verify(&self, key: &[u8; HEALTH_KEY_LEN], mac: &[u8; 32]) -> bool {
+        self.hmac(key).verify_slice(mac).is_ok()
+    }
+
+    fn hmac(&self, key: &[u8; HEALTH_KEY_LEN]) -> Hmac<Sha256> {
+        let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        // The magic and version keep it apart from anything else, and unlike
+        // a label they don't show up as text in the stub
+        hmac.update(&HEALTH_MAGIC.to_le_bytes());
+        hmac.update(&HEALTH_VERSION.to_le_bytes());
+        hmac.update(&self.counter.to_le_bytes());
+        hmac.update(&self.last_success.to_le_bytes());
+        hmac.update(&self.last_heartbeat.to_le_bytes());
+        hmac.update(&self.consecutive_failures.to_le_bytes());
+        hmac.update(&[self.should_kill_base as u8]);
+        hmac
+    }
+}
+
+impl Report {
+    pub fn to_frame(&self, mac: &[u8; 32]) -> [u8; REPORT_FRAME_LEN] {
+        let mut frame = [0u8; REPORT_FRAME_LEN];
+        frame[..8].copy_from_slice(&self.counter.to_le_bytes());
+        frame[8..16].copy_from_slice(&self.last_success.to_le_bytes());
+        frame[16..24].copy_from_slice(&self.last_heartbeat.to_le_bytes());
+        frame[24..28].copy_from_slice(&self.consecutive_failures.to_le_bytes());
+        frame[28] = self.should_kill_base as u8;
+        frame[32..].copy_from_slice(mac);
+        frame
+    }
+
+    /// The report and MAC in a frame, unverified
+    pub fn from_frame(frame: &[u8; REPORT_FRAME_LEN]) -> (Report, [u8; 32]) {
+        let report = Report {
+            counter: u64::from_le_bytes(frame[..8].try_into().unwrap()),
+            last_success: i64::from_le_bytes(frame[8..16].try_into().unwrap()),
+            last_heartbeat: i64::from_le_bytes(frame[16..24].try_into().unwrap()),
+            consecutive_failures: i32::from_le_bytes(frame[24..28].try_into().unwrap()),
+            should_kill_base: frame[28] != 0,
+        };
+        (report, frame[32..].try_into().unwrap())
+    }
+}
+
+/// What the stub tells the overload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StubState {
+    /// The overload should run its kill method and exit
+    pub parent_requests_kill: bool,
+    /// An integrity check failed
+    pub integrity_violation: bool,
+    /// PID of the base, 0 until it runs
+    pub base_pid: i32,
+    /// Last success the stub accepted before the overload was restarted, or
+    /// that an earlier launch left on disk. The overload's grace period runs
+    /// on from here, and it may lie before boot (negative). 0 for a fresh
+    /// grace period, [`NOT_VERIFIED`] while the base waits for the first
+    /// success.
+    pub accepted_success: i64,
+    /// Set once the base ended. The stub terminates the overload after
+    /// `shutdown_window`.
+    pub base_exit: Option<BaseExit>,
+    /// The base's latest resource use, once the stub sampled it
+    pub base_usage: Option<BaseUsage>,
+}
+
+/// How the base ended
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BaseExit {
+    /// Exit code, 0 when a signal ended the base
+    pub code: i32,
+    /// Signal that ended the base, 0 if it exited (always 0 on Windows)
+    pub signal: i32,
+    /// Milliseconds from the base's start to its end
+    pub runtime_ms: u64,
+    /// Peak resident set size in bytes (the peak working set on Windows), 0
+    /// if unknown
+    pub peak_rss: u64,
+    /// Seconds the stub waits for the overload to exit before terminating it
+    pub shutdown_window: u32,
+}
+
+/// What the base and everything it started use, sampled by the stub from
+/// `/proc` (Linux only)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BaseUsage {
+    /// When the stub took the sample, on [`clock`]
+    pub sampled_at: i64,
+    /// CPU time (user and system), including that of exited processes the
+    /// tree waited for. Never decreases.
+    pub cpu_ms: u64,
+    /// Resident set size in bytes
+    pub rss: u64,
+    pub threads: u32,
+    /// Running processes the base started, at any depth
+    pub children: u32,
+}
+
+/// `accepted_success` in hybrid mode: the overload's reports carry no success
+/// (`last_success` 0) until its check passed
+pub const NOT_VERIFIED: i64 = i64::MIN;
+
+impl StubState {
+    /// Frames start with the magic and version, so the first one the
+    /// overload reads also validates the stream
+    pub fn to_frame(&self) -> [u8; STATE_FRAME_LEN] {
+        let mut frame = [0u8; STATE_FRAME_LEN];
+        frame[..4].copy_from_slice(&HEALTH_MAGIC.to_le_bytes());
+        frame[4..6].copy_from_slice(&HEALTH_VERSION.to_le_bytes());
+        frame[6] = self.parent_requests_kill as u8;
+        frame[7] = self.integrity_violation as u8;
+        frame[8..12].copy_from_slice(&self.base_pid.to_le_bytes());
+        frame[16..24].copy_from_slice(&self.accepted_success.to_le_bytes());
+        if let Some(exit) = &self.base_exit {
+            frame[12] = 1;
+            frame[24..28].copy_from_slice(&exit.code.to_le_bytes());
+            frame[28..32].copy_from_slice(&exit.signal.to_le_bytes());
+            frame[32..40].copy_from_slice(&exit.runtime_ms.to_le_bytes());
+            frame[40..48].copy_from_slice(&exit.peak_rss.to_le_bytes());
+            frame[48..52].copy_from_slice(&exit.shutdown_window.to_le_bytes());
+        }
+        if let Some(usage) = &self.base_usage {
+            frame[13] = 1;
+            frame[56..64].copy_from_slice(&usage.sampled_at.to_le_bytes());
+            frame[64..72].copy_from_slice(&usage.cpu_ms.to_le_bytes());
+            frame[72..80].copy_from_slice(&usage.rss.to_le_bytes());
+            frame[80..84].copy_from_slice(&usage.threads.to_le_bytes());
+            frame[84..88].copy_from_slice(&usage.children.to_le_bytes());
+        }
+        frame
+    }
+
+    pub fn from_frame(frame: &[u8; STATE_FRAME_LEN]) -> Result<Self, ProtocolError> {
+        if u32::from_le_bytes(frame[..4].try_into().unwrap()) != HEALTH_MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+        let version = u16::from_le_bytes(frame[4..6].try_into().unwrap());
+        if version != HEALTH_VERSION {
+            return Err(ProtocolError::Version(version));
+        }
+        Ok(StubState {
+            parent_requests_kill: frame[6] != 0,
+            integrity_violation: frame[7] != 0,
+            base_pid: i32::from_le_bytes(frame[8..12].try_into().unwrap()),
+            accepted_success: i64::from_le_bytes(frame[16..24].try_into().unwrap()),
+            base_exit: (frame[12] != 0).then(|| BaseExit {
+                code: i32::from_le_bytes(frame[24..28].try_into().unwrap()),
+                signal: i32::from_le_bytes(frame[28..32].try_into().unwrap()),
+                runtime_ms: u64::from_le_bytes(frame[32..40].try_into().unwrap()),
+                peak_rss: u64::from_le_bytes(frame[40..48].try_into().unwrap()),
+                shutdown_window: u32::from_le_bytes(frame[48..52].try_into().unwrap()),
+            }),
+            base_usage: (frame[13] != 0).then(|| BaseUsage {
+                sampled_at: i64::from_le_bytes(frame[56..64].try_into().unwrap()),
+                cpu_ms: u64::from_le_bytes(frame[64..72].try_into().unwrap()),
+                rss: u64::from_le_bytes(frame[72..80].try_into().unwrap()),
+                threads: u32::from_le_bytes(frame[80..84].try_into().unwrap()),
+                children: u32::from_le_bytes(frame[84..88].try_into().unwrap()),
+            }),
+        })
+    }
+}
+
+/// Why a region can't be used with this layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Not initialized, or not a health region at all
+    BadMagic,
+    /// Written by a stub with a different layout version
+    Version(u16),
+    /// Smaller than this layout
+    TooSmall(u32),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic => write!(f, "not an initialized health region"),
+            ProtocolError::Version(version) => {
+                write!(f, "health protocol version {} (expected {})", version, HEALTH_VERSION)
+            }
+            ProtocolError::TooSmall(size) => {
+                write!(f, "health region of {} bytes (expected at least {})", size, HealthStatus::SIZE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl HealthStatus {
+    pub const SIZE: usize = std::mem::size_of::<HealthStatus>();
+
+    /// Initialize a freshly mapped region
+    ///
+    /// # Safety
+    /// `status` must point to at least [`HealthStatus::SIZE`] writable bytes
+    /// that no other process reads yet.
+    pub unsafe fn init(status: *mut HealthStatus) {
+        std::ptr::write_bytes(status as *mut u8, 0, Self::SIZE);
+        let status = &mut *status;
+        status.header.version = HEALTH_VERSION;
+        status.header.header_size = std::mem::size_of::<HealthHeader>() as u16;
+        status.header.size = Self::SIZE as u32;
+        status.header.magic.store(HEALTH_MAGIC, Ordering::Release);
+    }
+
+    /// Check that the region was initialized with this layout
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        if self.header.magic.load(Ordering::Acquire) != HEALTH_MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+        if self.header.version != HEALTH_VERSION {
+            return Err(ProtocolError::Version(self.header.version));
+        }
+        if (self.header.size as usize) < Self::SIZE {
+            return Err(ProtocolError::TooSmall(self.header.size));
+        }
+        Ok(())
+    }
+
+    /// Write a report and its MAC. Only one writer (the overload) may call this.
+    pub fn publish(&self, report: &Report, key: &[u8; HEALTH_KEY_LEN]) {
+        let sequence = self.report_sequence.load(Ordering::Relaxed);
+        self.report_sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.report_counter.store(report.counter, Ordering::Relaxed);
+        self.last_success.store(report.last_success, Ordering::Relaxed);
+        self.last_heartbeat.store(report.last_heartbeat, Ordering::Relaxed);
+        self.consecutive_failures.store(report.consecutive_failures, Ordering::Relaxed);
+        self.should_kill_base.store(report.should_kill_base as i32, Ordering::Relaxed);
+        let mac = report.mac(key);
+        for (word, bytes) in self.report_mac.iter().zip(mac.chunks_exact(8)) {
+            word.store(u64::from_le_bytes(bytes.try_into().unwrap()), Ordering::Relaxed);
+        }
+
+        self.report_sequence.store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    pub fn state(&self) -> StubState {
+        // The exit is published last
+        let base_exit = (self.base_exited.load(Ordering::Acquire) != 0).then(|| BaseExit {
+            code: self.exit_code.load(Ordering::Relaxed),
+            signal: self.exit_signal.load(Ordering::Relaxed),
+            runtime_ms: self.base_runtime_ms.load(Ordering::Relaxed),
+            peak_rss: self.base_peak_rss.load(Ordering::Relaxed),
+            shutdown_window: self.shutdown_window.load(Ordering::Relaxed),
+        });
+        StubState {
+            parent_requests_kill: self.parent_requests_kill.load(Ordering::Relaxed) != 0,
+            integrity_violation: self.integrity_violation.load(Ordering::Relaxed) != 0,
+            base_pid: self.base_pid.load(Ordering::Relaxed),
+            accepted_success: self.accepted_success.load(Ordering::Relaxed),
+            base_exit,
+            base_usage: self.usage(),
+        }
+    }
+
+    /// The latest usage sample. The stub only holds the lock for a few
+    /// stores, so a read that overlaps one is simply retried.
+    fn usage(&self) -> Option<BaseUsage> {
+        loop {
+            let before = self.usage_sequence.load(Ordering::Acquire);
+            if before == 0 {
+                return None;
+            }
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let usage = BaseUsage {
+                sampled_at: self.usage_sampled_at.load(Ordering::Relaxed),
+                cpu_ms: self.base_cpu_ms.load(Ordering::Relaxed),
+                rss: self.base_rss.load(Ordering::Relaxed),
+                threads: self.base_threads.load(Ordering::Relaxed),
+                children: self.base_children.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.usage_sequence.load(Ordering::Relaxed) == before {
+                return Some(usage);
+            }
+        }
+    }
+
+    /// Only the stub calls this
+    pub fn set_state(&self, state: &StubState) {
+        self.parent_requests_kill.store(state.parent_requests_kill as i32, Ordering::Relaxed);
+        self.integrity_violation.store(state.integrity_violation as i32, Ordering::Relaxed);
+        self.base_pid.store(state.base_pid, Ordering::Relaxed);
+        self.accepted_success.store(state.accepted_success, Ordering::Relaxed);
+        match &state.base_exit {
+            Some(exit) => {
+                self.exit_code.store(exit.code, Ordering::Relaxed);
+                self.exit_signal.store(exit.signal, Ordering::Relaxed);
+                self.base_runtime_ms.store(exit.runtime_ms, Ordering::Relaxed);
+                self.base_peak_rss.store(exit.peak_rss, Ordering::Relaxed);
+                self.shutdown_window.store(exit.shutdown_window, Ordering::Relaxed);
+                self.base_exited.store(1, Ordering::Release);
+            }
+            None => self.base_exited.store(0, Ordering::Relaxed),
+        }
+        if let Some(usage) = &state.base_usage {
+            let sequence = self.usage_sequence.load(Ordering::Relaxed);
+            self.usage_sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+            fence(Ordering::Release);
+            self.usage_sampled_at.store(usage.sampled_at, Ordering::Relaxed);
+            self.base_cpu_ms.store(usage.cpu_ms, Ordering::Relaxed);
+            self.base_rss.store(usage.rss, Ordering::Relaxed);
+            self.base_threads.store(usage.threads, Ordering::Relaxed);
+            self.base_children.store(usage.children, Ordering::Relaxed);
+            self.usage_sequence.store(sequence.wrapping_add(2), Ordering::Release);
+        }
+    }
+
+    /// Drop the last report once its writer is gone, before another overload
+    /// takes over the region. Only the stub calls this.
+    pub fn clear_report(&self) {
+        self.publish(&Report::default(), &[0u8; HEALTH_KEY_LEN]);
+    }
+
+    /// The last complete report and its MAC, unverified. None while the
+    /// overload is writing one.
+    pub fn read_report(&self) -> Option<(Report, [u8; 32])> {
+        let before = self.report_sequence.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            return None;
+        }
+
+        let report = Report {
+            counter: self.report_counter.load(Ordering::Relaxed),
+            last_success: self.last_success.load(Ordering::Relaxed),
+            last_heartbeat: self.last_heartbeat.load(Ordering::Relaxed),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            should_kill_base: self.should_kill_base.load(Ordering::Relaxed) != 0,
+        };
+        let mut mac = [0u8; 32];
+        for (bytes, word) in mac.chunks_exact_mut(8).zip(&self.report_mac) {
+            bytes.copy_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+        }
+
+        fence(Ordering::Acquire);
+        (self.report_sequence.load(Ordering::Relaxed) == before).then_some((report, mac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::{offset_of, MaybeUninit};
+
+    #[test]
+    fn test_layout_and_reports() {
+        // Offsets are part of the protocol
+        assert_eq!(offset_of!(HealthStatus, report_sequence), 16);
+        assert_eq!(offset_of!(HealthStatus, last_heartbeat), 40);
+        assert_eq!(offset_of!(HealthStatus, report_mac), 56);
+        assert_eq!(offset_of!(HealthStatus, parent_requests_kill), 88);
+        assert_eq!(offset_of!(HealthStatus, exit_code), 112);
+        assert_eq!(offset_of!(HealthStatus, usage_sequence), 144);
+        assert_eq!(HealthStatus::SIZE, 184);
+
+        let mut region = MaybeUninit::<HealthStatus>::uninit();
+        unsafe { std::ptr::write_bytes(region.as_mut_ptr(), 0, 1) };
+        let status = unsafe { region.assume_init_mut() };
+        assert_eq!(status.validate(), Err(ProtocolError::BadMagic));
+
+        unsafe { HealthStatus::init(status) };
+        assert_eq!(status.validate(), Ok(()));
+        let (empty, _) = status.read_report().unwrap();
+        assert_eq!(empty.counter, 0);
+
+        let key = [7u8; HEALTH_KEY_LEN];
+        let report = Report { counter: 1, last_success: 1234, last_heartbeat: 1240, ..Default::default() };
+        status.publish(&report, &key);
+        let (read, mac) = status.read_report().unwrap();
+        assert_eq!(read, report);
+        assert!(read.verify(&key, &mac));
+        assert!(!read.verify(&[8u8; HEALTH_KEY_LEN], &mac));
+        let forged = Report { last_success: 9999, ..read };
+        assert!(!forged.verify(&key, &mac));
+
+        status.clear_report();
+        assert_eq!(status.read_report().unwrap().0.counter, 0);
+
+        // A write in progress is never read
+        status.report_sequence.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(status.read_report(), None);
+
+        // Stream frames carry the same values
+        let frame = read.to_frame(&mac);
+        assert_eq!(Report::from_frame(&frame), (read, mac));
+        let state = StubState { parent_requests_kill: true, base_pid: 42, accepted_success: 1234, ..Default::default() };
+        status.set_state(&state);
+        assert_eq!(status.state(), state);
+        assert_eq!(StubState::from_frame(&state.to_frame()), Ok(state));
+        let exit = BaseExit { code: 3, runtime_ms: 1500, peak_rss: 4 << 20, shutdown_window: 5, ..Default::default() };
+        let exited = StubState { base_pid: 42, base_exit: Some(exit), ..state };
+        status.set_state(&exited);
+        assert_eq!(status.state(), exited);
+        assert_eq!(StubState::from_frame(&exited.to_frame()), Ok(exited));
+        let usage = BaseUsage { sampled_at: 1300, cpu_ms: 4200, rss: 8 << 20, threads: 5, children: 2 };
+        let sampled = StubState { base_usage: Some(usage), ..exited };
+        status.set_state(&sampled);
+        assert_eq!(status.state(), sampled);
+        assert_eq!(StubState::from_frame(&sampled.to_frame()), Ok(sampled));
+        assert_eq!(StubState::from_frame(&[0u8; STATE_FRAME_LEN]), Err(ProtocolError::BadMagic));
+
+        status.header.version = 1;
+        assert_eq!(status.validate(), Err(ProtocolError::Version(1)));
+        status.header.version = HEALTH_VERSION;
+        status.header.size = 48;
+        assert_eq!(status.validate(), Err(ProtocolError::TooSmall(48)));
+    }
+}
@@ -0,0 +1,35 @@
+//! Builds one variant of the stub.
+//!
+//! Weaver embeds several stubs per platform, each built with a different
+//! nonzero `KILLCODE_STUB_VARIANT`, and picks one per merge. The variant
+//! shuffles the order of the stub's functions (x86-64 Linux, linked by LLD)
+//! and masks the constants `variant::constant` hides with a key derived from
+//! it. Without it, the plain stub is built.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// splitmix64, so nearby variant numbers give unrelated keys
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=KILLCODE_STUB_VARIANT");
+    let variant: u64 = env::var("KILLCODE_STUB_VARIANT").ok().and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+
+    let mask = if variant == 0 { 0 } else { mix(variant) };
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("variant.rs");
+    fs::write(out, format!("pub const CONSTANT_MASK: u64 = {:#x};\n", mask)).unwrap();
+
+    // Rust links x86-64 Linux binaries with its own LLD. LLD takes a 32-bit
+    // seed, where 0 is random and -1 reverses.
+    if variant != 0 && env::var("TARGET").unwrap() == "x86_64-unknown-linux-gnu" {
+        let seed = (mix(variant ^ 0x5eed) as u32 & 0x7fff_ffff) | 1;
+        println!("cargo:rustc-link-arg-bins=-Wl,--shuffle-sections=.text.*={}", seed);
+    }
+}
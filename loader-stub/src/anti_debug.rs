@@ -0,0 +1,82 @@
+use std::os::unix::io::RawFd;
+
+/// Refuse to run under a tracer and watch the stub, base and overload for one
+pub const ANTI_DEBUG_DETECT_TRACER: u8 = 0x01;
+/// Make the stub and its children non-dumpable
+pub const ANTI_DEBUG_NON_DUMPABLE: u8 = 0x02;
+/// Refuse to start when LD_PRELOAD or LD_AUDIT is set
+pub const ANTI_DEBUG_BLOCK_PRELOAD: u8 = 0x04;
+/// Set RLIMIT_CORE to 0 for the stub and its children
+pub const ANTI_DEBUG_NO_CORE_DUMPS: u8 = 0x08;
+
+const INJECTION_VARS: [&str; 2] = ["LD_PRELOAD", "LD_AUDIT"];
+
+/// Apply the protections selected in the footer before anything is launched
+pub fn apply(flags: u8) -> Result<(), String> {
+    if flags & ANTI_DEBUG_BLOCK_PRELOAD != 0 {
+        for var in INJECTION_VARS {
+            if std::env::var_os(var).is_some_and(|value| !value.is_empty()) {
+                return Err(format!("{} is set, refusing to start", var));
+            }
+        }
+    }
+
+    if flags & ANTI_DEBUG_NO_CORE_DUMPS != 0 {
+        // Inherited by the base and overload across fork and exec
+        let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+            return Err(format!("Failed to disable core dumps: {}", std::io::Error::last_os_error()));
+        }
+    }
+
+    if flags & ANTI_DEBUG_NON_DUMPABLE != 0 && unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(format!("Failed to clear dumpable flag: {}", std::io::Error::last_os_error()));
+    }
+
+    if flags & ANTI_DEBUG_DETECT_TRACER != 0 {
+        let tracer = tracer_pid("self");
+        if tracer != 0 {
+            return Err(format!("Debugger attached (tracer PID {}), refusing to start", tracer));
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep a child's image non-dumpable across exec.
+///
+/// exec resets the dumpable flag, except when the executed file is not
+/// readable by the caller. Making the memfd execute-only keeps the base and
+/// overload non-dumpable and hides the image behind /proc/<pid>/exe
+/// (root bypasses file permissions, so this has no effect for root).
+pub fn restrict_image(flags: u8, fd: RawFd) {
+    if flags & ANTI_DEBUG_NON_DUMPABLE != 0 {
+        unsafe {
+            libc::fchmod(fd, 0o100);
+        }
+    }
+}
+
+/// Return the first of `pids` (or the stub itself) that has a tracer attached
+pub fn find_traced(flags: u8, pids: &[i32]) -> Option<i32> {
+    if flags & ANTI_DEBUG_DETECT_TRACER == 0 {
+        return None;
+    }
+    if tracer_pid("self") != 0 {
+        return Some(std::process::id() as i32);
+    }
+    pids.iter().copied().find(|&pid| pid > 0 && tracer_pid(&pid.to_string()) != 0)
+}
+
+/// TracerPid from /proc/<pid>/status, 0 when not traced or unreadable
+fn tracer_pid(pid: &str) -> i32 {
+    std::fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("TracerPid:"))
+                .and_then(|value| value.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,88 @@
+//! Noticing the system clock being set while the base runs.
+//!
+//! Grace periods run on the boot clock the health channel uses, so setting
+//! the wall clock moves neither end of them. The two clocks are still compared
+//! on every monitor cycle: when the wall clock jumps against the boot clock,
+//! someone set it, and the footer's `clock_jump_action` decides whether that
+//! counts as tampering.
+
+use killcode_overload::protocol;
+
+use crate::common::{log_clock_jumped, wall_time};
+use crate::ConfigFooter;
+
+/// Footer `clock_jump_action`: kill the base when the clock is set back (0
+/// only logs jumps)
+pub const CLOCK_JUMP_KILL_ON_ROLLBACK: u8 = 1;
+/// Footer `clock_jump_action`: kill the base when the clock is set either way
+pub const CLOCK_JUMP_KILL: u8 = 2;
+
+/// Drift between the clocks that is adjustment rather than a jump (NTP slewing,
+/// whole-second rounding on both sides)
+const TOLERANCE: i64 = 60;
+
+pub struct ClockWatch {
+    action: u8,
+    /// Both clocks at the last comparison
+    wall: i64,
+    boot: i64,
+}
+
+impl ClockWatch {
+    pub fn from_footer(footer: &ConfigFooter) -> Self {
+        Self { action: footer.clock_jump_action, wall: wall_time(), boot: protocol::clock() }
+    }
+
+    /// Compare the clocks since the last call. A jump is logged; true if the
+    /// base must be killed for it.
+    pub fn tampered(&mut self) -> bool {
+        let (wall, boot) = (wall_time(), protocol::clock());
+        let jump = (wall - self.wall) - (boot - self.boot);
+        self.wall = wall;
+        self.boot = boot;
+        if jump.abs() <= TOLERANCE {
+            return false;
+        }
+        log_clock_jumped(jump);
+        match self.action {
+            CLOCK_JUMP_KILL => true,
+            CLOCK_JUMP_KILL_ON_ROLLBACK => jump < 0,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A watch whose last comparison saw the wall clock `offset` seconds off
+    /// from now, as if it was set by `-offset` since
+    fn watch(action: u8, offset: i64) -> ClockWatch {
+        ClockWatch { action, wall: wall_time() + offset, boot: protocol::clock() }
+    }
+
+    #[test]
+    fn test_clock_jump_actions() {
+        // Set back by an hour
+        assert!(!watch(0, 3600).tampered());
+        assert!(watch(CLOCK_JUMP_KILL_ON_ROLLBACK, 3600).tampered());
+        assert!(watch(CLOCK_JUMP_KILL, 3600).tampered());
+
+        // Set forward by an hour
+        assert!(!watch(0, -3600).tampered());
+        assert!(!watch(CLOCK_JUMP_KILL_ON_ROLLBACK, -3600).tampered());
+        assert!(watch(CLOCK_JUMP_KILL, -3600).tampered());
+    }
+
+    #[test]
+    fn test_clock_drift_tolerated() {
+        assert!(!watch(CLOCK_JUMP_KILL, TOLERANCE - 2).tampered());
+        assert!(!watch(CLOCK_JUMP_KILL, 2 - TOLERANCE).tampered());
+
+        // A jump is judged once, then the clocks are compared from it
+        let mut clock = watch(CLOCK_JUMP_KILL, 3600);
+        assert!(clock.tampered());
+        assert!(!clock.tampered());
+    }
+}
@@ -1,28 +1,96 @@
+use std::fs::File;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{HealthStatus, HEALTH_CHECK_INTERVAL};
+use killcode_overload::protocol::Report;
+use sha2::{Digest, Sha256};
+
+use crate::enforce::{ENFORCE_DEGRADED, ENFORCE_NOTIFY, ENFORCE_SUSPEND, ENFORCE_TERMINATE};
+use crate::logging::{self, Level};
+use crate::strings::{self, S};
+use crate::verify::SYNC_MODE_HYBRID;
+use crate::{ConfigFooter, HEALTH_CHECK_INTERVAL};
+
+/// Size of the NUL-padded log path fields in the footer.
+///
+/// Must match `STDIO_PATH_LEN` in weaver's `core::merger::stdio`.
+pub const STDIO_PATH_LEN: usize = 256;
+
+/// Where a stream goes (footer `overload_std*` and `diagnostics_output`).
+/// Journald is Linux only.
+pub const ROUTE_NULL: u8 = 1;
+pub const ROUTE_FILE: u8 = 2;
+pub const ROUTE_SYSLOG: u8 = 3;
+#[cfg(target_os = "linux")]
+pub const ROUTE_JOURNALD: u8 = 4;
+
+/// Path stored in a NUL-padded footer field
+pub fn footer_path(field: &[u8; STDIO_PATH_LEN]) -> Option<&str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(STDIO_PATH_LEN);
+    std::str::from_utf8(&field[..len]).ok().filter(|path| !path.is_empty())
+}
+
+/// The stub's own arguments for a payload, with argv[0] replaced by its name
+#[cfg(unix)]
+pub fn payload_args(name: &str) -> Vec<std::ffi::OsString> {
+    std::iter::once(name.into()).chain(std::env::args_os().skip(1)).collect()
+}
+
+/// Open a log file for appending, creating it readable by the owner only
+pub fn open_log(path: &str) -> std::io::Result<File> {
+    let mut options = File::options();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
 
-/// Get current Unix timestamp in seconds
+/// Current time on the health channel's clock (seconds since boot), which
+/// setting the system clock doesn't move
 pub fn current_time() -> i64 {
+    killcode_overload::protocol::clock()
+}
+
+/// Current Unix timestamp in seconds, which setting the system clock moves
+pub fn wall_time() -> i64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs() as i64
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Name for a per-launch object (shared memory, payload file) derived from the
+/// merge's seed, so merged binaries don't share recognizable names
+pub fn instance_name(footer: &ConfigFooter, label: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(footer.name_seed)
+        .chain_update(label.as_bytes())
+        .finalize();
+    digest[..6].iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-/// Initialize health status struct with default values
-pub unsafe fn init_health_status(health_ptr: *mut HealthStatus) {
-    (*health_ptr).last_success = current_time();
-    (*health_ptr).is_alive = 1;
-    (*health_ptr).consecutive_failures = 0;
-    (*health_ptr).should_kill_base = 0;
-    (*health_ptr).parent_requests_kill = 0;
-    (*health_ptr).base_pid = 0;
+/// Footer `overload_lost_action`: log a crashed or hung overload and leave
+/// the base to the grace period (0 kills the base)
+pub const OVERLOAD_LOST_CONTINUE: u8 = 1;
+
+/// Check if health monitoring should be enabled. Hybrid mode always needs
+/// the channel to see the overload's first success.
+pub fn should_enable_health_monitoring(footer: &ConfigFooter) -> bool {
+    // Only the Linux stub samples the base's resource use
+    let usage = cfg!(target_os = "linux") && footer.usage_interval > 0;
+    footer.sync_mode == SYNC_MODE_HYBRID
+        || (footer.sync_mode == 0
+            && (footer.grace_period > 0 || footer.network_failure_kill_count > 0 || footer.heartbeat_timeout > 0 || usage))
 }
 
-/// Check if health monitoring should be enabled
-pub fn should_enable_health_monitoring(sync_mode: bool, grace_period: u32, network_failure_kill_count: u32) -> bool {
-    !sync_mode && (grace_period > 0 || network_failure_kill_count > 0)
+/// How the stub lost the overload while the base was running
+pub enum OverloadLoss {
+    /// No report for longer than the heartbeat timeout
+    Unresponsive { silent_for: i64, timeout: u32 },
+    /// Exited with a non-zero code
+    Exited(i32),
+    /// Killed by a signal, e.g. a segfault or the OOM killer
+    #[cfg(unix)]
+    Signaled(nix::sys::signal::Signal),
 }
 
 /// Result of health check evaluation
@@ -35,20 +103,20 @@ pub enum HealthCheckResult {
     NetworkFailureThreshold { failures: i32, threshold: u32 },
     /// Overload requested base termination
     OverloadRequestedKill,
-    /// Overload heartbeat lost
-    HeartbeatLost,
+    /// Overload crashed or hung
+    OverloadLost(OverloadLoss),
 }
 
-/// Evaluate health status and determine if action is needed
-/// Returns None if base process has exited, otherwise returns HealthCheckResult
-pub unsafe fn evaluate_health_status(
-    health_ptr: *const HealthStatus,
+/// Evaluate the overload's last accepted report and determine if action is needed
+pub fn evaluate_health_status(
+    report: &Report,
     grace_period: u32,
     network_failure_kill_count: u32,
+    heartbeat_timeout: u32,
 ) -> HealthCheckResult {
-    let status = &*health_ptr;
     let now = current_time();
-    let time_since_success = now - status.last_success;
+    let time_since_success = now - report.last_success;
+    let failures = report.consecutive_failures;
 
     // Check 1: Grace period
     if grace_period > 0 && time_since_success > grace_period as i64 {
@@ -59,31 +127,27 @@ pub unsafe fn evaluate_health_status(
     }
 
     // Check 2: Network failure threshold
-    if network_failure_kill_count > 0 && status.consecutive_failures >= network_failure_kill_count as i32 {
+    if network_failure_kill_count > 0 && failures >= network_failure_kill_count as i32 {
         return HealthCheckResult::NetworkFailureThreshold {
-            failures: status.consecutive_failures,
+            failures,
             threshold: network_failure_kill_count,
         };
     }
 
     // Check 3: Overload requested base kill
-    if status.should_kill_base != 0 {
+    if report.should_kill_base {
         return HealthCheckResult::OverloadRequestedKill;
     }
 
-    // Check 4: Overload heartbeat lost
-    if status.is_alive == 0 {
-        return HealthCheckResult::HeartbeatLost;
+    // Check 4: Overload stopped reporting
+    let silent_for = now - report.last_heartbeat;
+    if heartbeat_timeout > 0 && silent_for > heartbeat_timeout as i64 {
+        return HealthCheckResult::OverloadLost(OverloadLoss::Unresponsive { silent_for, timeout: heartbeat_timeout });
     }
 
     HealthCheckResult::Ok
 }
 
-/// Signal overload to execute kill method by setting parent_requests_kill flag
-pub unsafe fn signal_overload_to_kill(health_ptr: *mut HealthStatus) {
-    (*health_ptr).parent_requests_kill = 1;
-}
-
 /// Get the health check interval as a Duration
 pub fn health_check_interval() -> std::time::Duration {
     std::time::Duration::from_secs(HEALTH_CHECK_INTERVAL as u64)
@@ -94,99 +158,301 @@ pub fn overload_kill_wait_duration() -> std::time::Duration {
     std::time::Duration::from_secs(15)
 }
 
-// Log message helpers - centralized logging for consistent output
+// Log message helpers - centralized logging for consistent output.
+// The text lives in the encrypted string table (see strings.rs).
+
+fn emit(level: Level, id: S, args: &[&dyn std::fmt::Display]) {
+    logging::write(level, &strings::format(id, args));
+}
 
-pub fn log_health_monitoring_enabled(shm_name: &str) {
-    eprintln!("[KillCode] Health monitoring enabled: {}", shm_name);
+pub fn log_stub_starting(footer: &ConfigFooter) {
+    emit(Level::Info, S::StubStarting, &[]);
+    emit(Level::Debug, S::StubConfig, &[&footer.sync_mode, &footer.grace_period, &footer.network_failure_kill_count]);
+}
+
+pub fn log_health_monitoring_enabled(transport: &str) {
+    emit(Level::Debug, S::HealthMonitoringEnabled, &[&transport]);
+}
+
+pub fn log_health_report_rejected(kind: &str, counter: u64) {
+    emit(Level::Error, S::HealthReportRejected, &[&kind, &counter]);
 }
 
 pub fn log_health_monitor_started() {
-    eprintln!("[KillCode] Health monitor started");
+    emit(Level::Debug, S::HealthMonitorStarted, &[]);
 }
 
 pub fn log_sync_mode_waiting(pid: impl std::fmt::Display) {
-    eprintln!("[KillCode] Sync mode: Waiting for overload verification (PID: {})...", pid);
+    emit(Level::Debug, S::SyncModeWaiting, &[&pid]);
 }
 
 pub fn log_verification_failed(exit_code: impl std::fmt::Display) {
-    eprintln!("[KillCode] ❌ Overload verification failed (exit code: {})", exit_code);
+    emit(Level::Error, S::VerificationFailed, &[&exit_code]);
 }
 
 pub fn log_verification_successful() {
-    eprintln!("[KillCode] ✅ Overload verification successful");
+    emit(Level::Info, S::VerificationSuccessful, &[]);
+}
+
+pub fn log_hybrid_mode_waiting(pid: impl std::fmt::Display) {
+    emit(Level::Debug, S::HybridModeWaiting, &[&pid]);
+}
+
+pub fn log_verification_rejected(reason: &str) {
+    emit(Level::Error, S::VerificationRejected, &[&reason]);
+}
+
+pub fn log_verification_timed_out(timeout: u32) {
+    emit(Level::Error, S::VerificationTimedOut, &[&timeout]);
+}
+
+pub fn log_starting_unverified() {
+    emit(Level::Info, S::StartingUnverified, &[]);
+}
+
+pub fn log_reverification_started(pid: impl std::fmt::Display) {
+    emit(Level::Debug, S::ReverificationStarted, &[&pid]);
+}
+
+pub fn log_reverification_passed() {
+    emit(Level::Debug, S::ReverificationPassed, &[]);
+}
+
+pub fn log_reverification_timed_out(timeout: u64) {
+    emit(Level::Error, S::ReverificationTimedOut, &[&timeout]);
+}
+
+pub fn log_reverification_start_failed(error: &str) {
+    emit(Level::Error, S::ReverificationStartFailed, &[&error]);
+}
+
+pub fn log_reverification_failures(failures: i32, threshold: u32) {
+    emit(Level::Error, S::ReverificationFailures, &[&failures, &threshold]);
+}
+
+pub fn log_clock_jumped(jump: i64) {
+    emit(Level::Error, S::ClockJumped, &[&jump]);
+}
+
+pub fn log_clock_tampered() {
+    emit(Level::Error, S::ClockTampered, &[]);
+}
+
+pub fn log_enforcing(action: u8, terminate_timeout: u64) {
+    match action {
+        ENFORCE_TERMINATE => emit(Level::Error, S::EnforceTerminate, &[&terminate_timeout]),
+        ENFORCE_SUSPEND => emit(Level::Error, S::EnforceSuspend, &[]),
+        ENFORCE_NOTIFY => emit(Level::Error, S::EnforceNotify, &[]),
+        ENFORCE_DEGRADED => emit(Level::Error, S::EnforceDegraded, &[]),
+        _ => emit(Level::Error, S::EnforceKill, &[]),
+    }
+}
+
+pub fn log_base_restored() {
+    emit(Level::Info, S::BaseRestored, &[]);
+}
+
+pub fn log_status_write_failed(error: &str) {
+    emit(Level::Error, S::StatusWriteFailed, &[&error]);
+}
+
+pub fn log_signaling_overload_kill() {
+    emit(Level::Error, S::SignalingOverloadKill, &[]);
+}
+
+pub fn log_grace_record_resumed(age: i64) {
+    emit(Level::Info, S::GraceRecordResumed, &[&age]);
+}
+
+pub fn log_grace_record_missing(reason: &str) {
+    emit(Level::Info, S::GraceRecordMissing, &[&reason]);
+}
+
+pub fn log_grace_record_tampered(reason: &str) {
+    emit(Level::Error, S::GraceRecordTampered, &[&reason]);
+}
+
+pub fn log_grace_record_save_failed(error: &str) {
+    emit(Level::Error, S::GraceRecordSaveFailed, &[&error]);
 }
 
 pub fn log_async_mode_started(pid: impl std::fmt::Display) {
-    eprintln!("[KillCode] Async mode: Overload running in background (PID: {})", pid);
+    emit(Level::Debug, S::AsyncModeStarted, &[&pid]);
 }
 
 pub fn log_overload_start_failed(error: &str) {
-    eprintln!("[KillCode] Failed to start overload binary: {}", error);
+    emit(Level::Error, S::OverloadStartFailed, &[&error]);
 }
 
 #[cfg(any(target_os = "windows", target_os = "macos"))]
 pub fn log_base_start_failed(error: &str) {
-    eprintln!("[KillCode] Failed to start base binary: {}", error);
+    emit(Level::Error, S::BaseStartFailed, &[&error]);
 }
 
 pub fn log_starting_base() {
-    eprintln!("[KillCode] Starting base binary...");
+    emit(Level::Debug, S::StartingBase, &[]);
 }
 
 pub fn log_base_completed_terminating_overload(pid: impl std::fmt::Display) {
-    eprintln!("[KillCode] Base binary completed, terminating overload (PID: {})", pid);
+    emit(Level::Debug, S::BaseCompletedTerminatingOverload, &[&pid]);
+}
+
+pub fn log_overload_shutdown_window(seconds: u32) {
+    emit(Level::Debug, S::OverloadShutdownWindow, &[&seconds]);
+}
+
+pub fn log_overload_shut_down() {
+    emit(Level::Debug, S::OverloadShutDown, &[]);
 }
 
 pub fn log_base_exited(exit_code: impl std::fmt::Display) {
-    eprintln!("[KillCode] Base binary exited with code: {}", exit_code);
+    emit(Level::Info, S::BaseExited, &[&exit_code]);
 }
 
 pub fn log_grace_period_exceeded(time_since_success: i64, grace_period: u32) {
-    eprintln!("[KillCode] ⚠️  Grace period exceeded ({} > {} seconds), killing base", time_since_success, grace_period);
+    emit(Level::Error, S::GracePeriodExceeded, &[&time_since_success, &grace_period]);
 }
 
 pub fn log_network_failure_threshold(failures: i32, threshold: u32) {
-    eprintln!("[KillCode] ⚠️  Network failure threshold exceeded ({}/{}), signaling overload to kill parent", failures, threshold);
+    emit(Level::Error, S::NetworkFailureThreshold, &[&failures, &threshold]);
 }
 
 pub fn log_fallback_kill() {
-    eprintln!("[KillCode] Fallback: Killing base directly (overload didn't respond)");
+    emit(Level::Error, S::FallbackKill, &[]);
 }
 
 pub fn log_overload_requested_kill() {
-    eprintln!("[KillCode] ⚠️  Overload requested base termination");
+    emit(Level::Error, S::OverloadRequestedKill, &[]);
+}
+
+pub fn log_overload_lost(loss: &OverloadLoss) {
+    match loss {
+        OverloadLoss::Unresponsive { silent_for, timeout } => {
+            emit(Level::Error, S::OverloadUnresponsive, &[silent_for, timeout])
+        }
+        OverloadLoss::Exited(code) => emit(Level::Error, S::OverloadCrashed, &[code]),
+        #[cfg(unix)]
+        OverloadLoss::Signaled(signal) => emit(Level::Error, S::OverloadKilled, &[signal]),
+    }
+}
+
+pub fn log_overload_exited() {
+    emit(Level::Info, S::OverloadExited, &[]);
+}
+
+pub fn log_overload_restart_scheduled(delay: std::time::Duration, restart: u32, max_restarts: u32) {
+    emit(Level::Info, S::OverloadRestartScheduled, &[&delay.as_secs(), &restart, &max_restarts]);
 }
 
-pub fn log_heartbeat_lost() {
-    eprintln!("[KillCode] ⚠️  Overload heartbeat lost, killing base");
+pub fn log_overload_restarted(pid: impl std::fmt::Display) {
+    emit(Level::Info, S::OverloadRestarted, &[&pid]);
+}
+
+pub fn log_overload_restart_failed(error: &str) {
+    emit(Level::Error, S::OverloadRestartFailed, &[&error]);
 }
 
 #[cfg(target_os = "linux")]
 pub fn log_forcing_sigkill() {
-    eprintln!("[KillCode] Forcing SIGKILL on overload");
+    emit(Level::Debug, S::ForcingSigkill, &[]);
+}
+
+pub fn log_health_channel_failed(error: impl std::fmt::Display) {
+    emit(Level::Error, S::HealthChannelFailed, &[&error]);
 }
 
-pub fn log_shm_map_failed(error: impl std::fmt::Display) {
-    eprintln!("[KillCode] Warning: Failed to map shared memory: {}", error);
+pub fn log_health_channel_required() {
+    emit(Level::Error, S::HealthChannelRequired, &[]);
 }
 
-pub fn log_shm_create_failed(error: impl std::fmt::Display) {
-    eprintln!("[KillCode] Warning: Failed to create shared memory: {}", error);
+pub fn log_health_transport_unavailable(error: &str) {
+    emit(Level::Info, S::HealthTransportUnavailable, &[&error]);
 }
 
 #[cfg(target_os = "macos")]
-pub fn log_overload_terminated_abnormally() {
-    eprintln!("[KillCode] ❌ Overload terminated abnormally");
+pub fn log_execv_failed() {
+    emit(Level::Error, S::ExecvFailed, &[]);
 }
 
 #[cfg(unix)]
-pub fn log_execv_failed() {
-    eprintln!("[KillCode] execv failed");
+pub fn log_overload_terminated_with_status(status: impl std::fmt::Debug) {
+    emit(Level::Error, S::OverloadTerminatedAbnormally, &[&format!(": {:?}", status)]);
+}
+
+#[cfg(unix)]
+pub fn log_base_waitpid_failed(error: impl std::fmt::Display) {
+    emit(Level::Error, S::WaitpidFailed, &[&error]);
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub fn log_writing_payload(name: &str, size: usize, path: &std::path::Path) {
+    emit(Level::Debug, S::WritingPayload, &[&name, &size, &path.display()]);
 }
 
 #[cfg(unix)]
 pub fn log_base_killed_by_signal(signal: impl std::fmt::Display) {
-    eprintln!("[KillCode] Base process killed by signal: {}", signal);
+    emit(Level::Info, S::BaseKilledBySignal, &[&signal]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_waiting_for_decryption_key(regions: usize) {
+    emit(Level::Debug, S::WaitingForDecryptionKey, &[&regions]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_functions_decrypted(regions: usize) {
+    emit(Level::Debug, S::FunctionsDecrypted, &[&regions]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_lazy_decryption_unavailable() {
+    emit(Level::Info, S::LazyDecryptionUnavailable, &[]);
+}
+
+pub fn log_fatal(error: &dyn std::fmt::Display) {
+    emit(Level::Error, S::Failure, &[error]);
+}
+
+pub fn log_integrity_verified(records: usize) {
+    emit(Level::Debug, S::IntegrityVerified, &[&records]);
+}
+
+pub fn log_integrity_violation(error: &str) {
+    emit(Level::Error, S::Failure, &[&error]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_protection_refused(error: &str) {
+    emit(Level::Error, S::Failure, &[&error]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_debugger_detected(pid: i32) {
+    emit(Level::Error, S::DebuggerDetected, &[&pid]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_in_process_load_failed(name: &str, error: &str) {
+    emit(Level::Error, S::InProcessLoadFailed, &[&name, &error]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_exec_strategy_failed(name: &str, strategy: &str, error: &str) {
+    emit(Level::Debug, S::ExecStrategyFailed, &[&name, &strategy, &error]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_payload_started(name: &str, strategy: &str) {
+    emit(Level::Debug, S::PayloadStarted, &[&name, &strategy]);
+}
+
+#[cfg(target_os = "linux")]
+pub fn log_no_exec_strategy(name: &str, failures: &str) {
+    logging::write_always(&strings::format(S::NoExecStrategy, &[&name, &failures]));
+}
+
+pub fn log_overload_log_failed(path: &str, error: impl std::fmt::Display) {
+    emit(Level::Error, S::OverloadLogFailed, &[&path, &error]);
 }
 
 /// Short delay used when force-killing processes (unix only)
@@ -194,3 +460,30 @@ pub fn log_base_killed_by_signal(signal: impl std::fmt::Display) {
 pub fn force_kill_delay() -> std::time::Duration {
     std::time::Duration::from_millis(100)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(silent_for: i64, heartbeat_timeout: u32) -> HealthCheckResult {
+        let now = current_time();
+        let report = Report { last_success: now, last_heartbeat: now - silent_for, ..Default::default() };
+        evaluate_health_status(&report, 0, 0, heartbeat_timeout)
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        assert!(matches!(evaluate(5, 10), HealthCheckResult::Ok));
+        assert!(matches!(
+            evaluate(30, 10),
+            HealthCheckResult::OverloadLost(OverloadLoss::Unresponsive { silent_for: 30..=31, timeout: 10 })
+        ));
+        // Without a timeout an overload can stay silent
+        assert!(matches!(evaluate(3600, 0), HealthCheckResult::Ok));
+
+        // The overload's own verdicts come before its silence
+        let now = current_time();
+        let report = Report { last_success: now, last_heartbeat: now - 30, should_kill_base: true, ..Default::default() };
+        assert!(matches!(evaluate_health_status(&report, 0, 0, 10), HealthCheckResult::OverloadRequestedKill));
+    }
+}
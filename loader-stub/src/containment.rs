@@ -0,0 +1,334 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+#[cfg(target_os = "linux")]
+use std::{ffi::CString, path::PathBuf, sync::OnceLock, thread, time::Duration};
+
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::{getpgid, getpid, Pid};
+
+#[cfg(target_os = "linux")]
+use crate::{common, ConfigFooter};
+
+/// How often a kill re-scans for processes the tree forked while it was being stopped
+const FREEZE_ROUNDS: usize = 16;
+
+/// How often orphans re-parented to the stub are reaped
+#[cfg(target_os = "linux")]
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often removing a stale cgroup is retried after killing its members
+#[cfg(target_os = "linux")]
+const STALE_CGROUP_ROUNDS: usize = 20;
+
+/// Whether the stub adopts everything the base orphans (`PR_SET_CHILD_SUBREAPER`).
+/// Then daemons the base started stay in the stub's tree and can be found.
+static SUBREAPER: AtomicBool = AtomicBool::new(false);
+
+/// Session of the overload, which is never part of the base's tree
+static OVERLOAD_SESSION: AtomicI32 = AtomicI32::new(0);
+
+/// Cgroup the base is started in, if the stub may create one
+#[cfg(target_os = "linux")]
+static CGROUP: OnceLock<Cgroup> = OnceLock::new();
+
+#[cfg(target_os = "linux")]
+struct Cgroup {
+    dir: PathBuf,
+    /// `cgroup.procs`, prepared before the fork so the child can join without allocating
+    procs: CString,
+}
+
+/// Prepare containment of the base before it is started (memfd mode).
+///
+/// The stub becomes a child subreaper and, if it may create one,
+/// puts the base in a cgroup of its own.
+#[cfg(target_os = "linux")]
+pub fn prepare(footer: &ConfigFooter) {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) } == 0 {
+        SUBREAPER.store(true, Ordering::Relaxed);
+    }
+    if let Some(cgroup) = create_cgroup(footer) {
+        let _ = CGROUP.set(cgroup);
+    }
+}
+
+/// Put the stub itself in the base's cgroup (in-process mode, where the stub
+/// becomes the base). The overload was started before and stays outside.
+#[cfg(target_os = "linux")]
+pub fn enter_in_process(footer: &ConfigFooter) {
+    if let Some(cgroup) = create_cgroup(footer) {
+        if join_cgroup(&cgroup.procs) {
+            let _ = CGROUP.set(cgroup);
+        } else {
+            let _ = std::fs::remove_dir(&cgroup.dir);
+        }
+    }
+}
+
+/// Called in the forked base before exec: die with the stub, join the
+/// cgroup and, unless the base is a terminal job, lead a process group.
+pub fn enter_base(parent: Pid, own_group: bool) {
+    die_with_parent(parent);
+    #[cfg(target_os = "linux")]
+    if let Some(cgroup) = CGROUP.get() {
+        join_cgroup(&cgroup.procs);
+    }
+    if own_group {
+        unsafe { libc::setpgid(0, 0) };
+    }
+}
+
+/// Called in a forked payload: get SIGKILL when the stub dies, so a killed
+/// stub never leaves the base or the overload running on their own
+pub fn die_with_parent(parent: Pid) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        if libc::getppid() != parent.as_raw() {
+            libc::_exit(1);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = parent;
+}
+
+/// Record the overload (which leads its own session) so its processes are
+/// never mistaken for the base's
+pub fn set_overload(overload: Pid) {
+    OVERLOAD_SESSION.store(overload.as_raw(), Ordering::Relaxed);
+}
+
+/// Send `signal` to the base and everything it started.
+///
+/// For SIGKILL the tree is first stopped, re-scanning until nothing new
+/// shows up, so a forking process can't outrun the kill. The base itself is
+/// signalled last: it may be the stub (in-process mode).
+pub fn signal_tree(base: Pid, signal: Signal) {
+    #[cfg(target_os = "linux")]
+    if let Some(cgroup) = CGROUP.get() {
+        signal_cgroup(cgroup, signal);
+    }
+    if base != getpid() && getpgid(Some(base)) == Ok(base) {
+        let _ = killpg(base, signal);
+    }
+
+    let mut signalled = vec![getpid(), base];
+    for _ in 0..FREEZE_ROUNDS {
+        let fresh: Vec<Pid> = descendants(base)
+            .into_iter()
+            .filter(|pid| !signalled.contains(pid))
+            .collect();
+        if fresh.is_empty() {
+            break;
+        }
+        for &pid in &fresh {
+            let _ = kill(pid, if signal == Signal::SIGKILL { Signal::SIGSTOP } else { signal });
+        }
+        signalled.extend(fresh);
+    }
+    if signal == Signal::SIGKILL {
+        for &pid in &signalled[2..] {
+            let _ = kill(pid, Signal::SIGKILL);
+        }
+    }
+    let _ = kill(base, signal);
+}
+
+/// Reap orphans the stub adopted as subreaper. They are never waited for
+/// otherwise and would stay zombies until the stub exits. The overload,
+/// including one restarted later, is left to the monitor thread.
+#[cfg(target_os = "linux")]
+pub fn start_reaper(base: Pid) {
+    use nix::sys::wait::{waitpid, WaitPidFlag};
+
+    if !SUBREAPER.load(Ordering::Relaxed) {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(REAP_INTERVAL);
+        let stub = getpid();
+        for process in processes() {
+            let overload = Pid::from_raw(OVERLOAD_SESSION.load(Ordering::Relaxed));
+            if process.ppid == stub && process.pid != base && process.pid != overload {
+                let _ = waitpid(process.pid, Some(WaitPidFlag::WNOHANG));
+            }
+        }
+    });
+}
+
+/// Remove the base's cgroup once the stub is done. Left in place if daemons
+/// of a base that exited on its own still live in it.
+#[cfg(target_os = "linux")]
+pub fn release() {
+    if let Some(cgroup) = CGROUP.get() {
+        let _ = std::fs::remove_dir(&cgroup.dir);
+    }
+}
+
+/// Processes below the base, not counting the overload's.
+///
+/// As subreaper the stub adopts whatever the base orphans, so the whole
+/// subtree of the stub is searched; otherwise that of the base.
+pub fn descendants(base: Pid) -> Vec<Pid> {
+    let root = if SUBREAPER.load(Ordering::Relaxed) { getpid() } else { base };
+    let overload_session = Pid::from_raw(OVERLOAD_SESSION.load(Ordering::Relaxed));
+    let children = child_lister();
+    let mut found = Vec::new();
+    let mut parents = vec![root];
+    while let Some(parent) = parents.pop() {
+        for child in children(parent) {
+            if child == overload_session || nix::unistd::getsid(Some(child)) == Ok(overload_session) {
+                continue;
+            }
+            if !found.contains(&child) {
+                found.push(child);
+                parents.push(child);
+            }
+        }
+    }
+    found
+}
+
+/// Lists the children of a process, from one snapshot of `/proc`
+#[cfg(target_os = "linux")]
+fn child_lister() -> impl Fn(Pid) -> Vec<Pid> {
+    let table = processes();
+    move |parent| table.iter().filter(|p| p.ppid == parent).map(|p| p.pid).collect()
+}
+
+#[cfg(target_os = "macos")]
+fn child_lister() -> impl Fn(Pid) -> Vec<Pid> {
+    |parent: Pid| {
+        let mut pids = vec![0 as libc::pid_t; 1024];
+        let size = (pids.len() * std::mem::size_of::<libc::pid_t>()) as i32;
+        let count = unsafe { libc::proc_listchildpids(parent.as_raw(), pids.as_mut_ptr().cast(), size) };
+        pids.truncate(count.max(0) as usize);
+        pids.into_iter().filter(|&pid| pid > 0).map(Pid::from_raw).collect()
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Process {
+    pid: Pid,
+    ppid: Pid,
+}
+
+/// Every process in `/proc` with its parent
+#[cfg(target_os = "linux")]
+fn processes() -> Vec<Process> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter_map(|pid| {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // The command name may contain spaces and parentheses
+            let ppid = stat[stat.rfind(')')? + 1..].split_whitespace().nth(1)?.parse().ok()?;
+            Some(Process { pid: Pid::from_raw(pid), ppid: Pid::from_raw(ppid) })
+        })
+        .collect()
+}
+
+/// A new cgroup below the stub's own on the cgroup v2 hierarchy, if the stub
+/// may create one there (root, or a delegated subtree such as a systemd user
+/// session)
+#[cfg(target_os = "linux")]
+fn create_cgroup(footer: &ConfigFooter) -> Option<Cgroup> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let mount = mountinfo
+        .lines()
+        .find(|line| line.split(" - ").nth(1).is_some_and(|fs| fs.starts_with("cgroup2 ")))?
+        .split_whitespace()
+        .nth(4)?;
+    let own = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let own = own.lines().find_map(|line| line.strip_prefix("0::"))?;
+
+    let parent = PathBuf::from(mount).join(own.trim_start_matches('/'));
+    let prefix = common::instance_name(footer, "cgroup");
+    remove_stale_cgroups(&parent, &prefix);
+
+    let dir = parent.join(format!("{}{}", prefix, getpid()));
+    std::fs::create_dir(&dir).ok()?;
+    let procs = CString::new(dir.join("cgroup.procs").into_os_string().into_encoded_bytes()).ok()?;
+    Some(Cgroup { dir, procs })
+}
+
+/// Remove cgroups of earlier launches of this binary whose stub was killed
+/// before it could clean up.
+///
+/// A killed stub's payloads die with it, but daemons that left the base's
+/// session don't, and nothing else would stop them. Their cgroup is named
+/// after the dead stub, so they are killed here first. Cgroups of stubs that
+/// are still running are left alone.
+#[cfg(target_os = "linux")]
+fn remove_stale_cgroups(parent: &std::path::Path, prefix: &str) {
+    let Ok(entries) = std::fs::read_dir(parent) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Some(stub) = entry.file_name().to_str().and_then(|name| name.strip_prefix(prefix)?.parse().ok()) else {
+            continue;
+        };
+        if running(stub) {
+            continue;
+        }
+        let dir = entry.path();
+        if std::fs::remove_dir(&dir).is_ok() {
+            continue;
+        }
+        let cgroup = Cgroup { dir, procs: CString::default() };
+        signal_cgroup(&cgroup, Signal::SIGKILL);
+        // Members leave the cgroup once they are reaped
+        for _ in 0..STALE_CGROUP_ROUNDS {
+            thread::sleep(Duration::from_millis(50));
+            if std::fs::remove_dir(&cgroup.dir).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+/// Whether the process exists and hasn't exited yet (zombies are still
+/// there until their parent reaps them)
+#[cfg(target_os = "linux")]
+fn running(pid: i32) -> bool {
+    let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+        return false;
+    };
+    stat.rfind(')')
+        .and_then(|end| stat[end + 1..].split_whitespace().next())
+        .is_some_and(|state| state != "Z")
+}
+
+/// Move the calling process into the cgroup. Only uses raw syscalls, so it is
+/// safe between fork and exec.
+#[cfg(target_os = "linux")]
+fn join_cgroup(procs: &CString) -> bool {
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return false;
+        }
+        let joined = libc::write(fd, b"0".as_ptr().cast(), 1) == 1;
+        libc::close(fd);
+        joined
+    }
+}
+
+/// Signal every member but the stub. SIGKILL goes to all of them at once
+/// with `cgroup.kill` (Linux 5.14) where possible.
+#[cfg(target_os = "linux")]
+fn signal_cgroup(cgroup: &Cgroup, signal: Signal) {
+    if signal == Signal::SIGKILL && std::fs::write(cgroup.dir.join("cgroup.kill"), "1").is_ok() {
+        return;
+    }
+    let stub = getpid();
+    if let Ok(procs) = std::fs::read_to_string(cgroup.dir.join("cgroup.procs")) {
+        for pid in procs.lines().filter_map(|line| line.parse().ok()).map(Pid::from_raw) {
+            if pid != stub {
+                let _ = kill(pid, signal);
+            }
+        }
+    }
+}
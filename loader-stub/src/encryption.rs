@@ -0,0 +1,309 @@
+use std::cell::UnsafeCell;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::OnceLock;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
+use sha2::{Digest, Sha256};
+
+use crate::ConfigFooter;
+
+/// Decrypt lazily on first execution instead of before the base starts
+pub const ENCRYPTION_FLAG_LAZY: u8 = 0x01;
+/// Re-encrypt the decrypted bodies before the stub terminates the base
+pub const ENCRYPTION_FLAG_REENCRYPT_ON_EXIT: u8 = 0x02;
+
+/// A function body encrypted inside the base payload (see weaver's
+/// `core::protection::function_encryption`).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EncryptedRegion {
+    pub file_offset: u64,
+    pub vaddr: u64,
+    pub size: u64,
+}
+
+const REGION_SIZE: usize = 24;
+
+/// Read the encrypted region table that follows the overload payload
+pub fn read_regions(file: &mut File, footer: &ConfigFooter) -> std::io::Result<Vec<EncryptedRegion>> {
+    let mut regions = Vec::with_capacity(footer.encrypted_regions_count as usize);
+    if footer.encrypted_regions_count == 0 {
+        return Ok(regions);
+    }
+
+    file.seek(SeekFrom::Start(footer.encrypted_regions_offset))?;
+    for _ in 0..footer.encrypted_regions_count {
+        let mut bytes = [0u8; REGION_SIZE];
+        file.read_exact(&mut bytes)?;
+        let field = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        regions.push(EncryptedRegion {
+            file_offset: field(0),
+            vaddr: field(1),
+            size: field(2),
+        });
+    }
+    Ok(regions)
+}
+
+/// Check a key delivered by the overload against the hash embedded at merge time
+pub fn verify_key(key: &[u8; 32], footer: &ConfigFooter) -> bool {
+    let digest: [u8; 32] = Sha256::digest(key).into();
+    digest == footer.encryption_key_hash
+}
+
+/// Apply the keystream to every region of the base payload.
+///
+/// ChaCha20 is symmetric, so the same call decrypts and re-encrypts.
+pub fn apply_regions(base_data: &mut [u8], regions: &[EncryptedRegion], key: &[u8; 32], nonce: &[u8; 12]) {
+    for region in regions {
+        let start = region.file_offset as usize;
+        let end = start + region.size as usize;
+        if end > base_data.len() {
+            continue;
+        }
+        apply_keystream(&mut base_data[start..end], region.file_offset, key, nonce);
+    }
+}
+
+/// Apply the keystream positioned at `offset` within the base payload
+pub fn apply_keystream(data: &mut [u8], offset: u64, key: &[u8; 32], nonce: &[u8; 12]) {
+    let mut cipher = ChaCha20::new(key.into(), nonce.into());
+    cipher.seek(offset);
+    cipher.apply_keystream(data);
+}
+
+/// Encrypted function pages of a base loaded into the stub's own process.
+///
+/// With lazy decryption the pages start out PROT_NONE and are decrypted by
+/// the SIGSEGV handler on first touch. The key stays here until [`wipe_pages`]
+/// re-encrypts the pages and clears it.
+struct PageGuard {
+    /// (start, end, file offset) of every region at its loaded address
+    regions: Vec<(usize, usize, u64)>,
+    pages: Vec<GuardedPage>,
+    key: UnsafeCell<[u8; 32]>,
+    nonce: [u8; 12],
+    page_size: usize,
+    reencrypt: bool,
+    wiped: AtomicBool,
+    lock: AtomicBool,
+}
+
+struct GuardedPage {
+    address: usize,
+    /// Whether the page currently holds plaintext
+    decrypted: AtomicBool,
+    /// Faults on the already decrypted page since it was last decrypted
+    idle_faults: AtomicU32,
+}
+
+/// Faults on a decrypted page are normally just threads that raced the
+/// decryption. Past this many the access itself is invalid (e.g. a write).
+const MAX_IDLE_FAULTS: u32 = 64;
+
+// The key is only touched while `lock` is held
+unsafe impl Sync for PageGuard {}
+
+static PAGE_GUARD: OnceLock<PageGuard> = OnceLock::new();
+
+const CODE_PROT: i32 = libc::PROT_READ | libc::PROT_EXEC;
+
+impl PageGuard {
+    fn acquire(&self) {
+        while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn release(&self) {
+        self.lock.store(false, Ordering::Release);
+    }
+
+    /// Apply the keystream to every region byte inside the page at `page`
+    unsafe fn apply_page(&self, page: usize, final_prot: i32) {
+        let page_end = page + self.page_size;
+        raw_syscall(libc::SYS_mprotect, [page, self.page_size, (libc::PROT_READ | libc::PROT_WRITE) as usize, 0]);
+        for &(start, end, file_offset) in &self.regions {
+            let (low, high) = (start.max(page), end.min(page_end));
+            if low < high {
+                let bytes = std::slice::from_raw_parts_mut(low as *mut u8, high - low);
+                apply_keystream(bytes, file_offset + (low - start) as u64, &*self.key.get(), &self.nonce);
+            }
+        }
+        raw_syscall(libc::SYS_mprotect, [page, self.page_size, final_prot as usize, 0]);
+    }
+}
+
+/// A system call without libc's wrapper, which sets errno on failure.
+///
+/// In-process bases only run on these two architectures.
+#[inline(always)]
+unsafe fn raw_syscall(number: libc::c_long, args: [usize; 4]) -> isize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let result: isize;
+        std::arch::asm!(
+            "syscall",
+            inlateout("rax") number as isize => result,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack)
+        );
+        result
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let result: isize;
+        std::arch::asm!(
+            "svc 0",
+            in("x8") number,
+            inlateout("x0") args[0] as isize => result,
+            in("x1") args[1],
+            in("x2") args[2],
+            in("x3") args[3],
+            options(nostack)
+        );
+        result
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        libc::syscall(number, args[0], args[1], args[2], args[3]) as isize
+    }
+}
+
+/// Decrypt the faulting page if it belongs to an encrypted function.
+///
+/// Runs on the base's threads, whose thread pointer belongs to the base's
+/// libc, so this must not touch the stub's thread-locals (no std I/O, no errno):
+/// it only makes raw system calls.
+extern "C" fn on_page_fault(signal: i32, info: *mut libc::siginfo_t, _context: *mut libc::c_void) {
+    unsafe {
+        let address = (*info).si_addr() as usize;
+        if let Some(guard) = PAGE_GUARD.get() {
+            let page = address & !(guard.page_size - 1);
+            if let Some(entry) = guard.pages.iter().find(|entry| entry.address == page) {
+                guard.acquire();
+                let mut handled = !guard.wiped.load(Ordering::Relaxed);
+                if handled && !entry.decrypted.load(Ordering::Relaxed) {
+                    guard.apply_page(page, CODE_PROT);
+                    entry.decrypted.store(true, Ordering::Relaxed);
+                    entry.idle_faults.store(0, Ordering::Relaxed);
+                } else if handled {
+                    handled = entry.idle_faults.fetch_add(1, Ordering::Relaxed) < MAX_IDLE_FAULTS;
+                }
+                guard.release();
+                if handled {
+                    return;
+                }
+            }
+        }
+
+        // Not ours (or the key is gone): let the fault take its default action.
+        // The kernel's sigaction is handler, flags, restorer and mask; all
+        // zeros is SIG_DFL.
+        let default = [0usize; 4];
+        raw_syscall(libc::SYS_rt_sigaction, [signal as usize, default.as_ptr() as usize, 0, 8]);
+    }
+}
+
+/// Track the encrypted regions of an in-process base loaded at `bias`.
+///
+/// With `lazy`, the regions must still be encrypted in memory: their pages are
+/// made inaccessible and decrypted on first touch. Otherwise the regions are
+/// already plaintext and are only remembered for [`wipe_pages`].
+pub fn guard_pages(
+    regions: &[EncryptedRegion],
+    bias: usize,
+    key: [u8; 32],
+    footer: &ConfigFooter,
+) -> Result<(), String> {
+    let lazy = footer.encryption_flags & ENCRYPTION_FLAG_LAZY != 0;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    let regions: Vec<(usize, usize, u64)> = regions
+        .iter()
+        .map(|r| (r.vaddr as usize + bias, (r.vaddr + r.size) as usize + bias, r.file_offset))
+        .collect();
+
+    let mut pages: Vec<usize> = regions
+        .iter()
+        .flat_map(|&(start, end, _)| (start & !(page_size - 1)..end).step_by(page_size))
+        .map(|address| address & !(page_size - 1))
+        .collect();
+    pages.sort_unstable();
+    pages.dedup();
+
+    let guard = PageGuard {
+        regions,
+        pages: pages
+            .iter()
+            .map(|&address| GuardedPage {
+                address,
+                decrypted: AtomicBool::new(!lazy),
+                idle_faults: AtomicU32::new(0),
+            })
+            .collect(),
+        key: UnsafeCell::new(key),
+        nonce: footer.encryption_nonce,
+        page_size,
+        reencrypt: footer.encryption_flags & ENCRYPTION_FLAG_REENCRYPT_ON_EXIT != 0,
+        wiped: AtomicBool::new(false),
+        lock: AtomicBool::new(false),
+    };
+    if PAGE_GUARD.set(guard).is_err() {
+        return Err("Encrypted pages are already guarded".to_string());
+    }
+
+    if lazy {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_page_fault as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+            libc::sigemptyset(&mut action.sa_mask);
+            for signal in [libc::SIGSEGV, libc::SIGBUS] {
+                if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                    return Err(format!("Failed to install fault handler: {}", std::io::Error::last_os_error()));
+                }
+            }
+            for &page in &pages {
+                libc::mprotect(page as *mut libc::c_void, page_size, libc::PROT_NONE);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Re-encrypt every decrypted page and forget the key, if the footer asks for it.
+///
+/// Called before the stub terminates an in-process base, so the plaintext
+/// bodies can't be dumped while it shuts down. Touching a wiped page is fatal.
+pub fn wipe_pages() {
+    let Some(guard) = PAGE_GUARD.get() else {
+        return;
+    };
+    if !guard.reencrypt {
+        return;
+    }
+
+    guard.acquire();
+    if !guard.wiped.swap(true, Ordering::Relaxed) {
+        unsafe {
+            for entry in &guard.pages {
+                if entry.decrypted.swap(false, Ordering::Relaxed) {
+                    guard.apply_page(entry.address, libc::PROT_NONE);
+                } else {
+                    libc::mprotect(entry.address as *mut libc::c_void, guard.page_size, libc::PROT_NONE);
+                }
+            }
+            ptr::write_volatile(guard.key.get(), [0u8; 32]);
+        }
+    }
+    guard.release();
+}
@@ -0,0 +1,492 @@
+//! What a failed health check does to the base.
+//!
+//! The footer holds one action per kind of failure. Killing and terminating
+//! end the base. Suspending it, notifying it and flagging it as degraded keep
+//! it alive while the monitor goes on checking, and are undone once the checks
+//! pass again.
+//!
+//! A base that wants to know polls the status file named in its environment:
+//! one line, `ok`, or the action and the failure, like `degraded grace_period`.
+//! It lives in the launch's private directory, so other launches of the same
+//! binary and other users can't touch it.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::common::{
+    self, log_base_restored, log_enforcing, log_fallback_kill, log_grace_period_exceeded,
+    log_network_failure_threshold, log_overload_lost, log_overload_requested_kill, log_reverification_failures,
+    log_signaling_overload_kill, overload_kill_wait_duration, HealthCheckResult, OVERLOAD_LOST_CONTINUE,
+};
+use crate::health::HealthChannel;
+use crate::rundir::RunDir;
+use crate::strings::{self, S};
+use crate::ConfigFooter;
+
+/// Footer `enforcement`: SIGTERM, then SIGKILL after `terminate_timeout`
+pub const ENFORCE_TERMINATE: u8 = 1;
+/// Footer `enforcement`: stop the base until the checks pass again
+pub const ENFORCE_SUSPEND: u8 = 2;
+/// Footer `enforcement`: send the base `notify_signal` and set its status
+pub const ENFORCE_NOTIFY: u8 = 3;
+/// Footer `enforcement`: only set the base's status (0 kills the base)
+pub const ENFORCE_DEGRADED: u8 = 4;
+
+/// Footer `notify_signal`: SIGUSR2 (0 is SIGUSR1)
+#[cfg(unix)]
+pub const NOTIFY_SIGUSR2: u8 = 1;
+/// Footer `notify_signal`: SIGHUP
+#[cfg(unix)]
+pub const NOTIFY_SIGHUP: u8 = 2;
+
+/// `terminate_timeout` of 0
+const DEFAULT_TERMINATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Set while the base is suspended on purpose, so waiting for it doesn't take
+/// the stop for job control
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+pub fn base_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// The kinds of failure, as indexes into the footer's `enforcement`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    GracePeriod = 0,
+    NetworkFailures = 1,
+    KillRequest = 2,
+    OverloadLost = 3,
+}
+
+impl Failure {
+    pub fn of(result: &HealthCheckResult) -> Option<Self> {
+        match result {
+            HealthCheckResult::Ok => None,
+            HealthCheckResult::GracePeriodExceeded { .. } => Some(Failure::GracePeriod),
+            HealthCheckResult::NetworkFailureThreshold { .. } => Some(Failure::NetworkFailures),
+            HealthCheckResult::OverloadRequestedKill => Some(Failure::KillRequest),
+            HealthCheckResult::OverloadLost(_) => Some(Failure::OverloadLost),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::GracePeriod => "grace_period",
+            Failure::NetworkFailures => "network_failures",
+            Failure::KillRequest => "kill_request",
+            Failure::OverloadLost => "overload_lost",
+        }
+    }
+}
+
+/// Where a health check result comes from
+#[derive(Clone, Copy)]
+pub enum Check<'a> {
+    /// The overload's reports, over its health channel
+    Reports(Option<&'a HealthChannel>),
+    /// The periodic re-verification runs
+    Reverification,
+}
+
+/// The base as the monitor thread controls it
+pub trait Base {
+    /// Terminate it right away
+    fn kill(&mut self);
+    /// Ask it to exit and kill it if it is still running after `timeout`
+    fn terminate(&mut self, timeout: Duration);
+    fn suspend(&mut self, suspended: bool);
+    /// Send it the notify signal (not on Windows)
+    fn notify(&mut self, signal: u8);
+}
+
+pub struct Enforcer {
+    actions: [u8; 4],
+    terminate_timeout: Duration,
+    notify_signal: u8,
+    /// The base's status file, if any action sets it
+    status: Option<StatusFile>,
+    /// The failure whose action is in effect and keeps the base alive
+    holding: Option<Failure>,
+    /// A lost overload leaves the base to the grace period
+    continue_without_overload: bool,
+}
+
+impl Enforcer {
+    /// Called before the base starts: names the status file in the
+    /// environment it inherits
+    pub fn from_footer(footer: &ConfigFooter) -> Self {
+        let actions = footer.enforcement;
+        let status = actions
+            .iter()
+            .any(|&action| action == ENFORCE_NOTIFY || action == ENFORCE_DEGRADED)
+            .then(|| StatusFile::create(footer))
+            .and_then(|created| created.map_err(|e| common::log_status_write_failed(&e)).ok());
+        let enforcer = Self {
+            actions,
+            terminate_timeout: match footer.terminate_timeout {
+                0 => DEFAULT_TERMINATE_TIMEOUT,
+                seconds => Duration::from_secs(seconds as u64),
+            },
+            notify_signal: footer.notify_signal,
+            status,
+            holding: None,
+            continue_without_overload: footer.overload_lost_action == OVERLOAD_LOST_CONTINUE,
+        };
+        if let Some(status) = &enforcer.status {
+            std::env::set_var(strings::get(S::StatusEnv), &status.path);
+        }
+        enforcer.set_status("ok");
+        enforcer
+    }
+
+    /// The action for this result is already in effect, so it isn't repeated
+    pub fn holds(&self, result: &HealthCheckResult) -> bool {
+        self.holding.is_some() && self.holding == Failure::of(result)
+    }
+
+    /// The failure's action kills the base, which network failures first
+    /// leave to the overload's kill method
+    pub fn kills(&self, failure: Failure) -> bool {
+        self.actions[failure as usize] == 0
+    }
+
+    /// Apply the failure's action. True once the base is gone.
+    pub fn enforce(&mut self, failure: Failure, base: &mut impl Base) -> bool {
+        let action = self.actions[failure as usize];
+        log_enforcing(action, self.terminate_timeout.as_secs());
+        if action != ENFORCE_SUSPEND && SUSPENDED.load(Ordering::Relaxed) {
+            base.suspend(false);
+            SUSPENDED.store(false, Ordering::Relaxed);
+        }
+        match action {
+            ENFORCE_SUSPEND => {
+                SUSPENDED.store(true, Ordering::Relaxed);
+                base.suspend(true);
+            }
+            ENFORCE_NOTIFY => {
+                self.set_status(&format!("notify {}", failure.name()));
+                base.notify(self.notify_signal);
+            }
+            ENFORCE_DEGRADED => self.set_status(&format!("degraded {}", failure.name())),
+            ENFORCE_TERMINATE => {
+                base.terminate(self.terminate_timeout);
+                return true;
+            }
+            _ => {
+                base.kill();
+                return true;
+            }
+        }
+        self.holding = Some(failure);
+        false
+    }
+
+    /// Act on one health check result. True once the base is gone.
+    ///
+    /// `restart` is called when the overload is lost: it schedules a restart
+    /// and says whether it did. Without one, the overload-lost action applies.
+    pub fn handle(
+        &mut self,
+        result: HealthCheckResult,
+        check: Check,
+        base: &mut impl Base,
+        restart: impl FnOnce() -> bool,
+    ) -> bool {
+        match result {
+            HealthCheckResult::Ok => {
+                self.recovered(base);
+                false
+            }
+            _ if self.holds(&result) => false,
+            HealthCheckResult::GracePeriodExceeded { time_since_success, grace_period } => {
+                log_grace_period_exceeded(time_since_success, grace_period);
+                self.enforce(Failure::GracePeriod, base)
+            }
+            HealthCheckResult::NetworkFailureThreshold { failures, threshold } => {
+                match check {
+                    Check::Reverification => log_reverification_failures(failures, threshold),
+                    Check::Reports(health) => {
+                        log_network_failure_threshold(failures, threshold);
+                        if self.kills(Failure::NetworkFailures) {
+                            log_signaling_overload_kill();
+                            if let Some(health) = health {
+                                health.request_kill();
+                            }
+                            thread::sleep(overload_kill_wait_duration());
+                            log_fallback_kill();
+                        }
+                    }
+                }
+                self.enforce(Failure::NetworkFailures, base)
+            }
+            HealthCheckResult::OverloadRequestedKill => {
+                log_overload_requested_kill();
+                self.enforce(Failure::KillRequest, base)
+            }
+            HealthCheckResult::OverloadLost(loss) => {
+                log_overload_lost(&loss);
+                !restart() && self.overload_gone(base)
+            }
+        }
+    }
+
+    /// The overload is gone for good: apply the overload-lost action unless
+    /// the base is left to the grace period. True once the base is gone.
+    pub fn overload_gone(&mut self, base: &mut impl Base) -> bool {
+        !self.continue_without_overload && self.enforce(Failure::OverloadLost, base)
+    }
+
+    /// The checks pass again: undo what is in effect. A lost overload never
+    /// comes back, so its action stays.
+    pub fn recovered(&mut self, base: &mut impl Base) {
+        if self.holding.is_none_or(|failure| failure == Failure::OverloadLost) {
+            return;
+        }
+        self.holding = None;
+        if SUSPENDED.swap(false, Ordering::Relaxed) {
+            base.suspend(false);
+        }
+        self.set_status("ok");
+        log_base_restored();
+    }
+
+    /// Replace the status file in one step, so the base never reads half a line
+    fn set_status(&self, status: &str) {
+        let Some(file) = &self.status else {
+            return;
+        };
+        let temporary = file.path.with_extension("new");
+        let _ = fs::remove_file(&temporary);
+        let written = create_new(&temporary)
+            .and_then(|mut new| writeln!(new, "{}", status))
+            .and_then(|()| fs::rename(&temporary, &file.path));
+        if let Err(e) = written {
+            common::log_status_write_failed(&e.to_string());
+        }
+    }
+}
+
+/// The status file in the launch's private directory, removed with it
+struct StatusFile {
+    path: PathBuf,
+    _dir: RunDir,
+}
+
+impl StatusFile {
+    fn create(footer: &ConfigFooter) -> Result<Self, String> {
+        let dir = RunDir::create(footer, "status")?;
+        let path = dir.path().join("status");
+        Ok(Self { path, _dir: dir })
+    }
+}
+
+#[cfg(unix)]
+fn create_new(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+#[cfg(windows)]
+fn create_new(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// A base running as a child process (or as the stub itself, in-process)
+#[cfg(unix)]
+pub struct ChildBase {
+    pub pid: i32,
+    /// The platform's immediate kill
+    pub kill: fn(i32),
+}
+
+#[cfg(unix)]
+impl Base for ChildBase {
+    fn kill(&mut self) {
+        (self.kill)(self.pid);
+    }
+
+    fn terminate(&mut self, timeout: Duration) {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+
+        let pid = Pid::from_raw(self.pid);
+        crate::containment::signal_tree(pid, Signal::SIGTERM);
+        // The main thread reaps the base, after which it can't be signalled
+        let deadline = std::time::Instant::now() + timeout;
+        while std::time::Instant::now() < deadline {
+            if kill(pid, None).is_err() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        (self.kill)(self.pid);
+    }
+
+    fn suspend(&mut self, suspended: bool) {
+        use nix::sys::signal::Signal;
+
+        let signal = if suspended { Signal::SIGSTOP } else { Signal::SIGCONT };
+        crate::containment::signal_tree(nix::unistd::Pid::from_raw(self.pid), signal);
+    }
+
+    fn notify(&mut self, signal: u8) {
+        use nix::sys::signal::{kill, Signal};
+
+        let signal = match signal {
+            NOTIFY_SIGUSR2 => Signal::SIGUSR2,
+            NOTIFY_SIGHUP => Signal::SIGHUP,
+            _ => Signal::SIGUSR1,
+        };
+        let _ = kill(nix::unistd::Pid::from_raw(self.pid), signal);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    use super::*;
+    use crate::common::OverloadLoss;
+
+    /// The tests share SUSPENDED, so they take turns
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn serial() -> MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A base that records what was done to it
+    #[derive(Default)]
+    struct FakeBase {
+        events: Vec<String>,
+    }
+
+    impl Base for FakeBase {
+        fn kill(&mut self) {
+            self.events.push("kill".to_string());
+        }
+
+        fn terminate(&mut self, timeout: Duration) {
+            self.events.push(format!("terminate {}", timeout.as_secs()));
+        }
+
+        fn suspend(&mut self, suspended: bool) {
+            self.events.push(format!("suspend {}", suspended));
+        }
+
+        fn notify(&mut self, signal: u8) {
+            self.events.push(format!("notify {}", signal));
+        }
+    }
+
+    fn enforcer(enforcement: [u8; 4], terminate_timeout: u32, overload_lost_action: u8) -> Enforcer {
+        Enforcer::from_footer(&ConfigFooter {
+            enforcement,
+            terminate_timeout,
+            overload_lost_action,
+            notify_signal: NOTIFY_SIGHUP,
+            ..unsafe { std::mem::zeroed() }
+        })
+    }
+
+    fn status(enforcer: &Enforcer) -> String {
+        fs::read_to_string(&enforcer.status.as_ref().unwrap().path).unwrap().trim_end().to_string()
+    }
+
+    /// Drain what was done to the base since the last call
+    fn events(base: &mut FakeBase) -> Vec<String> {
+        std::mem::take(&mut base.events)
+    }
+
+    #[test]
+    fn test_enforce_actions() {
+        let _serial = serial();
+        let mut base = FakeBase::default();
+
+        // Killing and terminating end the base, without a status file
+        let mut ending = enforcer([0, ENFORCE_TERMINATE, 0, 0], 0, 0);
+        assert!(ending.status.is_none());
+        assert!(ending.kills(Failure::GracePeriod) && !ending.kills(Failure::NetworkFailures));
+        assert!(ending.enforce(Failure::GracePeriod, &mut base));
+        assert_eq!(events(&mut base), ["kill"]);
+        assert!(ending.enforce(Failure::NetworkFailures, &mut base));
+        assert_eq!(events(&mut base), ["terminate 10"]);
+        assert!(enforcer([0, ENFORCE_TERMINATE, 0, 0], 3, 0).enforce(Failure::NetworkFailures, &mut base));
+        assert_eq!(events(&mut base), ["terminate 3"]);
+
+        // Notifying and flagging keep it alive and set its status
+        let mut keeping = enforcer([0, 0, ENFORCE_NOTIFY, ENFORCE_DEGRADED], 0, 0);
+        assert_eq!(status(&keeping), "ok");
+        assert!(!keeping.enforce(Failure::KillRequest, &mut base));
+        assert_eq!(events(&mut base), [format!("notify {}", NOTIFY_SIGHUP)]);
+        assert_eq!(status(&keeping), "notify kill_request");
+        assert!(keeping.holds(&HealthCheckResult::OverloadRequestedKill));
+        assert!(!keeping.enforce(Failure::OverloadLost, &mut base));
+        assert!(events(&mut base).is_empty());
+        assert_eq!(status(&keeping), "degraded overload_lost");
+
+        // A lost overload can leave the base to the grace period
+        let mut continuing = enforcer([0; 4], 0, OVERLOAD_LOST_CONTINUE);
+        assert!(!continuing.overload_gone(&mut base));
+        assert!(enforcer([0; 4], 0, 0).overload_gone(&mut base));
+        assert_eq!(events(&mut base), ["kill"]);
+    }
+
+    #[test]
+    fn test_enforce_holds_and_recovers() {
+        let _serial = serial();
+        let mut base = FakeBase::default();
+        let mut enforcer = enforcer([ENFORCE_DEGRADED, ENFORCE_SUSPEND, 0, ENFORCE_DEGRADED], 0, 0);
+        let grace = || HealthCheckResult::GracePeriodExceeded { time_since_success: 120, grace_period: 60 };
+        let failures = || HealthCheckResult::NetworkFailureThreshold { failures: 3, threshold: 3 };
+        let handle = |enforcer: &mut Enforcer, base: &mut FakeBase, result| {
+            enforcer.handle(result, Check::Reverification, base, || false)
+        };
+
+        // An action in effect isn't repeated, and passing checks undo it
+        assert!(!handle(&mut enforcer, &mut base, grace()));
+        assert_eq!(status(&enforcer), "degraded grace_period");
+        assert!(enforcer.holds(&grace()) && !enforcer.holds(&failures()));
+        fs::write(&enforcer.status.as_ref().unwrap().path, "untouched\n").unwrap();
+        assert!(!handle(&mut enforcer, &mut base, grace()));
+        assert_eq!(status(&enforcer), "untouched");
+        assert!(!handle(&mut enforcer, &mut base, HealthCheckResult::Ok));
+        assert_eq!(status(&enforcer), "ok");
+        assert!(!enforcer.holds(&grace()));
+
+        // A suspended base resumes once the checks pass, or when another
+        // failure's action replaces the stop
+        assert!(!handle(&mut enforcer, &mut base, failures()));
+        assert_eq!(events(&mut base), ["suspend true"]);
+        assert!(base_suspended());
+        assert!(!handle(&mut enforcer, &mut base, grace()));
+        assert_eq!(events(&mut base), ["suspend false"]);
+        assert!(!base_suspended());
+        assert_eq!(status(&enforcer), "degraded grace_period");
+        assert!(!handle(&mut enforcer, &mut base, failures()));
+        assert!(!handle(&mut enforcer, &mut base, HealthCheckResult::Ok));
+        assert_eq!(events(&mut base), ["suspend true", "suspend false"]);
+        assert!(!base_suspended());
+        assert_eq!(status(&enforcer), "ok");
+
+        // A restarted overload isn't a failure, and one lost for good stays lost
+        let lost = || HealthCheckResult::OverloadLost(OverloadLoss::Exited(1));
+        assert!(!enforcer.handle(lost(), Check::Reports(None), &mut base, || true));
+        assert_eq!(status(&enforcer), "ok");
+        assert!(!handle(&mut enforcer, &mut base, lost()));
+        assert!(!handle(&mut enforcer, &mut base, HealthCheckResult::Ok));
+        assert_eq!(status(&enforcer), "degraded overload_lost");
+        assert!(events(&mut base).is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+//! Payload files for the platforms that can only start a process from a file.
+//!
+//! Every launch writes its payloads into its own private directory (see
+//! `rundir`). Each payload is hashed once it is complete and checked again
+//! right before it is started. The directory is removed when the stub exits.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::common;
+use crate::rundir::RunDir;
+use crate::ConfigFooter;
+
+/// The launch's private directory. Removed with everything in it on drop.
+pub struct Extraction {
+    dir: RunDir,
+}
+
+/// A payload written to disk and the hash it has to match when started
+pub struct Payload {
+    pub path: PathBuf,
+    hash: [u8; 32],
+    /// Read-only, delete-on-close handle that keeps anyone from opening the
+    /// file for writing until it is dropped
+    #[cfg(windows)]
+    handle: File,
+}
+
+impl Extraction {
+    pub fn create(footer: &ConfigFooter) -> Result<Self, String> {
+        RunDir::create(footer, "run").map(|dir| Self { dir })
+    }
+
+    /// Write a payload, let `prepare` finish the file (macOS signs it), then
+    /// record its hash
+    pub fn extract(&self, name: &str, data: &[u8], prepare: impl FnOnce(&Path)) -> Result<Payload, String> {
+        let path = self.dir.path().join(payload_file_name(name));
+        common::log_writing_payload(name, data.len(), &path);
+        write_new(&path, data).map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+        prepare(&path);
+        Payload::seal(path)
+    }
+}
+
+impl Payload {
+    #[cfg(windows)]
+    fn seal(path: PathBuf) -> Result<Self, String> {
+        use std::os::windows::fs::OpenOptionsExt;
+        use windows_sys::Win32::Foundation::GENERIC_READ;
+        use windows_sys::Win32::Storage::FileSystem::{
+            DELETE, FILE_FLAG_DELETE_ON_CLOSE, FILE_SHARE_DELETE, FILE_SHARE_READ,
+        };
+
+        let handle = OpenOptions::new()
+            .access_mode(GENERIC_READ | DELETE)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_DELETE)
+            .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+            .open(&path)
+            .map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        let hash = hash_file(&handle).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Ok(Self { path, hash, handle })
+    }
+
+    #[cfg(not(windows))]
+    fn seal(path: PathBuf) -> Result<Self, String> {
+        let hash = open_payload(&path)
+            .and_then(|file| hash_file(&file))
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Ok(Self { path, hash })
+    }
+
+    /// Check the file still is what was extracted. Called right before it is started.
+    pub fn verify(&self) -> Result<(), String> {
+        #[cfg(windows)]
+        let current = {
+            use std::io::{Seek, SeekFrom};
+            (&self.handle).seek(SeekFrom::Start(0)).and_then(|_| hash_file(&self.handle))
+        };
+        #[cfg(not(windows))]
+        let current = open_payload(&self.path).and_then(|file| hash_file(&file));
+
+        match current {
+            Ok(hash) if hash == self.hash => Ok(()),
+            Ok(_) => Err(format!("{} was modified after it was written", self.path.display())),
+            Err(e) => Err(format!("Cannot verify {}: {}", self.path.display(), e)),
+        }
+    }
+
+    /// Unlink the file once the process runs from it. Windows can't delete a
+    /// running image, so there the file goes when the handle closes.
+    #[cfg(not(windows))]
+    pub fn unlink(&self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(windows)]
+fn payload_file_name(name: &str) -> String {
+    format!("{}.exe", name)
+}
+
+#[cfg(not(windows))]
+fn payload_file_name(name: &str) -> String {
+    name.to_string()
+}
+
+fn hash_file(mut file: &File) -> io::Result<[u8; 32]> {
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(Sha256::digest(&contents).into())
+}
+
+#[cfg(windows)]
+fn write_new(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::os::windows::fs::OpenOptionsExt;
+    // No sharing while it is written
+    OpenOptions::new().write(true).create_new(true).share_mode(0).open(path)?.write_all(data)
+}
+
+#[cfg(not(windows))]
+fn write_new(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o700)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(windows))]
+fn open_payload(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(path)
+}
@@ -0,0 +1,335 @@
+//! The last success, kept on disk across launches.
+//!
+//! Without it every launch starts a fresh grace period, so restarting the
+//! program would stretch the offline grace indefinitely. The record holds
+//! when the grace period last (re)started, on both the wall clock and the
+//! boot clock, together with the boot it was written in. It is signed with a
+//! per-merge key mixed with the machine's ID, so it can neither be edited nor
+//! carried over from another machine.
+//!
+//! Within the same boot the boot clock says how long ago the success was,
+//! whatever the system clock says. After a reboot only the wall clock can, and
+//! a record from the future means the clock was set back.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::common::{
+    self, log_grace_record_missing, log_grace_record_resumed, log_grace_record_save_failed,
+    log_grace_record_tampered,
+};
+use crate::health::HealthMonitor;
+use crate::strings::{self, S};
+use crate::ConfigFooter;
+
+/// Footer `grace_record_missing` and `grace_record_tampered`: the grace period
+/// is already used up, so the overload has to verify right away (0 starts a
+/// full one)
+pub const GRACE_RECORD_EXPIRED: u8 = 1;
+/// Footer `grace_record_missing` and `grace_record_tampered`: don't start
+pub const GRACE_RECORD_REFUSE: u8 = 2;
+
+/// `[wall clock][boot clock][boot][MAC]`
+const RECORD_LEN: usize = 8 + 8 + 16 + 32;
+
+pub struct GraceRecord {
+    /// The file and the machine-bound signing key, or why there are none
+    location: Result<(PathBuf, [u8; 32]), String>,
+    /// Digest of the current boot's ID
+    boot: [u8; 16],
+    /// Grace period start the file holds
+    saved: Option<i64>,
+    /// A failed save is only logged once
+    save_failed: bool,
+}
+
+enum Loaded {
+    Found(i64),
+    Missing(String),
+    Tampered(String),
+}
+
+impl GraceRecord {
+    /// Read the record and let the grace period run on from it, as the
+    /// footer's policies say. None if the footer keeps no record, Err if the
+    /// base must not start.
+    pub fn resume(footer: &ConfigFooter, monitor: Option<&mut HealthMonitor>) -> Result<Option<Self>, String> {
+        if footer.grace_record == 0 {
+            return Ok(None);
+        }
+        let mut record = Self {
+            location: location(footer),
+            boot: boot_id().unwrap_or_default(),
+            saved: None,
+            save_failed: false,
+        };
+
+        let now = common::current_time();
+        let action = match record.load() {
+            Loaded::Found(success) => {
+                log_grace_record_resumed(now - success);
+                record.saved = Some(success);
+                if let Some(monitor) = monitor {
+                    monitor.resume_grace_period(success);
+                }
+                return Ok(Some(record));
+            }
+            Loaded::Missing(reason) => {
+                log_grace_record_missing(&reason);
+                footer.grace_record_missing
+            }
+            Loaded::Tampered(reason) => {
+                log_grace_record_tampered(&reason);
+                footer.grace_record_tampered
+            }
+        };
+        match action {
+            GRACE_RECORD_REFUSE => Err("Grace record rejected".to_string()),
+            GRACE_RECORD_EXPIRED => {
+                if let Some(monitor) = monitor {
+                    monitor.resume_grace_period(now - footer.grace_period as i64 - 1);
+                }
+                Ok(Some(record))
+            }
+            _ => Ok(Some(record)),
+        }
+    }
+
+    fn load(&self) -> Loaded {
+        let (path, key) = match &self.location {
+            Ok(location) => location,
+            Err(e) => return Loaded::Missing(e.clone()),
+        };
+        let mut data = Vec::new();
+        match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Loaded::Missing("no record yet".to_string()),
+            Err(e) => return Loaded::Tampered(e.to_string()),
+        }
+        let Ok(data) = <[u8; RECORD_LEN]>::try_from(data.as_slice()) else {
+            return Loaded::Tampered("wrong size".to_string());
+        };
+        if mac(key, &data[..32]).verify_slice(&data[32..]).is_err() {
+            return Loaded::Tampered("bad signature".to_string());
+        }
+
+        let wall = i64::from_le_bytes(data[..8].try_into().unwrap());
+        let success = i64::from_le_bytes(data[8..16].try_into().unwrap());
+        let now = common::current_time();
+        if data[16..32] == self.boot && self.boot != [0; 16] {
+            if success > now {
+                return Loaded::Tampered("written in the future".to_string());
+            }
+            return Loaded::Found(success);
+        }
+        let elapsed = common::wall_time() - wall;
+        if elapsed < 0 {
+            return Loaded::Tampered("system clock set back".to_string());
+        }
+        Loaded::Found(now - elapsed)
+    }
+
+    /// Keep the grace period's current start. Only writes when it moved.
+    pub fn save(&mut self, success: i64) {
+        if self.saved == Some(success) {
+            return;
+        }
+        let Ok((path, key)) = &self.location else {
+            return;
+        };
+        let mut data = [0u8; RECORD_LEN];
+        let wall = common::wall_time() - (common::current_time() - success);
+        data[..8].copy_from_slice(&wall.to_le_bytes());
+        data[8..16].copy_from_slice(&success.to_le_bytes());
+        data[16..32].copy_from_slice(&self.boot);
+        let signature = mac(key, &data[..32]).finalize().into_bytes();
+        data[32..].copy_from_slice(&signature);
+
+        match write(path, &data) {
+            Ok(()) => self.saved = Some(success),
+            Err(e) if !self.save_failed => {
+                log_grace_record_save_failed(&e);
+                self.save_failed = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn mac(key: &[u8; 32], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac
+}
+
+/// Replace the record in one step, so a crash can't leave half of one
+fn write(path: &PathBuf, data: &[u8]) -> Result<(), String> {
+    let temporary = path.with_extension("new");
+    let mut options = File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&temporary)
+        .and_then(|mut file| file.write_all(data))
+        .and_then(|()| fs::rename(&temporary, path))
+        .map_err(|e| e.to_string())
+}
+
+/// The record's file in the user's state directory, named after the merge,
+/// and the key bound to this machine
+fn location(footer: &ConfigFooter) -> Result<(PathBuf, [u8; 32]), String> {
+    let machine = machine_id()?;
+    let key = mac(&footer.grace_record_key, &machine).finalize().into_bytes().into();
+    let directory = state_dir().ok_or_else(|| "no state directory".to_string())?;
+    fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+    Ok((directory.join(common::instance_name(footer, "grace")), key))
+}
+
+fn digest(id: &[u8]) -> [u8; 16] {
+    Sha256::digest(id)[..16].try_into().unwrap()
+}
+
+fn env_dir(var: S) -> Option<PathBuf> {
+    std::env::var_os(strings::get(var)).filter(|value| !value.is_empty()).map(PathBuf::from)
+}
+
+#[cfg(target_os = "linux")]
+fn state_dir() -> Option<PathBuf> {
+    env_dir(S::XdgStateHome).or_else(|| Some(env_dir(S::Home)?.join(strings::get(S::LocalStateDir))))
+}
+
+#[cfg(target_os = "linux")]
+fn machine_id() -> Result<Vec<u8>, String> {
+    fs::read(strings::get(S::MachineIdPath))
+        .or_else(|_| fs::read(strings::get(S::DbusMachineIdPath)))
+        .map(|id| id.trim_ascii().to_vec())
+        .map_err(|e| format!("no machine ID: {}", e))
+}
+
+#[cfg(target_os = "linux")]
+fn boot_id() -> Option<[u8; 16]> {
+    fs::read(strings::get(S::BootIdPath)).ok().map(|id| digest(id.trim_ascii()))
+}
+
+#[cfg(target_os = "macos")]
+fn state_dir() -> Option<PathBuf> {
+    Some(env_dir(S::Home)?.join(strings::get(S::MacStateDir)))
+}
+
+#[cfg(target_os = "macos")]
+fn machine_id() -> Result<Vec<u8>, String> {
+    let mut id = [0u8; 16];
+    let timeout = libc::timespec { tv_sec: 5, tv_nsec: 0 };
+    if unsafe { libc::gethostuuid(id.as_mut_ptr(), &timeout) } != 0 {
+        return Err(format!("no machine ID: {}", io::Error::last_os_error()));
+    }
+    Ok(id.to_vec())
+}
+
+#[cfg(target_os = "macos")]
+fn boot_id() -> Option<[u8; 16]> {
+    let name = std::ffi::CString::new(strings::get(S::BootSessionSysctl)).ok()?;
+    let mut id = [0u8; 64];
+    let mut len = id.len();
+    let result = unsafe { libc::sysctlbyname(name.as_ptr(), id.as_mut_ptr().cast(), &mut len, std::ptr::null_mut(), 0) };
+    (result == 0).then(|| digest(&id[..len]))
+}
+
+#[cfg(windows)]
+fn state_dir() -> Option<PathBuf> {
+    env_dir(S::LocalAppData)
+}
+
+#[cfg(windows)]
+fn machine_id() -> Result<Vec<u8>, String> {
+    let mut id = [0u8; 64];
+    let len = registry::read(S::CryptographyKey, S::MachineGuid, registry::RRF_RT_REG_SZ, &mut id)
+        .map_err(|e| format!("no machine ID: {}", e))?;
+    Ok(id[..len].to_vec())
+}
+
+#[cfg(windows)]
+fn boot_id() -> Option<[u8; 16]> {
+    let mut id = [0u8; 4];
+    let len = registry::read(S::PrefetchParametersKey, S::BootId, registry::RRF_RT_REG_DWORD, &mut id).ok()?;
+    Some(digest(&id[..len]))
+}
+
+#[cfg(windows)]
+mod registry {
+    use std::ffi::CString;
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::ERROR_SUCCESS;
+    pub use windows_sys::Win32::System::Registry::{RRF_RT_REG_DWORD, RRF_RT_REG_SZ};
+    use windows_sys::Win32::System::Registry::{RegGetValueA, HKEY_LOCAL_MACHINE, RRF_SUBKEY_WOW6464KEY};
+
+    use crate::strings::{self, S};
+
+    /// Read a value under HKEY_LOCAL_MACHINE into `data`, returning its length
+    pub fn read(key: S, value: S, kind: u32, data: &mut [u8]) -> Result<usize, u32> {
+        let key = CString::new(strings::get(key)).unwrap();
+        let value = CString::new(strings::get(value)).unwrap();
+        let mut len = data.len() as u32;
+        let result = unsafe {
+            RegGetValueA(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr() as *const u8,
+                value.as_ptr() as *const u8,
+                kind | RRF_SUBKEY_WOW6464KEY,
+                ptr::null_mut(),
+                data.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if result != ERROR_SUCCESS {
+            return Err(result);
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &std::path::Path, key: u8, boot: u8) -> GraceRecord {
+        GraceRecord { location: Ok((path.to_path_buf(), [key; 32])), boot: [boot; 16], saved: None, save_failed: false }
+    }
+
+    #[test]
+    fn test_grace_record_load() {
+        let path = std::env::temp_dir().join(format!("grace-record-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(matches!(record(&path, 1, 1).load(), Loaded::Missing(_)));
+
+        // Within the boot it was written in, the boot clock says when
+        let now = common::current_time();
+        record(&path, 1, 1).save(now - 100);
+        assert!(matches!(record(&path, 1, 1).load(), Loaded::Found(success) if success == now - 100));
+        // After a reboot the wall clock does, to the second
+        let rebooted = record(&path, 1, 2).load();
+        assert!(matches!(rebooted, Loaded::Found(success) if (now - 101..=now - 99).contains(&success)));
+
+        // Another machine's key, an edited or a cut record
+        assert!(matches!(record(&path, 2, 1).load(), Loaded::Tampered(_)));
+        let mut data = fs::read(&path).unwrap();
+        data[8] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(record(&path, 1, 1).load(), Loaded::Tampered(_)));
+        fs::write(&path, &data[..RECORD_LEN - 1]).unwrap();
+        assert!(matches!(record(&path, 1, 1).load(), Loaded::Tampered(_)));
+
+        // A success in the future on the boot clock, or on the wall clock
+        // after a reboot, means a clock was set back
+        record(&path, 1, 1).save(now + 1000);
+        assert!(matches!(record(&path, 1, 1).load(), Loaded::Tampered(_)));
+        assert!(matches!(record(&path, 1, 2).load(), Loaded::Tampered(_)));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1076 @@
+//! The stub's end of the health channel (see `killcode_overload::protocol`).
+//!
+//! The channel has no name anyone could open. It reaches the overload as an
+//! inherited descriptor, together with a pipe holding the per-launch report
+//! key, and the variable naming both is only set for the overload. The base
+//! never inherits either, so it can neither read the key nor write reports.
+//!
+//! A shared memory region is tried first. Where none can be created (no
+//! memfd and no writable /dev/shm in a container), the channel falls back to
+//! a socketpair, or a named pipe on Windows. The footer can pin one of them
+//! and decide whether the stub starts without a channel at all.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use killcode_overload::protocol::{
+    BaseExit, Report, StubState, HEALTH_KEY_LEN, NOT_VERIFIED, REPORT_FRAME_LEN,
+    STATE_FRAME_LEN,
+};
+
+use crate::common::{self, evaluate_health_status, HealthCheckResult};
+use crate::strings::{self, S};
+use crate::ConfigFooter;
+
+/// Random bytes from the OS, also for names nobody can guess
+pub use sys::random_key;
+
+/// Footer `health_transport`: shared memory only (0 tries it, then a stream)
+pub const HEALTH_TRANSPORT_SHARED_MEMORY: u8 = 1;
+/// Footer `health_transport`: socketpair (named pipe on Windows) only
+pub const HEALTH_TRANSPORT_STREAM: u8 = 2;
+
+/// Footer `health_channel_policy`: don't start anything without a channel
+/// (0 runs the base unmonitored)
+pub const HEALTH_POLICY_FAIL_CLOSED: u8 = 1;
+
+/// One way of carrying the health channel to the overload
+trait Transport: Send + Sync {
+    /// `TRANSPORT_*` of the protocol, named in the overload's environment
+    fn kind(&self) -> &'static str;
+    /// What backs it, for the logs
+    fn name(&self) -> &'static str;
+    /// Descriptor or handle the overload inherits
+    fn overload_end(&self) -> sys::RawChannel;
+    /// The overload's latest complete report, unverified
+    fn read_report(&self) -> Option<(Report, [u8; 32])>;
+    /// Hand the stub's state to the overload
+    fn publish(&self, state: &StubState);
+    /// Forget the reports of an overload that is gone
+    fn reset(&self);
+    /// Drop the mapping and descriptors in a forked child that runs the base
+    /// without exec
+    #[cfg(target_os = "linux")]
+    fn revoke(&self);
+}
+
+type OpenTransport = fn(&ConfigFooter) -> Result<Box<dyn Transport>, String>;
+
+/// Transports to try, in order, for the footer's `health_transport`
+fn transports(setting: u8) -> &'static [OpenTransport] {
+    match setting {
+        HEALTH_TRANSPORT_SHARED_MEMORY => &[sys::shared_memory],
+        HEALTH_TRANSPORT_STREAM => &[sys::stream],
+        _ => &[sys::shared_memory, sys::stream],
+    }
+}
+
+pub struct HealthChannel {
+    transport: Box<dyn Transport>,
+    /// Every overload start gets a fresh key, so a restarted overload starts
+    /// counting at 1 and nothing its predecessor signed is accepted anymore
+    key: Mutex<[u8; HEALTH_KEY_LEN]>,
+    /// Read end of a pipe the key was written to
+    key_pipe: Mutex<sys::KeyPipe>,
+    /// What the overload was last told
+    state: Mutex<StubState>,
+    /// Held while a state is published, never while it changes
+    publishing: Mutex<()>,
+}
+
+impl HealthChannel {
+    pub fn create(footer: &ConfigFooter) -> Result<Self, String> {
+        let key = sys::random_key()?;
+        let key_pipe = sys::KeyPipe::create(&key)?;
+        let mut failures = Vec::new();
+        for open in transports(footer.health_transport) {
+            match open(footer) {
+                Ok(transport) => {
+                    // Stream overloads read this first state when they connect
+                    transport.publish(&StubState::default());
+                    return Ok(Self {
+                        transport,
+                        key: Mutex::new(key),
+                        key_pipe: Mutex::new(key_pipe),
+                        state: Mutex::new(StubState::default()),
+                        publishing: Mutex::new(()),
+                    });
+                }
+                Err(e) => {
+                    common::log_health_transport_unavailable(&e);
+                    failures.push(e);
+                }
+            }
+        }
+        Err(failures.join("; "))
+    }
+
+    /// How the channel is shared, for the logs
+    pub fn transport(&self) -> &'static str {
+        self.transport.name()
+    }
+
+    /// Called in the forked overload: let it inherit the channel and the key
+    #[cfg(unix)]
+    pub fn inherit(&self) {
+        let (channel, key) = (self.transport.overload_end(), self.key_pipe().raw());
+        for fd in [channel, key] {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+        }
+        let value = format!("{}:{}:{}", self.transport.kind(), channel, key);
+        std::env::set_var(strings::get(S::HealthChannelEnv), value);
+    }
+
+    /// Make the handles inheritable (and name them in the environment) for
+    /// the overload's CreateProcess only
+    #[cfg(windows)]
+    pub fn share(&self, shared: bool) {
+        let (channel, key) = (self.transport.overload_end(), self.key_pipe().raw());
+        for handle in [channel, key] {
+            sys::set_inheritable(handle, shared);
+        }
+        if shared {
+            let value = format!("{}:{}:{}", self.transport.kind(), channel, key);
+            std::env::set_var(strings::get(S::HealthChannelEnv), value);
+        } else {
+            std::env::remove_var(strings::get(S::HealthChannelEnv));
+        }
+    }
+
+    /// Called in a forked child that runs the base without exec: drop the
+    /// mapping and the descriptors it would otherwise inherit
+    #[cfg(target_os = "linux")]
+    pub fn revoke(&self) {
+        self.transport.revoke();
+        unsafe { libc::close(self.key_pipe().raw()) };
+    }
+
+    fn key_pipe(&self) -> std::sync::MutexGuard<'_, sys::KeyPipe> {
+        self.key_pipe.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Prepare the channel for a restarted overload: a new key, none of the
+    /// old reports, and the last success it continues from
+    fn relaunch(&self, accepted_success: i64) -> Result<(), String> {
+        let key = sys::random_key()?;
+        *self.key_pipe() = sys::KeyPipe::create(&key)?;
+        *self.key.lock().unwrap_or_else(PoisonError::into_inner) = key;
+        self.transport.reset();
+        self.update(|state| state.accepted_success = accepted_success);
+        Ok(())
+    }
+
+    /// Publish the base's PID to the overload
+    pub fn set_base_pid(&self, pid: i32) {
+        self.update(|state| state.base_pid = pid);
+    }
+
+    /// Tell the overload how the base ended, before it is shut down
+    pub fn report_base_exit(&self, exit: BaseExit) {
+        self.update(|state| state.base_exit = Some(exit));
+    }
+
+    /// Publish the base's latest resource use
+    #[cfg(target_os = "linux")]
+    pub fn report_usage(&self, usage: killcode_overload::protocol::BaseUsage) {
+        self.update(|state| state.base_usage = Some(usage));
+    }
+
+    /// Ask the overload to run its kill method
+    pub fn request_kill(&self) {
+        self.update(|state| state.parent_requests_kill = true);
+    }
+
+    /// Tell the overload that the stub or a payload failed its integrity check
+    pub fn signal_integrity_violation(&self) {
+        self.update(|state| state.integrity_violation = true);
+    }
+
+    fn update(&self, change: impl FnOnce(&mut StubState)) {
+        change(&mut self.state());
+        // Whoever publishes last sends the state as it is by then, so a
+        // slower update can't replace a newer state with the one it made
+        let _publishing = self.publishing.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = *self.state();
+        self.transport.publish(&state);
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, StubState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn monitor(self: &Arc<Self>) -> HealthMonitor {
+        let now = common::current_time();
+        HealthMonitor {
+            channel: self.clone(),
+            accepted: Report { last_success: now, last_heartbeat: now, ..Default::default() },
+            rejected: 0,
+            unverified_since: now,
+            resumed: None,
+        }
+    }
+}
+
+/// Verifies the overload's reports for the monitor thread
+pub struct HealthMonitor {
+    channel: Arc<HealthChannel>,
+    /// Last report that verified. Until the overload reports, the stub's start.
+    accepted: Report,
+    /// Counter of the last rejected report, so each is only logged once
+    rejected: u64,
+    /// Where the grace period starts while the overload's reports carry no
+    /// success (hybrid mode, after an exit or a timeout)
+    unverified_since: i64,
+    /// Success an earlier launch left in the grace record
+    resumed: Option<i64>,
+}
+
+impl HealthMonitor {
+    pub fn evaluate(&mut self, grace_period: u32, network_failure_kill_count: u32, heartbeat_timeout: u32) -> HealthCheckResult {
+        self.poll();
+        let report = Report { last_success: self.last_success(), ..self.accepted };
+        evaluate_health_status(&report, grace_period, network_failure_kill_count, heartbeat_timeout)
+    }
+
+    /// Hybrid mode: the overload's reports carry no success until its check
+    /// passed. Called before it starts.
+    pub fn expect_verification(&mut self) {
+        self.channel.update(|state| state.accepted_success = NOT_VERIFIED);
+        self.accepted.last_success = 0;
+    }
+
+    /// Hybrid mode: Ok once the overload reported its first success, Err if
+    /// it requested the kill or failed too often before that
+    pub fn verification(&mut self, network_failure_kill_count: u32) -> Option<Result<(), String>> {
+        self.poll();
+        let failures = self.accepted.consecutive_failures;
+        if self.accepted.last_success > 0 {
+            Some(Ok(()))
+        } else if self.accepted.should_kill_base {
+            Some(Err("the overload requested base termination".to_string()))
+        } else if network_failure_kill_count > 0 && failures >= network_failure_kill_count as i32 {
+            Some(Err(format!("{} failed checks", failures)))
+        } else {
+            None
+        }
+    }
+
+    /// Hybrid mode: the overload exited with 0 before it reported, which
+    /// counts as a success now
+    pub fn passed_on_exit(&mut self) {
+        self.unverified_since = common::current_time();
+    }
+
+    /// Hybrid mode without a success: the grace period starts now, or runs on
+    /// from an earlier launch's success
+    pub fn start_grace_period(&mut self) {
+        self.unverified_since = self.resumed.unwrap_or_else(common::current_time);
+    }
+
+    /// The grace period runs on from a success of an earlier launch. Called
+    /// before the overload starts.
+    pub fn resume_grace_period(&mut self, since: i64) {
+        // 0 would tell the overload to start a fresh grace period
+        let since = if since == 0 { -1 } else { since };
+        self.resumed = Some(since);
+        // Hybrid mode only falls back to it when starting unverified
+        if self.accepted.last_success != 0 {
+            self.accepted.last_success = since;
+            self.channel.update(|state| state.accepted_success = since);
+        }
+    }
+
+    /// Where the grace period currently runs from
+    pub fn last_success(&self) -> i64 {
+        if self.accepted.last_success != 0 {
+            self.accepted.last_success
+        } else {
+            self.unverified_since
+        }
+    }
+
+    /// Hand the channel to a restarted overload. Its grace period runs on from
+    /// the last accepted success, and its heartbeat timeout from now.
+    pub fn overload_restarting(&mut self) -> Result<(), String> {
+        self.channel.relaunch(self.last_success())?;
+        self.accepted.counter = 0;
+        self.accepted.last_heartbeat = common::current_time();
+        self.rejected = 0;
+        Ok(())
+    }
+
+    /// Accept the current report if its MAC verifies and its counter is new.
+    /// Anything else keeps the last accepted one, so the grace period runs on.
+    fn poll(&mut self) {
+        let Some((report, mac)) = self.channel.transport.read_report() else {
+            return;
+        };
+        if report.counter == 0 || report == self.accepted {
+            return;
+        }
+
+        let key = *self.channel.key.lock().unwrap_or_else(PoisonError::into_inner);
+        let rejection = if !report.verify(&key, &mac) {
+            "forged"
+        } else if report.counter <= self.accepted.counter {
+            "replayed"
+        } else {
+            self.accepted = report;
+            return;
+        };
+        if report.counter != self.rejected {
+            self.rejected = report.counter;
+            common::log_health_report_rejected(rejection, report.counter);
+        }
+    }
+}
+
+/// Report frames a stream transport received, and the latest complete one
+#[derive(Default)]
+struct Inbox {
+    received: Vec<u8>,
+    latest: Option<(Report, [u8; 32])>,
+}
+
+impl Inbox {
+    fn push(&mut self, bytes: &[u8]) {
+        self.received.extend_from_slice(bytes);
+        let complete = self.received.len() / REPORT_FRAME_LEN * REPORT_FRAME_LEN;
+        if let Some(frame) = self.received[..complete].chunks_exact(REPORT_FRAME_LEN).last() {
+            self.latest = Some(Report::from_frame(frame.try_into().unwrap()));
+        }
+        self.received.drain(..complete);
+    }
+}
+
+/// State frames a stream transport couldn't send yet.
+///
+/// Writes never block, an overload that stops reading must not hold up the
+/// stub. A newer frame replaces one that hasn't started going out, since
+/// only the latest state matters. A frame that went out in part is always
+/// finished first, so the overload never sees the frames shifted.
+#[derive(Default)]
+struct Outbox {
+    /// Rest of a frame that went out in part
+    partial: Vec<u8>,
+    /// Latest frame that hasn't started going out
+    latest: Option<[u8; STATE_FRAME_LEN]>,
+}
+
+impl Outbox {
+    fn push(&mut self, frame: [u8; STATE_FRAME_LEN], write: impl FnMut(&[u8]) -> usize) {
+        self.latest = Some(frame);
+        self.flush(write);
+    }
+
+    /// Send what fits. `write` returns how many bytes it took without
+    /// blocking, 0 when none.
+    fn flush(&mut self, mut write: impl FnMut(&[u8]) -> usize) {
+        if !self.partial.is_empty() {
+            let sent = write(&self.partial);
+            self.partial.drain(..sent);
+            if !self.partial.is_empty() {
+                return;
+            }
+        }
+        if let Some(frame) = self.latest.take() {
+            match write(&frame) {
+                0 => self.latest = Some(frame),
+                sent => self.partial = frame[sent..].to_vec(),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::sync::{Mutex, PoisonError};
+
+    use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+
+    use killcode_overload::protocol::{
+        Report, StubState, HEALTH_KEY_LEN, TRANSPORT_SHARED_MEMORY, TRANSPORT_STREAM,
+    };
+
+    use super::{Inbox, Outbox, Transport};
+    use crate::common;
+    use crate::{ConfigFooter, HealthStatus};
+
+    pub type RawChannel = RawFd;
+
+    /// A region shared with the overload through an inherited descriptor
+    struct SharedMemory {
+        status: *mut HealthStatus,
+        region: OwnedFd,
+        name: &'static str,
+    }
+
+    // Every field of the region is accessed through atomics
+    unsafe impl Send for SharedMemory {}
+    unsafe impl Sync for SharedMemory {}
+
+    pub fn shared_memory(footer: &ConfigFooter) -> Result<Box<dyn Transport>, String> {
+        let (region, name) = anonymous_region(footer)?;
+        nix::unistd::ftruncate(&region, HealthStatus::SIZE as libc::off_t)
+            .map_err(|e| format!("ftruncate failed: {}", e))?;
+        let status = unsafe {
+            mmap(
+                None,
+                std::num::NonZeroUsize::new(HealthStatus::SIZE).unwrap(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                &region,
+                0,
+            )
+        }
+        .map_err(|e| format!("mmap failed: {}", e))?;
+        let status = status.as_ptr() as *mut HealthStatus;
+        unsafe { HealthStatus::init(status) };
+        Ok(Box::new(SharedMemory { status, region, name }))
+    }
+
+    impl SharedMemory {
+        fn status(&self) -> &HealthStatus {
+            unsafe { &*self.status }
+        }
+    }
+
+    impl Transport for SharedMemory {
+        fn kind(&self) -> &'static str {
+            TRANSPORT_SHARED_MEMORY
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn overload_end(&self) -> RawFd {
+            self.region.as_raw_fd()
+        }
+
+        fn read_report(&self) -> Option<(Report, [u8; 32])> {
+            self.status().read_report()
+        }
+
+        fn publish(&self, state: &StubState) {
+            self.status().set_state(state);
+        }
+
+        fn reset(&self) {
+            self.status().clear_report();
+        }
+
+        #[cfg(target_os = "linux")]
+        fn revoke(&self) {
+            unsafe {
+                libc::munmap(self.status as *mut libc::c_void, HealthStatus::SIZE);
+                libc::close(self.region.as_raw_fd());
+            }
+        }
+    }
+
+    impl Drop for SharedMemory {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.status as *mut libc::c_void, HealthStatus::SIZE) };
+        }
+    }
+
+    /// A memfd, or a shm object unlinked right away where memfds aren't available
+    #[cfg(target_os = "linux")]
+    fn anonymous_region(footer: &ConfigFooter) -> Result<(OwnedFd, &'static str), String> {
+        use nix::sys::memfd::{memfd_create, MFdFlags};
+        let name = CString::new(common::instance_name(footer, "health")).unwrap();
+        match memfd_create(name.as_c_str(), MFdFlags::MFD_CLOEXEC) {
+            Ok(fd) => Ok((fd, "memfd")),
+            Err(memfd) => unlinked_shm(footer)
+                .map(|fd| (fd, "unlinked shm"))
+                .map_err(|shm| format!("memfd_create failed: {}; {}", memfd, shm)),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn anonymous_region(footer: &ConfigFooter) -> Result<(OwnedFd, &'static str), String> {
+        unlinked_shm(footer).map(|fd| (fd, "unlinked shm"))
+    }
+
+    /// A shm object that is unlinked as soon as it is open, so nobody can
+    /// open it by name and nothing is left behind (shm_open sets FD_CLOEXEC).
+    /// A name left by a stub with the same PID that died in between is reused.
+    fn unlinked_shm(footer: &ConfigFooter) -> Result<OwnedFd, String> {
+        use nix::errno::Errno;
+        use nix::fcntl::OFlag;
+        use nix::sys::mman::{shm_open, shm_unlink};
+        use nix::sys::stat::Mode;
+
+        let name = format!("/{}{}", common::instance_name(footer, "health"), std::process::id());
+        let name_c = CString::new(name.clone()).unwrap();
+        let open = || {
+            shm_open(
+                name_c.as_c_str(),
+                OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR,
+                Mode::from_bits_truncate(0o600),
+            )
+        };
+        let fd = match open() {
+            Err(Errno::EEXIST) => {
+                let _ = shm_unlink(name.as_str());
+                open()
+            }
+            result => result,
+        }
+        .map_err(|e| format!("shm_open failed: {}", e))?;
+        let _ = shm_unlink(name.as_str());
+        Ok(fd)
+    }
+
+    /// A socketpair. The overload inherits one end, the stub reads reports
+    /// from the other.
+    struct SocketPair {
+        stub_end: OwnedFd,
+        overload_end: OwnedFd,
+        inbox: Mutex<Inbox>,
+        outbox: Mutex<Outbox>,
+    }
+
+    pub fn stream(_footer: &ConfigFooter) -> Result<Box<dyn Transport>, String> {
+        let mut fds = [0; 2];
+        if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+            return Err(format!("socketpair failed: {}", std::io::Error::last_os_error()));
+        }
+        let (stub_end, overload_end) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        for fd in [&stub_end, &overload_end] {
+            unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        // A write after the overload is gone must fail, not raise SIGPIPE
+        #[cfg(target_os = "macos")]
+        unsafe {
+            let on: libc::c_int = 1;
+            libc::setsockopt(
+                stub_end.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_NOSIGPIPE,
+                &on as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&on) as libc::socklen_t,
+            );
+        }
+        Ok(Box::new(SocketPair { stub_end, overload_end, inbox: Mutex::default(), outbox: Mutex::default() }))
+    }
+
+    impl Transport for SocketPair {
+        fn kind(&self) -> &'static str {
+            TRANSPORT_STREAM
+        }
+
+        fn name(&self) -> &'static str {
+            "socketpair"
+        }
+
+        fn overload_end(&self) -> RawFd {
+            self.overload_end.as_raw_fd()
+        }
+
+        fn read_report(&self) -> Option<(Report, [u8; 32])> {
+            let mut inbox = self.inbox.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut buffer = [0u8; 4096];
+            loop {
+                let read = unsafe {
+                    libc::recv(
+                        self.stub_end.as_raw_fd(),
+                        buffer.as_mut_ptr().cast(),
+                        buffer.len(),
+                        libc::MSG_DONTWAIT,
+                    )
+                };
+                if read <= 0 {
+                    break;
+                }
+                inbox.push(&buffer[..read as usize]);
+            }
+            // The stub reads often, which sends what didn't fit before
+            self.outbox().flush(|bytes| self.send(bytes));
+            inbox.latest
+        }
+
+        fn publish(&self, state: &StubState) {
+            self.outbox().push(state.to_frame(), |bytes| self.send(bytes));
+        }
+
+        fn reset(&self) {
+            // Take what is still queued, including a frame cut off by a crash
+            self.read_report();
+            *self.inbox.lock().unwrap_or_else(PoisonError::into_inner) = Inbox::default();
+        }
+
+        #[cfg(target_os = "linux")]
+        fn revoke(&self) {
+            unsafe {
+                libc::close(self.stub_end.as_raw_fd());
+                libc::close(self.overload_end.as_raw_fd());
+            }
+        }
+    }
+
+    impl SocketPair {
+        fn outbox(&self) -> std::sync::MutexGuard<'_, Outbox> {
+            self.outbox.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Send what fits without blocking
+        fn send(&self, bytes: &[u8]) -> usize {
+            // In-process bases reset SIGPIPE to its default in this process
+            #[cfg(target_os = "linux")]
+            let flags = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+            #[cfg(not(target_os = "linux"))]
+            let flags = libc::MSG_DONTWAIT;
+            let sent = unsafe { libc::send(self.stub_end.as_raw_fd(), bytes.as_ptr().cast(), bytes.len(), flags) };
+            sent.max(0) as usize
+        }
+    }
+
+    /// Read end of a pipe the key was written to
+    pub struct KeyPipe(OwnedFd);
+
+    impl KeyPipe {
+        pub fn create(key: &[u8; HEALTH_KEY_LEN]) -> Result<Self, String> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+                return Err(format!("pipe failed: {}", std::io::Error::last_os_error()));
+            }
+            for fd in fds {
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            }
+            let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+            { writer }.write_all(key).map_err(|e| format!("Cannot write the report key: {}", e))?;
+            Ok(Self(reader))
+        }
+
+        pub fn raw(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn random_key() -> Result<[u8; HEALTH_KEY_LEN], String> {
+        let mut key = [0u8; HEALTH_KEY_LEN];
+        let filled = unsafe { libc::getrandom(key.as_mut_ptr() as *mut libc::c_void, key.len(), 0) };
+        if filled != key.len() as isize {
+            return Err(format!("getrandom failed: {}", std::io::Error::last_os_error()));
+        }
+        Ok(key)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn random_key() -> Result<[u8; HEALTH_KEY_LEN], String> {
+        let mut key = [0u8; HEALTH_KEY_LEN];
+        if unsafe { libc::getentropy(key.as_mut_ptr() as *mut libc::c_void, key.len()) } != 0 {
+            return Err(format!("getentropy failed: {}", std::io::Error::last_os_error()));
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::CString;
+    use std::ptr;
+    use std::sync::{Mutex, PoisonError};
+
+    use windows_sys::Win32::Foundation::{
+        CloseHandle, GetLastError, SetHandleInformation, ERROR_PIPE_CONNECTED, GENERIC_READ, GENERIC_WRITE, HANDLE,
+        HANDLE_FLAG_INHERIT, INVALID_HANDLE_VALUE,
+    };
+    use windows_sys::Win32::Security::Cryptography::{BCryptGenRandom, BCRYPT_USE_SYSTEM_PREFERRED_RNG};
+    use windows_sys::Win32::Storage::FileSystem::{
+        CreateFileA, ReadFile, WriteFile, FILE_FLAG_FIRST_PIPE_INSTANCE, OPEN_EXISTING, PIPE_ACCESS_DUPLEX,
+    };
+    use windows_sys::Win32::System::Memory::{
+        CreateFileMappingA, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS, MEMORY_MAPPED_VIEW_ADDRESS,
+        PAGE_READWRITE,
+    };
+    use windows_sys::Win32::System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeA, CreatePipe, PeekNamedPipe, SetNamedPipeHandleState, PIPE_NOWAIT,
+        PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_WAIT,
+    };
+
+    use killcode_overload::protocol::{
+        Report, StubState, HEALTH_KEY_LEN, TRANSPORT_SHARED_MEMORY, TRANSPORT_STREAM,
+    };
+
+    use super::{Inbox, Outbox, Transport};
+    use crate::common;
+    use crate::{ConfigFooter, HealthStatus};
+
+    /// Handle value, as named in the overload's environment
+    pub type RawChannel = usize;
+
+    pub fn set_inheritable(handle: RawChannel, inheritable: bool) {
+        let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+        unsafe { SetHandleInformation(handle as HANDLE, HANDLE_FLAG_INHERIT, flags) };
+    }
+
+    /// An unnamed file mapping shared with the overload through an inherited handle
+    struct FileMapping {
+        status: *mut HealthStatus,
+        mapping: HANDLE,
+    }
+
+    // Every field of the region is accessed through atomics
+    unsafe impl Send for FileMapping {}
+    unsafe impl Sync for FileMapping {}
+
+    pub fn shared_memory(_footer: &ConfigFooter) -> Result<Box<dyn Transport>, String> {
+        unsafe {
+            let mapping = CreateFileMappingA(
+                INVALID_HANDLE_VALUE,
+                ptr::null(),
+                PAGE_READWRITE,
+                0,
+                HealthStatus::SIZE as u32,
+                ptr::null(),
+            );
+            if mapping.is_null() {
+                return Err(format!("CreateFileMappingA failed: {}", GetLastError()));
+            }
+            let view = MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, HealthStatus::SIZE);
+            if view.Value.is_null() {
+                let error = GetLastError();
+                CloseHandle(mapping);
+                return Err(format!("MapViewOfFile failed: {}", error));
+            }
+            let status = view.Value as *mut HealthStatus;
+            HealthStatus::init(status);
+            Ok(Box::new(FileMapping { status, mapping }))
+        }
+    }
+
+    impl Transport for FileMapping {
+        fn kind(&self) -> &'static str {
+            TRANSPORT_SHARED_MEMORY
+        }
+
+        fn name(&self) -> &'static str {
+            "unnamed file mapping"
+        }
+
+        fn overload_end(&self) -> RawChannel {
+            self.mapping as usize
+        }
+
+        fn read_report(&self) -> Option<(Report, [u8; 32])> {
+            unsafe { &*self.status }.read_report()
+        }
+
+        fn publish(&self, state: &StubState) {
+            unsafe { &*self.status }.set_state(state);
+        }
+
+        fn reset(&self) {
+            unsafe { &*self.status }.clear_report();
+        }
+    }
+
+    impl Drop for FileMapping {
+        fn drop(&mut self) {
+            unsafe {
+                UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: self.status as *mut _ });
+                CloseHandle(self.mapping);
+            }
+        }
+    }
+
+    /// A single-instance named pipe. The stub opens the client end itself
+    /// right away, so nobody else can connect, and the overload inherits it.
+    /// The stub's end doesn't block once connected.
+    struct NamedPipe {
+        server: HANDLE,
+        client: HANDLE,
+        inbox: Mutex<Inbox>,
+        outbox: Mutex<Outbox>,
+    }
+
+    unsafe impl Send for NamedPipe {}
+    unsafe impl Sync for NamedPipe {}
+
+    pub fn stream(footer: &ConfigFooter) -> Result<Box<dyn Transport>, String> {
+        let name = format!(r"\\.\pipe\{}{}", common::instance_name(footer, "health"), std::process::id());
+        let name_c = CString::new(name).unwrap();
+        unsafe {
+            let server = CreateNamedPipeA(
+                name_c.as_ptr() as *const u8,
+                PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                4096,
+                4096,
+                0,
+                ptr::null(),
+            );
+            if server == INVALID_HANDLE_VALUE {
+                return Err(format!("CreateNamedPipeA failed: {}", GetLastError()));
+            }
+            let client = CreateFileA(
+                name_c.as_ptr() as *const u8,
+                GENERIC_READ | GENERIC_WRITE,
+                0,
+                ptr::null(),
+                OPEN_EXISTING,
+                0,
+                ptr::null_mut(),
+            );
+            if client == INVALID_HANDLE_VALUE {
+                let error = GetLastError();
+                CloseHandle(server);
+                return Err(format!("Cannot open the named pipe: {}", error));
+            }
+            if ConnectNamedPipe(server, ptr::null_mut()) == 0 && GetLastError() != ERROR_PIPE_CONNECTED {
+                let error = GetLastError();
+                CloseHandle(client);
+                CloseHandle(server);
+                return Err(format!("ConnectNamedPipe failed: {}", error));
+            }
+            // A full buffer must not block the stub's writes
+            let mode = PIPE_READMODE_BYTE | PIPE_NOWAIT;
+            if SetNamedPipeHandleState(server, &mode, ptr::null(), ptr::null()) == 0 {
+                let error = GetLastError();
+                CloseHandle(client);
+                CloseHandle(server);
+                return Err(format!("SetNamedPipeHandleState failed: {}", error));
+            }
+            Ok(Box::new(NamedPipe { server, client, inbox: Mutex::default(), outbox: Mutex::default() }))
+        }
+    }
+
+    impl Transport for NamedPipe {
+        fn kind(&self) -> &'static str {
+            TRANSPORT_STREAM
+        }
+
+        fn name(&self) -> &'static str {
+            "named pipe"
+        }
+
+        fn overload_end(&self) -> RawChannel {
+            self.client as usize
+        }
+
+        fn read_report(&self) -> Option<(Report, [u8; 32])> {
+            let mut inbox = self.inbox.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut buffer = [0u8; 4096];
+            loop {
+                let mut available = 0;
+                let peeked = unsafe {
+                    PeekNamedPipe(self.server, ptr::null_mut(), 0, ptr::null_mut(), &mut available, ptr::null_mut())
+                };
+                if peeked == 0 || available == 0 {
+                    break;
+                }
+                let mut read = 0;
+                let wanted = buffer.len().min(available as usize) as u32;
+                if unsafe { ReadFile(self.server, buffer.as_mut_ptr(), wanted, &mut read, ptr::null_mut()) } == 0 {
+                    break;
+                }
+                inbox.push(&buffer[..read as usize]);
+            }
+            // The stub reads often, which sends what didn't fit before
+            self.outbox().flush(|bytes| self.write(bytes));
+            inbox.latest
+        }
+
+        fn publish(&self, state: &StubState) {
+            self.outbox().push(state.to_frame(), |bytes| self.write(bytes));
+        }
+
+        fn reset(&self) {
+            // Take what is still queued, including a frame cut off by a crash
+            self.read_report();
+            *self.inbox.lock().unwrap_or_else(PoisonError::into_inner) = Inbox::default();
+        }
+    }
+
+    impl NamedPipe {
+        fn outbox(&self) -> std::sync::MutexGuard<'_, Outbox> {
+            self.outbox.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Write what fits, the pipe doesn't wait for room
+        fn write(&self, bytes: &[u8]) -> usize {
+            let mut written = 0;
+            let wrote =
+                unsafe { WriteFile(self.server, bytes.as_ptr(), bytes.len() as u32, &mut written, ptr::null_mut()) };
+            if wrote == 0 {
+                return 0;
+            }
+            written as usize
+        }
+    }
+
+    impl Drop for NamedPipe {
+        fn drop(&mut self) {
+            unsafe {
+                CloseHandle(self.client);
+                CloseHandle(self.server);
+            }
+        }
+    }
+
+    /// Read end of a pipe the key was written to
+    pub struct KeyPipe(HANDLE);
+
+    unsafe impl Send for KeyPipe {}
+
+    impl KeyPipe {
+        pub fn create(key: &[u8; HEALTH_KEY_LEN]) -> Result<Self, String> {
+            unsafe {
+                let (mut reader, mut writer): (HANDLE, HANDLE) = (ptr::null_mut(), ptr::null_mut());
+                if CreatePipe(&mut reader, &mut writer, ptr::null(), 0) == 0 {
+                    return Err(format!("CreatePipe failed: {}", GetLastError()));
+                }
+                let mut written = 0;
+                let wrote = WriteFile(writer, key.as_ptr(), key.len() as u32, &mut written, ptr::null_mut());
+                CloseHandle(writer);
+                if wrote == 0 || written as usize != key.len() {
+                    CloseHandle(reader);
+                    return Err(format!("Cannot write the report key: {}", GetLastError()));
+                }
+                Ok(Self(reader))
+            }
+        }
+
+        pub fn raw(&self) -> RawChannel {
+            self.0 as usize
+        }
+    }
+
+    impl Drop for KeyPipe {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    pub fn random_key() -> Result<[u8; HEALTH_KEY_LEN], String> {
+        let mut key = [0u8; HEALTH_KEY_LEN];
+        let status = unsafe {
+            BCryptGenRandom(ptr::null_mut(), key.as_mut_ptr(), key.len() as u32, BCRYPT_USE_SYSTEM_PREFERRED_RNG)
+        };
+        if status != 0 {
+            return Err(format!("BCryptGenRandom failed: {:#x}", status));
+        }
+        Ok(key)
+    }
+}
+
+/// Stands in for the overload in tests of the stub's checks
+#[cfg(all(test, unix))]
+impl HealthChannel {
+    /// Sign `report` with the current key and send it over a stream channel
+    pub fn send_report(&self, report: &Report) {
+        let key = *self.key.lock().unwrap_or_else(PoisonError::into_inner);
+        let frame = report.to_frame(&report.mac(&key));
+        let sent = unsafe { libc::send(self.transport.overload_end(), frame.as_ptr().cast(), frame.len(), 0) };
+        assert_eq!(sent, frame.len() as isize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_framing() {
+        // Reports arrive cut anywhere, only complete frames count
+        let first = Report { counter: 1, last_success: 10, ..Default::default() };
+        let second = Report { counter: 2, last_success: 20, ..Default::default() };
+        let mut bytes = first.to_frame(&[1; 32]).to_vec();
+        bytes.extend_from_slice(&second.to_frame(&[2; 32]));
+        let mut inbox = Inbox::default();
+        inbox.push(&bytes[..REPORT_FRAME_LEN - 3]);
+        assert!(inbox.latest.is_none());
+        inbox.push(&bytes[REPORT_FRAME_LEN - 3..REPORT_FRAME_LEN + 5]);
+        assert_eq!(inbox.latest, Some((first, [1; 32])));
+        inbox.push(&bytes[REPORT_FRAME_LEN + 5..]);
+        assert_eq!(inbox.latest, Some((second, [2; 32])));
+        assert!(inbox.received.is_empty());
+
+        // A full stream takes nothing, then part of a frame: the rest goes
+        // out before anything newer, which replaces what never started
+        let frames: Vec<_> = (1..=3)
+            .map(|pid| StubState { base_pid: pid, ..Default::default() }.to_frame())
+            .collect();
+        let mut sent = Vec::new();
+        let mut outbox = Outbox::default();
+        outbox.push(frames[0], |_| 0);
+        outbox.push(frames[1], |bytes| {
+            sent.extend_from_slice(&bytes[..10]);
+            10
+        });
+        outbox.push(frames[2], |_| 0);
+        outbox.flush(|bytes| {
+            sent.extend_from_slice(bytes);
+            bytes.len()
+        });
+        outbox.flush(|bytes| {
+            sent.extend_from_slice(bytes);
+            bytes.len()
+        });
+        let received: Vec<_> = sent
+            .chunks_exact(STATE_FRAME_LEN)
+            .map(|frame| StubState::from_frame(frame.try_into().unwrap()).unwrap().base_pid)
+            .collect();
+        assert_eq!(received, [2, 3]);
+        assert_eq!(sent.len(), 2 * STATE_FRAME_LEN);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unread_stream_does_not_block() {
+        let footer = ConfigFooter { health_transport: HEALTH_TRANSPORT_STREAM, ..unsafe { std::mem::zeroed() } };
+        let channel = Arc::new(HealthChannel::create(&footer).unwrap());
+
+        // An overload that never reads: far more frames than the socket
+        // holds, then the base ends
+        let (done, finished) = std::sync::mpsc::channel();
+        let stub = channel.clone();
+        std::thread::spawn(move || {
+            for pid in 1..=2000 {
+                stub.set_base_pid(pid);
+            }
+            stub.report_base_exit(BaseExit { code: 7, ..Default::default() });
+            done.send(()).unwrap();
+        });
+        finished.recv_timeout(std::time::Duration::from_secs(10)).expect("publishing blocked");
+
+        // Once the overload catches up, every frame is whole and the last
+        // one, sent when the stub next reads, carries the exit
+        let mut received = Vec::new();
+        let mut drain = || {
+            let mut buffer = [0u8; 4096];
+            loop {
+                let read = unsafe {
+                    libc::recv(
+                        channel.transport.overload_end(),
+                        buffer.as_mut_ptr().cast(),
+                        buffer.len(),
+                        libc::MSG_DONTWAIT,
+                    )
+                };
+                if read <= 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..read as usize]);
+            }
+        };
+        drain();
+        channel.transport.read_report();
+        drain();
+        assert_eq!(received.len() % STATE_FRAME_LEN, 0);
+        let frames: Vec<_> = received
+            .chunks_exact(STATE_FRAME_LEN)
+            .map(|frame| StubState::from_frame(frame.try_into().unwrap()).unwrap())
+            .collect();
+        let last = frames.last().unwrap();
+        assert_eq!((last.base_pid, last.base_exit.map(|exit| exit.code)), (2000, Some(7)));
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::ConfigFooter;
+
+/// Executable region of the stub itself
+pub const INTEGRITY_KIND_STUB_CODE: u32 = 1;
+/// Embedded base payload
+pub const INTEGRITY_KIND_BASE: u32 = 2;
+/// Embedded overload payload
+pub const INTEGRITY_KIND_OVERLOAD: u32 = 3;
+
+const RECORD_SIZE: usize = 64;
+
+/// What to do when a hash no longer matches (`ConfigFooter::integrity_action`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityAction {
+    Exit,
+    KillBase,
+    NotifyOverload,
+}
+
+impl IntegrityAction {
+    pub fn from_footer(footer: &ConfigFooter) -> Self {
+        match footer.integrity_action {
+            1 => IntegrityAction::KillBase,
+            2 => IntegrityAction::NotifyOverload,
+            _ => IntegrityAction::Exit,
+        }
+    }
+}
+
+/// Hash of one region of the merged binary (see weaver's
+/// `core::protection::integrity`).
+#[derive(Debug, Clone, Copy)]
+pub struct IntegrityRecord {
+    pub kind: u32,
+    pub file_offset: u64,
+    pub size: u64,
+    pub vaddr: u64,
+    pub hash: [u8; 32],
+}
+
+impl IntegrityRecord {
+    fn describe(&self) -> String {
+        match self.kind {
+            INTEGRITY_KIND_STUB_CODE => format!("stub code at {:#x}", self.vaddr),
+            INTEGRITY_KIND_BASE => "base payload".to_string(),
+            INTEGRITY_KIND_OVERLOAD => "overload payload".to_string(),
+            _ => format!("unknown region {}", self.kind),
+        }
+    }
+}
+
+/// Read the integrity table and check its MAC against the footer.
+///
+/// The key sits in the footer next to the MAC, and the footer can be
+/// unsealed with the key at the end of the file. The MAC catches corruption
+/// and patches that don't know the format; whoever does can re-sign the
+/// table or clear `integrity_count`.
+pub fn read_records(file: &mut File, footer: &ConfigFooter) -> Result<Vec<IntegrityRecord>, String> {
+    if footer.integrity_count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut table = vec![0u8; footer.integrity_count as usize * RECORD_SIZE];
+    file.seek(SeekFrom::Start(footer.integrity_offset))
+        .and_then(|_| file.read_exact(&mut table))
+        .map_err(|e| format!("Failed to read integrity table: {}", e))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&footer.integrity_key).expect("HMAC accepts any key length");
+    mac.update(&table);
+    mac.verify_slice(&footer.integrity_mac)
+        .map_err(|_| "Integrity table signature mismatch".to_string())?;
+
+    Ok(table
+        .chunks_exact(RECORD_SIZE)
+        .map(|bytes| {
+            let field = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+            IntegrityRecord {
+                kind: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                file_offset: field(8),
+                size: field(16),
+                vaddr: field(24),
+                hash: bytes[32..64].try_into().unwrap(),
+            }
+        })
+        .collect())
+}
+
+fn check(record: &IntegrityRecord, data: &[u8]) -> Result<(), String> {
+    let digest: [u8; 32] = Sha256::digest(data).into();
+    if digest != record.hash {
+        return Err(format!("Integrity check failed for {}", record.describe()));
+    }
+    Ok(())
+}
+
+fn check_file_region(file: &mut File, record: &IntegrityRecord) -> Result<(), String> {
+    let mut data = vec![0u8; record.size as usize];
+    file.seek(SeekFrom::Start(record.file_offset))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(|e| format!("Failed to read {}: {}", record.describe(), e))?;
+    check(record, &data)
+}
+
+/// Integrity state handed to the platform runner and its monitor thread
+pub struct IntegrityGuard {
+    records: Vec<IntegrityRecord>,
+    pub action: IntegrityAction,
+    /// The startup check failed but the action lets the base run anyway
+    pub startup_violation: bool,
+}
+
+impl IntegrityGuard {
+    pub fn new(records: Vec<IntegrityRecord>, action: IntegrityAction) -> Self {
+        Self {
+            records,
+            action,
+            startup_violation: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.records.is_empty()
+    }
+
+    /// Whether a violation is reported through the health channel
+    pub fn notifies_overload(&self) -> bool {
+        self.is_enabled() && self.action == IntegrityAction::NotifyOverload
+    }
+
+    pub fn verify_startup(&self, file: &mut File, base_data: &[u8], overload_data: &[u8]) -> Result<(), String> {
+        verify_startup(file, &self.records, base_data, overload_data)
+    }
+
+    pub fn verify_runtime(&self) -> Result<(), String> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        verify_runtime(&self.records)
+    }
+}
+
+/// Verify the stub code on disk and the payloads as they were read, before
+/// anything is decrypted or launched
+fn verify_startup(
+    file: &mut File,
+    records: &[IntegrityRecord],
+    base_data: &[u8],
+    overload_data: &[u8],
+) -> Result<(), String> {
+    for record in records {
+        match record.kind {
+            INTEGRITY_KIND_BASE => check(record, base_data)?,
+            INTEGRITY_KIND_OVERLOAD => check(record, overload_data)?,
+            _ => check_file_region(file, record)?,
+        }
+    }
+    Ok(())
+}
+
+/// Re-verify the stub's code as it is mapped in memory.
+///
+/// Catches patches applied to the running stub (e.g. through ptrace) after
+/// the startup check passed.
+#[cfg(target_os = "linux")]
+fn verify_runtime(records: &[IntegrityRecord]) -> Result<(), String> {
+    let bias = load_bias();
+    for record in records.iter().filter(|r| r.kind == INTEGRITY_KIND_STUB_CODE) {
+        let code = unsafe {
+            std::slice::from_raw_parts((bias + record.vaddr as usize) as *const u8, record.size as usize)
+        };
+        check(record, code)?;
+    }
+    Ok(())
+}
+
+/// Re-verify the stub's code by re-reading the executable from disk
+#[cfg(not(target_os = "linux"))]
+fn verify_runtime(records: &[IntegrityRecord]) -> Result<(), String> {
+    let mut file = std::env::current_exe()
+        .and_then(File::open)
+        .map_err(|e| format!("Failed to open stub executable: {}", e))?;
+    for record in records.iter().filter(|r| r.kind == INTEGRITY_KIND_STUB_CODE) {
+        check_file_region(&mut file, record)?;
+    }
+    Ok(())
+}
+
+/// Load bias of the main executable (the first object reported by the loader)
+#[cfg(target_os = "linux")]
+fn load_bias() -> usize {
+    unsafe extern "C" fn first_object(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        data: *mut libc::c_void,
+    ) -> libc::c_int {
+        *(data as *mut usize) = (*info).dlpi_addr as usize;
+        1
+    }
+
+    let mut bias: usize = 0;
+    unsafe {
+        libc::dl_iterate_phdr(Some(first_object), &mut bias as *mut usize as *mut libc::c_void);
+    }
+    bias
+}
@@ -0,0 +1,232 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::ptr;
+
+use nix::sys::memfd::{memfd_create, MFdFlags};
+
+use crate::anti_debug;
+use crate::common::{self, footer_path, log_exec_strategy_failed, log_no_exec_strategy, log_payload_started};
+use crate::signals;
+use crate::userland;
+use crate::ConfigFooter;
+
+/// The stub maps the payloads itself instead of exec'ing them
+pub const EXEC_MODE_IN_PROCESS: u8 = 1;
+/// Only exec from a memfd
+pub const EXEC_MODE_MEMFD: u8 = 2;
+/// Only exec from a temporary file in an exec-allowed directory
+pub const EXEC_MODE_TMPFILE: u8 = 3;
+
+/// Exit code of a child that could not start its payload (as a shell's
+/// "found but not executable")
+pub const EXIT_CANNOT_EXECUTE: i32 = 126;
+
+/// Reported on the status pipe once every strategy failed
+const ALL_FAILED: u8 = 0xff;
+
+/// One way of starting a payload in a forked child
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Strategy {
+    /// `execveat(AT_EMPTY_PATH)` on a memfd. Fails with `vm.memfd_noexec=2` or
+    /// SELinux policies that deny executing memfds.
+    Memfd = 0,
+    /// `execveat` on an `O_TMPFILE` in an exec-allowed directory (a named file
+    /// that is unlinked right away where that isn't supported)
+    TmpFile = 1,
+    /// The stub's own ELF loader. Needs neither exec nor `/proc`.
+    InProcess = 2,
+}
+
+impl Strategy {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Strategy::Memfd),
+            1 => Some(Strategy::TmpFile),
+            2 => Some(Strategy::InProcess),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Strategy::Memfd => "memfd",
+            Strategy::TmpFile => "tmpfile",
+            Strategy::InProcess => "in-process",
+        }
+    }
+}
+
+/// Strategies tried for a footer `exec_mode`, in order. The default (0) tries
+/// memfd, then a temporary file, then in-process loading.
+fn strategies(exec_mode: u8) -> &'static [Strategy] {
+    match exec_mode {
+        EXEC_MODE_IN_PROCESS => &[Strategy::InProcess],
+        EXEC_MODE_MEMFD => &[Strategy::Memfd],
+        EXEC_MODE_TMPFILE => &[Strategy::TmpFile],
+        _ => &[Strategy::Memfd, Strategy::TmpFile, Strategy::InProcess],
+    }
+}
+
+/// Pipe the child reports each strategy on before trying it. The write end is
+/// close-on-exec, so the parent reads until a strategy succeeds.
+pub fn status_pipe() -> Result<(OwnedFd, OwnedFd), String> {
+    nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC).map_err(|e| format!("pipe2 failed: {}", e))
+}
+
+/// Called in the parent after the fork: wait until the child started the
+/// payload and log how. None if every strategy failed.
+pub fn wait_started(reader: OwnedFd, name: &str) -> Option<Strategy> {
+    let mut reports = Vec::new();
+    let _ = File::from(reader).read_to_end(&mut reports);
+    let strategy = reports.last().copied().and_then(Strategy::from_byte)?;
+    log_payload_started(name, strategy.name());
+    Some(strategy)
+}
+
+/// Called in the forked child: start the payload with the first strategy that
+/// works. Only returns if all of them failed, after logging why.
+pub fn exec(binary_data: &[u8], name: &str, footer: &ConfigFooter, status: OwnedFd) {
+    signals::restore_sigpipe();
+    let mut status = Some(status);
+    let mut failures = Vec::new();
+    for &strategy in strategies(footer.exec_mode) {
+        report(&status, strategy as u8);
+        let error = match strategy {
+            Strategy::Memfd => exec_memfd(binary_data, name, footer),
+            Strategy::TmpFile => exec_tmpfile(binary_data, name, footer),
+            Strategy::InProcess => exec_in_process(binary_data, name, &mut status),
+        };
+        log_exec_strategy_failed(name, strategy.name(), &error);
+        failures.push(format!("{}: {}", strategy.name(), error));
+    }
+    report(&status, ALL_FAILED);
+    log_no_exec_strategy(name, &failures.join("; "));
+}
+
+fn report(status: &Option<OwnedFd>, byte: u8) {
+    if let Some(fd) = status {
+        unsafe { libc::write(fd.as_raw_fd(), [byte].as_ptr().cast(), 1) };
+    }
+}
+
+fn exec_memfd(binary_data: &[u8], name: &str, footer: &ConfigFooter) -> String {
+    let memfd_name = CString::new(common::instance_name(footer, name)).unwrap();
+    let fd = match memfd_create(memfd_name.as_c_str(), MFdFlags::MFD_CLOEXEC) {
+        Ok(fd) => fd,
+        Err(e) => return format!("memfd_create failed: {}", e),
+    };
+    let mut file = File::from(fd);
+    if let Err(e) = file.write_all(binary_data) {
+        return format!("write failed: {}", e);
+    }
+    anti_debug::restrict_image(footer.anti_debug_flags, file.as_raw_fd());
+    execveat(file.as_raw_fd(), name)
+}
+
+fn exec_tmpfile(binary_data: &[u8], name: &str, footer: &ConfigFooter) -> String {
+    let mut failures = Vec::new();
+    for dir in exec_dirs(footer) {
+        let error = match tmpfile_image(&dir, binary_data, name, footer) {
+            Ok(file) => {
+                anti_debug::restrict_image(footer.anti_debug_flags, file.as_raw_fd());
+                execveat(file.as_raw_fd(), name)
+            }
+            Err(e) => e.to_string(),
+        };
+        failures.push(format!("{}: {}", dir.display(), error));
+    }
+    failures.join(", ")
+}
+
+/// Load the payload into the forked child. The status pipe has no exec to
+/// close it, so it is closed right before the jump.
+fn exec_in_process(binary_data: &[u8], name: &str, status: &mut Option<OwnedFd>) -> String {
+    let env: Vec<_> = std::env::vars_os().collect();
+    match userland::prepare(binary_data, &common::payload_args(name), &env) {
+        Ok(image) => {
+            status.take();
+            userland::reset_signals();
+            unsafe { userland::jump(image) }
+        }
+        Err(e) => e,
+    }
+}
+
+/// Directories a temporary image may be written to: the footer's `exec_dir`,
+/// or else the merged binary's own directory (it ran from there, so exec is
+/// allowed), `$TMPDIR` and `/dev/shm`
+fn exec_dirs(footer: &ConfigFooter) -> Vec<PathBuf> {
+    if let Some(dir) = footer_path(&footer.exec_dir) {
+        return vec![PathBuf::from(dir)];
+    }
+    let own_dir = crate::own_path().ok().and_then(|exe| exe.parent().map(PathBuf::from));
+    own_dir
+        .into_iter()
+        .chain([std::env::temp_dir(), PathBuf::from("/dev/shm")])
+        .collect()
+}
+
+/// A read-only descriptor of the payload written to `dir`.
+///
+/// A file still open for writing can't be exec'd (`ETXTBSY`), so the
+/// `O_TMPFILE` is re-opened read-only through `/proc/self/fd`. Without
+/// `O_TMPFILE` or `/proc`, a named file is used and unlinked as soon as it is open.
+fn tmpfile_image(dir: &PathBuf, binary_data: &[u8], name: &str, footer: &ConfigFooter) -> std::io::Result<File> {
+    let anonymous = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .mode(0o700)
+        .custom_flags(libc::O_TMPFILE)
+        .open(dir)
+        .and_then(|mut file| {
+            file.write_all(binary_data)?;
+            File::open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        });
+    if anonymous.is_ok() {
+        return anonymous;
+    }
+
+    let path = dir.join(format!(".{}{}", common::instance_name(footer, name), std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o700)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)?;
+    let written = file.write_all(binary_data);
+    drop(file);
+    let image = written.and_then(|_| File::open(&path));
+    let _ = std::fs::remove_file(&path);
+    image
+}
+
+/// Exec the file behind `fd` without going through `/proc`. Only returns on failure.
+fn execveat(fd: RawFd, name: &str) -> String {
+    let args: Vec<CString> = common::payload_args(name)
+        .into_iter()
+        .filter_map(|arg| CString::new(arg.into_vec()).ok())
+        .collect();
+    let mut argv: Vec<*const libc::c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(ptr::null());
+    let env: Vec<CString> = std::env::vars_os()
+        .filter_map(|(key, value)| {
+            let mut entry = key.into_vec();
+            entry.push(b'=');
+            entry.extend(value.into_vec());
+            CString::new(entry).ok()
+        })
+        .collect();
+    let mut envp: Vec<*const libc::c_char> = env.iter().map(|entry| entry.as_ptr()).collect();
+    envp.push(ptr::null());
+
+    unsafe {
+        libc::syscall(libc::SYS_execveat, fd, c"".as_ptr(), argv.as_ptr(), envp.as_ptr(), libc::AT_EMPTY_PATH);
+    }
+    std::io::Error::last_os_error().to_string()
+}
@@ -1,201 +1,298 @@
-use std::ffi::CString;
+use std::convert::Infallible;
 use std::fs::File;
-use std::io::Write;
-use std::mem;
-use std::os::unix::io::AsRawFd;
-use std::ptr;
+use std::io::Read;
+use std::os::unix::io::{IntoRawFd, OwnedFd, RawFd};
 use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
 use nix::fcntl::OFlag;
-use nix::sys::memfd::{memfd_create, MFdFlags};
-use nix::sys::mman::{mmap, shm_open, MapFlags, ProtFlags};
 use nix::sys::signal::{kill, Signal};
-use nix::sys::stat::Mode;
-use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-use nix::unistd::{execv, fork, getpid, sleep, ForkResult, Pid};
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, getpid, pipe2, ForkResult, Pid};
 
 use crate::common::{
-    self, evaluate_health_status, force_kill_delay, health_check_interval, init_health_status,
-    log_async_mode_started, log_base_completed_terminating_overload, log_base_exited,
-    log_base_killed_by_signal, log_fallback_kill, log_forcing_sigkill, log_grace_period_exceeded,
-    log_health_monitor_started, log_health_monitoring_enabled, log_heartbeat_lost,
-    log_network_failure_threshold, log_overload_requested_kill, log_overload_start_failed,
-    log_shm_create_failed, log_shm_map_failed, log_starting_base, log_sync_mode_waiting,
-    log_verification_failed, log_verification_successful, overload_kill_wait_duration,
-    should_enable_health_monitoring, signal_overload_to_kill, HealthCheckResult,
+    self, force_kill_delay, health_check_interval,
+    log_async_mode_started, log_base_exited, log_health_monitor_started, log_health_monitoring_enabled,
+    log_overload_restart_failed, log_overload_restart_scheduled, log_overload_restarted,
+    log_clock_tampered, log_reverification_started, log_overload_start_failed,
+    log_health_channel_failed, log_starting_base,
+    should_enable_health_monitoring, HealthCheckResult, log_health_channel_required,
+    log_functions_decrypted, log_lazy_decryption_unavailable,
+    log_waiting_for_decryption_key, log_integrity_violation,
+    log_debugger_detected, log_protection_refused, log_in_process_load_failed,
 };
-use crate::{ConfigFooter, HealthStatus};
-
+use crate::anti_debug::{self, ANTI_DEBUG_DETECT_TRACER};
+use crate::clock::ClockWatch;
+use crate::containment;
+use crate::enforce::{ChildBase, Check, Enforcer};
+use crate::encryption::{self, EncryptedRegion, ENCRYPTION_FLAG_LAZY};
+use crate::grace_record::GraceRecord;
+use crate::health::{HealthChannel, HEALTH_POLICY_FAIL_CLOSED};
+use crate::integrity::{IntegrityAction, IntegrityGuard};
+use crate::launch;
+use crate::overload::OverloadProcess;
+use crate::restart::RestartPolicy;
+use crate::reverify::Reverification;
+use crate::signals::{self, BaseExit};
+use crate::stdio::OverloadStdio;
+use crate::strings::{self, S};
+use crate::terminal;
+use crate::usage;
+use crate::userland;
+use crate::verify::{self, SYNC_MODE_HYBRID, SYNC_MODE_SYNC};
+use crate::ConfigFooter;
+
+/// Fork and exec the overload, recording its PID in `overload_pid_ref`
 unsafe fn execute_binary(
     binary_data: &[u8],
     name: &str,
-    is_base: bool,
-    sync_mode: bool,
     overload_pid_ref: &mut Option<Pid>,
-) -> Result<i32, String> {
-    let name_c = CString::new(name).unwrap();
-    let fd = memfd_create(name_c.as_c_str(), MFdFlags::MFD_CLOEXEC)
-        .map_err(|e| format!("memfd_create failed: {}", e))?;
-
-    let mut file = File::from(fd);
-    file.write_all(binary_data)
-        .map_err(|e| format!("Failed to write binary data: {}", e))?;
-
-    let raw_fd = file.as_raw_fd();
-    mem::forget(file);
+    key_fd: Option<RawFd>,
+    health: Option<&HealthChannel>,
+    footer: &ConfigFooter,
+) -> Result<(), String> {
+    let parent = getpid();
+    let stdio = OverloadStdio::open(footer);
+    let (status_reader, status_writer) = launch::status_pipe()?;
 
     match fork() {
         Ok(ForkResult::Parent { child }) => {
-            nix::unistd::close(raw_fd).ok();
-
-            if !is_base {
-                *overload_pid_ref = Some(child);
-
-                if sync_mode {
-                    log_sync_mode_waiting(child);
-                    match waitpid(child, None) {
-                        Ok(WaitStatus::Exited(_, code)) => {
-                            if code != 0 {
-                                log_verification_failed(code);
-                                return Err(format!("Overload verification failed with code {}", code));
-                            }
-                            log_verification_successful();
-                        }
-                        Ok(status) => {
-                            eprintln!("[KillCode] ❌ Overload terminated abnormally: {:?}", status);
-                            return Err(format!("Overload terminated abnormally: {:?}", status));
-                        }
-                        Err(e) => return Err(format!("waitpid failed: {}", e)),
-                    }
-                } else {
-                    log_async_mode_started(child);
-                }
-                Ok(0)
-            } else {
-                let mut status_code = -1;
-                match waitpid(child, None) {
-                    Ok(WaitStatus::Exited(_, code)) => status_code = code,
-                    Ok(WaitStatus::Signaled(_, sig, _)) => {
-                        log_base_killed_by_signal(sig);
-                        status_code = -1;
-                    }
-                    Err(e) => eprintln!("[KillCode] waitpid failed for base: {}", e),
-                    _ => {}
-                }
-
-                if let Some(overload_pid) = *overload_pid_ref {
-                    log_base_completed_terminating_overload(overload_pid);
-                    let _ = kill(overload_pid, Signal::SIGTERM);
-                    sleep(1);
-
-                    match waitpid(overload_pid, Some(WaitPidFlag::WNOHANG)) {
-                        Ok(WaitStatus::StillAlive) => {
-                            log_forcing_sigkill();
-                            let _ = kill(overload_pid, Signal::SIGKILL);
-                            let _ = waitpid(overload_pid, None);
-                        }
-                        _ => {}
-                    }
-                }
-
-                Ok(status_code)
+            drop(status_writer);
+            stdio.start();
+            if let Some(key_fd) = key_fd {
+                nix::unistd::close(key_fd).ok();
             }
+            if launch::wait_started(status_reader, name).is_none() {
+                let _ = waitpid(child, None);
+                return Err(format!("Could not start {}", name));
+            }
+
+            *overload_pid_ref = Some(child);
+            containment::set_overload(child);
+            Ok(())
         }
         Ok(ForkResult::Child) => {
-            let fd_path = format!("/proc/self/fd/{}", raw_fd);
-            let fd_path_c = CString::new(fd_path).unwrap();
-            let args = [name_c.clone()];
-            let _ = execv(&fd_path_c, &args);
-            common::log_execv_failed();
-            std::process::exit(1);
+            drop(status_reader);
+            // The stub that becomes the base can't reap or terminate this
+            // process, so in-process mode relies on this too
+            containment::die_with_parent(parent);
+            terminal::detach_from_terminal();
+            stdio.apply();
+            if let Some(key_fd) = key_fd {
+                // Let the key pipe survive exec and tell the overload where it is
+                libc::fcntl(key_fd, libc::F_SETFD, 0);
+                std::env::set_var(strings::get(S::KeyFdEnv), key_fd.to_string());
+            }
+            if let Some(health) = health {
+                health.inherit();
+            }
+            launch::exec(binary_data, name, footer, status_writer);
+            std::process::exit(launch::EXIT_CANNOT_EXECUTE);
         }
-        Err(e) => {
-            nix::unistd::close(raw_fd).ok();
-            Err(format!("fork failed: {}", e))
+        Err(e) => Err(format!("fork failed: {}", e)),
+    }
+}
+
+/// Start the overload again from the embedded payload, without a key pipe
+fn restart_overload(overload_data: &[u8], footer: &ConfigFooter, health: Option<&HealthChannel>) -> Result<Pid, String> {
+    let mut pid = None;
+    unsafe { execute_binary(overload_data, "overload", &mut pid, None, health, footer)? };
+    pid.ok_or_else(|| "The overload did not start".to_string())
+}
+
+/// Replace the stub's main thread with the base.
+///
+/// The base keeps the stub's PID and the monitor thread keeps running next to
+/// it. Only returns if the base could not be loaded.
+fn exec_base_in_process(
+    mut base_data: Vec<u8>,
+    encrypted_regions: &[EncryptedRegion],
+    decryption_key: Option<[u8; 32]>,
+    footer: &ConfigFooter,
+    base_pid_cell: Option<Arc<AtomicI32>>,
+    health: Option<&HealthChannel>,
+) -> Result<Infallible, String> {
+    let env: Vec<_> = std::env::vars_os().collect();
+    let image = userland::prepare(&base_data, &common::payload_args("base"), &env)?;
+    let lazy = footer.encryption_flags & ENCRYPTION_FLAG_LAZY != 0;
+
+    // Same as with exec: handlers installed by the stub don't survive into the base
+    userland::reset_signals();
+
+    if let Some(mut key) = decryption_key {
+        // The plaintext now only lives in the mapped image (lazy images are
+        // still encrypted there)
+        if !lazy {
+            encryption::apply_regions(&mut base_data, encrypted_regions, &key, &footer.encryption_nonce);
         }
+        encryption::guard_pages(encrypted_regions, image.bias, key, footer)?;
+        key.fill(0);
     }
+    drop(base_data);
+
+    let pid = getpid().as_raw();
+    if let Some(cell) = base_pid_cell {
+        cell.store(pid, Ordering::Relaxed);
+    }
+    if let Some(health) = health {
+        health.set_base_pid(pid);
+    }
+
+    unsafe { userland::jump(image) }
+}
+
+/// Wait for the overload to write the 32-byte function key to the key pipe.
+///
+/// The overload only does this after its license check succeeds. If it exits
+/// without writing a key, the read hits EOF and the base is never started.
+fn receive_decryption_key(key_reader: OwnedFd, footer: &ConfigFooter) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    File::from(key_reader)
+        .read_exact(&mut key)
+        .map_err(|e| format!("Overload did not deliver a decryption key: {}", e))?;
+
+    if !encryption::verify_key(&key, footer) {
+        return Err("Overload delivered an invalid decryption key".to_string());
+    }
+    Ok(key)
 }
 
-/// Kill base process with SIGTERM followed by SIGKILL
+/// Kill the base and everything it started with SIGTERM followed by SIGKILL
 fn kill_base(base_pid: i32) {
-    let _ = kill(Pid::from_raw(base_pid), Signal::SIGTERM);
+    encryption::wipe_pages();
+    containment::signal_tree(Pid::from_raw(base_pid), Signal::SIGTERM);
     thread::sleep(force_kill_delay());
-    let _ = kill(Pid::from_raw(base_pid), Signal::SIGKILL);
+    containment::signal_tree(Pid::from_raw(base_pid), Signal::SIGKILL);
 }
 
 pub fn run(
-    base_data: Vec<u8>,
+    mut base_data: Vec<u8>,
     overload_data: Vec<u8>,
+    encrypted_regions: Vec<EncryptedRegion>,
     footer: ConfigFooter,
+    integrity: IntegrityGuard,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let sync_mode = footer.sync_mode != 0;
     let grace_period = footer.grace_period;
     let network_failure_kill_count = footer.network_failure_kill_count;
+    let mut heartbeat_timeout = footer.heartbeat_timeout;
+    let anti_debug_flags = footer.anti_debug_flags;
 
-    let mut health_ptr: *mut HealthStatus = ptr::null_mut();
-    let mut _shm_fd_keeper = None;
-
-    if should_enable_health_monitoring(sync_mode, grace_period, network_failure_kill_count) {
-        let pid = getpid();
-        let shm_name = format!("/overload_health_{}", pid);
-        let shm_name_c = CString::new(shm_name.clone()).unwrap();
+    if let Err(e) = anti_debug::apply(anti_debug_flags) {
+        log_protection_refused(&e);
+        return Err(e.into());
+    }
 
-        match shm_open(
-            shm_name_c.as_c_str(),
-            OFlag::O_CREAT | OFlag::O_RDWR,
-            Mode::from_bits_truncate(0o600),
-        ) {
-            Ok(fd) => {
-                let _ = nix::unistd::ftruncate(&fd, mem::size_of::<HealthStatus>() as libc::off_t);
-
-                unsafe {
-                    let ptr = mmap(
-                        None,
-                        std::num::NonZeroUsize::new(mem::size_of::<HealthStatus>()).unwrap(),
-                        ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                        MapFlags::MAP_SHARED,
-                        &fd,
-                        0,
-                    );
-
-                    match ptr {
-                        Ok(p) => {
-                            health_ptr = p.as_ptr() as *mut HealthStatus;
-                            init_health_status(health_ptr);
-                            std::env::set_var("KILLCODE_HEALTH_SHM", &shm_name);
-                            log_health_monitoring_enabled(&shm_name);
-                            _shm_fd_keeper = Some(fd);
-                        }
-                        Err(e) => log_shm_map_failed(e),
-                    }
+    let mut health = None;
+    if should_enable_health_monitoring(&footer) || integrity.notifies_overload()
+    {
+        match HealthChannel::create(&footer) {
+            Ok(channel) => {
+                if integrity.startup_violation {
+                    channel.signal_integrity_violation();
                 }
+                log_health_monitoring_enabled(channel.transport());
+                health = Some(Arc::new(channel));
             }
-            Err(e) => log_shm_create_failed(e),
+            Err(e) => {
+                log_health_channel_failed(e);
+                if footer.health_channel_policy == HEALTH_POLICY_FAIL_CLOSED {
+                    log_health_channel_required();
+                    return Err("No health channel".into());
+                }
+            }
+        }
+    }
+
+    // Key pipe for encrypted function bodies: the overload writes the key
+    // into it once the license check passes
+    let key_pipe = if encrypted_regions.is_empty() {
+        None
+    } else {
+        Some(pipe2(OFlag::O_CLOEXEC).map_err(|e| format!("pipe2 failed: {}", e))?)
+    };
+    let (key_reader, key_writer) = match key_pipe {
+        Some((reader, writer)) => (Some(reader), Some(writer.into_raw_fd())),
+        None => (None, None),
+    };
+
+    // In hybrid mode the monitor also sees the overload's first success
+    let mut health_monitor = health.as_ref().map(HealthChannel::monitor);
+    if footer.sync_mode == SYNC_MODE_HYBRID {
+        if let Some(health_monitor) = &mut health_monitor {
+            health_monitor.expect_verification();
         }
     }
+    // Before the overload starts: it takes the grace period's start from the channel
+    let mut grace_record = GraceRecord::resume(&footer, health_monitor.as_mut())?;
 
     let mut overload_pid = None;
     unsafe {
-        if let Err(e) = execute_binary(&overload_data, "overload", false, sync_mode, &mut overload_pid) {
+        if let Err(e) = execute_binary(
+            &overload_data,
+            "overload",
+            &mut overload_pid,
+            key_writer,
+            health.as_deref(),
+            &footer,
+        ) {
             log_overload_start_failed(&e);
             return Err(e.into());
         }
     }
+    let overload = overload_pid.map(|pid| Arc::new(OverloadProcess::new(pid)));
+    if let (Some(pid), Some(overload)) = (overload_pid, &overload) {
+        if footer.sync_mode == 0 {
+            log_async_mode_started(pid);
+        } else {
+            verify::hold_base(&footer, health_monitor.as_mut(), pid, || overload.verdict(), || overload.kill())?;
+        }
+    }
 
-    let monitor_handle = if !sync_mode
-        && !health_ptr.is_null()
-        && (grace_period > 0 || network_failure_kill_count > 0)
-    {
+    let mut decryption_key = None;
+    if let Some(key_reader) = key_reader {
+        log_waiting_for_decryption_key(encrypted_regions.len());
+        match receive_decryption_key(key_reader, &footer) {
+            Ok(key) => {
+                let lazy = footer.encryption_flags & ENCRYPTION_FLAG_LAZY != 0;
+                if lazy && footer.exec_mode != launch::EXEC_MODE_IN_PROCESS {
+                    log_lazy_decryption_unavailable();
+                }
+                // Lazy in-process images are decrypted page by page on first touch
+                if !lazy || footer.exec_mode != launch::EXEC_MODE_IN_PROCESS {
+                    encryption::apply_regions(&mut base_data, &encrypted_regions, &key, &footer.encryption_nonce);
+                    log_functions_decrypted(encrypted_regions.len());
+                }
+                decryption_key = Some(key);
+            }
+            Err(e) => {
+                if let Some(overload) = &overload {
+                    overload.kill();
+                }
+                return Err(e.into());
+            }
+        }
+    }
+
+    let monitor_health = footer.sync_mode != SYNC_MODE_SYNC
+        && health.is_some()
+        && (grace_period > 0 || network_failure_kill_count > 0 || heartbeat_timeout > 0);
+    let detect_tracer = anti_debug_flags & ANTI_DEBUG_DETECT_TRACER != 0;
+    let mut reverify = Reverification::<OverloadProcess>::from_footer(&footer);
+    let monitor_handle = if monitor_health || reverify.is_some() || integrity.is_enabled() || detect_tracer {
         let base_pid_cell = Arc::new(AtomicI32::new(0));
         let base_pid_clone = base_pid_cell.clone();
-        let health_ptr_addr = health_ptr as usize;
+        let health = health.clone();
+        let overload = overload.clone();
+        let mut restarts = RestartPolicy::from_footer(&footer);
+        let mut clock = ClockWatch::from_footer(&footer);
+        let mut enforcer = Enforcer::from_footer(&footer);
+        let mut restart_at = None;
 
         Some((
             thread::spawn(move || {
                 log_health_monitor_started();
-                let health_ptr = health_ptr_addr as *mut HealthStatus;
                 loop {
                     thread::sleep(health_check_interval());
 
@@ -207,35 +304,117 @@ pub fn run(
                     if kill(Pid::from_raw(base_pid), None).is_err() {
                         break;
                     }
+                    let mut base = ChildBase { pid: base_pid, kill: kill_base };
+
+                    let overload_pid = overload.as_ref().and_then(|overload| overload.pid());
+                    let watched = [base_pid, overload_pid.map_or(0, |pid| pid.as_raw())];
+                    if let Some(pid) = anti_debug::find_traced(anti_debug_flags, &watched) {
+                        log_debugger_detected(pid);
+                        kill_base(base_pid);
+                        if let Some(overload) = &overload {
+                            overload.kill();
+                        }
+                        std::process::exit(1);
+                    }
 
-                    unsafe {
-                        match evaluate_health_status(health_ptr, grace_period, network_failure_kill_count) {
-                            HealthCheckResult::Ok => {}
-                            HealthCheckResult::GracePeriodExceeded { time_since_success, grace_period } => {
-                                log_grace_period_exceeded(time_since_success, grace_period);
-                                kill_base(base_pid);
-                                break;
+                    if let Err(e) = integrity.verify_runtime() {
+                        log_integrity_violation(&e);
+                        match integrity.action {
+                            IntegrityAction::NotifyOverload if health.is_some() => {
+                                if let Some(health) = &health {
+                                    health.signal_integrity_violation();
+                                }
                             }
-                            HealthCheckResult::NetworkFailureThreshold { failures, threshold } => {
-                                log_network_failure_threshold(failures, threshold);
-                                signal_overload_to_kill(health_ptr);
-                                thread::sleep(overload_kill_wait_duration());
-                                log_fallback_kill();
+                            IntegrityAction::Exit => {
                                 kill_base(base_pid);
-                                break;
+                                if let Some(overload) = &overload {
+                                    overload.kill();
+                                }
+                                std::process::exit(1);
                             }
-                            HealthCheckResult::OverloadRequestedKill => {
-                                log_overload_requested_kill();
+                            _ => {
                                 kill_base(base_pid);
                                 break;
                             }
-                            HealthCheckResult::HeartbeatLost => {
-                                log_heartbeat_lost();
-                                kill_base(base_pid);
-                                break;
+                        }
+                    }
+
+                    if clock.tampered() {
+                        log_clock_tampered();
+                        kill_base(base_pid);
+                        break;
+                    }
+
+                    if let Some(reverify) = &mut reverify {
+                        let start = || {
+                            let pid = restart_overload(&overload_data, &footer, None)?;
+                            log_reverification_started(pid);
+                            Ok(OverloadProcess::new(pid))
+                        };
+                        let result = reverify.check(start, grace_period, network_failure_kill_count);
+                        if enforcer.handle(result, Check::Reverification, &mut base, || false) {
+                            break;
+                        }
+                        if let Some(record) = &mut grace_record {
+                            record.save(reverify.last_success());
+                        }
+                    }
+
+                    let Some(health_monitor) = health_monitor.as_mut().filter(|_| monitor_health) else {
+                        continue;
+                    };
+
+                    // The base runs on the last accepted report in the meantime
+                    if restart_at.is_some_and(|at| Instant::now() >= at) {
+                        restart_at = None;
+                        let restarted = health_monitor
+                            .overload_restarting()
+                            .and_then(|()| restart_overload(&overload_data, &footer, health.as_deref()));
+                        match restarted {
+                            Ok(pid) => {
+                                log_overload_restarted(pid);
+                                if let Some(overload) = &overload {
+                                    overload.restarted(pid);
+                                }
+                                restarts.started();
+                                heartbeat_timeout = footer.heartbeat_timeout;
+                            }
+                            Err(e) => {
+                                log_overload_restart_failed(&e);
+                                if enforcer.overload_gone(&mut base) {
+                                    break;
+                                }
                             }
                         }
                     }
+
+                    let result = match overload.as_ref().and_then(|overload| overload.poll()) {
+                        Some(loss) => HealthCheckResult::OverloadLost(loss),
+                        None => health_monitor.evaluate(grace_period, network_failure_kill_count, heartbeat_timeout),
+                    };
+                    if let Some(record) = &mut grace_record {
+                        record.save(health_monitor.last_success());
+                    }
+                    let restart = || {
+                        // Report a hang once; a restart or the grace period takes it from here
+                        heartbeat_timeout = 0;
+                        let Some(delay) = restarts.next_restart() else {
+                            return false;
+                        };
+                        // A hung overload is still running
+                        if let Some(overload) = &overload {
+                            overload.kill();
+                        }
+                        log_overload_restart_scheduled(delay, restarts.restarts(), footer.overload_max_restarts);
+                        restart_at = Some(Instant::now() + delay);
+                        true
+                    };
+                    if enforcer.handle(result, Check::Reports(health.as_deref()), &mut base, restart) {
+                        break;
+                    }
+                }
+                if let Some(reverify) = &mut reverify {
+                    reverify.stop();
                 }
             }),
             base_pid_cell,
@@ -245,64 +424,68 @@ pub fn run(
     };
 
     log_starting_base();
-    let base_exit_code = unsafe {
-        let name_c = CString::new("base").unwrap();
-        let fd = memfd_create(name_c.as_c_str(), MFdFlags::MFD_CLOEXEC)
-            .map_err(|e| format!("memfd_create failed: {}", e))?;
-
-        let mut file = File::from(fd);
-        file.write_all(&base_data)
-            .map_err(|e| format!("Failed to write binary data: {}", e))?;
-        let raw_fd = file.as_raw_fd();
-        mem::forget(file);
+    if footer.exec_mode == launch::EXEC_MODE_IN_PROCESS {
+        let pid_cell = monitor_handle.as_ref().map(|(_, cell)| cell.clone());
+        containment::enter_in_process(&footer);
+        usage::start(getpid(), health.clone(), &footer);
+        let Err(e) = exec_base_in_process(base_data, &encrypted_regions, decryption_key, &footer, pid_cell, health.as_deref());
+        log_in_process_load_failed("base", &e);
+        if let Some(overload) = &overload {
+            overload.kill();
+        }
+        return Err(e.into());
+    }
 
+    let base_exit = unsafe {
+        let interactive = terminal::is_interactive();
+        let parent = getpid();
+        containment::prepare(&footer);
+        let (status_reader, status_writer) = launch::status_pipe()?;
+        signals::install_forwarding();
         match fork() {
             Ok(ForkResult::Parent { child }) => {
-                nix::unistd::close(raw_fd).ok();
+                drop(status_writer);
+                // The plaintext now only lives in the child that starts the
+                // base; don't keep a decrypted copy or the key around in the stub
+                if let Some(mut key) = decryption_key.take() {
+                    encryption::apply_regions(&mut base_data, &encrypted_regions, &key, &footer.encryption_nonce);
+                    key.fill(0);
+                }
+                signals::forward_to(child.as_raw());
+                containment::start_reaper(child);
 
                 if let Some((_, ref pid_cell)) = monitor_handle {
                     pid_cell.store(child.as_raw(), Ordering::Relaxed);
                 }
 
-                if !health_ptr.is_null() {
-                    (*health_ptr).base_pid = child.as_raw();
+                if let Some(health) = &health {
+                    health.set_base_pid(child.as_raw());
                 }
 
-                let mut status_code = -1;
-                match waitpid(child, None) {
-                    Ok(WaitStatus::Exited(_, code)) => status_code = code,
-                    Ok(WaitStatus::Signaled(_, sig, _)) => {
-                        log_base_killed_by_signal(sig);
-                        status_code = -1;
-                    }
-                    _ => {}
-                }
+                launch::wait_started(status_reader, "base");
+                let started = Instant::now();
+                usage::start(child, health.clone(), &footer);
+                let (base_exit, peak_rss) = terminal::wait_for_base(child, interactive);
 
-                if let Some(ov_pid) = overload_pid {
-                    log_base_completed_terminating_overload(ov_pid);
-                    let _ = kill(ov_pid, Signal::SIGTERM);
-                    sleep(1);
-                    match waitpid(ov_pid, Some(WaitPidFlag::WNOHANG)) {
-                        Ok(WaitStatus::StillAlive) => {
-                            let _ = kill(ov_pid, Signal::SIGKILL);
-                            let _ = waitpid(ov_pid, None);
-                        }
-                        _ => {}
-                    }
+                if let Some(health) = &health {
+                    health.report_base_exit(base_exit.report(started, peak_rss, &footer));
+                }
+                if let Some(overload) = &overload {
+                    overload.terminate(footer.shutdown_window);
                 }
-                Ok(status_code)
+                Ok(base_exit)
             }
             Ok(ForkResult::Child) => {
-                let fd_path = format!("/proc/self/fd/{}", raw_fd);
-                let fd_path_c = CString::new(fd_path).unwrap();
-                let args = [name_c.clone()];
-                let _ = execv(&fd_path_c, &args);
-                std::process::exit(1);
-            }
-            Err(e) => {
-                nix::unistd::close(raw_fd).ok();
-                Err(format!("fork failed: {}", e))
+                drop(status_reader);
+                // A terminal job keeps the stub's process group for job control
+                containment::enter_base(parent, !interactive);
+                if let Some(health) = &health {
+                    health.revoke();
+                }
+                launch::exec(&base_data, "base", &footer, status_writer);
+                std::process::exit(launch::EXIT_CANNOT_EXECUTE);
             }
+            Err(e) => Err(format!("fork failed: {}", e)),
         }
     }?;
 
@@ -310,6 +493,9 @@ pub fn run(
         let _ = handle.join();
     }
 
-    log_base_exited(base_exit_code);
-    std::process::exit(base_exit_code);
+    containment::release();
+    if let BaseExit::Code(code) = base_exit {
+        log_base_exited(code);
+    }
+    signals::exit_like(base_exit);
 }
@@ -0,0 +1,288 @@
+use std::fs::File;
+use std::io::Write;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_compact::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+#[cfg(target_os = "linux")]
+use crate::common::ROUTE_JOURNALD;
+#[cfg(unix)]
+use crate::common::ROUTE_SYSLOG;
+use crate::common::{footer_path, open_log, ROUTE_FILE, ROUTE_NULL};
+use crate::strings::{self, S};
+use crate::ConfigFooter;
+
+/// Footer `log_flags`: write JSON lines instead of text
+pub const LOG_FLAG_JSON: u8 = 0x01;
+/// Footer `log_flags`: debug build, the log level may be overridden from the
+/// environment if weaver's signature checks out
+pub const LOG_FLAG_ENV_OVERRIDE: u8 = 0x02;
+
+/// Hex Ed25519 key that debug builds are signed with, fixed when the stub is built.
+/// Stubs built without it never honor the environment override.
+const DEBUG_PUBLIC_KEY: Option<&str> = option_env!("KILLCODE_DEBUG_PUBKEY");
+
+/// Severity of a stub message. A message is written if its level is at most
+/// the configured one, so `Off` silences the stub.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    fn from_footer(value: u8) -> Self {
+        match value {
+            1 => Level::Error,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => Level::Off,
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// syslog / journald priority
+    #[cfg(unix)]
+    fn priority(self) -> i32 {
+        match self {
+            Level::Error => libc::LOG_ERR,
+            Level::Info => libc::LOG_INFO,
+            _ => libc::LOG_DEBUG,
+        }
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(File),
+    #[cfg(unix)]
+    Syslog,
+    #[cfg(target_os = "linux")]
+    Journald(UnixDatagram),
+}
+
+struct Logger {
+    level: Level,
+    json: bool,
+    sink: Sink,
+    /// Diagnostics routed to `null`: not even `write_always` gets through
+    #[cfg(target_os = "linux")]
+    muted: bool,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Used until the footer is read: only errors, on stderr
+static EARLY: Logger = Logger {
+    level: Level::Error,
+    json: false,
+    sink: Sink::Stderr,
+    #[cfg(target_os = "linux")]
+    muted: false,
+};
+
+/// Configure logging from the footer. `base` and `overload` are the payloads
+/// as embedded, which a debug build's signature covers.
+pub fn init(footer: &ConfigFooter, base: &[u8], overload: &[u8]) {
+    let mut level = Level::from_footer(footer.log_level);
+    let mut json = footer.log_flags & LOG_FLAG_JSON != 0;
+    if let Some((env_level, env_json)) = env_override(footer, base, overload) {
+        level = env_level;
+        json |= env_json;
+    }
+
+    let sink = match footer.diagnostics_output {
+        ROUTE_NULL => {
+            level = Level::Off;
+            Sink::Stderr
+        }
+        ROUTE_FILE => match footer_path(&footer.diagnostics_log_path).map(open_log) {
+            Some(Ok(file)) => Sink::File(file),
+            _ => Sink::Stderr,
+        },
+        #[cfg(unix)]
+        ROUTE_SYSLOG => Sink::Syslog,
+        #[cfg(target_os = "linux")]
+        ROUTE_JOURNALD => journal_socket().map_or(Sink::Syslog, Sink::Journald),
+        _ => Sink::Stderr,
+    };
+    let _ = LOGGER.set(Logger {
+        level,
+        json,
+        sink,
+        #[cfg(target_os = "linux")]
+        muted: footer.diagnostics_output == ROUTE_NULL,
+    });
+}
+
+/// Level (and `,json`) from the environment, for signed debug builds only
+fn env_override(footer: &ConfigFooter, base: &[u8], overload: &[u8]) -> Option<(Level, bool)> {
+    if footer.log_flags & LOG_FLAG_ENV_OVERRIDE == 0 {
+        return None;
+    }
+    let value = std::env::var(strings::get(S::LogEnv)).ok()?;
+    if !is_signed_debug_build(footer, base, overload) {
+        return None;
+    }
+    let mut options = value.split(',').map(str::trim);
+    let level = Level::parse(options.next()?)?;
+    Some((level, options.any(|option| option == "json")))
+}
+
+/// Whether weaver signed this merge as a debug build with the key the stub was built for
+fn is_signed_debug_build(footer: &ConfigFooter, base: &[u8], overload: &[u8]) -> bool {
+    let Some(key) = DEBUG_PUBLIC_KEY.and_then(decode_key) else {
+        return false;
+    };
+    let message = override_message(&footer.name_seed, base, overload);
+    PublicKey::new(key)
+        .verify(message, &Signature::new(footer.log_override_signature))
+        .is_ok()
+}
+
+/// What weaver signs: the merge's seed and hashes of both embedded payloads,
+/// so a signature can't be moved to another merge.
+///
+/// Must match `override_message` in weaver's `core::merger::logging`.
+fn override_message(name_seed: &[u8; 8], base: &[u8], overload: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"log-override")
+        .chain_update(name_seed)
+        .chain_update(Sha256::digest(base))
+        .chain_update(Sha256::digest(overload))
+        .finalize()
+        .into()
+}
+
+fn decode_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Write one message if `level` is enabled
+pub fn write(level: Level, message: &str) {
+    let logger = LOGGER.get().unwrap_or(&EARLY);
+    if level == Level::Off || level > logger.level {
+        return;
+    }
+    output(logger, level, message);
+}
+
+/// Write an error whatever the level, for failures that leave the user with
+/// nothing running and no other hint why. Only the `null` route drops it.
+#[cfg(target_os = "linux")]
+pub fn write_always(message: &str) {
+    let logger = LOGGER.get().unwrap_or(&EARLY);
+    if !logger.muted {
+        output(logger, Level::Error, message);
+    }
+}
+
+fn output(logger: &Logger, level: Level, message: &str) {
+    let tag = strings::get(S::Tag);
+    let line = if logger.json {
+        json_line(level, &tag, message)
+    } else {
+        format!("{} {}", tag, message)
+    };
+    match &logger.sink {
+        Sink::Stderr => eprintln!("{}", line),
+        Sink::File(file) => {
+            let _ = writeln!(&*file, "{}", line);
+        }
+        #[cfg(unix)]
+        Sink::Syslog => syslog(level.priority(), &line),
+        #[cfg(target_os = "linux")]
+        Sink::Journald(socket) => journal_send(socket, level.priority(), &line),
+    }
+}
+
+fn json_line(level: Level, tag: &str, message: &str) -> String {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{{\"ts\":{}.{:03},\"level\":\"{}\",\"pid\":{},\"tag\":\"{}\",\"msg\":\"{}\"}}",
+        time.as_secs(),
+        time.subsec_millis(),
+        level.name(),
+        std::process::id(),
+        json_escape(tag),
+        json_escape(message)
+    )
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Send one line to syslog with the program's name as ident
+#[cfg(unix)]
+pub fn syslog(priority: i32, message: &str) {
+    if let Ok(message) = std::ffi::CString::new(message) {
+        unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+    }
+}
+
+/// Socket for journald's native protocol, if journald is running
+#[cfg(target_os = "linux")]
+pub fn journal_socket() -> Option<UnixDatagram> {
+    let socket = UnixDatagram::unbound().ok()?;
+    socket.connect(strings::get(S::JournalSocket)).ok()?;
+    Some(socket)
+}
+
+/// Send one entry to journald, identified by the program's name
+#[cfg(target_os = "linux")]
+pub fn journal_send(socket: &UnixDatagram, priority: i32, message: &str) {
+    let identifier = std::env::args()
+        .next()
+        .and_then(|arg0| arg0.rsplit('/').next().map(str::to_owned))
+        .unwrap_or_default();
+    let entry = format!(
+        "PRIORITY={}\nSYSLOG_IDENTIFIER={}\nMESSAGE={}\n",
+        priority,
+        identifier,
+        message.replace('\n', " ")
+    );
+    let _ = socket.send(entry.as_bytes());
+}
@@ -2,16 +2,58 @@ use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::mem;
 
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use sha2::{Digest, Sha256};
+
+#[cfg(target_os = "linux")]
+mod anti_debug;
+mod clock;
 mod common;
+#[cfg(unix)]
+mod containment;
+#[cfg(target_os = "linux")]
+mod encryption;
+mod enforce;
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+mod extract;
+mod grace_record;
+mod health;
+mod integrity;
+#[cfg(target_os = "linux")]
+mod launch;
+mod logging;
+#[cfg(unix)]
+mod overload;
+mod restart;
+mod reverify;
+mod rundir;
+#[cfg(unix)]
+mod signals;
+#[cfg(unix)]
+mod stdio;
+mod strings;
+#[cfg(unix)]
+mod terminal;
+#[cfg(target_os = "linux")]
+mod usage;
+mod variant;
+mod verify;
 
 #[cfg(target_os = "linux")]
 mod linux;
+#[cfg(target_os = "linux")]
+mod userland;
 #[cfg(target_os = "windows")]
 mod windows;
 #[cfg(target_os = "macos")]
 mod macos;
 
-const MAGIC_BYTES: &[u8; 8] = b"KILLCODE";
+/// Length of the per-merge key appended after the sealed footer
+const FOOTER_KEY_LEN: usize = 16;
+/// Footer key followed by the masked padding length (the location key)
+const FOOTER_TRAILER_LEN: usize = FOOTER_KEY_LEN + 4;
+const MAX_FOOTER_PADDING: u64 = 512;
 const HEALTH_CHECK_INTERVAL: u32 = 5;
 
 #[repr(C)]
@@ -23,43 +65,127 @@ pub struct ConfigFooter {
     pub overload_offset: u64,
     pub overload_size: u64,
     pub grace_period: u32,
-    pub sync_mode: u8, // 0 or 1
+    pub sync_mode: u8, // 0=async, SYNC_MODE_*
     pub network_failure_kill_count: u32,
+    pub encrypted_regions_offset: u64,
+    pub encrypted_regions_count: u32,
+    pub encryption_flags: u8,
+    pub encryption_nonce: [u8; 12],
+    pub encryption_key_hash: [u8; 32],
+    pub integrity_offset: u64,
+    pub integrity_count: u32,
+    pub integrity_action: u8, // 0=exit, 1=kill base, 2=notify overload
+    pub integrity_key: [u8; 32],
+    pub integrity_mac: [u8; 32],
+    pub anti_debug_flags: u8, // ANTI_DEBUG_* bits, Linux only
+    pub name_seed: [u8; 8],   // Per-merge seed for shared memory and payload names
+    pub exec_mode: u8,        // EXEC_MODE_* (Linux only)
+    pub overload_stdin: u8,   // ROUTE_* for each of the overload's streams
+    pub overload_stdout: u8,
+    pub overload_stderr: u8,
+    pub diagnostics_output: u8, // ROUTE_* for the stub's own messages
+    pub overload_log_path: [u8; common::STDIO_PATH_LEN],
+    pub diagnostics_log_path: [u8; common::STDIO_PATH_LEN],
+    pub log_level: u8, // 0=off, 1=error, 2=info, 3=debug
+    pub log_flags: u8, // LOG_FLAG_* bits
+    pub log_override_signature: [u8; 64],
+    pub exec_dir: [u8; common::STDIO_PATH_LEN], // Exec-allowed directory for the tmpfile strategy
+    pub health_transport: u8,      // HEALTH_TRANSPORT_*
+    pub health_channel_policy: u8, // HEALTH_POLICY_*: start without a health channel or not
+    pub heartbeat_timeout: u32,    // Seconds without a report before the overload counts as hung (0=off)
+    pub overload_lost_action: u8,  // OVERLOAD_LOST_*: what a crashed or hung overload does to the base
+    pub overload_max_restarts: u32,    // Restarts of a lost overload before overload_lost_action applies
+    pub overload_restart_backoff: u32, // Seconds before the first restart, doubled for each further one
+    pub overload_restart_window: u32,  // Seconds an overload must run before the restart count resets (0=never)
+    pub sync_timeout: u32,             // Seconds the base waits for the overload's verification (0=no limit)
+    pub sync_timeout_action: u8,       // SYNC_TIMEOUT_*: what a verification timeout does
+    pub reverify_interval: u32,        // Seconds between re-runs of a sync-mode overload (0=off)
+    pub clock_jump_action: u8,         // CLOCK_JUMP_*: what setting the system clock does to the base
+    pub grace_record: u8,              // 1 = keep the grace period across launches
+    pub grace_record_missing: u8,      // GRACE_RECORD_*: a launch without a record
+    pub grace_record_tampered: u8,     // GRACE_RECORD_*: a launch with a record that fails its checks
+    pub grace_record_key: [u8; 32],    // Signs the record, mixed with the machine ID
+    pub enforcement: [u8; 4],          // ENFORCE_* per failure (grace, network failures, kill request, lost overload)
+    pub terminate_timeout: u32,        // Seconds a terminated base gets before SIGKILL (0=10)
+    pub notify_signal: u8,             // NOTIFY_*: signal for ENFORCE_NOTIFY
+    pub shutdown_window: u32,          // Seconds the overload gets to exit on its own after the base (0=none)
+    pub usage_interval: u32,           // Seconds between samples of the base's resource use (0=off, Linux only)
 }
 
-#[repr(C)]
-pub struct HealthStatus {
-    pub last_success: i64,          // Timestamp of last successful check (time_t)
-    pub consecutive_failures: i32,  // Counter of network failures
-    pub is_alive: i32,              // Heartbeat flag (1=alive, 0=dead)
-    pub should_kill_base: i32,      // Signal from overload to kill base
-    pub parent_requests_kill: i32,  // Signal from parent: kill yourself now
-    pub base_pid: i32,              // PID of the base process
-}
+/// Health shared memory layout, shared with overloads through the SDK crate
+pub use killcode_overload::protocol::HealthStatus;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // 1. Read self
-    let mut self_file = File::open(std::env::current_exe()?)?;
+/// Decrypt the sealed footer at the end of the merged binary.
+///
+/// Layout: `[encrypted ConfigFooter][random padding][footer key][padding length ^ location mask]`.
+/// The magic and the footer's position are both derived from the per-merge key,
+/// so no fixed marker or offset exists in the file.
+fn open_footer(self_file: &mut File) -> Result<ConfigFooter, Box<dyn std::error::Error>> {
+    let footer_size = mem::size_of::<ConfigFooter>() as u64;
     let file_len = self_file.metadata()?.len();
-
-    if file_len < mem::size_of::<ConfigFooter>() as u64 {
+    if file_len < footer_size + FOOTER_TRAILER_LEN as u64 {
         return Err("File too small to contain footer".into());
     }
 
-    // 2. Read footer
-    self_file.seek(SeekFrom::End(-(mem::size_of::<ConfigFooter>() as i64)))?;
+    let mut footer_key = [0u8; FOOTER_KEY_LEN];
+    let mut masked_len = [0u8; 4];
+    self_file.seek(SeekFrom::End(-(FOOTER_TRAILER_LEN as i64)))?;
+    self_file.read_exact(&mut footer_key)?;
+    self_file.read_exact(&mut masked_len)?;
+
+    let mask = Sha256::new().chain_update(b"location").chain_update(footer_key).finalize();
+    let padding_len = (u32::from_le_bytes(masked_len) ^ u32::from_le_bytes(mask[..4].try_into().unwrap())) as u64;
+    let trailer_start = file_len - FOOTER_TRAILER_LEN as u64;
+    if padding_len > MAX_FOOTER_PADDING || trailer_start < footer_size + padding_len {
+        return Err("Invalid footer".into());
+    }
+
     let mut footer_bytes = [0u8; mem::size_of::<ConfigFooter>()];
+    self_file.seek(SeekFrom::Start(trailer_start - padding_len - footer_size))?;
     self_file.read_exact(&mut footer_bytes)?;
 
+    let stream_key: [u8; 32] = Sha256::new().chain_update(b"footer").chain_update(footer_key).finalize().into();
+    ChaCha20::new(&stream_key.into(), &[0u8; 12].into()).apply_keystream(&mut footer_bytes);
+
     let footer: ConfigFooter = unsafe { mem::transmute(footer_bytes) };
+    let magic = Sha256::new().chain_update(b"magic").chain_update(footer_key).finalize();
+    if footer.magic[..] != magic[..8] {
+        return Err("Invalid footer".into());
+    }
+    Ok(footer)
+}
 
-    if &footer.magic != MAGIC_BYTES {
-        return Err("Invalid magic bytes in footer".into());
+/// Path of the merged binary. Without `/proc` (hardened containers), Linux
+/// falls back to the path it was exec'd by (`AT_EXECFN` in the aux vector).
+pub fn own_path() -> std::io::Result<std::path::PathBuf> {
+    let exe = std::env::current_exe();
+    #[cfg(target_os = "linux")]
+    if exe.is_err() {
+        use std::os::unix::ffi::OsStrExt;
+        let execfn = unsafe { libc::getauxval(libc::AT_EXECFN) } as *const libc::c_char;
+        if !execfn.is_null() {
+            let path = unsafe { std::ffi::CStr::from_ptr(execfn) };
+            return Ok(std::ffi::OsStr::from_bytes(path.to_bytes()).into());
+        }
     }
+    exe
+}
+
+fn main() {
+    // Reported through the configured log route (stderr until the footer is read)
+    if let Err(e) = run() {
+        common::log_fatal(&*e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    // 1. Read self
+    let mut self_file = File::open(own_path()?)?;
+
+    // 2. Read footer
+    let footer = open_footer(&mut self_file)?;
 
-    eprintln!("[KillCode] V2 Stub execution starting");
-    eprintln!("[KillCode] Config: sync={}, grace_period={}s, failure_threshold={}", 
-             footer.sync_mode, footer.grace_period, footer.network_failure_kill_count);
 
     // 3. Read binaries
     let mut base_data = vec![0u8; footer.base_size as usize];
@@ -70,16 +196,81 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     self_file.seek(SeekFrom::Start(footer.overload_offset))?;
     self_file.read_exact(&mut overload_data)?;
 
+    logging::init(&footer, &base_data, &overload_data);
+    common::log_stub_starting(&footer);
+
+    // Verify stub code and payloads against the hashes recorded at merge time
+    let mut integrity = integrity::IntegrityGuard::new(
+        integrity::read_records(&mut self_file, &footer)?,
+        integrity::IntegrityAction::from_footer(&footer),
+    );
+    if integrity.is_enabled() {
+        match integrity.verify_startup(&mut self_file, &base_data, &overload_data) {
+            Ok(()) => common::log_integrity_verified(footer.integrity_count as usize),
+            Err(e) => {
+                common::log_integrity_violation(&e);
+                if !integrity.notifies_overload() {
+                    return Err(e.into());
+                }
+                integrity.startup_violation = true;
+            }
+        }
+    }
+
+    // Function encryption is only implemented by the Linux stub
+    #[cfg(target_os = "linux")]
+    let encrypted_regions = encryption::read_regions(&mut self_file, &footer)?;
+    #[cfg(not(target_os = "linux"))]
+    if footer.encrypted_regions_count > 0 {
+        return Err("Encrypted functions are not supported on this platform".into());
+    }
+
     // Dispatch to OS-specific implementation
     #[cfg(target_os = "linux")]
-    return linux::run(base_data, overload_data, footer);
+    return linux::run(base_data, overload_data, encrypted_regions, footer, integrity);
 
     #[cfg(target_os = "windows")]
-    return windows::run(base_data, overload_data, footer);
+    return windows::run(base_data, overload_data, footer, integrity);
 
     #[cfg(target_os = "macos")]
-    return macos::run(base_data, overload_data, footer);
+    return macos::run(base_data, overload_data, footer, integrity);
 
     #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
     return Err("Unsupported platform".into());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sealed by weaver's `footer::seal`, see its `test_stub_footer_fixture_is_current`
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/sealed_footer.bin");
+
+    #[test]
+    fn test_open_footer() {
+        let mut merged = b"stub and payloads".to_vec();
+        merged.extend(std::fs::read(FIXTURE).unwrap());
+        let path = std::env::temp_dir().join(format!("footer-test-{}", std::process::id()));
+        std::fs::write(&path, &merged).unwrap();
+        let footer = open_footer(&mut File::open(&path).unwrap());
+
+        let trailer = merged.len() - FOOTER_TRAILER_LEN;
+        merged[trailer] ^= 1;
+        std::fs::write(&path, &merged).unwrap();
+        let tampered = open_footer(&mut File::open(&path).unwrap());
+        std::fs::write(&path, b"short").unwrap();
+        let short = open_footer(&mut File::open(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+
+        let footer = footer.unwrap();
+        assert_eq!((footer.base_offset, footer.base_size), (1000, 2000));
+        assert_eq!((footer.overload_offset, footer.overload_size), (3000, 4000));
+        assert_eq!((footer.grace_period, footer.sync_mode, footer.network_failure_kill_count), (300, 2, 5));
+        assert_eq!(&footer.name_seed, b"fixture!");
+        assert_eq!(footer.exec_dir, [b'x'; common::STDIO_PATH_LEN]);
+        assert_eq!(footer.enforcement, [1, 2, 3, 4]);
+        assert_eq!((footer.shutdown_window, footer.usage_interval), (12, 45));
+        assert!(tampered.is_err());
+        assert!(short.is_err());
+    }
+}
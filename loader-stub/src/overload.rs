@@ -0,0 +1,136 @@
+//! The overload while it runs next to the base (async mode).
+//!
+//! The monitor thread reaps it as soon as it terminates, so the PID is
+//! forgotten before anything could reuse it and later signals never reach an
+//! unrelated process.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+use crate::common::{self, OverloadLoss};
+use crate::reverify::Run;
+
+/// How long the overload has to exit after SIGTERM once the base is done
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the shutdown window checks whether the overload exited
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+pub struct OverloadProcess {
+    /// 0 once reaped
+    pid: AtomicI32,
+}
+
+impl OverloadProcess {
+    pub fn new(pid: Pid) -> Self {
+        Self { pid: AtomicI32::new(pid.as_raw()) }
+    }
+
+    /// PID while the overload hasn't been reaped
+    pub fn pid(&self) -> Option<Pid> {
+        let pid = self.pid.load(Ordering::Relaxed);
+        (pid > 0).then(|| Pid::from_raw(pid))
+    }
+
+    /// Watch an overload that was started again
+    pub fn restarted(&self, pid: Pid) {
+        self.pid.store(pid.as_raw(), Ordering::Relaxed);
+    }
+
+    /// Reap the overload if it terminated. A clean exit is only logged, the
+    /// base then runs on the overload's last report.
+    pub fn poll(&self) -> Option<OverloadLoss> {
+        let pid = self.pid()?;
+        let loss = match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, 0)) => {
+                common::log_overload_exited();
+                None
+            }
+            Ok(WaitStatus::Exited(_, code)) => Some(OverloadLoss::Exited(code)),
+            Ok(WaitStatus::Signaled(_, signal, _)) => Some(OverloadLoss::Signaled(signal)),
+            // Still running, or already reaped elsewhere
+            Ok(_) => return None,
+            Err(_) => None,
+        };
+        self.pid.store(0, Ordering::Relaxed);
+        loss
+    }
+
+    /// Sync and hybrid mode, while the base waits: Some(true) once the
+    /// overload exited with 0, Some(false) for any other end (logged)
+    pub fn verdict(&self) -> Option<bool> {
+        let pid = self.pid()?;
+        let passed = match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => return None,
+            Ok(WaitStatus::Exited(_, 0)) => true,
+            Ok(WaitStatus::Exited(_, code)) => {
+                common::log_verification_failed(code);
+                false
+            }
+            Ok(status) => {
+                common::log_overload_terminated_with_status(status);
+                false
+            }
+            Err(e) => {
+                common::log_overload_terminated_with_status(e);
+                false
+            }
+        };
+        self.pid.store(0, Ordering::Relaxed);
+        Some(passed)
+    }
+
+    /// Kill the overload right away
+    pub fn kill(&self) {
+        if let Some(pid) = self.pid() {
+            let _ = kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+            self.pid.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Once the base is done: give the overload `shutdown_window` seconds to
+    /// exit on its own, then ask it to with SIGTERM and SIGKILL it if it
+    /// still runs after a second
+    pub fn terminate(&self, shutdown_window: u32) {
+        let Some(pid) = self.pid() else {
+            return;
+        };
+        if shutdown_window > 0 {
+            common::log_overload_shutdown_window(shutdown_window);
+            let deadline = Instant::now() + Duration::from_secs(shutdown_window as u64);
+            while Instant::now() < deadline {
+                if !matches!(waitpid(pid, Some(WaitPidFlag::WNOHANG)), Ok(WaitStatus::StillAlive)) {
+                    common::log_overload_shut_down();
+                    self.pid.store(0, Ordering::Relaxed);
+                    return;
+                }
+                thread::sleep(SHUTDOWN_POLL);
+            }
+        }
+        common::log_base_completed_terminating_overload(pid);
+        let _ = kill(pid, Signal::SIGTERM);
+        thread::sleep(TERMINATE_TIMEOUT);
+        if let Ok(WaitStatus::StillAlive) = waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            #[cfg(target_os = "linux")]
+            common::log_forcing_sigkill();
+            let _ = kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+        }
+        self.pid.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Run for OverloadProcess {
+    fn verdict(&mut self) -> Option<bool> {
+        OverloadProcess::verdict(self)
+    }
+
+    fn kill(&mut self) {
+        OverloadProcess::kill(self)
+    }
+}
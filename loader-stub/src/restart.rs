@@ -0,0 +1,107 @@
+//! Restarting an overload that crashed or hung while the base keeps running.
+//!
+//! Restarts are spaced out with exponential backoff. Once an overload has run
+//! for the footer's window, it counts as recovered and the budget starts over.
+
+use std::time::{Duration, Instant};
+
+use crate::ConfigFooter;
+
+/// Longest wait between two restarts, however often the overload failed
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+pub struct RestartPolicy {
+    max_restarts: u32,
+    /// Wait before the first restart, doubled for each one after it
+    backoff: Duration,
+    /// Uptime after which the count starts over (zero: never)
+    window: Duration,
+    /// Restarts since the count last started over
+    restarts: u32,
+    /// When the current overload was started
+    started: Instant,
+}
+
+impl RestartPolicy {
+    pub fn from_footer(footer: &ConfigFooter) -> Self {
+        Self {
+            max_restarts: footer.overload_max_restarts,
+            backoff: Duration::from_secs(footer.overload_restart_backoff as u64),
+            window: Duration::from_secs(footer.overload_restart_window as u64),
+            restarts: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// The overload was lost: how long to wait before starting it again, or
+    /// None once the restarts are used up
+    pub fn next_restart(&mut self) -> Option<Duration> {
+        if !self.window.is_zero() && self.started.elapsed() >= self.window {
+            self.restarts = 0;
+        }
+        if self.restarts >= self.max_restarts {
+            return None;
+        }
+        let delay = self.backoff.saturating_mul(1 << self.restarts.min(16)).min(MAX_BACKOFF);
+        self.restarts += 1;
+        Some(delay)
+    }
+
+    /// A new overload is running
+    pub fn started(&mut self) {
+        self.started = Instant::now();
+    }
+
+    /// Restarts since the count last started over
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_restarts: u32, backoff: u64, window: u64) -> RestartPolicy {
+        RestartPolicy {
+            max_restarts,
+            backoff: Duration::from_secs(backoff),
+            window: Duration::from_secs(window),
+            restarts: 0,
+            started: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_restart_backoff() {
+        // Each restart waits twice as long as the one before, up to the cap
+        let mut restarts = policy(12, 2, 0);
+        let delays: Vec<u64> = (0..4).map(|_| restarts.next_restart().unwrap().as_secs()).collect();
+        assert_eq!(delays, [2, 4, 8, 16]);
+        assert_eq!(restarts.restarts(), 4);
+        let rest: Vec<Duration> = std::iter::from_fn(|| restarts.next_restart()).collect();
+        assert_eq!(rest.len(), 8);
+        assert_eq!(rest[..2], [Duration::from_secs(32), Duration::from_secs(64)]);
+        assert_eq!(rest.last(), Some(&MAX_BACKOFF));
+
+        // Without a window the budget never comes back
+        restarts.started();
+        assert_eq!(restarts.next_restart(), None);
+        assert_eq!(policy(0, 2, 0).next_restart(), None);
+    }
+
+    #[test]
+    fn test_restart_window() {
+        let mut restarts = policy(2, 1, 60);
+        assert_eq!(restarts.next_restart(), Some(Duration::from_secs(1)));
+        assert_eq!(restarts.next_restart(), Some(Duration::from_secs(2)));
+        // Lost again before the window is up: the budget is spent
+        restarts.started();
+        assert_eq!(restarts.next_restart(), None);
+
+        // An overload that ran for the window starts the count and the backoff over
+        restarts.started = Instant::now() - Duration::from_secs(60);
+        assert_eq!(restarts.next_restart(), Some(Duration::from_secs(1)));
+        assert_eq!(restarts.restarts(), 1);
+    }
+}
@@ -0,0 +1,236 @@
+//! Re-running a one-shot overload while the base runs (sync mode).
+//!
+//! Every `reverify_interval` seconds the monitor thread starts the embedded
+//! overload again. An exit with 0 counts as a success and anything else as a
+//! failure, and the results are judged like an overload's reports: the grace
+//! period runs from the last passed run, and `network_failure_kill_count`
+//! failed runs in a row kill the base. A run still going after `sync_timeout`
+//! is killed and counts as failed.
+
+use std::time::{Duration, Instant};
+
+use killcode_overload::protocol::Report;
+
+use crate::common::{
+    self, evaluate_health_status, log_reverification_passed, log_reverification_start_failed,
+    log_reverification_timed_out, HealthCheckResult,
+};
+use crate::verify::SYNC_MODE_SYNC;
+use crate::ConfigFooter;
+
+/// One run of the overload, as the platform started it
+pub trait Run {
+    /// Some(true) once it exited with 0, Some(false) for any other end (logged)
+    fn verdict(&mut self) -> Option<bool>;
+    fn kill(&mut self);
+}
+
+pub struct Reverification<R: Run> {
+    interval: Duration,
+    /// How long a run may take (zero: no limit)
+    timeout: Duration,
+    next_run: Instant,
+    /// The run in flight and when it started
+    run: Option<(R, Instant)>,
+    /// The runs' results as the overload would have reported them
+    report: Report,
+}
+
+impl<R: Run> Reverification<R> {
+    /// None unless the footer asks for re-verification in sync mode
+    pub fn from_footer(footer: &ConfigFooter) -> Option<Self> {
+        if footer.sync_mode != SYNC_MODE_SYNC || footer.reverify_interval == 0 {
+            return None;
+        }
+        let interval = Duration::from_secs(footer.reverify_interval as u64);
+        // The check before the base started was the first success
+        let now = common::current_time();
+        Some(Self {
+            interval,
+            timeout: Duration::from_secs(footer.sync_timeout as u64),
+            next_run: Instant::now() + interval,
+            run: None,
+            report: Report { last_success: now, last_heartbeat: now, ..Default::default() },
+        })
+    }
+
+    /// Collect the run in flight, start the next one when it is due, and
+    /// judge the results. Runs are only as punctual as the monitor's cycle.
+    pub fn check(
+        &mut self,
+        start: impl FnOnce() -> Result<R, String>,
+        grace_period: u32,
+        network_failure_kill_count: u32,
+    ) -> HealthCheckResult {
+        if let Some((run, started)) = &mut self.run {
+            let passed = match run.verdict() {
+                Some(passed) => Some(passed),
+                None if !self.timeout.is_zero() && started.elapsed() >= self.timeout => {
+                    log_reverification_timed_out(self.timeout.as_secs());
+                    run.kill();
+                    Some(false)
+                }
+                None => None,
+            };
+            if let Some(passed) = passed {
+                self.run = None;
+                self.record(passed);
+            }
+        }
+
+        if self.run.is_none() && Instant::now() >= self.next_run {
+            self.next_run = Instant::now() + self.interval;
+            match start() {
+                Ok(run) => self.run = Some((run, Instant::now())),
+                Err(e) => {
+                    log_reverification_start_failed(&e);
+                    self.record(false);
+                }
+            }
+        }
+
+        self.report.last_heartbeat = common::current_time();
+        evaluate_health_status(&self.report, grace_period, network_failure_kill_count, 0)
+    }
+
+    fn record(&mut self, passed: bool) {
+        if passed {
+            log_reverification_passed();
+            self.report.last_success = common::current_time();
+            self.report.consecutive_failures = 0;
+        } else {
+            self.report.consecutive_failures += 1;
+        }
+    }
+
+    /// Where the grace period currently runs from
+    pub fn last_success(&self) -> i64 {
+        self.report.last_success
+    }
+
+    /// Stop a run that is still going once the base is gone
+    pub fn stop(&mut self) {
+        if let Some((mut run, _)) = self.run.take() {
+            run.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// A run that ends with `verdict` and records being killed
+    struct FakeRun {
+        verdict: Option<bool>,
+        killed: Rc<Cell<bool>>,
+    }
+
+    impl Run for FakeRun {
+        fn verdict(&mut self) -> Option<bool> {
+            self.verdict
+        }
+
+        fn kill(&mut self) {
+            self.killed.set(true);
+        }
+    }
+
+    fn reverification(timeout: u32) -> Reverification<FakeRun> {
+        let footer = ConfigFooter {
+            sync_mode: SYNC_MODE_SYNC,
+            reverify_interval: 60,
+            sync_timeout: timeout,
+            ..unsafe { std::mem::zeroed() }
+        };
+        Reverification::from_footer(&footer).unwrap()
+    }
+
+    /// Make the next run due and check with one that ends with `verdict`
+    fn run(reverify: &mut Reverification<FakeRun>, verdict: Option<bool>) -> HealthCheckResult {
+        reverify.next_run = Instant::now();
+        let killed = Rc::new(Cell::new(false));
+        reverify.check(|| Ok(FakeRun { verdict, killed }), 0, 3)
+    }
+
+    #[test]
+    fn test_reverify_from_footer() {
+        let footer: ConfigFooter = unsafe { std::mem::zeroed() };
+        assert!(Reverification::<FakeRun>::from_footer(&footer).is_none());
+        let async_mode = ConfigFooter { reverify_interval: 60, ..footer };
+        assert!(Reverification::<FakeRun>::from_footer(&async_mode).is_none());
+        assert!(reverification(0).last_success() >= common::current_time() - 1);
+    }
+
+    #[test]
+    fn test_reverify_failures_in_a_row() {
+        let mut reverify = reverification(0);
+
+        // Each run is collected by the check after the one that started it
+        assert!(matches!(run(&mut reverify, Some(false)), HealthCheckResult::Ok));
+        assert!(matches!(run(&mut reverify, Some(false)), HealthCheckResult::Ok));
+        assert_eq!(reverify.report.consecutive_failures, 1);
+
+        // A passed run starts the count over
+        assert!(matches!(run(&mut reverify, Some(true)), HealthCheckResult::Ok));
+        assert_eq!(reverify.report.consecutive_failures, 2);
+        assert!(matches!(run(&mut reverify, Some(false)), HealthCheckResult::Ok));
+        assert_eq!(reverify.report.consecutive_failures, 0);
+
+        // Runs that cannot start count as failed too
+        reverify.stop();
+        let failed = || Err("no overload".to_string());
+        for _ in 0..2 {
+            reverify.next_run = Instant::now();
+            assert!(matches!(reverify.check(failed, 0, 3), HealthCheckResult::Ok));
+        }
+        reverify.next_run = Instant::now();
+        assert!(matches!(
+            reverify.check(failed, 0, 3),
+            HealthCheckResult::NetworkFailureThreshold { failures: 3, threshold: 3 }
+        ));
+    }
+
+    #[test]
+    fn test_reverify_timeout() {
+        let mut reverify = reverification(5);
+        let killed = Rc::new(Cell::new(false));
+        reverify.next_run = Instant::now();
+        let hung = FakeRun { verdict: None, killed: killed.clone() };
+        reverify.check(|| Ok(hung), 0, 3);
+
+        // Not collected and not due again while it runs within the timeout
+        reverify.next_run = Instant::now();
+        reverify.check(|| panic!("started while a run is in flight"), 0, 3);
+        assert!(!killed.get());
+
+        // Past the timeout it is killed and counted as failed
+        reverify.run.as_mut().unwrap().1 = Instant::now() - Duration::from_secs(5);
+        reverify.next_run = Instant::now() + reverify.interval;
+        reverify.check(|| panic!("not due"), 0, 3);
+        assert!(killed.get());
+        assert!(reverify.run.is_none());
+        assert_eq!(reverify.report.consecutive_failures, 1);
+    }
+
+    #[test]
+    fn test_reverify_grace_period() {
+        let mut reverify = reverification(0);
+        reverify.report.last_success = common::current_time() - 120;
+        // A passed run moves the grace period up once it is collected
+        assert!(matches!(run(&mut reverify, Some(true)), HealthCheckResult::Ok));
+        reverify.next_run = Instant::now() + reverify.interval;
+        assert!(matches!(reverify.check(|| panic!("not due"), 60, 3), HealthCheckResult::Ok));
+        assert!(reverify.last_success() >= common::current_time() - 1);
+
+        // No passed run within the grace period kills the base
+        reverify.report.last_success = common::current_time() - 120;
+        assert!(matches!(
+            reverify.check(|| panic!("not due"), 60, 3),
+            HealthCheckResult::GracePeriodExceeded { grace_period: 60, .. }
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+//! A directory of the stub's own in the temp dir.
+//!
+//! Each launch creates its own, named after the stub's PID and a random
+//! suffix and accessible only to the current user, so nothing in it can be
+//! replaced, read or pre-created by anyone else. It is removed with
+//! everything in it on drop. One left behind by a stub that was killed, or
+//! whose process became an in-process base, is removed by the next launch
+//! once its PID is gone.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::common;
+use crate::health;
+use crate::ConfigFooter;
+
+/// Directory names tried for one launch before giving up
+const MAX_DIR_ATTEMPTS: u32 = 16;
+
+pub struct RunDir {
+    path: PathBuf,
+}
+
+impl RunDir {
+    /// Create the launch's directory for `label` (its name prefix)
+    pub fn create(footer: &ConfigFooter, label: &str) -> Result<Self, String> {
+        let temp_dir = std::env::temp_dir();
+        let prefix = common::instance_name(footer, label);
+        remove_stale(&temp_dir, &prefix);
+
+        let pid = std::process::id();
+        for _ in 0..MAX_DIR_ATTEMPTS {
+            let random = health::random_key()?;
+            let suffix: String = random[..4].iter().map(|b| format!("{:02x}", b)).collect();
+            let path = temp_dir.join(format!("{}{}-{}", prefix, pid, suffix));
+            match create_private_dir(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(format!("Cannot create {}: {}", path.display(), e)),
+            }
+        }
+        Err(format!("Cannot create a private directory in {}", temp_dir.display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Remove directories of earlier launches whose stub is no longer running
+fn remove_stale(temp_dir: &Path, prefix: &str) {
+    let Ok(entries) = fs::read_dir(temp_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let pid = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|rest| rest.split_once('-'))
+            .and_then(|(pid, _)| pid.parse::<u32>().ok());
+        if pid.is_some_and(|pid| !process_alive(pid)) {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+#[cfg(windows)]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::{mem, ptr};
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorA, SDDL_REVISION_1,
+    };
+    use windows_sys::Win32::Security::SECURITY_ATTRIBUTES;
+    use windows_sys::Win32::Storage::FileSystem::CreateDirectoryA;
+
+    // Protected DACL: full access for the owner and SYSTEM, inherited by the
+    // files in it, nothing for anyone else
+    let sddl = CString::new("D:P(A;OICI;FA;;;OW)(A;OICI;FA;;;SY)").unwrap();
+    let path = CString::new(dir.to_string_lossy().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    unsafe {
+        let mut descriptor = ptr::null_mut();
+        if ConvertStringSecurityDescriptorToSecurityDescriptorA(
+            sddl.as_ptr() as *const u8,
+            SDDL_REVISION_1,
+            &mut descriptor,
+            ptr::null_mut(),
+        ) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let attributes = SECURITY_ATTRIBUTES {
+            nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor,
+            bInheritHandle: 0,
+        };
+        let created = CreateDirectoryA(path.as_ptr() as *const u8, &attributes);
+        let result = if created == 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+        LocalFree(descriptor);
+        result
+    }
+}
+
+#[cfg(not(windows))]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().mode(0o700).create(dir)
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, GetLastError, ERROR_ACCESS_DENIED};
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            return GetLastError() == ERROR_ACCESS_DENIED;
+        }
+        let mut exit_code: u32 = 0;
+        let running = GetExitCodeProcess(process, &mut exit_code) != 0 && exit_code == crate::windows::STILL_ACTIVE;
+        CloseHandle(process);
+        running
+    }
+}
+
+#[cfg(not(windows))]
+fn process_alive(pid: u32) -> bool {
+    let signalled = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
@@ -0,0 +1,140 @@
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Instant;
+
+use killcode_overload::protocol;
+
+use crate::ConfigFooter;
+
+/// Signals the stub passes on to the base.
+///
+/// The job-control signals are caught too, so a stop from the terminal only
+/// stops the base and the stub follows once it sees the base stopped.
+const FORWARDED_SIGNALS: [i32; 11] = [
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGWINCH,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+    libc::SIGCONT,
+];
+
+/// PID the forwarded signals go to (0 until the base is running)
+static FORWARD_PID: AtomicI32 = AtomicI32::new(0);
+
+/// How the base ended, reproduced by the stub when it exits
+#[derive(Debug, Clone, Copy)]
+pub enum BaseExit {
+    Code(i32),
+    Signal(i32),
+}
+
+impl BaseExit {
+    /// What the overload is told about it
+    pub fn report(self, started: Instant, peak_rss: u64, footer: &ConfigFooter) -> protocol::BaseExit {
+        let (code, signal) = match self {
+            BaseExit::Code(code) => (code, 0),
+            BaseExit::Signal(signal) => (0, signal),
+        };
+        protocol::BaseExit {
+            code,
+            signal,
+            runtime_ms: started.elapsed().as_millis() as u64,
+            peak_rss,
+            shutdown_window: footer.shutdown_window,
+        }
+    }
+}
+
+/// Whether the signal was generated by the terminal, which already delivers
+/// it to the whole foreground process group (base included)
+unsafe fn from_terminal(info: *const libc::siginfo_t) -> bool {
+    #[cfg(target_os = "linux")]
+    return (*info).si_code == libc::SI_KERNEL;
+    #[cfg(target_os = "macos")]
+    return (*info).si_pid == 0;
+}
+
+extern "C" fn forward(signal: i32, info: *mut libc::siginfo_t, _context: *mut libc::c_void) {
+    unsafe {
+        let pid = FORWARD_PID.load(Ordering::Relaxed);
+        if pid <= 0 {
+            // Nothing to forward to yet: act as if the stub didn't handle it
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+            return;
+        }
+        if !from_terminal(info) {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+/// Forward the common termination and job signals to the base.
+///
+/// Call right before the base is forked; the child's handlers are reset by exec.
+pub fn install_forwarding() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = forward as *const () as usize;
+        // SA_RESTART keeps the stub's waitpid on the base from failing with EINTR
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        for signal in FORWARDED_SIGNALS {
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+    }
+}
+
+/// Rust's runtime ignores SIGPIPE and exec keeps it ignored. Call in the
+/// child before starting a payload, so it starts with the default like the
+/// unmerged binary.
+pub fn restore_sigpipe() {
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+}
+
+/// Start forwarding to `pid`
+pub fn forward_to(pid: i32) {
+    FORWARD_PID.store(pid, Ordering::Relaxed);
+}
+
+/// Stop the stub the way the base was stopped, returning once it is continued
+pub fn stop_like(signal: i32) {
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    install_forwarding();
+}
+
+/// Leave the way the base did: with its exit code, or killed by the same signal.
+///
+/// Re-raising keeps `$?`, `WIFSIGNALED` and systemd/Kubernetes reporting identical
+/// to the unmerged binary. Core dumps are disabled first, since a core of the
+/// stub would contain both payloads.
+pub fn exit_like(exit: BaseExit) -> ! {
+    match exit {
+        BaseExit::Code(code) => std::process::exit(code),
+        BaseExit::Signal(signal) => unsafe {
+            let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            libc::setrlimit(libc::RLIMIT_CORE, &limit);
+            libc::signal(signal, libc::SIG_DFL);
+
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, signal);
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+            libc::raise(signal);
+
+            // Only reached for signals whose default action doesn't terminate
+            std::process::exit(128 + signal)
+        },
+    }
+}
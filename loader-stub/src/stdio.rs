@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::thread;
+
+#[cfg(target_os = "linux")]
+use crate::common::ROUTE_JOURNALD;
+use crate::common::{self, footer_path, open_log, ROUTE_FILE, ROUTE_NULL, ROUTE_SYSLOG};
+use crate::logging;
+use crate::ConfigFooter;
+
+/// The overload's standard streams, opened before it is forked.
+///
+/// Streams routed to syslog or journald get a pipe whose lines a stub thread
+/// relays, so nothing the overload prints ends up in the base's output.
+pub struct OverloadStdio {
+    /// Descriptor for each of stdin, stdout and stderr (None = inherit)
+    targets: [Option<OwnedFd>; 3],
+    /// Read ends of the relayed pipes, their route and the priority their lines get
+    relays: Vec<(OwnedFd, u8, i32)>,
+}
+
+impl OverloadStdio {
+    pub fn open(footer: &ConfigFooter) -> Self {
+        let mut relays = Vec::new();
+        let routes = [footer.overload_stdin, footer.overload_stdout, footer.overload_stderr];
+        let targets = std::array::from_fn(|stream| match routes[stream] {
+            ROUTE_NULL => null(),
+            ROUTE_FILE => log_file(footer),
+            route if is_relayed(route) => {
+                let Some((reader, writer)) = cloexec_pipe() else {
+                    return null();
+                };
+                let priority = if stream == libc::STDERR_FILENO as usize { libc::LOG_ERR } else { libc::LOG_INFO };
+                relays.push((reader, route, priority));
+                Some(writer)
+            }
+            _ => None,
+        });
+        Self { targets, relays }
+    }
+
+    /// Called in the forked overload: install the streams
+    pub fn apply(&self) {
+        for (stream, target) in self.targets.iter().enumerate() {
+            if let Some(fd) = target {
+                unsafe { libc::dup2(fd.as_raw_fd(), stream as RawFd) };
+            }
+        }
+    }
+
+    /// Called in the stub once the overload is forked: close the overload's
+    /// ends and start relaying its syslog and journald streams
+    pub fn start(self) {
+        drop(self.targets);
+        for (reader, route, priority) in self.relays {
+            thread::spawn(move || {
+                #[cfg(target_os = "linux")]
+                let journal = (route == ROUTE_JOURNALD).then(logging::journal_socket).flatten();
+                #[cfg(not(target_os = "linux"))]
+                let _ = route;
+
+                for line in BufReader::new(File::from(reader)).split(b'\n').map_while(Result::ok) {
+                    let line = String::from_utf8_lossy(&line);
+                    #[cfg(target_os = "linux")]
+                    if let Some(socket) = &journal {
+                        logging::journal_send(socket, priority, &line);
+                        continue;
+                    }
+                    logging::syslog(priority, &line);
+                }
+            });
+        }
+    }
+}
+
+/// Routes whose lines go through a relay thread. Journald falls back to
+/// syslog if it isn't running.
+fn is_relayed(route: u8) -> bool {
+    #[cfg(target_os = "linux")]
+    if route == ROUTE_JOURNALD {
+        return true;
+    }
+    route == ROUTE_SYSLOG
+}
+
+fn null() -> Option<OwnedFd> {
+    File::options().read(true).write(true).open("/dev/null").ok().map(OwnedFd::from)
+}
+
+/// The overload's log file, or `/dev/null` if it can't be opened, so the
+/// output still stays out of the base's streams
+fn log_file(footer: &ConfigFooter) -> Option<OwnedFd> {
+    let path = footer_path(&footer.overload_log_path).unwrap_or_default();
+    match open_log(path) {
+        Ok(file) => Some(file.into()),
+        Err(e) => {
+            common::log_overload_log_failed(path, e);
+            null()
+        }
+    }
+}
+
+fn cloexec_pipe() -> Option<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return None;
+        }
+        for fd in fds {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        Some((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])))
+    }
+}
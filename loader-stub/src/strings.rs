@@ -0,0 +1,231 @@
+use std::fmt::Display;
+use std::ptr;
+
+use crate::variant::constant;
+
+/// Locates the table for weaver, which re-keys it and overwrites the marker on every merge.
+///
+/// Must match `STRING_TABLE_MARKER` in weaver's `core::protection::polymorph`.
+const STRING_TABLE_MARKER: [u8; 16] = *b"\x8fKC\x1dSTRTBL\xa4\x3e\x91\x07v1";
+
+/// Key the table is encrypted with at build time. Replaced per merge.
+const BUILD_KEY: u64 = 0x6b69_6c6c_636f_6465;
+
+macro_rules! string_table {
+    ($($id:ident => $text:literal,)*) => {
+        /// Strings stored in the encrypted table
+        #[allow(dead_code)]
+        #[derive(Clone, Copy)]
+        #[repr(usize)]
+        pub enum S {
+            $($id,)*
+        }
+
+        // Only evaluated at compile time, so the plaintext never reaches the binary
+        const TEXTS: &[&str] = &[$($text,)*];
+    };
+}
+
+string_table! {
+    Tag => "[KillCode]",
+    StubStarting => "V2 Stub execution starting",
+    StubConfig => "Config: sync={}, grace_period={}s, failure_threshold={}",
+    HealthMonitoringEnabled => "Health monitoring enabled: {}",
+    HealthMonitorStarted => "Health monitor started",
+    HealthReportRejected => "⚠️  Rejected {} health report (counter {})",
+    SyncModeWaiting => "Sync mode: Waiting for overload verification (PID: {})...",
+    VerificationFailed => "❌ Overload verification failed (exit code: {})",
+    VerificationSuccessful => "✅ Overload verification successful",
+    VerificationRejected => "❌ Overload verification failed: {}",
+    VerificationTimedOut => "❌ Overload verification timed out after {}s",
+    StartingUnverified => "Starting the base without verification",
+    ReverificationStarted => "Re-verifying the license (PID: {})",
+    ReverificationPassed => "✅ Re-verification passed",
+    ReverificationTimedOut => "❌ Re-verification timed out after {}s",
+    ReverificationStartFailed => "❌ Could not start the re-verification: {}",
+    ReverificationFailures => "⚠️  {} re-verifications failed in a row (threshold {})",
+    ClockJumped => "⚠️  System clock jumped by {}s",
+    ClockTampered => "⚠️  System clock was tampered with, killing base",
+    EnforceKill => "Killing base",
+    EnforceTerminate => "Terminating base (killed after {}s)",
+    EnforceSuspend => "Suspending base until the checks pass again",
+    EnforceNotify => "Notifying base",
+    EnforceDegraded => "Base runs degraded until the checks pass again",
+    BaseRestored => "✅ Checks pass again, base restored",
+    StatusWriteFailed => "Cannot write the base's status file: {}",
+    GraceRecordResumed => "Grace period runs on from a success {}s ago",
+    GraceRecordMissing => "No grace record ({})",
+    GraceRecordTampered => "⚠️  Grace record rejected: {}",
+    GraceRecordSaveFailed => "Cannot save the grace record: {}",
+    HybridModeWaiting => "Hybrid mode: Waiting for the overload's first success (PID: {})...",
+    AsyncModeStarted => "Async mode: Overload running in background (PID: {})",
+    OverloadStartFailed => "Failed to start overload binary: {}",
+    BaseStartFailed => "Failed to start base binary: {}",
+    StartingBase => "Starting base binary...",
+    BaseCompletedTerminatingOverload => "Base binary completed, terminating overload (PID: {})",
+    OverloadShutdownWindow => "Base binary completed, waiting up to {}s for the overload to exit",
+    OverloadShutDown => "Overload exited after the base",
+    BaseExited => "Base binary exited with code: {}",
+    GracePeriodExceeded => "⚠️  Grace period exceeded ({} > {} seconds)",
+    NetworkFailureThreshold => "⚠️  Network failure threshold exceeded ({}/{})",
+    SignalingOverloadKill => "Signaling overload to kill parent",
+    FallbackKill => "Fallback: Killing base directly (overload didn't respond)",
+    OverloadRequestedKill => "⚠️  Overload requested base termination",
+    OverloadUnresponsive => "⚠️  Overload sent no report for {}s (heartbeat timeout {}s)",
+    OverloadCrashed => "⚠️  Overload exited with code {} while the base was running",
+    OverloadKilled => "⚠️  Overload was killed by {} while the base was running",
+    OverloadExited => "Overload exited, the base runs on its last report",
+    OverloadRestartScheduled => "Restarting the overload in {}s (restart {} of {})",
+    OverloadRestarted => "Overload restarted (PID: {})",
+    OverloadRestartFailed => "❌ Could not restart the overload: {}",
+    ForcingSigkill => "Forcing SIGKILL on overload",
+    HealthChannelFailed => "Warning: Failed to create the health channel: {}",
+    HealthChannelRequired => "Refusing to start without a health channel",
+    HealthTransportUnavailable => "Health transport unavailable: {}",
+    OverloadTerminatedAbnormally => "❌ Overload terminated abnormally{}",
+    ExecvFailed => "execv failed",
+    WaitpidFailed => "waitpid failed for base: {}",
+    BaseKilledBySignal => "Base process killed by signal: {}",
+    WritingPayload => "Writing {} binary ({} bytes) to: {}",
+    WaitingForDecryptionKey => "Waiting for overload to deliver decryption key ({} encrypted functions)...",
+    FunctionsDecrypted => "✅ Decrypted {} function bodies",
+    LazyDecryptionUnavailable => "Lazy decryption requires in-process loading, decrypting before launch",
+    IntegrityVerified => "✅ Integrity verified ({} regions)",
+    Failure => "❌ {}",
+    DebuggerDetected => "❌ Debugger attached to PID {}, terminating",
+    InProcessLoadFailed => "❌ In-process loading of {} failed: {}",
+    OverloadLogFailed => "Cannot open overload log {}: {}, discarding its output",
+    ExecStrategyFailed => "Starting {} with {} failed: {}",
+    PayloadStarted => "Started {} with {}",
+    NoExecStrategy => "❌ Cannot start {}, every execution strategy failed ({}). Set exec_dir to a directory that allows exec, or use exec_mode=in_process",
+    LogEnv => "KILLCODE_LOG",
+    HealthChannelEnv => "KILLCODE_HEALTH_FD",
+    KeyFdEnv => "KILLCODE_KEY_FD",
+    JournalSocket => "/run/systemd/journal/socket",
+    StatusEnv => "KILLCODE_STATUS",
+    NtDll => "ntdll.dll",
+    NtSuspendProcess => "NtSuspendProcess",
+    NtResumeProcess => "NtResumeProcess",
+    Home => "HOME",
+    XdgStateHome => "XDG_STATE_HOME",
+    LocalStateDir => ".local/state",
+    MacStateDir => "Library/Application Support",
+    LocalAppData => "LOCALAPPDATA",
+    MachineIdPath => "/etc/machine-id",
+    DbusMachineIdPath => "/var/lib/dbus/machine-id",
+    BootIdPath => "/proc/sys/kernel/random/boot_id",
+    BootSessionSysctl => "kern.bootsessionuuid",
+    CryptographyKey => "SOFTWARE\\Microsoft\\Cryptography",
+    MachineGuid => "MachineGuid",
+    PrefetchParametersKey => "SYSTEM\\CurrentControlSet\\Control\\Session Manager\\Memory Management\\PrefetchParameters",
+    BootId => "BootId",
+}
+
+const COUNT: usize = TEXTS.len();
+
+const fn offsets() -> [usize; COUNT + 1] {
+    let mut offsets = [0usize; COUNT + 1];
+    let mut i = 0;
+    while i < COUNT {
+        offsets[i + 1] = offsets[i] + TEXTS[i].len();
+        i += 1;
+    }
+    offsets
+}
+
+const OFFSETS: [usize; COUNT + 1] = offsets();
+const DATA_LEN: usize = OFFSETS[COUNT];
+
+/// splitmix64's increment and multipliers
+const SPLITMIX: [u64; 3] = [0x9E37_79B9_7F4A_7C15, 0xBF58_476D_1CE4_E5B9, 0x94D0_49BB_1331_11EB];
+
+/// Keystream byte at `position`: splitmix64 in counter mode
+const fn keystream(key: u64, position: usize, splitmix: &[u64; 3]) -> u8 {
+    let mut z = key.wrapping_add(((position / 8) as u64).wrapping_mul(splitmix[0]));
+    z = (z ^ (z >> 30)).wrapping_mul(splitmix[1]);
+    z = (z ^ (z >> 27)).wrapping_mul(splitmix[2]);
+    z ^= z >> 31;
+    (z >> ((position % 8) * 8)) as u8
+}
+
+const fn encrypt() -> [u8; DATA_LEN] {
+    let mut data = [0u8; DATA_LEN];
+    let mut i = 0;
+    while i < COUNT {
+        let text = TEXTS[i].as_bytes();
+        let mut j = 0;
+        while j < text.len() {
+            let position = OFFSETS[i] + j;
+            data[position] = text[j] ^ keystream(BUILD_KEY, position, &SPLITMIX);
+            j += 1;
+        }
+        i += 1;
+    }
+    data
+}
+
+/// Layout shared with weaver: `[marker][key][len][data]`
+#[repr(C)]
+struct StringTable {
+    marker: [u8; 16],
+    key: [u8; 8],
+    len: u32,
+    data: [u8; DATA_LEN],
+}
+
+#[used]
+static STRING_TABLE: StringTable = StringTable {
+    marker: STRING_TABLE_MARKER,
+    key: BUILD_KEY.to_le_bytes(),
+    len: DATA_LEN as u32,
+    data: encrypt(),
+};
+
+/// Decrypt a string from the table
+pub fn get(id: S) -> String {
+    // Read the key through a volatile load so the compiler can't fold the
+    // build-time key into plaintext constants
+    let key = u64::from_le_bytes(unsafe { ptr::read_volatile(&STRING_TABLE.key) });
+    // Masked so the keystream's constants look different in every variant
+    let splitmix = [constant!(SPLITMIX[0]), constant!(SPLITMIX[1]), constant!(SPLITMIX[2])];
+    let (start, end) = (OFFSETS[id as usize], OFFSETS[id as usize + 1]);
+    let bytes: Vec<u8> = (start..end)
+        .map(|position| STRING_TABLE.data[position] ^ keystream(key, position, &splitmix))
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Decrypt a string and substitute each `{}` with the next argument
+pub fn format(id: S, args: &[&dyn Display]) -> String {
+    let text = get(id);
+    let mut out = String::with_capacity(text.len());
+    let mut args = args.iter();
+    let mut rest = text.as_str();
+    while let Some(index) = rest.find("{}") {
+        out.push_str(&rest[..index]);
+        if let Some(arg) = args.next() {
+            out.push_str(&arg.to_string());
+        }
+        rest = &rest[index + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_table() {
+        // Only the encrypted table holds the text
+        assert!(!STRING_TABLE.data.windows(8).any(|window| window == b"KILLCODE"));
+
+        assert_eq!(get(S::Tag), "[KillCode]");
+        // The SDK reads the variable the stub sets
+        assert_eq!(get(S::HealthChannelEnv), killcode_overload::protocol::HEALTH_CHANNEL_ENV);
+        assert_eq!(format(S::OverloadRestarted, &[&42]), "Overload restarted (PID: 42)");
+        // Missing arguments leave their placeholder empty
+        assert_eq!(format(S::GracePeriodExceeded, &[&7]), "⚠️  Grace period exceeded (7 >  seconds)");
+    }
+}
@@ -0,0 +1,75 @@
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+
+use crate::common::{log_base_killed_by_signal, log_base_waitpid_failed};
+use crate::signals::{self, BaseExit};
+
+/// Whether the stub runs as a job on its controlling terminal (in the
+/// foreground or background).
+///
+/// The base stays in the stub's process group, so it is part of the shell's
+/// job along with anything the merged binary is piped into.
+pub fn is_interactive() -> bool {
+    unsafe {
+        libc::isatty(libc::STDIN_FILENO) == 1 && libc::tcgetsid(libc::STDIN_FILENO) == libc::getsid(0)
+    }
+}
+
+/// Wait for the base to end and report how it did, along with its peak
+/// resident set size in bytes.
+///
+/// Interactively, stops of the base (`Ctrl-Z`, `SIGTTIN` in the background,
+/// a program stopping itself) are mirrored by the stub, so the shell sees the
+/// job stopped, and the base is continued along with the stub.
+pub fn wait_for_base(base: Pid, interactive: bool) -> (BaseExit, u64) {
+    let flags = if interactive { libc::WUNTRACED } else { 0 };
+    loop {
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        let result = unsafe { libc::wait4(base.as_raw(), &mut status, flags, &mut usage) };
+        let waited = Errno::result(result).and_then(|pid| WaitStatus::from_raw(Pid::from_raw(pid), status));
+        // Kilobytes on Linux, bytes on macOS
+        #[cfg(target_os = "linux")]
+        let peak_rss = usage.ru_maxrss.max(0) as u64 * 1024;
+        #[cfg(not(target_os = "linux"))]
+        let peak_rss = usage.ru_maxrss.max(0) as u64;
+        match waited {
+            Ok(WaitStatus::Exited(_, code)) => return (BaseExit::Code(code), peak_rss),
+            Ok(WaitStatus::Signaled(_, sig, _)) => {
+                log_base_killed_by_signal(sig);
+                return (BaseExit::Signal(sig as i32), peak_rss);
+            }
+            // Stopped by a suspend action rather than by job control
+            Ok(WaitStatus::Stopped(..)) if crate::enforce::base_suspended() => {}
+            Ok(WaitStatus::Stopped(_, sig)) => {
+                signals::stop_like(sig as i32);
+                let _ = kill(base, Signal::SIGCONT);
+            }
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => {
+                log_base_waitpid_failed(e);
+                return (BaseExit::Code(1), 0);
+            }
+        }
+    }
+}
+
+/// Called in the forked overload: start a new session without a controlling
+/// terminal, so it gets no terminal signals and takes no part in job control.
+///
+/// A terminal on stdin is swapped for `/dev/null` so the overload can't read
+/// the base's input. Output still goes wherever the stub's does.
+pub fn detach_from_terminal() {
+    unsafe {
+        libc::setsid();
+        if libc::isatty(libc::STDIN_FILENO) == 1 {
+            let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY);
+            if null >= 0 {
+                libc::dup2(null, libc::STDIN_FILENO);
+                libc::close(null);
+            }
+        }
+    }
+}
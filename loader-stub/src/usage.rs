@@ -0,0 +1,130 @@
+//! The base's resource use, published to the overload.
+//!
+//! Overloads that enforce usage-based licenses (CPU hours, concurrent
+//! workers) can't see the base themselves. A thread samples the base and
+//! everything it started from `/proc` every `usage_interval` seconds and puts
+//! the totals in the health channel, where the SDK reads them.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use killcode_overload::protocol::{self, BaseUsage};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+
+use crate::containment;
+use crate::health::HealthChannel;
+use crate::ConfigFooter;
+
+/// Sample the base until it is gone, if the footer asks for it
+pub fn start(base: Pid, health: Option<Arc<HealthChannel>>, footer: &ConfigFooter) {
+    let (Some(health), interval) = (health, footer.usage_interval) else {
+        return;
+    };
+    if interval == 0 {
+        return;
+    }
+    let mut meter = Meter::new(base);
+    thread::spawn(move || loop {
+        health.report_usage(meter.sample());
+        thread::sleep(Duration::from_secs(interval as u64));
+        if kill(base, None).is_err() {
+            break;
+        }
+    });
+}
+
+struct Meter {
+    base: Pid,
+    /// Milliseconds per clock tick
+    tick_ms: u64,
+    page_size: u64,
+    /// Highest CPU time reported so far. Processes that exit without being
+    /// waited for inside the tree take theirs along.
+    cpu_ms: u64,
+}
+
+/// One process's share, from `/proc/<pid>/stat`
+struct Stat {
+    ticks: u64,
+    threads: u32,
+    rss_pages: u64,
+}
+
+impl Meter {
+    fn new(base: Pid) -> Self {
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+        Self { base, tick_ms: 1000 / ticks_per_second, page_size, cpu_ms: 0 }
+    }
+
+    fn sample(&mut self) -> BaseUsage {
+        let mut tree = containment::descendants(self.base);
+        // As subreaper the stub's subtree is searched, which holds the base
+        let children = tree.iter().filter(|&&pid| pid != self.base).count() as u32;
+        if !tree.contains(&self.base) {
+            tree.push(self.base);
+        }
+
+        let mut usage = BaseUsage { sampled_at: protocol::clock(), children, ..Default::default() };
+        let mut ticks = 0;
+        for stat in tree.into_iter().filter_map(stat) {
+            ticks += stat.ticks;
+            usage.threads += stat.threads;
+            usage.rss += stat.rss_pages * self.page_size;
+        }
+        self.cpu_ms = self.cpu_ms.max(ticks * self.tick_ms);
+        usage.cpu_ms = self.cpu_ms;
+        usage
+    }
+}
+
+fn stat(pid: Pid) -> Option<Stat> {
+    parse_stat(&std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+fn parse_stat(stat: &str) -> Option<Stat> {
+    // Fields after the command name, which may contain spaces and
+    // parentheses, starting with the state (field 3 in proc(5))
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |number: usize| fields.get(number - 3)?.parse::<u64>().ok();
+    // utime, stime and those of the children it waited for
+    let ticks = field(14)? + field(15)? + field(16)? + field(17)?;
+    Some(Stat { ticks, threads: field(20)? as u32, rss_pages: field(24)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stat_parsing() {
+        // pid, comm, then fields 3 to 24: utime 10, stime 20, cutime 30,
+        // cstime 40, 3 threads and 500 resident pages
+        let rest = "S 1 1 1 0 -1 4194560 100 0 0 0 10 20 30 40 20 0 3 0 12345 4096000 500";
+        for comm in ["sleep", "a b", "x) (y", "((", ") S 1 2 3 4 5 6 7 8 9 10 11 12 13 14"] {
+            let stat = parse_stat(&format!("4321 ({}) {}\n", comm, rest)).unwrap();
+            assert_eq!(stat.ticks, 100, "{}", comm);
+            assert_eq!(stat.threads, 3, "{}", comm);
+            assert_eq!(stat.rss_pages, 500, "{}", comm);
+        }
+        assert!(parse_stat("4321 (sleep S 1 1").is_none());
+        assert!(parse_stat("4321 (sleep) S 1 1").is_none());
+
+        // A real process whose executable name has spaces and parentheses
+        let dir = std::env::temp_dir().join(format!("usage-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sleep = dir.join("a) (b c");
+        std::fs::copy("/bin/sleep", &sleep).unwrap();
+        let mut child = std::process::Command::new(&sleep).arg("5").spawn().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let stat = stat(Pid::from_raw(child.id() as i32));
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = std::fs::remove_dir_all(&dir);
+        let stat = stat.unwrap();
+        assert_eq!(stat.threads, 1);
+        assert!(stat.rss_pages > 0);
+    }
+}
@@ -0,0 +1,350 @@
+use std::ffi::{CStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const HOST_MACHINE: u16 = 62; // EM_X86_64
+#[cfg(target_arch = "aarch64")]
+const HOST_MACHINE: u16 = 183; // EM_AARCH64
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const HOST_MACHINE: u16 = 0;
+
+/// Stack handed to the loaded image, matching the usual 8 MiB RLIMIT_STACK
+const STACK_SIZE: usize = 8 << 20;
+
+/// Auxiliary vector entries copied from the stub's own auxv
+const INHERITED_AUXV: [libc::c_ulong; 10] = [
+    libc::AT_UID,
+    libc::AT_EUID,
+    libc::AT_GID,
+    libc::AT_EGID,
+    libc::AT_SECURE,
+    libc::AT_HWCAP,
+    libc::AT_HWCAP2,
+    libc::AT_CLKTCK,
+    libc::AT_SYSINFO_EHDR,
+    libc::AT_MINSIGSTKSZ,
+];
+
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+/// An ELF image mapped into the current process
+struct MappedImage {
+    bias: usize,
+    entry: usize,
+    phdr: usize,
+    phnum: usize,
+    interp: Option<String>,
+}
+
+/// A program that is fully mapped and only needs the jump into it
+pub struct PreparedImage {
+    /// Load bias of the main image (0 for ET_EXEC)
+    pub bias: usize,
+    stack: usize,
+    entry: usize,
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Truncated ELF image".to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Truncated ELF image".to_string())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Truncated ELF image".to_string())
+}
+
+fn program_headers(image: &[u8]) -> Result<(u16, u64, u64, Vec<ProgramHeader>), String> {
+    if image.len() < 64 || image[..4] != *b"\x7fELF" {
+        return Err("Not an ELF image".to_string());
+    }
+    if image[4] != 2 || image[5] != 1 {
+        return Err("In-process loading requires a 64-bit little-endian ELF".to_string());
+    }
+    if read_u16(image, 18)? != HOST_MACHINE {
+        return Err("In-process loading is not supported for this architecture".to_string());
+    }
+
+    let kind = read_u16(image, 16)?;
+    let entry = read_u64(image, 24)?;
+    let phoff = read_u64(image, 32)?;
+    let phentsize = read_u16(image, 54)? as u64;
+    let phnum = read_u16(image, 56)? as u64;
+
+    let mut headers = Vec::with_capacity(phnum as usize);
+    for i in 0..phnum {
+        let at = (phoff + i * phentsize) as usize;
+        headers.push(ProgramHeader {
+            kind: read_u32(image, at)?,
+            flags: read_u32(image, at + 4)?,
+            offset: read_u64(image, at + 8)?,
+            vaddr: read_u64(image, at + 16)?,
+            filesz: read_u64(image, at + 32)?,
+            memsz: read_u64(image, at + 40)?,
+        });
+    }
+    Ok((kind, entry, phoff, headers))
+}
+
+fn segment_protection(flags: u32) -> i32 {
+    let mut prot = libc::PROT_NONE;
+    if flags & PF_R != 0 {
+        prot |= libc::PROT_READ;
+    }
+    if flags & PF_W != 0 {
+        prot |= libc::PROT_WRITE;
+    }
+    if flags & PF_X != 0 {
+        prot |= libc::PROT_EXEC;
+    }
+    prot
+}
+
+/// Map every PT_LOAD segment of `image` into anonymous memory
+unsafe fn map_image(image: &[u8]) -> Result<MappedImage, String> {
+    let (kind, entry, phoff, headers) = program_headers(image)?;
+    if kind != ET_EXEC && kind != ET_DYN {
+        return Err("ELF image is not an executable".to_string());
+    }
+
+    let page = page_size();
+    let loads: Vec<&ProgramHeader> = headers.iter().filter(|h| h.kind == PT_LOAD).collect();
+    let low = loads.iter().map(|h| h.vaddr as usize).min().ok_or("ELF image has no loadable segments")? & !(page - 1);
+    let high = loads.iter().map(|h| (h.vaddr + h.memsz) as usize).max().unwrap_or(low);
+    let span = (high - low + page - 1) & !(page - 1);
+
+    // Reserve the whole image first so segments keep their relative layout
+    let (hint, fixed) = if kind == ET_EXEC {
+        (low as *mut libc::c_void, libc::MAP_FIXED_NOREPLACE)
+    } else {
+        (ptr::null_mut(), 0)
+    };
+    let base = libc::mmap(
+        hint,
+        span,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | fixed,
+        -1,
+        0,
+    );
+    if base == libc::MAP_FAILED || (kind == ET_EXEC && base as usize != low) {
+        return Err(format!("Failed to reserve {:#x} bytes at {:#x}: {}", span, low, std::io::Error::last_os_error()));
+    }
+    let bias = base as usize - low;
+
+    for header in &loads {
+        let start = header.offset as usize;
+        let end = start + header.filesz as usize;
+        if end > image.len() || header.filesz > header.memsz {
+            return Err("ELF segment lies outside the image".to_string());
+        }
+        ptr::copy_nonoverlapping(image[start..end].as_ptr(), (header.vaddr as usize + bias) as *mut u8, end - start);
+    }
+
+    // Gaps between segments stay inaccessible, like a kernel-loaded image
+    libc::mprotect(base, span, libc::PROT_NONE);
+    for header in &loads {
+        let seg_start = (header.vaddr as usize + bias) & !(page - 1);
+        let seg_end = (header.vaddr as usize + bias + header.memsz as usize + page - 1) & !(page - 1);
+        if libc::mprotect(seg_start as *mut libc::c_void, seg_end - seg_start, segment_protection(header.flags)) != 0 {
+            return Err(format!("mprotect failed: {}", std::io::Error::last_os_error()));
+        }
+    }
+
+    let phdr = match headers.iter().find(|h| h.kind == PT_PHDR) {
+        Some(header) => header.vaddr as usize + bias,
+        None => loads
+            .iter()
+            .find(|h| h.offset <= phoff && phoff < h.offset + h.filesz)
+            .map(|h| (h.vaddr + phoff - h.offset) as usize + bias)
+            .ok_or("Program headers are not mapped")?,
+    };
+
+    let interp = headers.iter().find(|h| h.kind == PT_INTERP).map(|header| {
+        let start = header.offset as usize;
+        let bytes = image.get(start..start + header.filesz as usize).unwrap_or_default();
+        String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string()
+    });
+
+    Ok(MappedImage { bias, entry: entry as usize + bias, phdr, phnum: headers.len(), interp })
+}
+
+/// Build the initial stack the kernel would have built for an exec:
+/// `argc, argv..., NULL, envp..., NULL, auxv..., AT_NULL` followed by the strings
+unsafe fn build_stack(
+    image: &MappedImage,
+    interp_base: usize,
+    argv: &[OsString],
+    env: &[(OsString, OsString)],
+) -> Result<usize, String> {
+    let stack = libc::mmap(
+        ptr::null_mut(),
+        STACK_SIZE,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK | libc::MAP_NORESERVE,
+        -1,
+        0,
+    );
+    if stack == libc::MAP_FAILED {
+        return Err(format!("Failed to allocate stack: {}", std::io::Error::last_os_error()));
+    }
+
+    let mut cursor = stack as usize + STACK_SIZE;
+    let mut push = |bytes: &[u8]| -> usize {
+        cursor -= bytes.len() + 1;
+        ptr::copy_nonoverlapping(bytes.as_ptr(), cursor as *mut u8, bytes.len());
+        *((cursor + bytes.len()) as *mut u8) = 0;
+        cursor
+    };
+
+    let execfn = push(argv.first().map(|arg| arg.as_bytes()).unwrap_or_default());
+    let argv_ptrs: Vec<usize> = argv.iter().map(|arg| push(arg.as_bytes())).collect();
+    let env_ptrs: Vec<usize> = env
+        .iter()
+        .map(|(key, value)| {
+            let mut entry = key.as_bytes().to_vec();
+            entry.push(b'=');
+            entry.extend_from_slice(value.as_bytes());
+            push(&entry)
+        })
+        .collect();
+    let platform = libc::getauxval(libc::AT_PLATFORM) as *const libc::c_char;
+    let platform = if platform.is_null() { 0 } else { push(CStr::from_ptr(platform).to_bytes()) };
+    let mut random = [0u8; 16];
+    libc::getrandom(random.as_mut_ptr() as *mut libc::c_void, random.len(), 0);
+    let random = push(&random);
+
+    let mut auxv: Vec<(libc::c_ulong, usize)> = vec![
+        (libc::AT_PHDR, image.phdr),
+        (libc::AT_PHENT, 56),
+        (libc::AT_PHNUM, image.phnum),
+        (libc::AT_PAGESZ, page_size()),
+        (libc::AT_BASE, interp_base),
+        (libc::AT_FLAGS, 0),
+        (libc::AT_ENTRY, image.entry),
+        (libc::AT_RANDOM, random),
+        (libc::AT_EXECFN, execfn),
+    ];
+    if platform != 0 {
+        auxv.push((libc::AT_PLATFORM, platform));
+    }
+    for kind in INHERITED_AUXV {
+        let value = libc::getauxval(kind);
+        if value != 0 || kind == libc::AT_SECURE {
+            auxv.push((kind, value as usize));
+        }
+    }
+    auxv.push((libc::AT_NULL, 0));
+
+    let mut words = vec![argv_ptrs.len()];
+    words.extend(&argv_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    for (kind, value) in auxv {
+        words.push(kind as usize);
+        words.push(value);
+    }
+
+    let sp = (cursor - words.len() * 8) & !15;
+    ptr::copy_nonoverlapping(words.as_ptr(), sp as *mut usize, words.len());
+    Ok(sp)
+}
+
+/// Map `image` (and its interpreter, if dynamic) and build its initial stack.
+///
+/// Nothing is executed yet, so the caller can still wipe plaintext copies
+/// and set up page protections before calling [`jump`].
+pub fn prepare(image: &[u8], argv: &[OsString], env: &[(OsString, OsString)]) -> Result<PreparedImage, String> {
+    unsafe {
+        let main = map_image(image)?;
+        let (entry, interp_base) = match &main.interp {
+            Some(path) => {
+                let interp_data = std::fs::read(path).map_err(|e| format!("Failed to read interpreter {}: {}", path, e))?;
+                let interp = map_image(&interp_data)?;
+                (interp.entry, interp.bias)
+            }
+            None => (main.entry, 0),
+        };
+        let stack = build_stack(&main, interp_base, argv, env)?;
+        Ok(PreparedImage { bias: main.bias, stack, entry })
+    }
+}
+
+/// Give the calling thread the signal state a fresh exec would have:
+/// caught signals go back to their default action and the alternate stack is dropped.
+/// SIGPIPE, which only the stub's runtime ignores, is restored too.
+pub fn reset_signals() {
+    unsafe {
+        for signal in 1..=libc::SIGRTMAX() {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, ptr::null(), &mut action) == 0
+                && action.sa_sigaction != libc::SIG_DFL
+                && action.sa_sigaction != libc::SIG_IGN
+            {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+        crate::signals::restore_sigpipe();
+        let disable = libc::stack_t { ss_sp: ptr::null_mut(), ss_flags: libc::SS_DISABLE, ss_size: 0 };
+        libc::sigaltstack(&disable, ptr::null_mut());
+    }
+}
+
+/// Switch to the prepared stack and jump to the entry point.
+///
+/// The calling thread becomes the loaded program; other threads keep running.
+pub unsafe fn jump(image: PreparedImage) -> ! {
+    #[cfg(target_arch = "x86_64")]
+    std::arch::asm!(
+        "mov rsp, {stack}",
+        "xor edx, edx",
+        "jmp {entry}",
+        stack = in(reg) image.stack,
+        entry = in(reg) image.entry,
+        options(noreturn)
+    );
+    #[cfg(target_arch = "aarch64")]
+    std::arch::asm!(
+        "mov sp, {stack}",
+        "mov x0, xzr",
+        "br {entry}",
+        stack = in(reg) image.stack,
+        entry = in(reg) image.entry,
+        options(noreturn)
+    );
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = image;
+        unreachable!("prepare rejects images for this architecture")
+    }
+}
@@ -0,0 +1,19 @@
+//! What differs between the stub's variants (see `build.rs`)
+
+include!(concat!(env!("OUT_DIR"), "/variant.rs"));
+
+/// A `u64` constant that doesn't appear in the code: the binary only holds it
+/// masked with this variant's key, and the key itself
+macro_rules! constant {
+    ($value:expr) => {{
+        const MASKED: u64 = $value ^ $crate::variant::CONSTANT_MASK;
+        $crate::variant::unmask(MASKED)
+    }};
+}
+pub(crate) use constant;
+
+/// The compiler can't see through `black_box`, so it can't fold the mask away
+#[inline(always)]
+pub fn unmask(masked: u64) -> u64 {
+    std::hint::black_box(masked) ^ CONSTANT_MASK
+}
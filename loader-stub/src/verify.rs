@@ -0,0 +1,209 @@
+//! Holding the base back until the overload's license check passed.
+//!
+//! Sync mode waits for the overload to exit with 0. Hybrid mode waits for its
+//! first reported success over the health channel and then keeps it running
+//! under the monitor thread like async mode. Both can give up after the
+//! footer's `sync_timeout`.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::common::{
+    log_hybrid_mode_waiting, log_starting_unverified, log_sync_mode_waiting, log_verification_rejected,
+    log_verification_successful, log_verification_timed_out,
+};
+use crate::health::HealthMonitor;
+use crate::ConfigFooter;
+
+/// Footer `sync_mode`: wait for the overload to exit (0 is async)
+pub const SYNC_MODE_SYNC: u8 = 1;
+/// Footer `sync_mode`: wait for the overload's first success, then monitor it
+pub const SYNC_MODE_HYBRID: u8 = 2;
+
+/// Footer `sync_timeout_action`: start the base anyway (0 refuses to start it)
+pub const SYNC_TIMEOUT_START_BASE: u8 = 1;
+
+/// How often the overload is checked while the base waits
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+enum Verification {
+    /// The overload reported a success and keeps running (hybrid mode)
+    Reported,
+    /// The overload exited with 0
+    Exited,
+    /// The check failed, already logged
+    Failed,
+    /// No outcome within the timeout, and the base starts anyway
+    StartUnverified,
+    /// No outcome within the timeout, and the base must not start
+    TimedOut,
+}
+
+/// Hold the base back until the overload passed. `exited` polls the overload:
+/// None while it runs, Some(true) for exit code 0, Some(false) for a failure
+/// it logged. `kill` stops an overload that failed or must not keep running.
+pub fn hold_base(
+    footer: &ConfigFooter,
+    monitor: Option<&mut HealthMonitor>,
+    pid: impl std::fmt::Display,
+    exited: impl FnMut() -> Option<bool>,
+    kill: impl FnOnce(),
+) -> Result<(), String> {
+    let hybrid = footer.sync_mode == SYNC_MODE_HYBRID;
+    if hybrid {
+        log_hybrid_mode_waiting(pid);
+    } else {
+        log_sync_mode_waiting(pid);
+    }
+    match wait(footer, monitor.filter(|_| hybrid), exited) {
+        Verification::Reported | Verification::Exited => Ok(()),
+        Verification::StartUnverified => {
+            // A hung check has no business running next to the base
+            if !hybrid {
+                kill();
+            }
+            Ok(())
+        }
+        Verification::Failed | Verification::TimedOut => {
+            kill();
+            Err("Overload verification failed".to_string())
+        }
+    }
+}
+
+/// Wait for the overload's verdict. Without a monitor, hybrid mode can only
+/// wait for the exit.
+fn wait(
+    footer: &ConfigFooter,
+    mut monitor: Option<&mut HealthMonitor>,
+    mut exited: impl FnMut() -> Option<bool>,
+) -> Verification {
+    let deadline = (footer.sync_timeout > 0).then(|| Instant::now() + Duration::from_secs(footer.sync_timeout as u64));
+    loop {
+        match exited() {
+            Some(true) => {
+                log_verification_successful();
+                // Nothing reports for it anymore, so the grace period starts now
+                if let Some(monitor) = monitor {
+                    monitor.passed_on_exit();
+                }
+                return Verification::Exited;
+            }
+            Some(false) => return Verification::Failed,
+            None => {}
+        }
+
+        if let Some(monitor) = monitor.as_deref_mut() {
+            match monitor.verification(footer.network_failure_kill_count) {
+                Some(Ok(())) => {
+                    log_verification_successful();
+                    return Verification::Reported;
+                }
+                Some(Err(reason)) => {
+                    log_verification_rejected(&reason);
+                    return Verification::Failed;
+                }
+                None => {}
+            }
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            log_verification_timed_out(footer.sync_timeout);
+            if footer.sync_timeout_action != SYNC_TIMEOUT_START_BASE {
+                return Verification::TimedOut;
+            }
+            log_starting_unverified();
+            if let Some(monitor) = monitor {
+                monitor.start_grace_period();
+            }
+            return Verification::StartUnverified;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::Arc;
+
+    use killcode_overload::protocol::{self, Report};
+
+    use super::*;
+    use crate::common;
+    use crate::health::{HealthChannel, HEALTH_TRANSPORT_STREAM};
+
+    fn footer(sync_mode: u8, sync_timeout: u32, sync_timeout_action: u8) -> ConfigFooter {
+        ConfigFooter {
+            sync_mode,
+            sync_timeout,
+            sync_timeout_action,
+            network_failure_kill_count: 3,
+            health_transport: HEALTH_TRANSPORT_STREAM,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+
+    /// Hold the base with an overload that `exited` polls, and whether it was killed
+    fn hold(footer: &ConfigFooter, monitor: Option<&mut HealthMonitor>, exited: Option<bool>) -> (bool, bool) {
+        let mut killed = false;
+        let held = hold_base(footer, monitor, 1, || exited, || killed = true);
+        (held.is_ok(), killed)
+    }
+
+    #[test]
+    fn test_sync_verification() {
+        let sync = footer(SYNC_MODE_SYNC, 0, 0);
+        assert_eq!(hold(&sync, None, Some(true)), (true, false));
+        assert_eq!(hold(&sync, None, Some(false)), (false, true));
+
+        // Nothing within the timeout: refuse the base, or start it without
+        // leaving the hung check running
+        let started = Instant::now();
+        assert_eq!(hold(&footer(SYNC_MODE_SYNC, 1, 0), None, None), (false, true));
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(hold(&footer(SYNC_MODE_SYNC, 1, SYNC_TIMEOUT_START_BASE), None, None), (true, true));
+    }
+
+    /// A channel and the monitor of a hybrid-mode stub waiting on it
+    fn hybrid_monitor(footer: &ConfigFooter) -> (Arc<HealthChannel>, HealthMonitor) {
+        let channel = Arc::new(HealthChannel::create(footer).unwrap());
+        let mut monitor = channel.monitor();
+        monitor.expect_verification();
+        (channel, monitor)
+    }
+
+    #[test]
+    fn test_hybrid_verification() {
+        let hybrid = footer(SYNC_MODE_HYBRID, 1, 0);
+
+        // The first success starts the base and the monitor goes on from it
+        let (channel, mut monitor) = hybrid_monitor(&hybrid);
+        let success = protocol::clock() - 5;
+        channel.send_report(&Report { counter: 1, consecutive_failures: 2, ..Default::default() });
+        assert_eq!(monitor.verification(3), None);
+        channel.send_report(&Report { counter: 2, last_success: success, ..Default::default() });
+        assert_eq!(hold(&hybrid, Some(&mut monitor), None), (true, false));
+        assert_eq!(monitor.last_success(), success);
+
+        // Too many failures or a kill request before it stop the overload
+        let (channel, mut monitor) = hybrid_monitor(&hybrid);
+        channel.send_report(&Report { counter: 1, consecutive_failures: 3, ..Default::default() });
+        assert_eq!(hold(&hybrid, Some(&mut monitor), None), (false, true));
+        let (channel, mut monitor) = hybrid_monitor(&hybrid);
+        channel.send_report(&Report { counter: 1, should_kill_base: true, ..Default::default() });
+        assert_eq!(hold(&hybrid, Some(&mut monitor), None), (false, true));
+
+        // An overload that exits with 0 before it reports passed as well
+        let (_channel, mut monitor) = hybrid_monitor(&hybrid);
+        assert_eq!(hold(&hybrid, Some(&mut monitor), Some(true)), (true, false));
+        assert!(monitor.last_success() >= common::current_time() - 1);
+
+        // An unverified start keeps the overload running, and the grace
+        // period runs from the timeout
+        let hybrid = footer(SYNC_MODE_HYBRID, 1, SYNC_TIMEOUT_START_BASE);
+        let (_channel, mut monitor) = hybrid_monitor(&hybrid);
+        let started = common::current_time();
+        assert_eq!(hold(&hybrid, Some(&mut monitor), None), (true, false));
+        assert!(monitor.last_success() > started);
+    }
+}
@@ -1,212 +1,502 @@
 use std::ffi::CString;
-use std::fs;
 use std::mem;
-use std::path::PathBuf;
 use std::ptr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use windows_sys::Win32::Foundation::{CloseHandle, GetLastError, HANDLE, INVALID_HANDLE_VALUE};
-use windows_sys::Win32::System::Environment::SetEnvironmentVariableA;
-use windows_sys::Win32::System::Memory::{
-    CreateFileMappingA, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS, MEMORY_MAPPED_VIEW_ADDRESS,
-    PAGE_READWRITE,
+use windows_sys::Win32::Foundation::{
+    CloseHandle, DuplicateHandle, GetLastError, DUPLICATE_SAME_ACCESS, GENERIC_READ, GENERIC_WRITE, HANDLE,
+    INVALID_HANDLE_VALUE, WAIT_OBJECT_0,
+};
+use windows_sys::Win32::Security::SECURITY_ATTRIBUTES;
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileA, FILE_APPEND_DATA, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_ALWAYS,
+    OPEN_EXISTING,
+};
+use windows_sys::Win32::System::Console::{
+    GetStdHandle, SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_C_EVENT, CTRL_LOGOFF_EVENT,
+    CTRL_SHUTDOWN_EVENT, STD_ERROR_HANDLE, STD_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE,
+};
+use windows_sys::Win32::System::LibraryLoader::{GetModuleHandleA, GetProcAddress};
+use windows_sys::Win32::System::ProcessStatus::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+use windows_sys::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectA, JobObjectExtendedLimitInformation, SetInformationJobObject,
+    TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
 };
 use windows_sys::Win32::System::Threading::{
-    CreateProcessA, GetCurrentProcessId, GetExitCodeProcess, TerminateProcess, WaitForSingleObject,
-    INFINITE, PROCESS_INFORMATION, STARTUPINFOA,
+    CreateProcessA, ExitProcess, GetCurrentProcess, GetExitCodeProcess, ResumeThread,
+    TerminateProcess, WaitForSingleObject, CREATE_SUSPENDED, INFINITE, PROCESS_INFORMATION,
+    STARTF_USESTDHANDLES, STARTUPINFOA,
 };
 
 use crate::common::{
-    evaluate_health_status, health_check_interval, init_health_status, log_async_mode_started,
+    self, health_check_interval, log_async_mode_started,
     log_base_completed_terminating_overload, log_base_exited, log_base_start_failed,
-    log_fallback_kill, log_grace_period_exceeded, log_health_monitor_started,
-    log_health_monitoring_enabled, log_heartbeat_lost, log_network_failure_threshold,
-    log_overload_requested_kill, log_overload_start_failed, log_shm_create_failed,
-    log_shm_map_failed, log_starting_base, log_sync_mode_waiting, log_verification_failed,
-    log_verification_successful, overload_kill_wait_duration, should_enable_health_monitoring,
-    signal_overload_to_kill, HealthCheckResult,
+    log_health_monitor_started, log_health_monitoring_enabled, log_overload_exited,
+    log_overload_restart_failed, log_overload_restart_scheduled,
+    log_overload_restarted, log_overload_start_failed, log_health_channel_failed,
+    log_clock_tampered, log_reverification_started,
+    log_starting_base, log_verification_failed, should_enable_health_monitoring,
+    HealthCheckResult, log_health_channel_required, log_integrity_violation, OverloadLoss,
+    log_overload_shut_down, log_overload_shutdown_window,
 };
-use crate::{ConfigFooter, HealthStatus};
+use killcode_overload::protocol;
 
-pub fn run(
-    base_data: Vec<u8>,
-    overload_data: Vec<u8>,
-    footer: ConfigFooter,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let sync_mode = footer.sync_mode != 0;
-    let grace_period = footer.grace_period;
-    let network_failure_kill_count = footer.network_failure_kill_count;
+use crate::clock::ClockWatch;
+use crate::enforce::{Base, Check, Enforcer};
+use crate::extract::{Extraction, Payload};
+use crate::restart::RestartPolicy;
+use crate::reverify::{Reverification, Run};
+use crate::strings::{self, S};
+use crate::grace_record::GraceRecord;
+use crate::health::{HealthChannel, HEALTH_POLICY_FAIL_CLOSED};
+use crate::integrity::{IntegrityAction, IntegrityGuard};
+use crate::verify::{self, SYNC_MODE_HYBRID, SYNC_MODE_SYNC};
+use crate::common::{footer_path, ROUTE_FILE, ROUTE_NULL, ROUTE_SYSLOG};
+use crate::ConfigFooter;
 
-    // 1. Setup Shared Memory (if async and monitoring needed)
-    let mut health_ptr: *mut HealthStatus = ptr::null_mut();
-    let mut health_shm_handle: HANDLE = ptr::null_mut();
-    let mut health_view: MEMORY_MAPPED_VIEW_ADDRESS = unsafe { mem::zeroed() };
+/// Exit code GetExitCodeProcess reports while a process is still running
+pub const STILL_ACTIVE: u32 = 259;
 
-    if should_enable_health_monitoring(sync_mode, grace_period, network_failure_kill_count) {
-        unsafe {
-            let pid = GetCurrentProcessId();
-            let shm_name = format!("Local\\OverloadHealth_{}", pid);
-            let shm_name_c = CString::new(shm_name.clone()).unwrap();
-
-            health_shm_handle = CreateFileMappingA(
-                INVALID_HANDLE_VALUE,
-                ptr::null(),
-                PAGE_READWRITE,
+/// How long the stub waits for the base after a close, logoff or shutdown event.
+/// Windows terminates the stub about 5 seconds after delivering the event.
+const CONSOLE_CLOSE_WAIT_MS: u32 = 4500;
+
+/// Inheritable handles for the overload's stdin, stdout and stderr
+struct OverloadStdio([HANDLE; 3]);
+
+impl OverloadStdio {
+    /// None if every stream is inherited, so the overload starts exactly as before
+    unsafe fn open(footer: &ConfigFooter) -> Option<Self> {
+        let routes = [footer.overload_stdin, footer.overload_stdout, footer.overload_stderr];
+        if routes.iter().all(|&route| !matches!(route, ROUTE_NULL | ROUTE_FILE | ROUTE_SYSLOG)) {
+            return None;
+        }
+        let std_handles = [STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, STD_ERROR_HANDLE];
+        Some(Self(std::array::from_fn(|stream| {
+            overload_stream(routes[stream], std_handles[stream], footer)
+        })))
+    }
+
+    unsafe fn close(self) {
+        for handle in self.0 {
+            if !handle.is_null() && handle != INVALID_HANDLE_VALUE {
+                CloseHandle(handle);
+            }
+        }
+    }
+}
+
+/// Inheritable handle for one of the overload's streams.
+///
+/// Syslog doesn't exist on Windows (weaver rejects it), so it discards like
+/// `null`. A log file that can't be opened discards too, keeping the
+/// overload's output out of the base's streams.
+unsafe fn overload_stream(route: u8, std_handle: STD_HANDLE, footer: &ConfigFooter) -> HANDLE {
+    let inheritable = SECURITY_ATTRIBUTES {
+        nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: ptr::null_mut(),
+        bInheritHandle: 1,
+    };
+    let open = |path: &CString, access: u32, disposition| {
+        CreateFileA(
+            path.as_ptr() as *const u8,
+            access,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            &inheritable,
+            disposition,
+            FILE_ATTRIBUTE_NORMAL,
+            ptr::null_mut(),
+        )
+    };
+    let null = || open(&CString::new("NUL").unwrap(), GENERIC_READ | GENERIC_WRITE, OPEN_EXISTING);
+
+    match route {
+        ROUTE_FILE => {
+            let path = footer_path(&footer.overload_log_path).unwrap_or_default();
+            let handle = match CString::new(path) {
+                Ok(path_c) => open(&path_c, FILE_APPEND_DATA, OPEN_ALWAYS),
+                Err(_) => INVALID_HANDLE_VALUE,
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                common::log_overload_log_failed(path, GetLastError());
+                return null();
+            }
+            handle
+        }
+        ROUTE_NULL | ROUTE_SYSLOG => null(),
+        _ => {
+            let mut duplicate: HANDLE = ptr::null_mut();
+            DuplicateHandle(
+                GetCurrentProcess(),
+                GetStdHandle(std_handle),
+                GetCurrentProcess(),
+                &mut duplicate,
                 0,
-                mem::size_of::<HealthStatus>() as u32,
-                shm_name_c.as_ptr() as *const u8,
+                1,
+                DUPLICATE_SAME_ACCESS,
             );
+            duplicate
+        }
+    }
+}
 
-            if health_shm_handle != ptr::null_mut() {
-                health_view = MapViewOfFile(
-                    health_shm_handle,
-                    FILE_MAP_ALL_ACCESS,
-                    0,
-                    0,
-                    mem::size_of::<HealthStatus>(),
-                );
-
-                if !health_view.Value.is_null() {
-                    health_ptr = health_view.Value as *mut HealthStatus;
-                    init_health_status(health_ptr);
-
-                    // Set env var for overload
-                    let env_name = CString::new("KILLCODE_HEALTH_SHM").unwrap();
-                    SetEnvironmentVariableA(
-                        env_name.as_ptr() as *const u8,
-                        shm_name_c.as_ptr() as *const u8,
-                    );
-                    log_health_monitoring_enabled(&shm_name);
-                } else {
-                    log_shm_map_failed("MapViewOfFile returned null");
-                }
-            } else {
-                log_shm_create_failed(GetLastError());
+/// Job object for a payload and everything it starts.
+///
+/// The job is killed when its last handle closes, so if the stub dies for
+/// any reason (including TerminateProcess) the payloads die with it.
+/// Null if the stub may not create or assign jobs.
+unsafe fn kill_on_close_job() -> HANDLE {
+    let job = CreateJobObjectA(ptr::null(), ptr::null());
+    if job.is_null() {
+        return job;
+    }
+    set_job_limits(job, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE);
+    job
+}
+
+unsafe fn set_job_limits(job: HANDLE, flags: u32) {
+    let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+    limits.BasicLimitInformation.LimitFlags = flags;
+    SetInformationJobObject(
+        job,
+        JobObjectExtendedLimitInformation,
+        &limits as *const _ as *const _,
+        mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+    );
+}
+
+/// Terminate a payload and every process it started
+unsafe fn terminate_tree(job: HANDLE, process: HANDLE, exit_code: u32) {
+    if !job.is_null() {
+        TerminateJobObject(job, exit_code);
+    }
+    TerminateProcess(process, exit_code);
+}
+
+/// The base as the monitor thread controls it. Windows has no signal to ask a
+/// process to exit, so terminating only gives it the timeout to finish on its
+/// own, and notifying only sets the status file.
+struct WindowsBase {
+    process: HANDLE,
+    job: HANDLE,
+}
+
+impl Base for WindowsBase {
+    fn kill(&mut self) {
+        unsafe { terminate_tree(self.job, self.process, 1) };
+    }
+
+    fn terminate(&mut self, timeout: Duration) {
+        unsafe {
+            if WaitForSingleObject(self.process, timeout.as_millis() as u32) != WAIT_OBJECT_0 {
+                terminate_tree(self.job, self.process, 1);
             }
         }
     }
 
-    // 2. Prepare binaries
-    let temp_dir = std::env::temp_dir();
-    let base_path = temp_dir.join("base.exe");
-    let overload_path = temp_dir.join("overload.exe");
+    fn suspend(&mut self, suspended: bool) {
+        let name = if suspended { S::NtSuspendProcess } else { S::NtResumeProcess };
+        unsafe {
+            let ntdll = CString::new(strings::get(S::NtDll)).unwrap();
+            let function = CString::new(strings::get(name)).unwrap();
+            let module = GetModuleHandleA(ntdll.as_ptr() as *const u8);
+            if module.is_null() {
+                return;
+            }
+            if let Some(function) = GetProcAddress(module, function.as_ptr() as *const u8) {
+                let function: unsafe extern "system" fn(HANDLE) -> i32 = mem::transmute(function);
+                function(self.process);
+            }
+        }
+    }
+
+    fn notify(&mut self, _signal: u8) {}
+}
+
+/// Execute an extracted binary: started suspended so it is in its job before
+/// it can start anything
+unsafe fn execute_binary(
+    payload: &Payload,
+    is_base: bool,
+    footer: &ConfigFooter,
+    health: Option<&HealthChannel>,
+) -> Result<(HANDLE, u32, HANDLE), String> {
+    payload.verify()?;
+    let path_str = payload.path.to_str().ok_or("Invalid path")?;
+    let path_c = CString::new(path_str).map_err(|_| "Invalid path CString")?;
+    
+    let mut si: STARTUPINFOA = mem::zeroed();
+    si.cb = mem::size_of::<STARTUPINFOA>() as u32;
+    let mut pi: PROCESS_INFORMATION = mem::zeroed();
+
+    let stdio = if is_base { None } else { OverloadStdio::open(footer) };
+    if let Some(OverloadStdio([stdin, stdout, stderr])) = &stdio {
+        si.dwFlags |= STARTF_USESTDHANDLES;
+        si.hStdInput = *stdin;
+        si.hStdOutput = *stdout;
+        si.hStdError = *stderr;
+    }
+
+    // Only the overload inherits the health channel, and only while
+    // it is being created
+    let health = health.filter(|_| !is_base);
+    if let Some(health) = health {
+        health.share(true);
+    }
+
+    // CreateProcessA requires a mutable command line string if the first arg is NULL,
+    // OR if the first arg is provided, it uses that as the executable.
+    // We'll pass the path as the first argument (lpApplicationName) and NULL for command line.
+    let success = CreateProcessA(
+        path_c.as_ptr() as *const u8,
+        ptr::null_mut(),
+        ptr::null(),
+        ptr::null(),
+        (stdio.is_some() || health.is_some()) as i32,
+        CREATE_SUSPENDED,
+        ptr::null(),
+        ptr::null(),
+        &si,
+        &mut pi,
+    );
+    if let Some(stdio) = stdio {
+        stdio.close();
+    }
+    if let Some(health) = health {
+        health.share(false);
+    }
+
+    if success == 0 {
+        return Err(format!("CreateProcessA failed: {}", GetLastError()));
+    }
+
+    let mut job = kill_on_close_job();
+    if !job.is_null() && AssignProcessToJobObject(job, pi.hProcess) == 0 {
+        CloseHandle(job);
+        job = ptr::null_mut();
+    }
+    ResumeThread(pi.hThread);
+    CloseHandle(pi.hThread);
+    Ok((pi.hProcess, pi.dwProcessId, job))
+}
+
+/// A re-verification run and the payload it runs from
+struct OverloadRun {
+    process: HANDLE,
+    job: HANDLE,
+    _payload: Payload,
+}
 
-    // Write binaries
-    fs::write(&base_path, &base_data)?;
-    fs::write(&overload_path, &overload_data)?;
+unsafe impl Send for OverloadRun {}
 
-    // Helper to execute binary
-    let execute_binary = |path: &PathBuf, is_base: bool| -> Result<(HANDLE, u32), String> {
+impl Run for OverloadRun {
+    fn verdict(&mut self) -> Option<bool> {
         unsafe {
-            let path_str = path.to_str().ok_or("Invalid path")?;
-            let path_c = CString::new(path_str).map_err(|_| "Invalid path CString")?;
-            
-            let mut si: STARTUPINFOA = mem::zeroed();
-            si.cb = mem::size_of::<STARTUPINFOA>() as u32;
-            let mut pi: PROCESS_INFORMATION = mem::zeroed();
-
-            // CreateProcessA requires a mutable command line string if the first arg is NULL,
-            // OR if the first arg is provided, it uses that as the executable.
-            // We'll pass the path as the first argument (lpApplicationName) and NULL for command line.
-            let success = CreateProcessA(
-                path_c.as_ptr() as *const u8,
-                ptr::null_mut(),
-                ptr::null(),
-                ptr::null(),
-                0,
-                0,
-                ptr::null(),
-                ptr::null(),
-                &si,
-                &mut pi,
-            );
+            if WaitForSingleObject(self.process, 0) != WAIT_OBJECT_0 {
+                return None;
+            }
+            let mut exit_code: u32 = 0;
+            GetExitCodeProcess(self.process, &mut exit_code);
+            if exit_code != 0 {
+                log_verification_failed(exit_code);
+            }
+            Some(exit_code == 0)
+        }
+    }
 
-            if success == 0 {
-                return Err(format!("CreateProcessA failed: {}", GetLastError()));
+    fn kill(&mut self) {
+        unsafe { terminate_tree(self.job, self.process, 1) }
+    }
+}
+
+impl Drop for OverloadRun {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.process);
+            if !self.job.is_null() {
+                CloseHandle(self.job);
             }
+        }
+    }
+}
+
+/// Process handles used by the console control handler
+static BASE_PROCESS: AtomicUsize = AtomicUsize::new(0);
+static OVERLOAD_PROCESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Console control events reach every process attached to the console, so the
+/// base already gets them. The stub only has to stay alive long enough to
+/// report the base's exit code as its own.
+unsafe extern "system" fn on_console_event(event: u32) -> i32 {
+    let base = BASE_PROCESS.load(Ordering::Relaxed) as HANDLE;
+    if base.is_null() {
+        return 0;
+    }
 
-            CloseHandle(pi.hThread);
-            Ok((pi.hProcess, pi.dwProcessId))
+    match event {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT => 1,
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+            WaitForSingleObject(base, CONSOLE_CLOSE_WAIT_MS);
+            let mut exit_code: u32 = 1;
+            if GetExitCodeProcess(base, &mut exit_code) == 0 || exit_code == STILL_ACTIVE {
+                TerminateProcess(base, 1);
+                exit_code = 1;
+            }
+            let overload = OVERLOAD_PROCESS.load(Ordering::Relaxed) as HANDLE;
+            if !overload.is_null() {
+                TerminateProcess(overload, 0);
+            }
+            ExitProcess(exit_code)
         }
-    };
+        _ => 0,
+    }
+}
 
-    // 3. Start Overload
-    let mut overload_handle: HANDLE = ptr::null_mut();
-    let mut overload_pid: u32 = 0;
+pub fn run(
+    base_data: Vec<u8>,
+    overload_data: Vec<u8>,
+    footer: ConfigFooter,
+    integrity: IntegrityGuard,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let grace_period = footer.grace_period;
+    let network_failure_kill_count = footer.network_failure_kill_count;
+    let mut heartbeat_timeout = footer.heartbeat_timeout;
 
-    match execute_binary(&overload_path, false) {
-        Ok((h, pid)) => {
-            overload_handle = h;
-            overload_pid = pid;
+    // 1. Set up the health channel (if async and monitoring needed)
+    let mut health = None;
+    if should_enable_health_monitoring(&footer) || integrity.notifies_overload() {
+        match HealthChannel::create(&footer) {
+            Ok(channel) => {
+                if integrity.startup_violation {
+                    channel.signal_integrity_violation();
+                }
+                log_health_monitoring_enabled(channel.transport());
+                health = Some(Arc::new(channel));
+            }
+            Err(e) => {
+                log_health_channel_failed(e);
+                if footer.health_channel_policy == HEALTH_POLICY_FAIL_CLOSED {
+                    log_health_channel_required();
+                    return Err("No health channel".into());
+                }
+            }
+        }
+    }
 
-            if sync_mode {
-                log_sync_mode_waiting(overload_pid);
-                unsafe {
-                    WaitForSingleObject(overload_handle, INFINITE);
+    // 2. Prepare binaries in this launch's private directory. Each file stays
+    // open without write sharing and is deleted once its handle closes.
+    let extraction = Arc::new(Extraction::create(&footer)?);
+    let base_payload = extraction.extract("base", &base_data, |_| {})?;
+    let mut overload_payload = extraction.extract("overload", &overload_data, |_| {})?;
+
+    // In hybrid mode the monitor also sees the overload's first success
+    let mut health_monitor = health.as_ref().map(HealthChannel::monitor);
+    if footer.sync_mode == SYNC_MODE_HYBRID {
+        if let Some(health_monitor) = &mut health_monitor {
+            health_monitor.expect_verification();
+        }
+    }
+    // Before the overload starts: it takes the grace period's start from the channel
+    let mut grace_record = GraceRecord::resume(&footer, health_monitor.as_mut())?;
+
+    // 3. Start Overload
+    let overload = unsafe { execute_binary(&overload_payload, false, &footer, health.as_deref()) };
+    let (mut overload_handle, mut overload_job, mut overload_pid) = match overload {
+        Ok((overload_handle, overload_pid, overload_job)) => {
+            if footer.sync_mode == 0 {
+                log_async_mode_started(overload_pid);
+            } else {
+                let exited = || unsafe {
+                    if WaitForSingleObject(overload_handle, 0) != WAIT_OBJECT_0 {
+                        return None;
+                    }
                     let mut exit_code: u32 = 0;
                     GetExitCodeProcess(overload_handle, &mut exit_code);
-
                     if exit_code != 0 {
                         log_verification_failed(exit_code);
+                    }
+                    Some(exit_code == 0)
+                };
+                let kill = || unsafe { terminate_tree(overload_job, overload_handle, 1) };
+                if let Err(e) = verify::hold_base(&footer, health_monitor.as_mut(), overload_pid, exited, kill) {
+                    unsafe {
                         CloseHandle(overload_handle);
-                        let _ = fs::remove_file(&base_path);
-                        let _ = fs::remove_file(&overload_path);
-                        return Err("Overload verification failed".into());
+                        if !overload_job.is_null() {
+                            CloseHandle(overload_job);
+                        }
                     }
-                    log_verification_successful();
+                    return Err(e.into());
                 }
-            } else {
-                log_async_mode_started(overload_pid);
             }
+            (overload_handle, overload_job, overload_pid)
         }
         Err(e) => {
             log_overload_start_failed(&e);
-            let _ = fs::remove_file(&base_path);
-            let _ = fs::remove_file(&overload_path);
             return Err(e.into());
         }
-    }
+    };
 
     // 4. Start Base
+    let mut enforcer = Enforcer::from_footer(&footer);
     log_starting_base();
-    let (base_handle, base_pid) = match execute_binary(&base_path, true) {
-        Ok((h, pid)) => {
-            if !health_ptr.is_null() {
-                unsafe { (*health_ptr).base_pid = pid as i32; }
+    let (base_handle, base_job) = match unsafe { execute_binary(&base_payload, true, &footer, health.as_deref()) } {
+        Ok((h, pid, job)) => {
+            if let Some(health) = &health {
+                health.set_base_pid(pid as i32);
             }
-            (h, pid)
+            (h, job)
         },
         Err(e) => {
             log_base_start_failed(&e);
             if overload_handle != ptr::null_mut() {
                 unsafe {
-                    TerminateProcess(overload_handle, 0);
+                    terminate_tree(overload_job, overload_handle, 0);
                     CloseHandle(overload_handle);
                 }
             }
-            let _ = fs::remove_file(&base_path);
-            let _ = fs::remove_file(&overload_path);
             return Err(e.into());
         }
     };
 
+    let started = Instant::now();
+    OVERLOAD_PROCESS.store(overload_handle as usize, Ordering::Relaxed);
+    BASE_PROCESS.store(base_handle as usize, Ordering::Relaxed);
+    unsafe {
+        SetConsoleCtrlHandler(Some(on_console_event), 1);
+    }
+
     // 5. Start Health Monitor Thread
     let monitor_running = Arc::new(AtomicBool::new(true));
-    let monitor_handle = if !sync_mode
-        && !health_ptr.is_null()
-        && (grace_period > 0 || network_failure_kill_count > 0)
-    {
+    let monitor_health = footer.sync_mode != SYNC_MODE_SYNC
+        && health.is_some()
+        && (grace_period > 0 || network_failure_kill_count > 0 || heartbeat_timeout > 0);
+    let mut reverify = Reverification::<OverloadRun>::from_footer(&footer);
+    let monitor_handle = if monitor_health || reverify.is_some() || integrity.is_enabled() {
         let monitor_running_clone = monitor_running.clone();
-        let health_ptr_addr = health_ptr as usize;
+        let health = health.clone();
         let base_handle_val = base_handle as usize;
+        let base_job_val = base_job as usize;
+        let overload_handle_val = overload_handle as usize;
+        let overload_job_val = overload_job as usize;
+        let extraction = extraction.clone();
+        let mut restarts = RestartPolicy::from_footer(&footer);
+        let mut clock = ClockWatch::from_footer(&footer);
+        let mut restart_at = None;
 
         Some(thread::spawn(move || {
             log_health_monitor_started();
-            let health_ptr = health_ptr_addr as *mut HealthStatus;
             let base_handle = base_handle_val as HANDLE;
+            let base_job = base_job_val as HANDLE;
+            let mut base = WindowsBase { process: base_handle, job: base_job };
+            let mut overload_handle = overload_handle_val as HANDLE;
+            let mut overload_job = overload_job_val as HANDLE;
+            let mut overload_running = !overload_handle.is_null();
+            // The payload of a restarted overload, deleted once it is closed
+            let mut restarted = None;
+            let mut launches = 1;
 
             while monitor_running_clone.load(Ordering::Relaxed) {
                 thread::sleep(health_check_interval());
@@ -218,38 +508,144 @@ pub fn run(
                 unsafe {
                     // Check if base is still running
                     let mut exit_code: u32 = 0;
-                    if GetExitCodeProcess(base_handle, &mut exit_code) != 0 && exit_code != 259 {
-                        break; // Base finished (259 is STILL_ACTIVE)
+                    if GetExitCodeProcess(base_handle, &mut exit_code) != 0 && exit_code != STILL_ACTIVE {
+                        break; // Base finished
                     }
 
-                    match evaluate_health_status(health_ptr, grace_period, network_failure_kill_count) {
-                        HealthCheckResult::Ok => {}
-                        HealthCheckResult::GracePeriodExceeded { time_since_success, grace_period } => {
-                            log_grace_period_exceeded(time_since_success, grace_period);
-                            TerminateProcess(base_handle, 1);
-                            break;
+                    if let Err(e) = integrity.verify_runtime() {
+                        log_integrity_violation(&e);
+                        match integrity.action {
+                            IntegrityAction::NotifyOverload if health.is_some() => {
+                                if let Some(health) = &health {
+                                    health.signal_integrity_violation();
+                                }
+                            }
+                            IntegrityAction::Exit => {
+                                terminate_tree(base_job, base_handle, 1);
+                                if !overload_handle.is_null() {
+                                    terminate_tree(overload_job, overload_handle, 1);
+                                }
+                                std::process::exit(1);
+                            }
+                            _ => {
+                                terminate_tree(base_job, base_handle, 1);
+                                break;
+                            }
                         }
-                        HealthCheckResult::NetworkFailureThreshold { failures, threshold } => {
-                            log_network_failure_threshold(failures, threshold);
-                            signal_overload_to_kill(health_ptr);
-                            thread::sleep(overload_kill_wait_duration());
-                            log_fallback_kill();
-                            TerminateProcess(base_handle, 1);
+                    }
+
+                    if clock.tampered() {
+                        log_clock_tampered();
+                        terminate_tree(base_job, base_handle, 1);
+                        break;
+                    }
+
+                    if let Some(reverify) = &mut reverify {
+                        let start = || {
+                            launches += 1;
+                            let payload = extraction.extract(&format!("overload{}", launches), &overload_data, |_| {})?;
+                            let (process, pid, job) = execute_binary(&payload, false, &footer, None)?;
+                            log_reverification_started(pid);
+                            Ok(OverloadRun { process, job, _payload: payload })
+                        };
+                        let result = reverify.check(start, grace_period, network_failure_kill_count);
+                        if enforcer.handle(result, Check::Reverification, &mut base, || false) {
                             break;
                         }
-                        HealthCheckResult::OverloadRequestedKill => {
-                            log_overload_requested_kill();
-                            TerminateProcess(base_handle, 1);
-                            break;
+                        if let Some(record) = &mut grace_record {
+                            record.save(reverify.last_success());
                         }
-                        HealthCheckResult::HeartbeatLost => {
-                            log_heartbeat_lost();
-                            TerminateProcess(base_handle, 1);
-                            break;
+                    }
+
+                    let Some(health_monitor) = health_monitor.as_mut().filter(|_| monitor_health) else {
+                        continue;
+                    };
+
+                    // The base runs on the last accepted report in the meantime
+                    if restart_at.is_some_and(|at| Instant::now() >= at) {
+                        restart_at = None;
+                        // An earlier overload's image may still be there
+                        launches += 1;
+                        let name = format!("overload{}", launches);
+                        let started = health_monitor.overload_restarting().and_then(|()| {
+                            let payload = extraction.extract(&name, &overload_data, |_| {})?;
+                            let process = execute_binary(&payload, false, &footer, health.as_deref())?;
+                            Ok((process, payload))
+                        });
+                        match started {
+                            Ok(((handle, pid, job), payload)) => {
+                                log_overload_restarted(pid);
+                                OVERLOAD_PROCESS.store(handle as usize, Ordering::Relaxed);
+                                CloseHandle(overload_handle);
+                                if !overload_job.is_null() {
+                                    CloseHandle(overload_job);
+                                }
+                                overload_handle = handle;
+                                overload_job = job;
+                                overload_running = true;
+                                restarted = Some((pid, payload));
+                                restarts.started();
+                                heartbeat_timeout = footer.heartbeat_timeout;
+                            }
+                            Err(e) => {
+                                log_overload_restart_failed(&e);
+                                if enforcer.overload_gone(&mut base) {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
+                    // The handle stays valid after the overload exits, so this
+                    // only has to report it once
+                    let mut overload_lost = None;
+                    let mut overload_exit_code: u32 = 0;
+                    if overload_running
+                        && GetExitCodeProcess(overload_handle, &mut overload_exit_code) != 0
+                        && overload_exit_code != STILL_ACTIVE
+                    {
+                        overload_running = false;
+                        if overload_exit_code == 0 {
+                            log_overload_exited();
+                        } else {
+                            overload_lost = Some(OverloadLoss::Exited(overload_exit_code as i32));
+                        }
+                    }
+
+                    let result = match overload_lost {
+                        Some(loss) => HealthCheckResult::OverloadLost(loss),
+                        None => health_monitor.evaluate(grace_period, network_failure_kill_count, heartbeat_timeout),
+                    };
+                    if let Some(record) = &mut grace_record {
+                        record.save(health_monitor.last_success());
+                    }
+                    let restart = || {
+                        // Report a hang once; a restart or the grace period takes it from here
+                        heartbeat_timeout = 0;
+                        let Some(delay) = restarts.next_restart() else {
+                            return false;
+                        };
+                        // A hung overload is still running
+                        if overload_running {
+                            terminate_tree(overload_job, overload_handle, 1);
+                            overload_running = false;
                         }
+                        log_overload_restart_scheduled(delay, restarts.restarts(), footer.overload_max_restarts);
+                        restart_at = Some(Instant::now() + delay);
+                        true
+                    };
+                    if enforcer.handle(result, Check::Reports(health.as_deref()), &mut base, restart) {
+                        break;
                     }
                 }
             }
+
+            if let Some(reverify) = &mut reverify {
+                reverify.stop();
+            }
+
+            // Cleaned up by the main thread like the first overload
+            restarted.map(|(pid, payload)| (overload_handle as usize, overload_job as usize, pid, payload))
         }))
     } else {
         None
@@ -263,32 +659,61 @@ pub fn run(
         
         // Stop monitor
         monitor_running.store(false, Ordering::Relaxed);
-        if let Some(handle) = monitor_handle {
-            let _ = handle.join();
+        if let Some(Ok(Some((handle, job, pid, payload)))) = monitor_handle.map(|handle| handle.join()) {
+            // The monitor already closed the first overload's handles
+            overload_handle = handle as HANDLE;
+            overload_job = job as HANDLE;
+            overload_pid = pid;
+            overload_payload = payload;
         }
 
-        // Cleanup Base
+        if let Some(health) = &health {
+            let mut memory: PROCESS_MEMORY_COUNTERS = mem::zeroed();
+            memory.cb = mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+            let peak_rss = if K32GetProcessMemoryInfo(base_handle, &mut memory, memory.cb) != 0 {
+                memory.PeakWorkingSetSize as u64
+            } else {
+                0
+            };
+            health.report_base_exit(protocol::BaseExit {
+                code: base_exit_code as i32,
+                signal: 0,
+                runtime_ms: started.elapsed().as_millis() as u64,
+                peak_rss,
+                shutdown_window: footer.shutdown_window,
+            });
+        }
+
+        // Cleanup Base. Processes a base that exited on its own left behind
+        // are its business, so its job no longer kills them on close.
+        if !base_job.is_null() {
+            set_job_limits(base_job, 0);
+            CloseHandle(base_job);
+        }
         CloseHandle(base_handle);
-        // We can try to delete the file, but it might be locked for a moment.
-        // Windows is picky about deleting running executables.
-        // We'll try, but ignore errors.
-        let _ = fs::remove_file(&base_path);
+        drop(base_payload);
 
-        // Cleanup Overload
+        // Cleanup Overload, once it had the shutdown window to exit on its own
         if overload_handle != ptr::null_mut() {
-            log_base_completed_terminating_overload(overload_pid);
-            TerminateProcess(overload_handle, 0);
+            let window = footer.shutdown_window;
+            if window > 0 {
+                log_overload_shutdown_window(window);
+            }
+            if window > 0 && WaitForSingleObject(overload_handle, window.saturating_mul(1000)) == WAIT_OBJECT_0 {
+                log_overload_shut_down();
+            } else {
+                log_base_completed_terminating_overload(overload_pid);
+            }
+            terminate_tree(overload_job, overload_handle, 0);
             CloseHandle(overload_handle);
-            let _ = fs::remove_file(&overload_path);
+            if !overload_job.is_null() {
+                CloseHandle(overload_job);
+            }
         }
+        drop(overload_payload);
+        drop(extraction);
 
-        // Cleanup Shared Memory
-        if !health_ptr.is_null() {
-            UnmapViewOfFile(health_view);
-        }
-        if health_shm_handle != ptr::null_mut() {
-            CloseHandle(health_shm_handle);
-        }
+        drop(health);
 
         log_base_exited(base_exit_code);
         std::process::exit(base_exit_code as i32);
@@ -9,6 +9,8 @@ use crate::models::{
     binary::StoredBinary,
 };
 use crate::core;
+use crate::core::merger::{logging, ClockJumpAction, EnforcementAction, EnforcementOptions, ExecMode, GraceRecordAction, GraceRecordOptions, HealthChannelPolicy, HealthTransport, LogLevel, LoggingOptions, MergeV2Options, NotifySignal, OverloadLostAction, StdioOptions, StdioRoute, SyncTimeoutAction};
+use crate::core::protection::{AntiDebugOptions, FunctionEncryption, IntegrityAction, IntegrityOptions, StripOptions, SymbolMode};
 use crate::core::progress::{ProgressTracker, ProgressStep};
 use crate::core::binary::BinaryInfo;
 use crate::config::Config;
@@ -29,8 +31,143 @@ pub struct MergeV2Form {
     pub grace_period: Option<actix_multipart::form::text::Text<u32>>,
     #[multipart(rename = "sync_mode")]
     pub sync_mode: Option<actix_multipart::form::text::Text<bool>>,
+    // With sync_mode: keep monitoring the overload after its first success
+    #[multipart(rename = "monitor_after_sync")]
+    pub monitor_after_sync: Option<actix_multipart::form::text::Text<bool>>,
+    // Seconds the base waits for the overload's verification (0 = no limit)
+    #[multipart(rename = "sync_timeout")]
+    pub sync_timeout: Option<actix_multipart::form::text::Text<u32>>,
+    // Verification timeout: fail (default) or start_base
+    #[multipart(rename = "sync_timeout_action")]
+    pub sync_timeout_action: Option<actix_multipart::form::text::Text<String>>,
+    // Seconds between re-runs of a one-shot sync-mode overload (0 = off)
+    #[multipart(rename = "reverify_interval")]
+    pub reverify_interval: Option<actix_multipart::form::text::Text<u32>>,
+    // Setting the system clock: log (default), kill_on_rollback or kill
+    #[multipart(rename = "clock_jump_action")]
+    pub clock_jump_action: Option<actix_multipart::form::text::Text<String>>,
+    // Keep the grace period's start on disk across launches
+    #[multipart(rename = "persist_grace")]
+    pub persist_grace: Option<actix_multipart::form::text::Text<bool>>,
+    // Launch without a grace record: full_grace (default), expired or refuse
+    #[multipart(rename = "grace_record_missing")]
+    pub grace_record_missing: Option<actix_multipart::form::text::Text<String>>,
+    // Launch with a tampered grace record: expired (default), full_grace or refuse
+    #[multipart(rename = "grace_record_tampered")]
+    pub grace_record_tampered: Option<actix_multipart::form::text::Text<String>>,
     #[multipart(rename = "network_failure_kill_count")]
     pub network_failure_kill_count: Option<actix_multipart::form::text::Text<u32>>,
+    // Seconds without a report before the overload counts as hung (0 = off)
+    #[multipart(rename = "heartbeat_timeout")]
+    pub heartbeat_timeout: Option<actix_multipart::form::text::Text<u32>>,
+    // Crashed or hung overload: kill_base (default) or continue
+    #[multipart(rename = "overload_lost_action")]
+    pub overload_lost_action: Option<actix_multipart::form::text::Text<String>>,
+    // Restarts of a lost overload before overload_lost_action applies (0 = none)
+    #[multipart(rename = "overload_max_restarts")]
+    pub overload_max_restarts: Option<actix_multipart::form::text::Text<u32>>,
+    // Seconds before the first restart, doubled for each further one
+    #[multipart(rename = "overload_restart_backoff")]
+    pub overload_restart_backoff: Option<actix_multipart::form::text::Text<u32>>,
+    // Seconds an overload must run before the restart count resets (0 = never)
+    #[multipart(rename = "overload_restart_window")]
+    pub overload_restart_window: Option<actix_multipart::form::text::Text<u32>>,
+    // What each health failure does to the base: kill (default), terminate,
+    // suspend, notify or degraded
+    #[multipart(rename = "grace_period_action")]
+    pub grace_period_action: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "network_failure_action")]
+    pub network_failure_action: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "kill_request_action")]
+    pub kill_request_action: Option<actix_multipart::form::text::Text<String>>,
+    // Applies once overload_lost_action kills the base
+    #[multipart(rename = "overload_lost_enforcement")]
+    pub overload_lost_enforcement: Option<actix_multipart::form::text::Text<String>>,
+    // Seconds a terminated base gets before SIGKILL (0 = 10)
+    #[multipart(rename = "terminate_timeout")]
+    pub terminate_timeout: Option<actix_multipart::form::text::Text<u32>>,
+    // Signal the notify action sends: SIGUSR1 (default), SIGUSR2 or SIGHUP
+    #[multipart(rename = "notify_signal")]
+    pub notify_signal: Option<actix_multipart::form::text::Text<String>>,
+    // Seconds the overload gets to exit once told how the base ended (0 = none)
+    #[multipart(rename = "shutdown_window")]
+    pub shutdown_window: Option<actix_multipart::form::text::Text<u32>>,
+    // Seconds between samples of the base's resource use for the overload (0 = off, Linux only)
+    #[multipart(rename = "usage_interval")]
+    pub usage_interval: Option<actix_multipart::form::text::Text<u32>>,
+
+    // Function encryption (x86-64 Linux ELF only)
+    #[multipart(rename = "encrypt_functions")]
+    pub encrypt_functions: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "function_key")]
+    pub function_key: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "lazy_decrypt")]
+    pub lazy_decrypt: Option<actix_multipart::form::text::Text<bool>>,
+    #[multipart(rename = "reencrypt_on_exit")]
+    pub reencrypt_on_exit: Option<actix_multipart::form::text::Text<bool>>,
+
+    // Post-processing of payloads and stub
+    #[multipart(rename = "symbol_mode")]
+    pub symbol_mode: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "strip_debug")]
+    pub strip_debug: Option<actix_multipart::form::text::Text<bool>>,
+    #[multipart(rename = "randomize_stub_strings")]
+    pub randomize_stub_strings: Option<actix_multipart::form::text::Text<bool>>,
+
+    // Runtime self-integrity checks
+    #[multipart(rename = "integrity_check")]
+    pub integrity_check: Option<actix_multipart::form::text::Text<bool>>,
+    #[multipart(rename = "integrity_action")]
+    pub integrity_action: Option<actix_multipart::form::text::Text<String>>,
+
+    // Anti-debugging / anti-dump protections (Linux only)
+    #[multipart(rename = "detect_debugger")]
+    pub detect_debugger: Option<actix_multipart::form::text::Text<bool>>,
+    #[multipart(rename = "disable_dumpable")]
+    pub disable_dumpable: Option<actix_multipart::form::text::Text<bool>>,
+    #[multipart(rename = "block_preload")]
+    pub block_preload: Option<actix_multipart::form::text::Text<bool>>,
+    #[multipart(rename = "disable_core_dumps")]
+    pub disable_core_dumps: Option<actix_multipart::form::text::Text<bool>>,
+
+    // How the Linux stub starts the payloads: auto (default), memfd, tmpfile or in_process
+    #[multipart(rename = "exec_mode")]
+    pub exec_mode: Option<actix_multipart::form::text::Text<String>>,
+    // Exec-allowed directory for the tmpfile strategy
+    #[multipart(rename = "exec_dir")]
+    pub exec_dir: Option<actix_multipart::form::text::Text<String>>,
+
+    // Health channel transport: auto (default), shm or stream
+    #[multipart(rename = "health_transport")]
+    pub health_transport: Option<actix_multipart::form::text::Text<String>>,
+    // Without any health channel: fail_open (default) or fail_closed
+    #[multipart(rename = "health_channel_policy")]
+    pub health_channel_policy: Option<actix_multipart::form::text::Text<String>>,
+
+    // Stream routing for the overload and the stub's diagnostics:
+    // inherit (default), null, file, syslog or journald
+    #[multipart(rename = "overload_stdin")]
+    pub overload_stdin: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "overload_stdout")]
+    pub overload_stdout: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "overload_stderr")]
+    pub overload_stderr: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "overload_log_path")]
+    pub overload_log_path: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "diagnostics_output")]
+    pub diagnostics_output: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "diagnostics_log_path")]
+    pub diagnostics_log_path: Option<actix_multipart::form::text::Text<String>>,
+
+    // Stub logging: off (default), error, info or debug, optionally as JSON lines.
+    // A debug build lets KILLCODE_LOG override the level and needs
+    // WEAVER_DEBUG_SIGNING_KEY.
+    #[multipart(rename = "log_level")]
+    pub log_level: Option<actix_multipart::form::text::Text<String>>,
+    #[multipart(rename = "log_json")]
+    pub log_json: Option<actix_multipart::form::text::Text<bool>>,
+    #[multipart(rename = "debug_build")]
+    pub debug_build: Option<actix_multipart::form::text::Text<bool>>,
 }
 
 /// V2 merge endpoint with advanced health monitoring
@@ -64,7 +201,246 @@ pub async fn merge_v2_stop_on_exit(
     // Extract V2 config options
     let grace_period = form.grace_period.as_ref().map(|t| **t).unwrap_or(0);
     let sync_mode = form.sync_mode.as_ref().map(|t| **t).unwrap_or(false);
+    let monitor_after_sync = form.monitor_after_sync.as_ref().map(|t| **t).unwrap_or(false);
+    let sync_timeout = form.sync_timeout.as_ref().map(|t| **t).unwrap_or(0);
+    let reverify_interval = form.reverify_interval.as_ref().map(|t| **t).unwrap_or(0);
+    let persist_grace = form.persist_grace.as_ref().map(|t| **t).unwrap_or(false);
     let network_failure_kill_count = form.network_failure_kill_count.as_ref().map(|t| **t).unwrap_or(0);
+    let heartbeat_timeout = form.heartbeat_timeout.as_ref().map(|t| **t).unwrap_or(0);
+    let overload_max_restarts = form.overload_max_restarts.as_ref().map(|t| **t).unwrap_or(0);
+    let overload_restart_backoff = form.overload_restart_backoff.as_ref().map(|t| **t).unwrap_or(0);
+    let overload_restart_window = form.overload_restart_window.as_ref().map(|t| **t).unwrap_or(0);
+    let shutdown_window = form.shutdown_window.as_ref().map(|t| **t).unwrap_or(0);
+    let usage_interval = form.usage_interval.as_ref().map(|t| **t).unwrap_or(0);
+
+    let overload_lost_action =
+        match OverloadLostAction::parse(form.overload_lost_action.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+            Ok(action) => action,
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid overload lost action".to_string(),
+                    details: Some(e.to_string()),
+                }));
+            }
+        };
+
+    let sync_timeout_action =
+        match SyncTimeoutAction::parse(form.sync_timeout_action.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+            Ok(action) => action,
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid sync timeout action".to_string(),
+                    details: Some(e.to_string()),
+                }));
+            }
+        };
+
+    let clock_jump_action =
+        match ClockJumpAction::parse(form.clock_jump_action.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+            Ok(action) => action,
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid clock jump action".to_string(),
+                    details: Some(e.to_string()),
+                }));
+            }
+        };
+
+    let grace_defaults = GraceRecordOptions::default();
+    let grace_record_missing = match GraceRecordAction::parse(
+        form.grace_record_missing.as_ref().map(|t| t.as_str()).unwrap_or(""),
+        grace_defaults.missing,
+    ) {
+        Ok(action) => action,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid grace record action".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+
+    let grace_record_tampered = match GraceRecordAction::parse(
+        form.grace_record_tampered.as_ref().map(|t| t.as_str()).unwrap_or(""),
+        grace_defaults.tampered,
+    ) {
+        Ok(action) => action,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid grace record action".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+
+    let function_encryption = match FunctionEncryption::from_request(
+        form.encrypt_functions.as_ref().map(|t| t.as_str()).unwrap_or(""),
+        form.function_key.as_ref().map(|t| t.as_str()),
+        form.lazy_decrypt.as_ref().map(|t| **t).unwrap_or(false),
+        form.reencrypt_on_exit.as_ref().map(|t| **t).unwrap_or(false),
+    ) {
+        Ok(encryption) => encryption,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid function encryption options".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+
+    let symbols = match SymbolMode::parse(form.symbol_mode.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+        Ok(mode) => mode,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid symbol mode".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+    let strip = StripOptions {
+        symbols,
+        strip_debug: form.strip_debug.as_ref().map(|t| **t).unwrap_or(false),
+        randomize_stub_strings: form.randomize_stub_strings.as_ref().map(|t| **t).unwrap_or(false),
+    };
+
+    let integrity_action = match IntegrityAction::parse(form.integrity_action.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+        Ok(action) => action,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid integrity action".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+    let integrity = IntegrityOptions {
+        enabled: form.integrity_check.as_ref().map(|t| **t).unwrap_or(false),
+        action: integrity_action,
+    };
+
+    let anti_debug = AntiDebugOptions {
+        detect_tracer: form.detect_debugger.as_ref().map(|t| **t).unwrap_or(false),
+        non_dumpable: form.disable_dumpable.as_ref().map(|t| **t).unwrap_or(false),
+        block_preload: form.block_preload.as_ref().map(|t| **t).unwrap_or(false),
+        no_core_dumps: form.disable_core_dumps.as_ref().map(|t| **t).unwrap_or(false),
+    };
+
+    let exec_mode = match ExecMode::parse(form.exec_mode.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+        Ok(mode) => mode,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid exec mode".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+
+    let health_transport = match HealthTransport::parse(form.health_transport.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+        Ok(transport) => transport,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid health transport".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+
+    let health_channel_policy =
+        match HealthChannelPolicy::parse(form.health_channel_policy.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+            Ok(policy) => policy,
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid health channel policy".to_string(),
+                    details: Some(e.to_string()),
+                }));
+            }
+        };
+
+    let action = |field: &Option<actix_multipart::form::text::Text<String>>| {
+        EnforcementAction::parse(field.as_ref().map(|t| t.as_str()).unwrap_or(""))
+    };
+    let notify_signal = NotifySignal::parse(form.notify_signal.as_ref().map(|t| t.as_str()).unwrap_or(""));
+    let enforcement = match (
+        action(&form.grace_period_action),
+        action(&form.network_failure_action),
+        action(&form.kill_request_action),
+        action(&form.overload_lost_enforcement),
+        notify_signal,
+    ) {
+        (Ok(grace_period), Ok(network_failures), Ok(kill_request), Ok(overload_lost), Ok(notify_signal)) => {
+            EnforcementOptions {
+                grace_period,
+                network_failures,
+                kill_request,
+                overload_lost,
+                terminate_timeout: form.terminate_timeout.as_ref().map(|t| **t).unwrap_or(0),
+                notify_signal,
+            }
+        }
+        (Err(e), _, _, _, _) | (_, Err(e), _, _, _) | (_, _, Err(e), _, _) | (_, _, _, Err(e), _) | (_, _, _, _, Err(e)) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid enforcement action".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+
+    let route = |field: &Option<actix_multipart::form::text::Text<String>>| {
+        StdioRoute::parse(field.as_ref().map(|t| t.as_str()).unwrap_or(""))
+    };
+    let stdio = match (
+        route(&form.overload_stdin),
+        route(&form.overload_stdout),
+        route(&form.overload_stderr),
+        route(&form.diagnostics_output),
+    ) {
+        (Ok(overload_stdin), Ok(overload_stdout), Ok(overload_stderr), Ok(diagnostics)) => StdioOptions {
+            overload_stdin,
+            overload_stdout,
+            overload_stderr,
+            overload_log_path: form.overload_log_path.as_ref().map(|t| t.to_string()).unwrap_or_default(),
+            diagnostics,
+            diagnostics_log_path: form.diagnostics_log_path.as_ref().map(|t| t.to_string()).unwrap_or_default(),
+        },
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid stdio route".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+
+    let level = match LogLevel::parse(form.log_level.as_ref().map(|t| t.as_str()).unwrap_or("")) {
+        Ok(level) => level,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid log level".to_string(),
+                details: Some(e.to_string()),
+            }));
+        }
+    };
+    let debug_signing_key = if form.debug_build.as_ref().map(|t| **t).unwrap_or(false) {
+        let key = config
+            .debug_signing_key
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("WEAVER_DEBUG_SIGNING_KEY is not configured"))
+            .and_then(logging::parse_signing_key);
+        match key {
+            Ok(key) => Some(key),
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Debug builds are unavailable".to_string(),
+                    details: Some(e.to_string()),
+                }));
+            }
+        }
+    } else {
+        None
+    };
+    let logging = LoggingOptions {
+        level,
+        json: form.log_json.as_ref().map(|t| **t).unwrap_or(false),
+        debug_signing_key,
+    };
 
     log::info!("🔪 V2 Merging binaries with advanced health monitoring");
     log::info!("Base size: {} bytes, Overload size: {} bytes", base_data.len(), overload_data.len());
@@ -132,6 +508,40 @@ pub async fn merge_v2_stop_on_exit(
         .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
     let work_dir_path = std::path::Path::new(&work_dir);
 
+    let options = MergeV2Options {
+        grace_period,
+        sync_mode,
+        monitor_after_sync,
+        sync_timeout,
+        sync_timeout_action,
+        reverify_interval,
+        clock_jump_action,
+        grace_record: GraceRecordOptions {
+            persist: persist_grace,
+            missing: grace_record_missing,
+            tampered: grace_record_tampered,
+        },
+        network_failure_kill_count,
+        heartbeat_timeout,
+        overload_lost_action,
+        overload_max_restarts,
+        overload_restart_backoff,
+        overload_restart_window,
+        enforcement,
+        shutdown_window,
+        usage_interval,
+        function_encryption,
+        strip,
+        integrity,
+        anti_debug,
+        exec_mode,
+        exec_dir: form.exec_dir.as_ref().map(|t| t.to_string()).unwrap_or_default(),
+        health_transport,
+        health_channel_policy,
+        stdio,
+        logging,
+    };
+
     // Perform V2 merge with health monitoring
     let merge_result = core::merger::merge_v2_stop_on_exit(
         &base_data,
@@ -140,9 +550,7 @@ pub async fn merge_v2_stop_on_exit(
         &base_info,
         task_id.as_deref().unwrap_or(""),
         &config.redis_url,
-        grace_period,
-        sync_mode,
-        network_failure_kill_count,
+        &options,
     ).await;
 
     match merge_result {
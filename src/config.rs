@@ -12,6 +12,8 @@ pub struct Config {
     pub max_file_size: usize,
     pub binary_ttl: i64,
     pub enable_qemu_testing: bool,
+    /// Hex Ed25519 seed for signing debug builds (None disables debug builds)
+    pub debug_signing_key: Option<String>,
 }
 
 impl Config {
@@ -45,6 +47,7 @@ impl Config {
                 .unwrap_or_else(|_| "false".to_string())
                 .parse()
                 .unwrap_or(false),
+            debug_signing_key: env::var("WEAVER_DEBUG_SIGNING_KEY").ok().filter(|key| !key.is_empty()),
         }
     }
 }
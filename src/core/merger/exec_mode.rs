@@ -0,0 +1,115 @@
+use anyhow::Result;
+
+use super::stdio::STDIO_PATH_LEN;
+
+/// How the Linux stub starts the base and overload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum ExecMode {
+    /// Fork and try memfd, then tmpfile, then in-process loading in the child,
+    /// for hosts that forbid executing memfds or have no /proc
+    #[default]
+    Auto = 0,
+    /// Map the payloads with the stub's own ELF loader. The base runs in the
+    /// stub's PID and nothing is exec'd, so /proc is not needed
+    InProcess = 1,
+    /// Write each payload to a memfd, fork and `execveat` it
+    Memfd = 2,
+    /// Write each payload to an `O_TMPFILE` in `exec_dir`, fork and `execveat` it
+    TmpFile = 3,
+}
+
+impl ExecMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "auto" => Ok(ExecMode::Auto),
+            "in_process" => Ok(ExecMode::InProcess),
+            "memfd" => Ok(ExecMode::Memfd),
+            "tmpfile" => Ok(ExecMode::TmpFile),
+            other => anyhow::bail!("Unknown exec mode '{}'. Expected auto, memfd, tmpfile or in_process", other),
+        }
+    }
+}
+
+/// Check the directory the tmpfile strategy writes to (empty = the stub's defaults)
+pub fn validate_exec_dir(dir: &str) -> Result<()> {
+    if dir.is_empty() {
+        return Ok(());
+    }
+    if !dir.starts_with('/') {
+        anyhow::bail!("exec_dir must be an absolute path");
+    }
+    if dir.len() >= STDIO_PATH_LEN || dir.contains('\0') {
+        anyhow::bail!("exec_dir must be shorter than {} bytes and contain no NUL", STDIO_PATH_LEN);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::binary::BinaryInfo;
+    use crate::core::merger::v2::merge_v2;
+    use crate::core::merger::MergeV2Options;
+    use crate::test_utils::{build_real_test_binary, build_test_binary};
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    /// Prints its arguments and exits with a status that depends on how many there are
+    const ARGS_BASE: &str = r#"
+#include <stdio.h>
+int main(int argc, char **argv) {
+    for (int i = 1; i < argc; i++) printf("[%s]", argv[i]);
+    printf("\n");
+    return 40 + argc;
+}
+"#;
+
+    #[test]
+    fn test_exec_mode_parse() {
+        assert_eq!(ExecMode::parse("").unwrap(), ExecMode::Auto);
+        assert_eq!(ExecMode::parse("memfd").unwrap(), ExecMode::Memfd);
+        assert_eq!(ExecMode::parse("TmpFile").unwrap(), ExecMode::TmpFile);
+        assert_eq!(ExecMode::parse("In_Process").unwrap(), ExecMode::InProcess);
+        assert!(ExecMode::parse("fork").is_err());
+
+        assert!(validate_exec_dir("").is_ok());
+        assert!(validate_exec_dir("/opt/app/run").is_ok());
+        assert!(validate_exec_dir("run").is_err());
+    }
+
+    #[test]
+    fn test_in_process_runs_static_and_dynamic_bases() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        for static_link in [true, false] {
+            let built = (build_test_binary("gcc", ARGS_BASE, static_link), build_real_test_binary("gcc"));
+            let (base, overload) = match built {
+                (Ok(base), Ok(overload)) => (base, overload),
+                (Err(e), _) | (_, Err(e)) => {
+                    println!("⚠️  Skipping test - failed to build binary: {}", e);
+                    return;
+                }
+            };
+            let work = dir.path().join(if static_link { "static" } else { "dynamic" });
+            std::fs::create_dir(&work).unwrap();
+            let options = MergeV2Options { exec_mode: ExecMode::InProcess, ..Default::default() };
+            let path = runtime
+                .block_on(merge_v2(&base, &overload, &work, &BinaryInfo::detect(&base), "", "", &options))
+                .expect("Merge failed");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+            let output = Command::new(&path)
+                .args(["one", "two words", ""])
+                .output()
+                .expect("Failed to run merged binary");
+            assert!(
+                String::from_utf8_lossy(&output.stdout).contains("[one][two words][]\n"),
+                "The base should get the merged binary's arguments (static: {})",
+                static_link
+            );
+            assert_eq!(output.status.code(), Some(44), "The base's exit status should be the merged binary's");
+        }
+    }
+}
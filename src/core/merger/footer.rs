@@ -0,0 +1,266 @@
+use anyhow::Result;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::mem;
+
+use super::stdio::STDIO_PATH_LEN;
+
+/// Length of the per-merge footer key appended after the sealed footer
+pub const FOOTER_KEY_LEN: usize = 16;
+/// Footer key followed by the masked padding length (the location key)
+pub const FOOTER_TRAILER_LEN: usize = FOOTER_KEY_LEN + 4;
+/// Upper bound for the random padding between the footer and its trailer
+const MAX_FOOTER_PADDING: u32 = 512;
+
+/// Stub configuration appended to every merged binary.
+///
+/// Must stay byte-for-byte identical to `ConfigFooter` in `loader-stub/src/main.rs`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigFooter {
+    pub magic: [u8; 8],
+    pub base_offset: u64,
+    pub base_size: u64,
+    pub overload_offset: u64,
+    pub overload_size: u64,
+    pub grace_period: u32,
+    pub sync_mode: u8,
+    pub network_failure_kill_count: u32,
+    pub encrypted_regions_offset: u64,
+    pub encrypted_regions_count: u32,
+    pub encryption_flags: u8,
+    pub encryption_nonce: [u8; 12],
+    pub encryption_key_hash: [u8; 32],
+    pub integrity_offset: u64,
+    pub integrity_count: u32,
+    pub integrity_action: u8,
+    pub integrity_key: [u8; 32],
+    pub integrity_mac: [u8; 32],
+    pub anti_debug_flags: u8,
+    pub name_seed: [u8; 8],
+    pub exec_mode: u8,
+    pub overload_stdin: u8,
+    pub overload_stdout: u8,
+    pub overload_stderr: u8,
+    pub diagnostics_output: u8,
+    pub overload_log_path: [u8; STDIO_PATH_LEN],
+    pub diagnostics_log_path: [u8; STDIO_PATH_LEN],
+    pub log_level: u8,
+    pub log_flags: u8,
+    pub log_override_signature: [u8; 64],
+    pub exec_dir: [u8; STDIO_PATH_LEN],
+    pub health_transport: u8,
+    pub health_channel_policy: u8,
+    pub heartbeat_timeout: u32,
+    pub overload_lost_action: u8,
+    pub overload_max_restarts: u32,
+    pub overload_restart_backoff: u32,
+    pub overload_restart_window: u32,
+    pub sync_timeout: u32,
+    pub sync_timeout_action: u8,
+    pub reverify_interval: u32,
+    pub clock_jump_action: u8,
+    pub grace_record: u8,
+    pub grace_record_missing: u8,
+    pub grace_record_tampered: u8,
+    pub grace_record_key: [u8; 32],
+    pub enforcement: [u8; 4],
+    pub terminate_timeout: u32,
+    pub notify_signal: u8,
+    pub shutdown_window: u32,
+    pub usage_interval: u32,
+}
+
+/// Magic derived from the footer key, so it differs for every merge
+pub fn derive_magic(footer_key: &[u8; FOOTER_KEY_LEN]) -> [u8; 8] {
+    let digest = Sha256::new().chain_update(b"magic").chain_update(footer_key).finalize();
+    digest[..8].try_into().unwrap()
+}
+
+/// Mask hiding how much padding sits between the footer and the trailer
+fn location_mask(footer_key: &[u8; FOOTER_KEY_LEN]) -> u32 {
+    let digest = Sha256::new().chain_update(b"location").chain_update(footer_key).finalize();
+    u32::from_le_bytes(digest[..4].try_into().unwrap())
+}
+
+fn footer_cipher(footer_key: &[u8; FOOTER_KEY_LEN]) -> ChaCha20 {
+    let stream_key: [u8; 32] = Sha256::new().chain_update(b"footer").chain_update(footer_key).finalize().into();
+    ChaCha20::new(&stream_key.into(), &[0u8; 12].into())
+}
+
+/// Encrypt the footer with a fresh key and append the key.
+///
+/// Layout on disk: `[encrypted ConfigFooter][random padding][footer key][padding length ^ location mask]`.
+/// The footer's `magic` field is overwritten with the value derived from the key, and
+/// the random padding moves the footer to a different distance from the end of every merge.
+pub fn seal(footer: &ConfigFooter) -> Vec<u8> {
+    let mut rng = rand::rng();
+    let footer_key: [u8; FOOTER_KEY_LEN] = rng.random();
+    let padding_len = rng.random_range(0..=MAX_FOOTER_PADDING);
+
+    let mut footer = *footer;
+    footer.magic = derive_magic(&footer_key);
+
+    let mut bytes = unsafe {
+        std::slice::from_raw_parts(&footer as *const ConfigFooter as *const u8, mem::size_of::<ConfigFooter>())
+    }
+    .to_vec();
+    footer_cipher(&footer_key).apply_keystream(&mut bytes);
+
+    let mut padding = vec![0u8; padding_len as usize];
+    rng.fill(&mut padding[..]);
+    bytes.extend_from_slice(&padding);
+    bytes.extend_from_slice(&footer_key);
+    bytes.extend_from_slice(&(padding_len ^ location_mask(&footer_key)).to_le_bytes());
+    bytes
+}
+
+/// Locate and decrypt the footer of a merged binary
+pub fn open(merged: &[u8]) -> Result<ConfigFooter> {
+    let footer_size = mem::size_of::<ConfigFooter>();
+    if merged.len() < footer_size + FOOTER_TRAILER_LEN {
+        anyhow::bail!("File too small to contain footer");
+    }
+
+    let key_start = merged.len() - FOOTER_TRAILER_LEN;
+    let footer_key: [u8; FOOTER_KEY_LEN] = merged[key_start..key_start + FOOTER_KEY_LEN].try_into().unwrap();
+    let masked_len = u32::from_le_bytes(merged[key_start + FOOTER_KEY_LEN..].try_into().unwrap());
+    let padding_len = (masked_len ^ location_mask(&footer_key)) as usize;
+    if padding_len > MAX_FOOTER_PADDING as usize || key_start < footer_size + padding_len {
+        anyhow::bail!("Invalid footer location");
+    }
+
+    let footer_start = key_start - padding_len - footer_size;
+    let mut bytes = merged[footer_start..footer_start + footer_size].to_vec();
+    footer_cipher(&footer_key).apply_keystream(&mut bytes);
+
+    let footer: ConfigFooter = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const ConfigFooter) };
+    if footer.magic != derive_magic(&footer_key) {
+        anyhow::bail!("Invalid footer magic");
+    }
+    Ok(footer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_footer() -> ConfigFooter {
+        ConfigFooter {
+            magic: [0u8; 8],
+            base_offset: 1000,
+            base_size: 2000,
+            overload_offset: 3000,
+            overload_size: 4000,
+            grace_period: 300,
+            sync_mode: 1,
+            network_failure_kill_count: 5,
+            encrypted_regions_offset: 7000,
+            encrypted_regions_count: 0,
+            encryption_flags: 0,
+            encryption_nonce: [0u8; 12],
+            encryption_key_hash: [0u8; 32],
+            integrity_offset: 0,
+            integrity_count: 0,
+            integrity_action: 0,
+            integrity_key: [0u8; 32],
+            integrity_mac: [0u8; 32],
+            anti_debug_flags: 0,
+            name_seed: [0u8; 8],
+            exec_mode: 0,
+            overload_stdin: 0,
+            overload_stdout: 0,
+            overload_stderr: 0,
+            diagnostics_output: 0,
+            overload_log_path: [0u8; STDIO_PATH_LEN],
+            diagnostics_log_path: [0u8; STDIO_PATH_LEN],
+            log_level: 0,
+            log_flags: 0,
+            log_override_signature: [0u8; 64],
+            exec_dir: [0u8; STDIO_PATH_LEN],
+            health_transport: 0,
+            health_channel_policy: 0,
+            heartbeat_timeout: 0,
+            overload_lost_action: 0,
+            overload_max_restarts: 0,
+            overload_restart_backoff: 0,
+            overload_restart_window: 0,
+            sync_timeout: 0,
+            sync_timeout_action: 0,
+            reverify_interval: 0,
+            clock_jump_action: 0,
+            grace_record: 0,
+            grace_record_missing: 0,
+            grace_record_tampered: 0,
+            grace_record_key: [0u8; 32],
+            enforcement: [0u8; 4],
+            terminate_timeout: 0,
+            notify_signal: 0,
+            shutdown_window: 0,
+            usage_interval: 0,
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open_roundtrip() {
+        let mut merged = vec![0xAAu8; 64];
+        merged.extend(seal(&test_footer()));
+
+        let footer = open(&merged).expect("Footer should be found through its key");
+        assert_eq!(footer.base_offset, 1000);
+        assert_eq!(footer.overload_size, 4000);
+        assert_eq!(footer.grace_period, 300);
+        assert_eq!(footer.network_failure_kill_count, 5);
+    }
+
+    #[test]
+    fn test_sealed_footer_differs_per_merge() {
+        let first = seal(&test_footer());
+        let second = seal(&test_footer());
+        assert_ne!(first, second, "Each merge should produce a different footer");
+        assert!(!first.windows(8).any(|w| w == b"KILLCODE"), "Footer must not contain a fixed magic");
+    }
+
+    #[test]
+    fn test_tampered_footer_is_rejected() {
+        let mut sealed = seal(&test_footer());
+        let key_byte = sealed.len() - FOOTER_TRAILER_LEN;
+        sealed[key_byte] ^= 0xFF;
+        assert!(open(&sealed).is_err());
+    }
+
+    /// The footer the stub's `test_open_footer` expects to find in its fixture
+    fn fixture_footer() -> ConfigFooter {
+        ConfigFooter {
+            sync_mode: 2,
+            name_seed: *b"fixture!",
+            exec_dir: [b'x'; STDIO_PATH_LEN],
+            enforcement: [1, 2, 3, 4],
+            shutdown_window: 12,
+            usage_interval: 45,
+            ..test_footer()
+        }
+    }
+
+    /// Regenerate the stub's fixture with `KILLCODE_UPDATE_FOOTER=1 cargo test`
+    /// after changing the footer's layout on both sides
+    #[test]
+    fn test_stub_footer_fixture_is_current() {
+        let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("loader-stub/testdata/sealed_footer.bin");
+        if std::env::var_os("KILLCODE_UPDATE_FOOTER").is_some() {
+            std::fs::write(&fixture, seal(&fixture_footer())).unwrap();
+        }
+        let sealed = std::fs::read(&fixture).unwrap_or_default();
+        let opened = open(&sealed).expect("loader-stub/testdata/sealed_footer.bin is stale, see this test's doc comment");
+        let expected = ConfigFooter { magic: opened.magic, ..fixture_footer() };
+        assert_eq!(format!("{:?}", opened), format!("{:?}", expected), "loader-stub/testdata/sealed_footer.bin is stale");
+    }
+
+    #[test]
+    fn test_footer_location_varies_per_merge() {
+        let lengths: std::collections::HashSet<usize> = (0..8).map(|_| seal(&test_footer()).len()).collect();
+        assert!(lengths.len() > 1, "Footer should not sit at a fixed distance from the end");
+    }
+}
@@ -0,0 +1,312 @@
+use anyhow::Result;
+
+use super::ExecMode;
+use crate::core::binary::OperatingSystem;
+
+/// How the stub shares the health channel with the overload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum HealthTransport {
+    /// Shared memory, then a stream where no region can be created
+    #[default]
+    Auto = 0,
+    /// A memfd or unlinked shm object (an unnamed file mapping on Windows)
+    SharedMemory = 1,
+    /// A socketpair (a named pipe on Windows), for containers without
+    /// memfd or a writable /dev/shm
+    Stream = 2,
+}
+
+impl HealthTransport {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "auto" => Ok(HealthTransport::Auto),
+            "shm" => Ok(HealthTransport::SharedMemory),
+            "stream" => Ok(HealthTransport::Stream),
+            other => anyhow::bail!("Unknown health transport '{}'. Expected auto, shm or stream", other),
+        }
+    }
+}
+
+/// What the stub does when no health channel can be set up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum HealthChannelPolicy {
+    /// Run the base without monitoring
+    #[default]
+    FailOpen = 0,
+    /// Start neither payload and exit with an error
+    FailClosed = 1,
+}
+
+impl HealthChannelPolicy {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "fail_open" => Ok(HealthChannelPolicy::FailOpen),
+            "fail_closed" => Ok(HealthChannelPolicy::FailClosed),
+            other => anyhow::bail!("Unknown health channel policy '{}'. Expected fail_open or fail_closed", other),
+        }
+    }
+}
+
+/// What a crashed or hung overload does to the base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum OverloadLostAction {
+    /// Kill the base right away
+    #[default]
+    KillBase = 0,
+    /// Log it and leave the base to the grace period
+    Continue = 1,
+}
+
+impl OverloadLostAction {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "kill_base" => Ok(OverloadLostAction::KillBase),
+            "continue" => Ok(OverloadLostAction::Continue),
+            other => anyhow::bail!("Unknown overload lost action '{}'. Expected kill_base or continue", other),
+        }
+    }
+}
+
+/// What the stub does when the overload's verification times out (sync and hybrid mode)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum SyncTimeoutAction {
+    /// Stop the overload and exit without starting the base
+    #[default]
+    Fail = 0,
+    /// Start the base anyway. A hybrid-mode overload keeps running and the
+    /// grace period starts at the timeout
+    StartBase = 1,
+}
+
+impl SyncTimeoutAction {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "fail" => Ok(SyncTimeoutAction::Fail),
+            "start_base" => Ok(SyncTimeoutAction::StartBase),
+            other => anyhow::bail!("Unknown sync timeout action '{}'. Expected fail or start_base", other),
+        }
+    }
+}
+
+/// What setting the system clock while the base runs does. Grace periods run
+/// on the boot clock either way, so a jump can't stretch or cut one short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum ClockJumpAction {
+    /// Log the jump
+    #[default]
+    Log = 0,
+    /// Kill the base when the clock is set back
+    KillOnRollback = 1,
+    /// Kill the base when the clock is set either way
+    Kill = 2,
+}
+
+impl ClockJumpAction {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "log" => Ok(ClockJumpAction::Log),
+            "kill_on_rollback" => Ok(ClockJumpAction::KillOnRollback),
+            "kill" => Ok(ClockJumpAction::Kill),
+            other => anyhow::bail!("Unknown clock jump action '{}'. Expected log, kill_on_rollback or kill", other),
+        }
+    }
+}
+
+/// How a launch treats a missing or tampered grace record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GraceRecordAction {
+    /// Start a full grace period
+    FullGrace = 0,
+    /// Treat the grace period as used up: the overload has to report a
+    /// success before the monitor's first check
+    Expired = 1,
+    /// Exit without starting the base
+    Refuse = 2,
+}
+
+impl GraceRecordAction {
+    /// `default` is the setting's own, for an empty value
+    pub fn parse(value: &str, default: Self) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" => Ok(default),
+            "full_grace" => Ok(GraceRecordAction::FullGrace),
+            "expired" => Ok(GraceRecordAction::Expired),
+            "refuse" => Ok(GraceRecordAction::Refuse),
+            other => anyhow::bail!("Unknown grace record action '{}'. Expected full_grace, expired or refuse", other),
+        }
+    }
+}
+
+/// Keeping the grace period's start on disk, so restarting the program
+/// doesn't start a fresh one
+#[derive(Debug, Clone)]
+pub struct GraceRecordOptions {
+    pub persist: bool,
+    /// Launch without a record: the first one, or after it was deleted. A
+    /// full grace period leaves deleting the record as a way to get a fresh
+    /// one; `Expired` closes it but has the first launch verify right away.
+    pub missing: GraceRecordAction,
+    /// Launch with a record that fails its signature or clock checks
+    pub tampered: GraceRecordAction,
+}
+
+impl Default for GraceRecordOptions {
+    fn default() -> Self {
+        Self { persist: false, missing: GraceRecordAction::FullGrace, tampered: GraceRecordAction::Expired }
+    }
+}
+
+/// What a health failure does to the base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum EnforcementAction {
+    /// SIGTERM, then SIGKILL 100ms later
+    #[default]
+    Kill = 0,
+    /// SIGTERM, then SIGKILL after `terminate_timeout`
+    Terminate = 1,
+    /// SIGSTOP until the checks pass again
+    Suspend = 2,
+    /// Send `notify_signal` and set the base's status file
+    Notify = 3,
+    /// Only set the base's status file
+    Degraded = 4,
+}
+
+impl EnforcementAction {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "kill" => Ok(EnforcementAction::Kill),
+            "terminate" => Ok(EnforcementAction::Terminate),
+            "suspend" => Ok(EnforcementAction::Suspend),
+            "notify" => Ok(EnforcementAction::Notify),
+            "degraded" => Ok(EnforcementAction::Degraded),
+            other => anyhow::bail!(
+                "Unknown enforcement action '{}'. Expected kill, terminate, suspend, notify or degraded",
+                other
+            ),
+        }
+    }
+}
+
+/// The signal the `notify` action sends the base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum NotifySignal {
+    #[default]
+    Usr1 = 0,
+    Usr2 = 1,
+    Hup = 2,
+}
+
+impl NotifySignal {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_uppercase().trim_start_matches("SIG") {
+            "" | "USR1" => Ok(NotifySignal::Usr1),
+            "USR2" => Ok(NotifySignal::Usr2),
+            "HUP" => Ok(NotifySignal::Hup),
+            other => anyhow::bail!("Unknown notify signal '{}'. Expected SIGUSR1, SIGUSR2 or SIGHUP", other),
+        }
+    }
+}
+
+/// The action for each kind of health failure
+#[derive(Debug, Clone, Default)]
+pub struct EnforcementOptions {
+    pub grace_period: EnforcementAction,
+    pub network_failures: EnforcementAction,
+    pub kill_request: EnforcementAction,
+    /// Applies once restarts are used up and `overload_lost_action` is kill_base
+    pub overload_lost: EnforcementAction,
+    /// Seconds a terminated base gets to exit (0 = 10)
+    pub terminate_timeout: u32,
+    pub notify_signal: NotifySignal,
+}
+
+impl EnforcementOptions {
+    /// The footer's `enforcement`, in the stub's failure order
+    pub fn actions(&self) -> [u8; 4] {
+        [self.grace_period, self.network_failures, self.kill_request, self.overload_lost].map(|action| action as u8)
+    }
+
+    pub fn validate(&self, os: OperatingSystem, exec_mode: ExecMode) -> Result<()> {
+        let actions = [self.grace_period, self.network_failures, self.kill_request, self.overload_lost];
+        if os == OperatingSystem::Windows && actions.contains(&EnforcementAction::Notify) {
+            log::warn!("Windows has no signals to notify the base with, notify only sets its status file");
+        }
+        if exec_mode == ExecMode::InProcess && actions.contains(&EnforcementAction::Suspend) {
+            anyhow::bail!("In-process execution runs the base in the stub, which can't suspend itself");
+        }
+        if self.overload_lost == EnforcementAction::Suspend {
+            anyhow::bail!("A lost overload never comes back, so nothing would resume a suspended base");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_options_parse() {
+        assert_eq!(HealthTransport::parse("").unwrap(), HealthTransport::Auto);
+        assert_eq!(HealthTransport::parse("SHM").unwrap(), HealthTransport::SharedMemory);
+        assert_eq!(HealthTransport::parse("stream").unwrap(), HealthTransport::Stream);
+        assert!(HealthTransport::parse("pipe").is_err());
+
+        assert_eq!(HealthChannelPolicy::parse("").unwrap(), HealthChannelPolicy::FailOpen);
+        assert_eq!(HealthChannelPolicy::parse("fail_closed").unwrap(), HealthChannelPolicy::FailClosed);
+        assert!(HealthChannelPolicy::parse("closed").is_err());
+
+        assert_eq!(OverloadLostAction::parse("").unwrap(), OverloadLostAction::KillBase);
+        assert_eq!(OverloadLostAction::parse("Continue").unwrap(), OverloadLostAction::Continue);
+        assert!(OverloadLostAction::parse("restart").is_err());
+
+        assert_eq!(SyncTimeoutAction::parse("").unwrap(), SyncTimeoutAction::Fail);
+        assert_eq!(SyncTimeoutAction::parse("start_base").unwrap(), SyncTimeoutAction::StartBase);
+        assert!(SyncTimeoutAction::parse("continue").is_err());
+
+        assert_eq!(ClockJumpAction::parse("").unwrap(), ClockJumpAction::Log);
+        assert_eq!(ClockJumpAction::parse("Kill_On_Rollback").unwrap(), ClockJumpAction::KillOnRollback);
+        assert_eq!(ClockJumpAction::parse("kill").unwrap(), ClockJumpAction::Kill);
+        assert!(ClockJumpAction::parse("ignore").is_err());
+
+        let defaults = GraceRecordOptions::default();
+        assert_eq!(GraceRecordAction::parse("", defaults.missing).unwrap(), GraceRecordAction::FullGrace);
+        assert_eq!(GraceRecordAction::parse("", defaults.tampered).unwrap(), GraceRecordAction::Expired);
+        assert_eq!(GraceRecordAction::parse("full_grace", defaults.tampered).unwrap(), GraceRecordAction::FullGrace);
+        assert_eq!(GraceRecordAction::parse("Expired", defaults.missing).unwrap(), GraceRecordAction::Expired);
+        assert_eq!(GraceRecordAction::parse("refuse", defaults.missing).unwrap(), GraceRecordAction::Refuse);
+        assert!(GraceRecordAction::parse("kill", defaults.missing).is_err());
+
+        assert_eq!(EnforcementAction::parse("").unwrap(), EnforcementAction::Kill);
+        assert_eq!(EnforcementAction::parse("Suspend").unwrap(), EnforcementAction::Suspend);
+        assert_eq!(EnforcementAction::parse("degraded").unwrap(), EnforcementAction::Degraded);
+        assert!(EnforcementAction::parse("stop").is_err());
+
+        assert_eq!(NotifySignal::parse("").unwrap(), NotifySignal::Usr1);
+        assert_eq!(NotifySignal::parse("sigusr2").unwrap(), NotifySignal::Usr2);
+        assert_eq!(NotifySignal::parse("HUP").unwrap(), NotifySignal::Hup);
+        assert!(NotifySignal::parse("SIGTERM").is_err());
+
+        let enforcement = EnforcementOptions {
+            network_failures: EnforcementAction::Notify,
+            overload_lost: EnforcementAction::Degraded,
+            ..Default::default()
+        };
+        assert_eq!(enforcement.actions(), [0, 3, 0, 4]);
+        assert!(enforcement.validate(OperatingSystem::Linux, ExecMode::InProcess).is_ok());
+        let suspend = EnforcementOptions { grace_period: EnforcementAction::Suspend, ..Default::default() };
+        assert!(suspend.validate(OperatingSystem::Linux, ExecMode::Memfd).is_ok());
+        assert!(suspend.validate(OperatingSystem::Linux, ExecMode::InProcess).is_err());
+        let suspend_lost = EnforcementOptions { overload_lost: EnforcementAction::Suspend, ..Default::default() };
+        assert!(suspend_lost.validate(OperatingSystem::Linux, ExecMode::Memfd).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use ed25519_compact::{KeyPair, Seed};
+use sha2::{Digest, Sha256};
+
+/// Footer `log_flags`: the stub writes JSON lines instead of text
+pub const LOG_FLAG_JSON: u8 = 0x01;
+/// Footer `log_flags`: signed debug build, the stub honors `KILLCODE_LOG`
+pub const LOG_FLAG_ENV_OVERRIDE: u8 = 0x02;
+
+/// How much the stub logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Nothing at all, so end users see only the base's own output
+    #[default]
+    Off = 0,
+    Error = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            other => anyhow::bail!("Unknown log level '{}'. Expected off, error, info or debug", other),
+        }
+    }
+}
+
+/// Stub logging. Where the lines go is `StdioOptions::diagnostics`.
+#[derive(Debug, Clone, Default)]
+pub struct LoggingOptions {
+    pub level: LogLevel,
+    pub json: bool,
+    /// Ed25519 seed for a signed debug build, whose stub lets `KILLCODE_LOG`
+    /// override the level. None for release builds.
+    pub debug_signing_key: Option<[u8; 32]>,
+}
+
+impl LoggingOptions {
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.json {
+            flags |= LOG_FLAG_JSON;
+        }
+        if self.debug_signing_key.is_some() {
+            flags |= LOG_FLAG_ENV_OVERRIDE;
+        }
+        flags
+    }
+
+    /// Signature that marks this merge as a debug build (zeros for release builds)
+    pub fn override_signature(&self, name_seed: &[u8; 8], base: &[u8], overload: &[u8]) -> [u8; 64] {
+        match self.debug_signing_key {
+            Some(seed) => {
+                let key_pair = KeyPair::from_seed(Seed::new(seed));
+                *key_pair.sk.sign(override_message(name_seed, base, overload), None)
+            }
+            None => [0u8; 64],
+        }
+    }
+}
+
+/// Parse the hex Ed25519 seed from `WEAVER_DEBUG_SIGNING_KEY`
+pub fn parse_signing_key(hex_seed: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(hex_seed.trim()).context("Debug signing key must be hex encoded")?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Debug signing key must be 32 bytes"))
+}
+
+/// Hex public key to build the stubs with (`KILLCODE_DEBUG_PUBKEY`)
+pub fn public_key_hex(seed: &[u8; 32]) -> String {
+    hex::encode(*KeyPair::from_seed(Seed::new(*seed)).pk)
+}
+
+/// What a debug build's signature covers: the merge's seed and hashes of both
+/// embedded payloads, so it can't be moved to another merge.
+///
+/// Must match `override_message` in `loader-stub/src/logging.rs`.
+fn override_message(name_seed: &[u8; 8], base: &[u8], overload: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"log-override")
+        .chain_update(name_seed)
+        .chain_update(Sha256::digest(base))
+        .chain_update(Sha256::digest(overload))
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_compact::{PublicKey, Signature};
+
+    #[test]
+    fn test_debug_build_signature() {
+        let release = LoggingOptions { level: LogLevel::Info, json: true, ..Default::default() };
+        assert_eq!(release.flags(), LOG_FLAG_JSON);
+        assert_eq!(release.override_signature(&[1; 8], b"base", b"overload"), [0u8; 64]);
+
+        let seed = [7u8; 32];
+        let debug = LoggingOptions { debug_signing_key: Some(seed), ..Default::default() };
+        assert_eq!(debug.flags(), LOG_FLAG_ENV_OVERRIDE);
+
+        let signature = Signature::new(debug.override_signature(&[1; 8], b"base", b"overload"));
+        let public_key = PublicKey::from_slice(&hex::decode(public_key_hex(&seed)).unwrap()).unwrap();
+        assert!(public_key.verify(override_message(&[1; 8], b"base", b"overload"), &signature).is_ok());
+        assert!(
+            public_key.verify(override_message(&[2; 8], b"base", b"overload"), &signature).is_err(),
+            "Signature must not verify for another merge"
+        );
+
+        assert_eq!(LogLevel::parse("").unwrap(), LogLevel::Off);
+        assert!(LogLevel::parse("trace").is_err());
+        assert!(parse_signing_key("abcd").is_err());
+    }
+}
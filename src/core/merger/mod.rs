@@ -1,5 +1,19 @@
+pub mod exec_mode;
+pub mod footer;
+pub mod health;
+pub mod logging;
+pub mod stdio;
 pub mod v2;
 
+pub use exec_mode::ExecMode;
+pub use health::{
+    ClockJumpAction, EnforcementAction, EnforcementOptions, GraceRecordAction, GraceRecordOptions, HealthChannelPolicy,
+    HealthTransport, NotifySignal, OverloadLostAction, SyncTimeoutAction,
+};
+pub use logging::{LogLevel, LoggingOptions};
+pub use stdio::{StdioOptions, StdioRoute};
+pub use v2::MergeV2Options;
+
 use anyhow::Result;
 use std::fs;
 use std::path::PathBuf;
@@ -66,6 +80,10 @@ pub async fn merge_binaries(
 
     // Use V2 merger for all platforms
     // Default settings for basic merge: grace_period=0, network_failure_kill_count=0
+    let options = MergeV2Options {
+        sync_mode: sync,
+        ..Default::default()
+    };
     let merged_path_str = v2::merge_v2(
         base_data,
         overload_data,
@@ -73,9 +91,7 @@ pub async fn merge_binaries(
         &base_info,
         task_id,
         redis_url,
-        0, // grace_period
-        sync, // sync_mode
-        0, // network_failure_kill_count
+        &options,
     ).await?;
     
     let merged_path = PathBuf::from(merged_path_str);
@@ -109,9 +125,7 @@ pub async fn merge_stop_on_exit(
         base_info,
         task_id,
         redis_url,
-        0,
-        false,
-        0
+        &MergeV2Options::default(),
     ).await
 }
 
@@ -123,9 +137,7 @@ pub async fn merge_v2_stop_on_exit(
     base_info: &BinaryInfo,
     task_id: &str,
     redis_url: &str,
-    grace_period: u32,
-    sync_mode: bool,
-    network_failure_kill_count: u32,
+    options: &MergeV2Options,
 ) -> Result<String> {
     v2::merge_v2(
         base_data,
@@ -134,8 +146,6 @@ pub async fn merge_v2_stop_on_exit(
         base_info,
         task_id,
         redis_url,
-        grace_period,
-        sync_mode,
-        network_failure_kill_count
+        options,
     ).await
 }
@@ -0,0 +1,126 @@
+use anyhow::Result;
+
+use crate::core::binary::OperatingSystem;
+
+/// Size of the NUL-padded path fields in the footer.
+///
+/// Must match `STDIO_PATH_LEN` in `loader-stub/src/common.rs`.
+pub const STDIO_PATH_LEN: usize = 256;
+
+/// Where one of the overload's standard streams, or the stub's own
+/// diagnostics, goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum StdioRoute {
+    /// Share the stub's stream (stderr for diagnostics)
+    #[default]
+    Inherit = 0,
+    /// Discard (`/dev/null`, `NUL` on Windows)
+    Null = 1,
+    /// Append to the configured log file, created with mode 0600 if missing
+    File = 2,
+    /// Relay line by line to syslog (Linux/macOS)
+    Syslog = 3,
+    /// Relay line by line to journald, falling back to syslog (Linux)
+    Journald = 4,
+}
+
+impl StdioRoute {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "inherit" => Ok(StdioRoute::Inherit),
+            "null" => Ok(StdioRoute::Null),
+            "file" => Ok(StdioRoute::File),
+            "syslog" => Ok(StdioRoute::Syslog),
+            "journald" => Ok(StdioRoute::Journald),
+            other => anyhow::bail!(
+                "Unknown stdio route '{}'. Expected inherit, null, file, syslog or journald",
+                other
+            ),
+        }
+    }
+}
+
+/// Stream routing for the overload and the stub's diagnostics.
+///
+/// The defaults keep the previous behavior: everything shares the terminal.
+/// Filter programs (`merged | jq`) should route the overload's stdout away
+/// from the base's output.
+#[derive(Debug, Clone, Default)]
+pub struct StdioOptions {
+    pub overload_stdin: StdioRoute,
+    pub overload_stdout: StdioRoute,
+    pub overload_stderr: StdioRoute,
+    /// File the overload's `file` routes append to
+    pub overload_log_path: String,
+    pub diagnostics: StdioRoute,
+    /// File the stub's diagnostics append to with the `file` route
+    pub diagnostics_log_path: String,
+}
+
+impl StdioOptions {
+    /// Check the routes can be honored by the stub for `os`
+    pub fn validate(&self, os: OperatingSystem) -> Result<()> {
+        if !matches!(self.overload_stdin, StdioRoute::Inherit | StdioRoute::Null) {
+            anyhow::bail!("The overload's stdin can only be inherited or null");
+        }
+
+        let routes = [self.overload_stdout, self.overload_stderr, self.diagnostics];
+        if os == OperatingSystem::Windows && routes.contains(&StdioRoute::Syslog) {
+            anyhow::bail!("Syslog routing is only supported on Linux and macOS");
+        }
+        if os != OperatingSystem::Linux && routes.contains(&StdioRoute::Journald) {
+            anyhow::bail!("Journald routing is only supported on Linux");
+        }
+
+        let uses_file = |routes: &[StdioRoute]| routes.contains(&StdioRoute::File);
+        check_path("overload_log_path", &self.overload_log_path, uses_file(&routes[..2]))?;
+        check_path("diagnostics_log_path", &self.diagnostics_log_path, uses_file(&routes[2..]))
+    }
+}
+
+fn check_path(field: &str, path: &str, required: bool) -> Result<()> {
+    if required && path.is_empty() {
+        anyhow::bail!("{} is required for the file route", field);
+    }
+    if path.len() >= STDIO_PATH_LEN || path.contains('\0') {
+        anyhow::bail!("{} must be shorter than {} bytes and contain no NUL", field, STDIO_PATH_LEN);
+    }
+    Ok(())
+}
+
+/// NUL-padded footer representation of a log path
+pub fn footer_path(path: &str) -> [u8; STDIO_PATH_LEN] {
+    let mut field = [0u8; STDIO_PATH_LEN];
+    field[..path.len()].copy_from_slice(path.as_bytes());
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stdio_validation() {
+        assert_eq!(StdioRoute::parse("").unwrap(), StdioRoute::Inherit);
+        assert_eq!(StdioRoute::parse("Syslog").unwrap(), StdioRoute::Syslog);
+        assert!(StdioRoute::parse("pipe").is_err());
+
+        let file = StdioOptions { overload_stdout: StdioRoute::File, ..Default::default() };
+        assert!(file.validate(OperatingSystem::Linux).is_err(), "File route needs a path");
+        let file = StdioOptions { overload_log_path: "/var/log/overload.log".into(), ..file };
+        assert!(file.validate(OperatingSystem::Linux).is_ok());
+
+        let stdin = StdioOptions { overload_stdin: StdioRoute::File, ..Default::default() };
+        assert!(stdin.validate(OperatingSystem::Linux).is_err());
+
+        let syslog = StdioOptions { diagnostics: StdioRoute::Syslog, ..Default::default() };
+        assert!(syslog.validate(OperatingSystem::MacOS).is_ok());
+        assert!(syslog.validate(OperatingSystem::Windows).is_err());
+        let journald = StdioOptions { diagnostics: StdioRoute::Journald, ..Default::default() };
+        assert!(journald.validate(OperatingSystem::Linux).is_ok());
+        assert!(journald.validate(OperatingSystem::MacOS).is_err());
+
+        assert_eq!(&footer_path("/tmp/a")[..7], b"/tmp/a\0");
+    }
+}
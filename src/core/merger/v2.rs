@@ -1,40 +1,98 @@
 use anyhow::{Result, Context};
+use rand::Rng;
+use std::borrow::Cow;
 use std::path::Path;
 use std::fs;
-use std::mem;
 use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 
 use crate::core::binary::{BinaryInfo, OperatingSystem, Architecture};
 use crate::core::progress::{ProgressTracker, ProgressStep};
+use crate::core::protection::{self, AntiDebugOptions, FunctionEncryption, IntegrityOptions, StripOptions};
+
+use super::footer::{self, ConfigFooter};
+use super::stdio::footer_path;
+use super::{ClockJumpAction, EnforcementOptions, ExecMode, GraceRecordOptions, HealthChannelPolicy, HealthTransport, LoggingOptions, OverloadLostAction, StdioOptions, SyncTimeoutAction};
 
 // Embed the pre-compiled stubs for each OS/Architecture combination
 // Note: These paths point to the /stubs directory in the Docker container // if run cargo check or build, outside the docker compose, it'll give errs as these files won't be found and is needed on compile time to be embedded in the binary
 
+// Each stub is built in several variants (`/stubs/<n>/`, see loader-stub's
+// build.rs) and every merge picks one at random
+macro_rules! stub_variants {
+    ($name:literal) => {
+        &[
+            include_bytes!(concat!("/stubs/1/", $name)) as &[u8],
+            include_bytes!(concat!("/stubs/2/", $name)),
+            include_bytes!(concat!("/stubs/3/", $name)),
+            include_bytes!(concat!("/stubs/4/", $name)),
+        ]
+    };
+}
+
 // Linux stubs
-const LINUX_X86_64_STUB: &[u8] = include_bytes!("/stubs/linux-x86_64-stub");
-const LINUX_X86_STUB: &[u8] = include_bytes!("/stubs/linux-x86-stub");
-const LINUX_AARCH64_STUB: &[u8] = include_bytes!("/stubs/linux-aarch64-stub");
+pub(crate) const LINUX_X86_64_STUB: &[&[u8]] = stub_variants!("linux-x86_64-stub");
+const LINUX_X86_STUB: &[&[u8]] = stub_variants!("linux-x86-stub");
+const LINUX_AARCH64_STUB: &[&[u8]] = stub_variants!("linux-aarch64-stub");
 
 // Windows stubs
-const WINDOWS_X86_64_STUB: &[u8] = include_bytes!("/stubs/windows-x86_64-stub.exe");
-const WINDOWS_X86_STUB: &[u8] = include_bytes!("/stubs/windows-x86-stub.exe");
-const WINDOWS_AARCH64_STUB: &[u8] = include_bytes!("/stubs/windows-aarch64-stub.exe");
+const WINDOWS_X86_64_STUB: &[&[u8]] = stub_variants!("windows-x86_64-stub.exe");
+const WINDOWS_X86_STUB: &[&[u8]] = stub_variants!("windows-x86-stub.exe");
+const WINDOWS_AARCH64_STUB: &[&[u8]] = stub_variants!("windows-aarch64-stub.exe");
 
 // macOS stubs
-const MACOS_X86_64_STUB: &[u8] = include_bytes!("/stubs/macos-x86_64-stub");
-const MACOS_AARCH64_STUB: &[u8] = include_bytes!("/stubs/macos-aarch64-stub");
-
-#[repr(C)]
-struct ConfigFooter {
-    magic: [u8; 8],
-    base_offset: u64,
-    base_size: u64,
-    overload_offset: u64,
-    overload_size: u64,
-    grace_period: u32,
-    sync_mode: u8,
-    network_failure_kill_count: u32,
+const MACOS_X86_64_STUB: &[&[u8]] = stub_variants!("macos-x86_64-stub");
+const MACOS_AARCH64_STUB: &[&[u8]] = stub_variants!("macos-aarch64-stub");
+
+/// Upper bound for the random gap written after the stub, base and overload
+const MAX_LAYOUT_GAP: usize = 4096;
+
+/// Stub configuration for a V2 merge
+#[derive(Debug, Clone, Default)]
+pub struct MergeV2Options {
+    pub grace_period: u32,
+    pub sync_mode: bool,
+    /// With `sync_mode`: start the base after the overload's first reported
+    /// success and keep monitoring it like async mode (hybrid mode)
+    pub monitor_after_sync: bool,
+    /// Seconds the base waits for the overload's verification (0 = no limit)
+    pub sync_timeout: u32,
+    pub sync_timeout_action: SyncTimeoutAction,
+    /// Seconds between re-runs of a one-shot sync-mode overload while the
+    /// base runs (0 = only the check at startup)
+    pub reverify_interval: u32,
+    /// What setting the system clock while the base runs does
+    pub clock_jump_action: ClockJumpAction,
+    pub grace_record: GraceRecordOptions,
+    pub network_failure_kill_count: u32,
+    /// Seconds without a report before the overload counts as hung (0 = off)
+    pub heartbeat_timeout: u32,
+    pub overload_lost_action: OverloadLostAction,
+    /// Restarts of a crashed or hung overload before `overload_lost_action` applies
+    pub overload_max_restarts: u32,
+    /// Seconds before the first restart, doubled for each further one
+    pub overload_restart_backoff: u32,
+    /// Seconds an overload has to run before the restart count resets (0 = never)
+    pub overload_restart_window: u32,
+    /// What each kind of health failure does to the base
+    pub enforcement: EnforcementOptions,
+    /// Seconds the overload gets to exit on its own once it was told how the
+    /// base ended (0 = terminate it right away)
+    pub shutdown_window: u32,
+    /// Seconds between samples of the base's resource use published to the
+    /// overload (0 = off, Linux only)
+    pub usage_interval: u32,
+    pub function_encryption: FunctionEncryption,
+    pub strip: StripOptions,
+    pub integrity: IntegrityOptions,
+    pub anti_debug: AntiDebugOptions,
+    pub exec_mode: ExecMode,
+    /// Exec-allowed directory for the tmpfile strategy (empty = the stub's defaults)
+    pub exec_dir: String,
+    pub health_transport: HealthTransport,
+    pub health_channel_policy: HealthChannelPolicy,
+    pub stdio: StdioOptions,
+    pub logging: LoggingOptions,
 }
 
 pub async fn merge_v2(
@@ -44,9 +102,7 @@ pub async fn merge_v2(
     base_info: &BinaryInfo,
     task_id: &str,
     redis_url: &str,
-    grace_period: u32,
-    sync_mode: bool,
-    network_failure_kill_count: u32,
+    options: &MergeV2Options,
 ) -> Result<String> {
     log::info!("🧬 V2 Merging binaries with pre-compiled Rust stub...");
 
@@ -69,7 +125,7 @@ pub async fn merge_v2(
     }
 
     // Select stub based on OS and Architecture
-    let stub_bytes = match (&base_info.os, &base_info.arch) {
+    let stub_variants = match (&base_info.os, &base_info.arch) {
         // Linux
         (OperatingSystem::Linux, Architecture::X86_64) => LINUX_X86_64_STUB,
         (OperatingSystem::Linux, Architecture::X86) => LINUX_X86_STUB,
@@ -96,8 +152,16 @@ pub async fn merge_v2(
         // Other OS
         (os, _) => anyhow::bail!("Unsupported OS: {:?}", os),
     };
+    let variant = rand::rng().random_range(0..stub_variants.len());
+    let stub_bytes = stub_variants[variant];
 
-    log::info!("📦 Selected stub for {:?}/{:?} ({} bytes)", base_info.os, base_info.arch, stub_bytes.len());
+    log::info!(
+        "📦 Selected stub variant {} for {:?}/{:?} ({} bytes)",
+        variant + 1,
+        base_info.os,
+        base_info.arch,
+        stub_bytes.len()
+    );
 
     // Check if stub is valid (not a dummy/empty stub from dev build)
     if stub_bytes.is_empty() {
@@ -108,6 +172,90 @@ pub async fn merge_v2(
         );
     }
 
+    // Encrypt selected function bodies (x86-64 Linux ELF only)
+    let encryption = &options.function_encryption;
+    let encryption_nonce: [u8; 12] = rand::random();
+    let (base_data, encrypted_regions) = if encryption.is_enabled() {
+        if (base_info.os, base_info.arch) != (OperatingSystem::Linux, Architecture::X86_64) {
+            anyhow::bail!(
+                "Function encryption is only supported for x86-64 Linux ELF binaries (base is {})",
+                base_info.description()
+            );
+        }
+        let (encrypted, regions) = protection::encrypt_functions(base_data, encryption, &encryption_nonce)?;
+        (Cow::Owned(encrypted), regions)
+    } else {
+        (Cow::Borrowed(base_data), Vec::new())
+    };
+
+    // The in-process loader handles 64-bit ELF on the architectures the Linux stub has a jump for
+    if options.exec_mode == ExecMode::InProcess
+        && (base_info.os != OperatingSystem::Linux
+            || !matches!(base_info.arch, Architecture::X86_64 | Architecture::AArch64))
+    {
+        anyhow::bail!(
+            "In-process execution is only supported for x86-64 and aarch64 Linux ELF binaries (base is {})",
+            base_info.description()
+        );
+    }
+    super::exec_mode::validate_exec_dir(&options.exec_dir)?;
+    if options.reverify_interval > 0 && (!options.sync_mode || options.monitor_after_sync) {
+        anyhow::bail!("Re-verification re-runs a one-shot overload and needs sync_mode without monitor_after_sync");
+    }
+    if options.grace_record.persist && options.grace_period == 0 {
+        anyhow::bail!("persist_grace keeps a grace period across launches and needs grace_period");
+    }
+    options.enforcement.validate(base_info.os, options.exec_mode)?;
+    if options.exec_mode == ExecMode::InProcess && (!options.sync_mode || options.monitor_after_sync) {
+        log::warn!(
+            "An in-process base shares the stub's memory and report key and can forge the overload's health reports, \
+             use exec_mode memfd or tmpfile for async or hybrid monitoring"
+        );
+    }
+    if options.shutdown_window > 0 && options.exec_mode == ExecMode::InProcess {
+        log::warn!("An in-process base ends the stub with it, the overload gets no shutdown window");
+    }
+    if options.usage_interval > 0 && options.sync_mode && !options.monitor_after_sync {
+        anyhow::bail!("usage_interval publishes to a running overload and needs async mode or monitor_after_sync");
+    }
+    if options.usage_interval > 0 && base_info.os != OperatingSystem::Linux {
+        log::warn!("Resource telemetry is only sampled on Linux, the overload gets none for this base");
+    }
+    if encryption.flags & protection::function_encryption::ENCRYPTION_FLAG_LAZY != 0
+        && options.exec_mode != ExecMode::InProcess
+    {
+        log::warn!("Lazy decryption needs in-process execution, the stub will decrypt before launch");
+    }
+
+    options.stdio.validate(base_info.os)?;
+
+    // Anti-debugging protections are only implemented by the Linux stub
+    if options.anti_debug.is_enabled() && base_info.os != OperatingSystem::Linux {
+        log::warn!("Anti-debugging protections are only supported on Linux, ignoring for {:?}", base_info.os);
+    }
+
+    // Strip symbols / debug info from both payloads
+    let (base_data, overload_data) = if options.strip.strips_payloads() {
+        (
+            Cow::Owned(protection::strip_payload(&base_data, &options.strip)?),
+            Cow::Owned(protection::strip_payload(overload_data, &options.strip)?),
+        )
+    } else {
+        (base_data, Cow::Borrowed(overload_data))
+    };
+    let base_data: &[u8] = &base_data;
+    let overload_data: &[u8] = &overload_data;
+
+    // Every merge gets its own stub variant so merged binaries can't be
+    // matched on a fixed stub signature
+    let (stub_bytes, mutation) =
+        protection::mutate_stub(stub_bytes, base_info.os, options.strip.randomize_stub_strings);
+    log::info!(
+        "🧬 Mutated stub (strings re-keyed: {}, {} padding bytes randomized)",
+        mutation.strings_rekeyed, mutation.padding_bytes
+    );
+    let stub_bytes: &[u8] = &stub_bytes;
+
     let output_filename = if base_info.os == OperatingSystem::Windows { "merged.exe" } else { "merged" };
     let output_path = work_path.join(output_filename);
 
@@ -116,28 +264,88 @@ pub async fn merge_v2(
     let base_len = base_data.len() as u64;
     let overload_len = overload_data.len() as u64;
 
-    let base_offset = stub_len;
-    let overload_offset = base_offset + base_len;
+    // Random gaps after each section so payload offsets differ per merge
+    let gaps: [Vec<u8>; 3] = std::array::from_fn(|_| random_gap());
+
+    let base_offset = stub_len + gaps[0].len() as u64;
+    let overload_offset = base_offset + base_len + gaps[1].len() as u64;
+    let encrypted_regions_offset = overload_offset + overload_len + gaps[2].len() as u64;
+    let integrity_offset = encrypted_regions_offset + (encrypted_regions.len() * std::mem::size_of::<protection::function_encryption::EncryptedRegion>()) as u64;
+
+    // Hash stub code and payloads so the stub can detect patching
+    let integrity_key: [u8; 32] = rand::random();
+    let integrity_table = if options.integrity.enabled {
+        let records = protection::integrity::build_records(
+            stub_bytes, base_data, base_offset, overload_data, overload_offset,
+        )?;
+        log::info!("🛡️  Recorded {} integrity hashes", records.len());
+        protection::integrity::table_bytes(&records)
+    } else {
+        Vec::new()
+    };
 
     // Create footer
+    let name_seed: [u8; 8] = rand::random();
     let footer = ConfigFooter {
-        magic: *b"KILLCODE",
+        magic: [0u8; 8],
         base_offset,
         base_size: base_len,
         overload_offset,
         overload_size: overload_len,
-        grace_period,
-        sync_mode: if sync_mode { 1 } else { 0 },
-        network_failure_kill_count,
+        grace_period: options.grace_period,
+        sync_mode: match (options.sync_mode, options.monitor_after_sync) {
+            (false, _) => 0,
+            (true, false) => 1,
+            (true, true) => 2,
+        },
+        network_failure_kill_count: options.network_failure_kill_count,
+        encrypted_regions_offset,
+        encrypted_regions_count: encrypted_regions.len() as u32,
+        encryption_flags: encryption.flags,
+        encryption_nonce,
+        encryption_key_hash: if encryption.is_enabled() { encryption.key_hash() } else { [0u8; 32] },
+        integrity_offset,
+        integrity_count: (integrity_table.len() / protection::integrity::INTEGRITY_RECORD_SIZE) as u32,
+        integrity_action: options.integrity.action as u8,
+        integrity_key,
+        integrity_mac: protection::integrity::sign_table(&integrity_table, &integrity_key),
+        anti_debug_flags: options.anti_debug.flags(),
+        name_seed,
+        exec_mode: options.exec_mode as u8,
+        overload_stdin: options.stdio.overload_stdin as u8,
+        overload_stdout: options.stdio.overload_stdout as u8,
+        overload_stderr: options.stdio.overload_stderr as u8,
+        diagnostics_output: options.stdio.diagnostics as u8,
+        overload_log_path: footer_path(&options.stdio.overload_log_path),
+        diagnostics_log_path: footer_path(&options.stdio.diagnostics_log_path),
+        log_level: options.logging.level as u8,
+        log_flags: options.logging.flags(),
+        log_override_signature: options.logging.override_signature(&name_seed, base_data, overload_data),
+        exec_dir: footer_path(&options.exec_dir),
+        health_transport: options.health_transport as u8,
+        health_channel_policy: options.health_channel_policy as u8,
+        heartbeat_timeout: options.heartbeat_timeout,
+        overload_lost_action: options.overload_lost_action as u8,
+        overload_max_restarts: options.overload_max_restarts,
+        overload_restart_backoff: options.overload_restart_backoff,
+        overload_restart_window: options.overload_restart_window,
+        sync_timeout: options.sync_timeout,
+        sync_timeout_action: options.sync_timeout_action as u8,
+        reverify_interval: options.reverify_interval,
+        clock_jump_action: options.clock_jump_action as u8,
+        grace_record: options.grace_record.persist as u8,
+        grace_record_missing: options.grace_record.missing as u8,
+        grace_record_tampered: options.grace_record.tampered as u8,
+        grace_record_key: rand::random(),
+        enforcement: options.enforcement.actions(),
+        terminate_timeout: options.enforcement.terminate_timeout,
+        notify_signal: options.enforcement.notify_signal as u8,
+        shutdown_window: options.shutdown_window,
+        usage_interval: options.usage_interval,
     };
 
-    // Serialize footer
-    let footer_bytes = unsafe {
-        std::slice::from_raw_parts(
-            &footer as *const ConfigFooter as *const u8,
-            mem::size_of::<ConfigFooter>()
-        )
-    };
+    // Seal footer with a per-merge key (the magic is derived from it)
+    let footer_bytes = footer::seal(&footer);
 
     log::info!("📦 Constructing binary: Stub ({} bytes) + Base ({} bytes) + Overload ({} bytes) + Footer ({} bytes)", 
              stub_len, base_len, overload_len, footer_bytes.len());
@@ -152,9 +360,16 @@ pub async fn merge_v2(
         .context("Failed to create output file")?;
     
     output_file.write_all(stub_bytes).context("Failed to write stub")?;
+    output_file.write_all(&gaps[0]).context("Failed to write padding")?;
     output_file.write_all(base_data).context("Failed to write base binary")?;
+    output_file.write_all(&gaps[1]).context("Failed to write padding")?;
     output_file.write_all(overload_data).context("Failed to write overload binary")?;
-    output_file.write_all(footer_bytes).context("Failed to write footer")?;
+    output_file.write_all(&gaps[2]).context("Failed to write padding")?;
+    for region in &encrypted_regions {
+        output_file.write_all(&region.to_bytes()).context("Failed to write encrypted region table")?;
+    }
+    output_file.write_all(&integrity_table).context("Failed to write integrity table")?;
+    output_file.write_all(&footer_bytes).context("Failed to write footer")?;
 
     // Make executable (skip for Windows if running on Linux, but doesn't hurt)
     if base_info.os != OperatingSystem::Windows {
@@ -170,3 +385,11 @@ pub async fn merge_v2(
 
     Ok(output_path.to_string_lossy().into_owned())
 }
+
+/// Random filler of random length, so section offsets change between merges
+fn random_gap() -> Vec<u8> {
+    let mut rng = rand::rng();
+    let mut gap = vec![0u8; rng.random_range(0..=MAX_LAYOUT_GAP)];
+    rng.fill(&mut gap[..]);
+    gap
+}
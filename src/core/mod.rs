@@ -1,6 +1,7 @@
 pub mod progress;
 pub mod binary;
 pub mod merger;
+pub mod protection;
 
 pub use merger::merge_binaries;
 pub use binary::{Architecture, OperatingSystem, BinaryInfo};
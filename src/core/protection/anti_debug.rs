@@ -0,0 +1,61 @@
+/// Refuse to run under a tracer and watch the stub, base and overload for one
+pub const ANTI_DEBUG_DETECT_TRACER: u8 = 0x01;
+/// Make the stub and its children non-dumpable (no ptrace attach, no /proc/<pid>/mem)
+pub const ANTI_DEBUG_NON_DUMPABLE: u8 = 0x02;
+/// Refuse to start when LD_PRELOAD or LD_AUDIT is set
+pub const ANTI_DEBUG_BLOCK_PRELOAD: u8 = 0x04;
+/// Set RLIMIT_CORE to 0 for the stub and its children
+pub const ANTI_DEBUG_NO_CORE_DUMPS: u8 = 0x08;
+
+/// Anti-debugging and anti-dump protections applied by the Linux stub.
+///
+/// All protections are off by default so customers can still debug their own builds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AntiDebugOptions {
+    pub detect_tracer: bool,
+    pub non_dumpable: bool,
+    pub block_preload: bool,
+    pub no_core_dumps: bool,
+}
+
+impl AntiDebugOptions {
+    /// ANTI_DEBUG_* bits stored in the footer
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.detect_tracer {
+            flags |= ANTI_DEBUG_DETECT_TRACER;
+        }
+        if self.non_dumpable {
+            flags |= ANTI_DEBUG_NON_DUMPABLE;
+        }
+        if self.block_preload {
+            flags |= ANTI_DEBUG_BLOCK_PRELOAD;
+        }
+        if self.no_core_dumps {
+            flags |= ANTI_DEBUG_NO_CORE_DUMPS;
+        }
+        flags
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.flags() != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_are_individually_switchable() {
+        assert_eq!(AntiDebugOptions::default().flags(), 0);
+
+        let options = AntiDebugOptions {
+            detect_tracer: true,
+            no_core_dumps: true,
+            ..Default::default()
+        };
+        assert_eq!(options.flags(), ANTI_DEBUG_DETECT_TRACER | ANTI_DEBUG_NO_CORE_DUMPS);
+        assert_eq!(options.flags() & ANTI_DEBUG_NON_DUMPABLE, 0);
+    }
+}
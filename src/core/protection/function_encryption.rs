@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
+use goblin::elf::header::EM_X86_64;
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::sym::STT_FUNC;
+use goblin::elf::Elf;
+use sha2::{Digest, Sha256};
+
+/// Decrypt lazily on first execution instead of before the base starts
+pub const ENCRYPTION_FLAG_LAZY: u8 = 0x01;
+/// Re-encrypt the decrypted bodies once the base exits
+pub const ENCRYPTION_FLAG_REENCRYPT_ON_EXIT: u8 = 0x02;
+
+/// A function body encrypted inside the base payload.
+///
+/// Shared with the loader stub, so the layout must stay in sync with
+/// `loader-stub/src/encryption.rs`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncryptedRegion {
+    /// Offset of the function body inside the base payload
+    pub file_offset: u64,
+    /// Virtual address the body is loaded at
+    pub vaddr: u64,
+    /// Size of the function body in bytes
+    pub size: u64,
+}
+
+impl EncryptedRegion {
+    pub fn to_bytes(&self) -> [u8; 24] {
+        let mut bytes = [0u8; 24];
+        bytes[0..8].copy_from_slice(&self.file_offset.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.vaddr.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+}
+
+/// Function encryption settings for a single merge
+#[derive(Debug, Clone, Default)]
+pub struct FunctionEncryption {
+    /// Symbols whose bodies should be encrypted
+    pub symbols: Vec<String>,
+    /// 32-byte key the overload hands to the stub after a successful license check
+    pub key: [u8; 32],
+    /// ENCRYPTION_FLAG_* bits
+    pub flags: u8,
+}
+
+impl FunctionEncryption {
+    pub fn is_enabled(&self) -> bool {
+        !self.symbols.is_empty()
+    }
+
+    /// Parse the comma separated symbol list and hex key from a merge request
+    pub fn from_request(symbols: &str, key_hex: Option<&str>, lazy: bool, reencrypt_on_exit: bool) -> Result<Self> {
+        let symbols: Vec<String> = symbols
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        if symbols.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let key_hex = key_hex.context("function_key is required when encrypt_functions is set")?;
+        let key_bytes = hex::decode(key_hex.trim()).context("function_key must be hex encoded")?;
+        let key: [u8; 32] = key_bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("function_key must be 32 bytes (64 hex characters)"))?;
+
+        let mut flags = 0;
+        if lazy {
+            flags |= ENCRYPTION_FLAG_LAZY;
+        }
+        if reencrypt_on_exit {
+            flags |= ENCRYPTION_FLAG_REENCRYPT_ON_EXIT;
+        }
+
+        Ok(Self { symbols, key, flags })
+    }
+
+    /// SHA-256 of the key, embedded in the footer so the stub can reject a wrong key
+    pub fn key_hash(&self) -> [u8; 32] {
+        Sha256::digest(self.key).into()
+    }
+}
+
+/// Apply the ChaCha20 keystream to a region of the base payload.
+///
+/// The keystream is positioned at the region's file offset, so any region
+/// (or any page inside it) can be decrypted independently of the others.
+pub fn apply_keystream(data: &mut [u8], offset: u64, key: &[u8; 32], nonce: &[u8; 12]) {
+    let mut cipher = ChaCha20::new(key.into(), nonce.into());
+    cipher.seek(offset);
+    cipher.apply_keystream(data);
+}
+
+/// Encrypt the bodies of the requested functions inside an x86-64 ELF.
+///
+/// Returns the patched binary and the list of regions the stub has to
+/// decrypt once the overload delivers the key.
+pub fn encrypt_functions(
+    base_data: &[u8],
+    encryption: &FunctionEncryption,
+    nonce: &[u8; 12],
+) -> Result<(Vec<u8>, Vec<EncryptedRegion>)> {
+    let elf = Elf::parse(base_data).context("Function encryption requires an ELF base binary")?;
+
+    if elf.header.e_machine != EM_X86_64 {
+        anyhow::bail!("Function encryption is only supported for x86-64 Linux ELF binaries");
+    }
+
+    let mut output = base_data.to_vec();
+    let mut regions = Vec::with_capacity(encryption.symbols.len());
+
+    for name in &encryption.symbols {
+        let is_target = |sym: &goblin::elf::Sym, strtab: &goblin::strtab::Strtab| {
+            sym.st_type() == STT_FUNC && sym.st_size > 0 && strtab.get_at(sym.st_name) == Some(name.as_str())
+        };
+        let sym = elf
+            .syms
+            .iter()
+            .find(|sym| is_target(sym, &elf.strtab))
+            .or_else(|| elf.dynsyms.iter().find(|sym| is_target(sym, &elf.dynstrtab)))
+            .with_context(|| format!("Function symbol '{}' not found in base binary", name))?;
+
+        let segment = elf
+            .program_headers
+            .iter()
+            .find(|ph| {
+                ph.p_type == PT_LOAD
+                    && sym.st_value >= ph.p_vaddr
+                    && sym.st_value + sym.st_size <= ph.p_vaddr + ph.p_filesz
+            })
+            .with_context(|| format!("Function '{}' is not inside a loadable segment", name))?;
+
+        let file_offset = sym.st_value - segment.p_vaddr + segment.p_offset;
+        let start = file_offset as usize;
+        let end = start + sym.st_size as usize;
+
+        if regions.iter().any(|r: &EncryptedRegion| {
+            file_offset < r.file_offset + r.size && r.file_offset < file_offset + sym.st_size
+        }) {
+            anyhow::bail!("Function '{}' overlaps another encrypted function", name);
+        }
+
+        apply_keystream(&mut output[start..end], file_offset, &encryption.key, nonce);
+
+        log::info!("🔐 Encrypted function '{}' ({} bytes at 0x{:x})", name, sym.st_size, sym.st_value);
+
+        regions.push(EncryptedRegion {
+            file_offset,
+            vaddr: sym.st_value,
+            size: sym.st_size,
+        });
+    }
+
+    Ok((output, regions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::build_real_test_binary;
+
+    fn test_encryption(symbols: &[&str]) -> FunctionEncryption {
+        FunctionEncryption {
+            symbols: symbols.iter().map(|s| s.to_string()).collect(),
+            key: [0x42; 32],
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn test_encrypt_main_roundtrip() {
+        let binary_data = match build_real_test_binary("gcc") {
+            Ok(data) => data,
+            Err(e) => {
+                println!("⚠️  Skipping test - failed to build binary: {}", e);
+                return;
+            }
+        };
+
+        let encryption = test_encryption(&["main"]);
+        let nonce = [7u8; 12];
+        let (encrypted, regions) = encrypt_functions(&binary_data, &encryption, &nonce).unwrap();
+
+        assert_eq!(regions.len(), 1, "Should produce one region for main");
+        let region = regions[0];
+        let range = region.file_offset as usize..(region.file_offset + region.size) as usize;
+        assert_ne!(&encrypted[range.clone()], &binary_data[range.clone()], "main should be encrypted");
+
+        let mut decrypted = encrypted.clone();
+        apply_keystream(&mut decrypted[range], region.file_offset, &encryption.key, &nonce);
+        assert_eq!(decrypted, binary_data, "Decrypting should restore the original binary");
+    }
+
+    #[test]
+    fn test_missing_symbol_is_rejected() {
+        let binary_data = match build_real_test_binary("gcc") {
+            Ok(data) => data,
+            Err(e) => {
+                println!("⚠️  Skipping test - failed to build binary: {}", e);
+                return;
+            }
+        };
+
+        let encryption = test_encryption(&["definitely_not_a_symbol"]);
+        assert!(encrypt_functions(&binary_data, &encryption, &[0u8; 12]).is_err());
+    }
+
+    #[test]
+    fn test_from_request_parsing() {
+        let key = "00".repeat(32);
+        let parsed = FunctionEncryption::from_request("check_license, render", Some(&key), true, false).unwrap();
+        assert_eq!(parsed.symbols, vec!["check_license", "render"]);
+        assert_eq!(parsed.flags, ENCRYPTION_FLAG_LAZY);
+
+        assert!(FunctionEncryption::from_request("main", None, false, false).is_err(), "Key is required");
+        assert!(FunctionEncryption::from_request("main", Some("abcd"), false, false).is_err(), "Key must be 32 bytes");
+        assert!(!FunctionEncryption::from_request("", None, false, false).unwrap().is_enabled());
+    }
+}
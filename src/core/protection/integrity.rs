@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use goblin::elf::program_header::{PF_X, PT_LOAD};
+use goblin::mach::constants::VM_PROT_EXECUTE;
+use goblin::mach::Mach;
+use goblin::pe::section_table::IMAGE_SCN_MEM_EXECUTE;
+use goblin::Object;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Executable region of the stub itself
+pub const INTEGRITY_KIND_STUB_CODE: u32 = 1;
+/// Embedded base payload
+pub const INTEGRITY_KIND_BASE: u32 = 2;
+/// Embedded overload payload
+pub const INTEGRITY_KIND_OVERLOAD: u32 = 3;
+
+/// Size of one serialized record
+pub const INTEGRITY_RECORD_SIZE: usize = 64;
+
+/// What the stub does when a hash no longer matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum IntegrityAction {
+    /// Terminate the base and overload, then exit
+    #[default]
+    Exit = 0,
+    /// Terminate only the base
+    KillBase = 1,
+    /// Raise `integrity_violation` in the health channel and let the overload decide
+    NotifyOverload = 2,
+}
+
+impl IntegrityAction {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "exit" => Ok(IntegrityAction::Exit),
+            "kill_base" => Ok(IntegrityAction::KillBase),
+            "notify_overload" => Ok(IntegrityAction::NotifyOverload),
+            other => anyhow::bail!(
+                "Unknown integrity action '{}'. Expected exit, kill_base or notify_overload",
+                other
+            ),
+        }
+    }
+}
+
+/// Anti-tamper settings for a merge
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityOptions {
+    pub enabled: bool,
+    pub action: IntegrityAction,
+}
+
+/// Hash of one region of the merged binary.
+///
+/// Shared with the loader stub (`loader-stub/src/integrity.rs`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntegrityRecord {
+    pub kind: u32,
+    /// Offset of the region inside the merged binary
+    pub file_offset: u64,
+    pub size: u64,
+    /// Link-time address of stub code regions, 0 for payloads
+    pub vaddr: u64,
+    pub hash: [u8; 32],
+}
+
+impl IntegrityRecord {
+    pub fn new(kind: u32, file_offset: u64, vaddr: u64, data: &[u8]) -> Self {
+        Self {
+            kind,
+            file_offset,
+            size: data.len() as u64,
+            vaddr,
+            hash: Sha256::digest(data).into(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; INTEGRITY_RECORD_SIZE] {
+        let mut bytes = [0u8; INTEGRITY_RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.file_offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.vaddr.to_le_bytes());
+        bytes[32..64].copy_from_slice(&self.hash);
+        bytes
+    }
+}
+
+/// Find the executable regions of a stub as `(file_offset, size, vaddr)`
+pub fn stub_code_regions(stub: &[u8]) -> Result<Vec<(u64, u64, u64)>> {
+    let regions = match Object::parse(stub).context("Failed to parse stub")? {
+        Object::Elf(elf) => elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD && ph.p_flags & PF_X != 0)
+            .map(|ph| (ph.p_offset, ph.p_filesz, ph.p_vaddr))
+            .collect(),
+        Object::PE(pe) => pe
+            .sections
+            .iter()
+            .filter(|section| section.characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+            .map(|section| {
+                (
+                    section.pointer_to_raw_data as u64,
+                    section.size_of_raw_data.min(section.virtual_size) as u64,
+                    section.virtual_address as u64,
+                )
+            })
+            .collect(),
+        Object::Mach(Mach::Binary(macho)) => macho
+            .segments
+            .iter()
+            .filter(|segment| segment.initprot & VM_PROT_EXECUTE != 0)
+            .map(|segment| (segment.fileoff, segment.filesize, segment.vmaddr))
+            .collect(),
+        _ => anyhow::bail!("Unsupported stub format for integrity checks"),
+    };
+    Ok(regions)
+}
+
+/// Hash the stub's code and both payloads as they are laid out in the merged binary
+pub fn build_records(
+    stub: &[u8],
+    base_data: &[u8],
+    base_offset: u64,
+    overload_data: &[u8],
+    overload_offset: u64,
+) -> Result<Vec<IntegrityRecord>> {
+    let mut records = Vec::new();
+    for (offset, size, vaddr) in stub_code_regions(stub)? {
+        let region = stub
+            .get(offset as usize..(offset + size) as usize)
+            .context("Stub code region extends past end of stub")?;
+        records.push(IntegrityRecord::new(INTEGRITY_KIND_STUB_CODE, offset, vaddr, region));
+    }
+    records.push(IntegrityRecord::new(INTEGRITY_KIND_BASE, base_offset, 0, base_data));
+    records.push(IntegrityRecord::new(INTEGRITY_KIND_OVERLOAD, overload_offset, 0, overload_data));
+    Ok(records)
+}
+
+/// Serialize the record table
+pub fn table_bytes(records: &[IntegrityRecord]) -> Vec<u8> {
+    records.iter().flat_map(|record| record.to_bytes()).collect()
+}
+
+/// HMAC-SHA256 over the record table, checked by the stub before trusting it
+///
+/// The key is written to the same footer, so this only guards against
+/// corruption and naive patching, not against someone who re-signs the table.
+pub fn sign_table(table: &[u8], key: &[u8; 32]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(table);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::build_real_test_binary;
+
+    #[test]
+    fn test_elf_code_regions_are_hashed() {
+        let stub = match build_real_test_binary("gcc") {
+            Ok(data) => data,
+            Err(e) => {
+                println!("⚠️  Skipping test - failed to build binary: {}", e);
+                return;
+            }
+        };
+
+        let base = b"base payload".to_vec();
+        let overload = b"overload payload".to_vec();
+        let base_offset = stub.len() as u64;
+        let overload_offset = base_offset + base.len() as u64;
+        let records = build_records(&stub, &base, base_offset, &overload, overload_offset).unwrap();
+
+        let code: Vec<_> = records.iter().filter(|r| r.kind == INTEGRITY_KIND_STUB_CODE).collect();
+        assert!(!code.is_empty(), "Stub should have at least one executable segment");
+        for record in code {
+            let region = &stub[record.file_offset as usize..(record.file_offset + record.size) as usize];
+            assert_eq!(record.hash, <[u8; 32]>::from(Sha256::digest(region)));
+        }
+
+        let base_record = records.iter().find(|r| r.kind == INTEGRITY_KIND_BASE).unwrap();
+        assert_eq!(base_record.file_offset, base_offset);
+        assert_eq!(base_record.size, base.len() as u64);
+    }
+
+    #[test]
+    fn test_table_signature_detects_changes() {
+        let records = vec![IntegrityRecord::new(INTEGRITY_KIND_BASE, 100, 0, b"payload")];
+        let key = [9u8; 32];
+        let mut table = table_bytes(&records);
+        let signature = sign_table(&table, &key);
+
+        table[40] ^= 1;
+        assert_ne!(sign_table(&table, &key), signature);
+    }
+
+    #[test]
+    fn test_integrity_action_parse() {
+        assert_eq!(IntegrityAction::parse("kill_base").unwrap(), IntegrityAction::KillBase);
+        assert_eq!(IntegrityAction::parse("").unwrap(), IntegrityAction::Exit);
+        assert!(IntegrityAction::parse("reboot").is_err());
+    }
+}
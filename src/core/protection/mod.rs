@@ -0,0 +1,11 @@
+pub mod anti_debug;
+pub mod function_encryption;
+pub mod integrity;
+pub mod polymorph;
+pub mod symbols;
+
+pub use anti_debug::AntiDebugOptions;
+pub use function_encryption::{encrypt_functions, FunctionEncryption};
+pub use integrity::{IntegrityAction, IntegrityOptions};
+pub use polymorph::mutate_stub;
+pub use symbols::{strip_payload, StripOptions, SymbolMode};
@@ -0,0 +1,349 @@
+use goblin::elf::header::{EM_386, EM_X86_64};
+use goblin::elf::Elf;
+use goblin::Object;
+use rand::Rng;
+
+use crate::core::binary::OperatingSystem;
+
+use super::symbols::{randomize_build_id, OBFUSCATED_CHARSET, STUB_LOG_TAG};
+
+/// Marks the encrypted string table compiled into every stub.
+///
+/// Must match `STRING_TABLE_MARKER` in `loader-stub/src/strings.rs`.
+const STRING_TABLE_MARKER: [u8; 16] = *b"\x8fKC\x1dSTRTBL\xa4\x3e\x91\x07v1";
+
+/// x86 `int3`, used by the linker and compiler to pad between functions
+const INT3: u8 = 0xCC;
+
+/// What changed while mutating a stub
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StubMutation {
+    pub strings_rekeyed: bool,
+    pub padding_bytes: usize,
+    pub build_id_randomized: bool,
+}
+
+/// Produce a per-merge copy of one of the pre-compiled stub variants.
+///
+/// The variants differ in their code: each is linked with its functions in a
+/// different order (x86-64 Linux) and holds the constants it masks under a
+/// different key (see loader-stub's build.rs). On top of that, bytes that are
+/// never executed or are decrypted at runtime change in every merge:
+///
+/// - the string table is re-encrypted with a fresh key and its marker erased
+/// - `int3` padding between functions (x86 ELF) is filled with random bytes
+/// - the GNU build-id is randomized (ELF)
+///
+/// macOS stubs are code signed, so they are returned unchanged.
+pub fn mutate_stub(stub: &[u8], os: OperatingSystem, randomize_tag: bool) -> (Vec<u8>, StubMutation) {
+    let mut mutated = stub.to_vec();
+    let mut mutation = StubMutation::default();
+    if os == OperatingSystem::MacOS {
+        log::warn!("⚠️  Skipping stub mutation for code signed macOS stub");
+        return (mutated, mutation);
+    }
+
+    mutation.strings_rekeyed = rekey_string_table(&mut mutated, randomize_tag);
+
+    if let Ok(Object::Elf(elf)) = Object::parse(stub) {
+        if elf.header.e_machine == EM_X86_64 || elf.header.e_machine == EM_386 {
+            mutation.padding_bytes = randomize_function_padding(&mut mutated, &elf);
+        }
+        randomize_build_id(&mut mutated, &elf);
+        mutation.build_id_randomized = true;
+    }
+
+    (mutated, mutation)
+}
+
+/// Keystream byte at `position`: splitmix64 in counter mode (same as the stub)
+fn keystream(key: u64, position: usize) -> u8 {
+    let mut z = key.wrapping_add(((position / 8) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> ((position % 8) * 8)) as u8
+}
+
+/// Re-encrypt the stub's string table with a random key.
+///
+/// Table layout: `[marker 16][key 8][len u32][data]`. The marker is only
+/// needed to find the table here, so it is overwritten with random bytes.
+/// Returns false if the stub has no string table.
+fn rekey_string_table(stub: &mut [u8], randomize_tag: bool) -> bool {
+    let Some(start) = stub.windows(STRING_TABLE_MARKER.len()).position(|w| w == STRING_TABLE_MARKER) else {
+        return false;
+    };
+    let key_at = start + 16;
+    let data_at = key_at + 12;
+    if data_at > stub.len() {
+        return false;
+    }
+    let old_key = u64::from_le_bytes(stub[key_at..key_at + 8].try_into().unwrap());
+    let len = u32::from_le_bytes(stub[key_at + 8..data_at].try_into().unwrap()) as usize;
+    if data_at + len > stub.len() {
+        return false;
+    }
+
+    let mut rng = rand::rng();
+    let data = &mut stub[data_at..data_at + len];
+    for (position, byte) in data.iter_mut().enumerate() {
+        *byte ^= keystream(old_key, position);
+    }
+
+    if randomize_tag {
+        let mut tag = STUB_LOG_TAG.to_vec();
+        for byte in &mut tag[1..STUB_LOG_TAG.len() - 1] {
+            *byte = OBFUSCATED_CHARSET[rng.random_range(0..OBFUSCATED_CHARSET.len())];
+        }
+        if data.starts_with(STUB_LOG_TAG) {
+            data[..tag.len()].copy_from_slice(&tag);
+        }
+    }
+
+    let new_key: u64 = rng.random();
+    for (position, byte) in data.iter_mut().enumerate() {
+        *byte ^= keystream(new_key, position);
+    }
+    stub[key_at..key_at + 8].copy_from_slice(&new_key.to_le_bytes());
+    rng.fill(&mut stub[start..key_at]);
+    true
+}
+
+/// Function address ranges from `.eh_frame_hdr` and the FDEs it points to
+fn function_ranges(data: &[u8], elf: &Elf) -> Option<Vec<(u64, u64)>> {
+    let section = |wanted: &str| {
+        elf.section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(wanted))
+    };
+    let hdr = section(".eh_frame_hdr")?;
+    let eh_frame = section(".eh_frame")?;
+
+    let read_i32 = |offset: usize| -> Option<i64> {
+        Some(i32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as i64)
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+    };
+
+    // Only the encoding every x86 toolchain emits: pcrel sdata4 pointer,
+    // udata4 count and a datarel sdata4 search table
+    let h = hdr.sh_offset as usize;
+    if data.get(h..h + 4)? != [1, 0x1b, 0x03, 0x3b] {
+        return None;
+    }
+    let count = read_u32(h + 8)? as usize;
+    let eh_frame_file = |vaddr: i64| -> Option<usize> {
+        let delta = vaddr.checked_sub(eh_frame.sh_addr as i64)?;
+        (delta >= 0 && (delta as u64) < eh_frame.sh_size).then(|| eh_frame.sh_offset as usize + delta as usize)
+    };
+
+    let mut ranges = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = h + 12 + i * 8;
+        let start = hdr.sh_addr as i64 + read_i32(entry)?;
+        let fde = eh_frame_file(hdr.sh_addr as i64 + read_i32(entry + 4)?)?;
+        if read_u32(fde)? == 0xffff_ffff {
+            return None;
+        }
+
+        // pc_begin is pcrel sdata4 in the CIEs of these toolchains; check it
+        // against the search table before trusting pc_range
+        let pc_begin_file = fde + 8;
+        let pc_begin_vaddr = eh_frame.sh_addr as i64 + (pc_begin_file - eh_frame.sh_offset as usize) as i64;
+        if pc_begin_vaddr + read_i32(pc_begin_file)? != start {
+            return None;
+        }
+        let size = read_u32(pc_begin_file + 4)? as u64;
+        ranges.push((start as u64, start as u64 + size));
+    }
+    ranges.sort_unstable();
+    Some(ranges)
+}
+
+/// Fill the `int3` padding between functions with random bytes.
+///
+/// Gaps come from the unwind tables, and only gaps made up entirely of
+/// `int3` are touched, so no reachable instruction changes.
+fn randomize_function_padding(stub: &mut [u8], elf: &Elf) -> usize {
+    let Some(text) = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".text"))
+    else {
+        return 0;
+    };
+    let Some(ranges) = function_ranges(stub, elf) else {
+        return 0;
+    };
+
+    let text_start = text.sh_addr;
+    let text_end = text.sh_addr + text.sh_size;
+    let mut rng = rand::rng();
+    let mut randomized = 0;
+    for pair in ranges.windows(2) {
+        let (gap_start, gap_end) = (pair[0].1, pair[1].0);
+        if gap_start >= gap_end || gap_start < text_start || gap_end > text_end {
+            continue;
+        }
+        let offset = (text.sh_offset + (gap_start - text_start)) as usize;
+        let gap = &mut stub[offset..offset + (gap_end - gap_start) as usize];
+        if gap.iter().all(|&b| b == INT3) {
+            rng.fill(gap);
+            randomized += gap.len();
+        }
+    }
+    randomized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::binary::BinaryInfo;
+    use crate::core::merger::v2::merge_v2;
+    use crate::core::merger::{LogLevel, MergeV2Options};
+    use crate::test_utils::build_real_test_binary;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    use crate::core::merger::v2::LINUX_X86_64_STUB;
+
+    fn table(key: u64, text: &[u8]) -> Vec<u8> {
+        let mut bytes = b"padding".to_vec();
+        bytes.extend_from_slice(&STRING_TABLE_MARKER);
+        bytes.extend_from_slice(&key.to_le_bytes());
+        bytes.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        bytes.extend(text.iter().enumerate().map(|(i, b)| b ^ keystream(key, i)));
+        bytes.extend_from_slice(b"trailer");
+        bytes
+    }
+
+    fn decrypt(bytes: &[u8]) -> Vec<u8> {
+        let key = u64::from_le_bytes(bytes[23..31].try_into().unwrap());
+        let len = u32::from_le_bytes(bytes[31..35].try_into().unwrap()) as usize;
+        bytes[35..35 + len].iter().enumerate().map(|(i, b)| b ^ keystream(key, i)).collect()
+    }
+
+    #[test]
+    fn test_string_table_is_rekeyed() {
+        let original = table(42, b"[KillCode]Health monitor started");
+        let (first, _) = mutate_stub(&original, OperatingSystem::Windows, false);
+        let (second, _) = mutate_stub(&original, OperatingSystem::Windows, false);
+
+        assert_ne!(first, second, "Each merge should encrypt strings differently");
+        assert!(!first.windows(16).any(|w| w == STRING_TABLE_MARKER), "Marker must be erased");
+        assert_eq!(decrypt(&first), b"[KillCode]Health monitor started");
+        assert_eq!(decrypt(&second), b"[KillCode]Health monitor started");
+    }
+
+    #[test]
+    fn test_tag_is_randomized_inside_table() {
+        let (mutated, _) = mutate_stub(&table(7, b"[KillCode]starting"), OperatingSystem::Linux, true);
+        let plain = decrypt(&mutated);
+        assert_eq!(plain.len(), 18);
+        assert!(!plain.starts_with(STUB_LOG_TAG));
+        assert!(plain.ends_with(b"]starting"));
+    }
+
+    #[test]
+    fn test_macos_stub_is_unchanged() {
+        let original = table(42, b"[KillCode]");
+        let (mutated, mutation) = mutate_stub(&original, OperatingSystem::MacOS, true);
+        assert_eq!(mutated, original);
+        assert_eq!(mutation, StubMutation::default());
+    }
+
+    #[test]
+    fn test_mutated_elf_differs_and_runs() {
+        let binary_data = match build_real_test_binary("gcc") {
+            Ok(data) => data,
+            Err(e) => {
+                println!("⚠️  Skipping test - failed to build binary: {}", e);
+                return;
+            }
+        };
+
+        let (first, mutation) = mutate_stub(&binary_data, OperatingSystem::Linux, false);
+        let (second, _) = mutate_stub(&binary_data, OperatingSystem::Linux, false);
+        assert!(mutation.build_id_randomized);
+        assert_eq!(first.len(), binary_data.len());
+        assert_ne!(first, second, "Each merge should produce a different stub");
+
+        let dir = tempfile::tempdir().unwrap();
+        for (index, data) in [first, second].iter().enumerate() {
+            let path = dir.path().join(format!("mutated{}", index));
+            std::fs::write(&path, data).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let output = Command::new(&path).output().expect("Failed to run mutated binary");
+            assert!(String::from_utf8_lossy(&output.stdout).contains("Test"), "Mutated binary should still run");
+        }
+    }
+
+    #[test]
+    fn test_merges_differ_and_run() {
+        let binary_data = match build_real_test_binary("gcc") {
+            Ok(data) => data,
+            Err(e) => {
+                println!("⚠️  Skipping test - failed to build binary: {}", e);
+                return;
+            }
+        };
+        let base_info = BinaryInfo::detect(&binary_data);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let merged: Vec<Vec<u8>> = (0..2)
+            .map(|index| {
+                let work = dir.path().join(index.to_string());
+                std::fs::create_dir(&work).unwrap();
+                // The second one hides the stub's log tag
+                let mut options = MergeV2Options::default();
+                options.logging.level = LogLevel::Debug;
+                options.strip.randomize_stub_strings = index == 1;
+                let path = runtime
+                    .block_on(merge_v2(&binary_data, &binary_data, &work, &base_info, "", "", &options))
+                    .expect("Merge failed");
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+                let output = Command::new(&path).output().expect("Failed to run merged binary");
+                assert!(String::from_utf8_lossy(&output.stdout).contains("Test"), "Merged binary should run");
+                let tagged = String::from_utf8_lossy(&output.stderr).contains("[KillCode]");
+                assert_eq!(tagged, index == 0, "Only the second merge should hide the log tag");
+                std::fs::read(&path).unwrap()
+            })
+            .collect();
+
+        let stub_len = LINUX_X86_64_STUB.iter().map(|stub| stub.len()).min().unwrap();
+        assert_ne!(merged[0][..stub_len], merged[1][..stub_len], "Each merge should get a different stub");
+        for binary in &merged {
+            let stub = binary[..stub_len].to_ascii_lowercase();
+            assert!(!stub.windows(8).any(|w| w == b"killcode"), "The stub should carry no readable name");
+        }
+    }
+
+    #[test]
+    fn test_stub_variants_differ_in_code() {
+        let text = |stub: &[u8]| {
+            let Ok(Object::Elf(elf)) = Object::parse(stub) else { panic!("Stub variant is not an ELF") };
+            let text = elf.section_headers.iter().find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(".text")).unwrap();
+            stub[text.sh_offset as usize..(text.sh_offset + text.sh_size) as usize].to_vec()
+        };
+        let texts: Vec<Vec<u8>> = LINUX_X86_64_STUB
+            .iter()
+            .map(|stub| text(&mutate_stub(stub, OperatingSystem::Linux, false).0))
+            .collect();
+
+        for (i, first) in texts.iter().enumerate() {
+            for second in &texts[i + 1..] {
+                let same = first.iter().zip(second).filter(|(a, b)| a == b).count();
+                assert!(same < first.len() / 2, "Variants should not share their code layout");
+            }
+        }
+        // The string table's keystream constants are masked per variant
+        for stub in LINUX_X86_64_STUB {
+            for constant in [0x9E37_79B9_7F4A_7C15u64, 0xBF58_476D_1CE4_E5B9, 0x94D0_49BB_1331_11EB] {
+                assert!(!stub.windows(8).any(|w| w == constant.to_le_bytes()));
+            }
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use anyhow::{Context, Result};
+use goblin::elf::section_header::{SHF_ALLOC, SHF_INFO_LINK, SHT_NOBITS, SHT_REL, SHT_RELA};
+use goblin::elf::Elf;
+use rand::Rng;
+
+/// Log prefix at the start of every stub's string table
+pub(crate) const STUB_LOG_TAG: &[u8] = b"[KillCode]";
+
+pub(crate) const OBFUSCATED_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_";
+
+/// What to do with the static symbol table of each payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymbolMode {
+    /// Leave symbols untouched
+    #[default]
+    Keep,
+    /// Remove `.symtab` / `.strtab`
+    Strip,
+    /// Keep the symbol table but replace every name with random characters
+    Obfuscate,
+}
+
+impl SymbolMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "keep" => Ok(SymbolMode::Keep),
+            "strip" => Ok(SymbolMode::Strip),
+            "obfuscate" => Ok(SymbolMode::Obfuscate),
+            other => anyhow::bail!("Unknown symbol mode '{}'. Expected keep, strip or obfuscate", other),
+        }
+    }
+}
+
+/// Post-processing applied to the payloads and stub of a merge
+#[derive(Debug, Clone, Default)]
+pub struct StripOptions {
+    pub symbols: SymbolMode,
+    /// Remove DWARF, `.comment` and randomize the GNU build-id
+    pub strip_debug: bool,
+    /// Replace the stub's `[KillCode]` log tag, inside its encrypted string
+    /// table, with a random one
+    pub randomize_stub_strings: bool,
+}
+
+impl StripOptions {
+    pub fn strips_payloads(&self) -> bool {
+        self.symbols != SymbolMode::Keep || self.strip_debug
+    }
+}
+
+fn is_debug_section(name: &str) -> bool {
+    name.starts_with(".debug")
+        || name.starts_with(".zdebug")
+        || matches!(name, ".comment" | ".gnu_debuglink" | ".gnu_debugaltlink")
+}
+
+fn is_symbol_section(name: &str) -> bool {
+    matches!(name, ".symtab" | ".strtab" | ".symtab_shndx")
+}
+
+/// Field accessors honoring the payload's ELF class and byte order
+struct ElfWriter {
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl ElfWriter {
+    fn read_u32(&self, data: &[u8], offset: usize) -> u32 {
+        let bytes: [u8; 4] = data[offset..offset + 4].try_into().unwrap();
+        if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+    }
+
+    fn write_u16(&self, out: &mut [u8], offset: usize, value: u16) {
+        let bytes = if self.little_endian { value.to_le_bytes() } else { value.to_be_bytes() };
+        out[offset..offset + 2].copy_from_slice(&bytes);
+    }
+
+    fn write_u32(&self, out: &mut [u8], offset: usize, value: u32) {
+        let bytes = if self.little_endian { value.to_le_bytes() } else { value.to_be_bytes() };
+        out[offset..offset + 4].copy_from_slice(&bytes);
+    }
+
+    fn write_word(&self, out: &mut [u8], offset: usize, value: u64) {
+        if self.is_64 {
+            let bytes = if self.little_endian { value.to_le_bytes() } else { value.to_be_bytes() };
+            out[offset..offset + 8].copy_from_slice(&bytes);
+        } else {
+            self.write_u32(out, offset, value as u32);
+        }
+    }
+
+    fn section_header_size(&self) -> usize {
+        if self.is_64 { 64 } else { 40 }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push_section_header(
+        &self,
+        out: &mut Vec<u8>,
+        name: u32,
+        sh_type: u32,
+        flags: u64,
+        addr: u64,
+        offset: u64,
+        size: u64,
+        link: u32,
+        info: u32,
+        addralign: u64,
+        entsize: u64,
+    ) {
+        let start = out.len();
+        out.resize(start + self.section_header_size(), 0);
+        let header = &mut out[start..];
+        self.write_u32(header, 0, name);
+        self.write_u32(header, 4, sh_type);
+        if self.is_64 {
+            self.write_word(header, 8, flags);
+            self.write_word(header, 16, addr);
+            self.write_word(header, 24, offset);
+            self.write_word(header, 32, size);
+            self.write_u32(header, 40, link);
+            self.write_u32(header, 44, info);
+            self.write_word(header, 48, addralign);
+            self.write_word(header, 56, entsize);
+        } else {
+            self.write_word(header, 8, flags);
+            self.write_word(header, 12, addr);
+            self.write_word(header, 16, offset);
+            self.write_word(header, 20, size);
+            self.write_u32(header, 24, link);
+            self.write_u32(header, 28, info);
+            self.write_word(header, 32, addralign);
+            self.write_word(header, 36, entsize);
+        }
+    }
+}
+
+fn align_to(out: &mut Vec<u8>, align: u64) {
+    let align = align.max(1) as usize;
+    let padded = out.len().div_ceil(align) * align;
+    out.resize(padded, 0);
+}
+
+/// Strip or obfuscate symbols and debug information of a payload.
+///
+/// ELF payloads are rewritten natively (the loadable image is untouched,
+/// only non-allocated sections are dropped). Other formats are returned as-is.
+pub fn strip_payload(data: &[u8], options: &StripOptions) -> Result<Vec<u8>> {
+    if !options.strips_payloads() {
+        return Ok(data.to_vec());
+    }
+
+    let elf = match Elf::parse(data) {
+        Ok(elf) => elf,
+        Err(_) => {
+            log::warn!("⚠️  Symbol stripping is only supported for ELF payloads, leaving payload untouched");
+            return Ok(data.to_vec());
+        }
+    };
+
+    let writer = ElfWriter { is_64: elf.is_64, little_endian: elf.little_endian };
+    let shstrndx = elf.header.e_shstrndx as usize;
+    let names: Vec<&str> = elf
+        .section_headers
+        .iter()
+        .map(|sh| elf.shdr_strtab.get_at(sh.sh_name).unwrap_or(""))
+        .collect();
+
+    let mut out = data.to_vec();
+    let mut rng = rand::rng();
+
+    // Randomize the build-id so builds can't be correlated
+    if options.strip_debug {
+        randomize_build_id(&mut out, &elf);
+    }
+
+    // Scramble the names referenced by the static symbol table in place
+    if options.symbols == SymbolMode::Obfuscate {
+        for (sh, name) in elf.section_headers.iter().zip(&names) {
+            if *name != ".strtab" {
+                continue;
+            }
+            let start = sh.sh_offset as usize;
+            let end = start + sh.sh_size as usize;
+            for byte in out[start..end].iter_mut().filter(|b| **b != 0) {
+                *byte = OBFUSCATED_CHARSET[rng.random_range(0..OBFUSCATED_CHARSET.len())];
+            }
+        }
+    }
+
+    let removed: Vec<bool> = elf
+        .section_headers
+        .iter()
+        .zip(&names)
+        .enumerate()
+        .map(|(index, (sh, name))| {
+            index != 0
+                && index != shstrndx
+                && sh.sh_flags & SHF_ALLOC as u64 == 0
+                && ((options.strip_debug && is_debug_section(name))
+                    || (options.symbols == SymbolMode::Strip && is_symbol_section(name)))
+        })
+        .collect();
+
+    if !removed.iter().any(|r| *r) {
+        return Ok(out);
+    }
+
+    // Everything covered by the program headers stays exactly where it is
+    let header_end = elf.header.e_phoff as usize
+        + elf.header.e_phnum as usize * elf.header.e_phentsize as usize;
+    let image_end = elf
+        .program_headers
+        .iter()
+        .map(|ph| (ph.p_offset + ph.p_filesz) as usize)
+        .chain([elf.header.e_ehsize as usize, header_end])
+        .max()
+        .unwrap_or(0)
+        .min(out.len());
+
+    let mut rewritten = out[..image_end].to_vec();
+    let mut index_map = vec![0u32; elf.section_headers.len()];
+    let mut next_index = 0u32;
+    for (index, is_removed) in removed.iter().enumerate() {
+        if !is_removed {
+            index_map[index] = next_index;
+            next_index += 1;
+        }
+    }
+
+    // Removed sections that live inside the image are zeroed in place
+    for (sh, is_removed) in elf.section_headers.iter().zip(&removed) {
+        let end = (sh.sh_offset + sh.sh_size) as usize;
+        if *is_removed && sh.sh_type != SHT_NOBITS && end <= image_end {
+            rewritten[sh.sh_offset as usize..end].fill(0);
+        }
+    }
+
+    // Rebuild the section name table so it no longer mentions removed sections
+    let mut shstrtab = vec![0u8];
+    let mut new_names = vec![0u32; elf.section_headers.len()];
+    for (index, name) in names.iter().enumerate() {
+        if index != 0 && !removed[index] {
+            new_names[index] = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+    }
+
+    let mut new_offsets: Vec<u64> = elf.section_headers.iter().map(|sh| sh.sh_offset).collect();
+    let mut new_sizes: Vec<u64> = elf.section_headers.iter().map(|sh| sh.sh_size).collect();
+    for (index, sh) in elf.section_headers.iter().enumerate() {
+        if index == 0 || removed[index] {
+            continue;
+        }
+        if index == shstrndx {
+            if sh.sh_offset as usize + sh.sh_size as usize <= image_end {
+                rewritten[sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize].fill(0);
+            }
+            align_to(&mut rewritten, 1);
+            new_offsets[index] = rewritten.len() as u64;
+            new_sizes[index] = shstrtab.len() as u64;
+            rewritten.extend_from_slice(&shstrtab);
+        } else if sh.sh_type != SHT_NOBITS && (sh.sh_offset + sh.sh_size) as usize > image_end {
+            let start = sh.sh_offset as usize;
+            let end = (sh.sh_offset + sh.sh_size) as usize;
+            let section = out.get(start..end).context("Section extends past end of file")?;
+            align_to(&mut rewritten, sh.sh_addralign);
+            new_offsets[index] = rewritten.len() as u64;
+            rewritten.extend_from_slice(section);
+        }
+    }
+
+    align_to(&mut rewritten, if writer.is_64 { 8 } else { 4 });
+    let shoff = rewritten.len() as u64;
+    let remap = |index: u32| -> u32 {
+        match removed.get(index as usize) {
+            Some(false) => index_map[index as usize],
+            _ => 0,
+        }
+    };
+
+    for (index, sh) in elf.section_headers.iter().enumerate() {
+        if removed[index] {
+            continue;
+        }
+        let links_section = sh.sh_type == SHT_REL || sh.sh_type == SHT_RELA || sh.sh_flags & SHF_INFO_LINK as u64 != 0;
+        writer.push_section_header(
+            &mut rewritten,
+            new_names[index],
+            sh.sh_type,
+            sh.sh_flags,
+            sh.sh_addr,
+            new_offsets[index],
+            new_sizes[index],
+            if index == 0 { 0 } else { remap(sh.sh_link) },
+            if links_section && sh.sh_info != 0 { remap(sh.sh_info) } else { sh.sh_info },
+            sh.sh_addralign,
+            sh.sh_entsize,
+        );
+    }
+
+    // Point the ELF header at the new section header table
+    let (shoff_at, shentsize_at) = if writer.is_64 { (0x28, 0x3A) } else { (0x20, 0x2E) };
+    writer.write_word(&mut rewritten, shoff_at, shoff);
+    writer.write_u16(&mut rewritten, shentsize_at, writer.section_header_size() as u16);
+    writer.write_u16(&mut rewritten, shentsize_at + 2, next_index as u16);
+    writer.write_u16(&mut rewritten, shentsize_at + 4, index_map[shstrndx] as u16);
+
+    log::info!(
+        "✂️  Removed {} sections ({} → {} bytes)",
+        removed.iter().filter(|r| **r).count(),
+        data.len(),
+        rewritten.len()
+    );
+
+    Ok(rewritten)
+}
+
+/// Overwrite the GNU build-id of an ELF image with random bytes.
+///
+/// `elf` must be parsed from the same bytes as `out`.
+pub(crate) fn randomize_build_id(out: &mut [u8], elf: &Elf) {
+    let writer = ElfWriter { is_64: elf.is_64, little_endian: elf.little_endian };
+    let mut rng = rand::rng();
+    for sh in &elf.section_headers {
+        if elf.shdr_strtab.get_at(sh.sh_name) != Some(".note.gnu.build-id") || sh.sh_size < 12 {
+            continue;
+        }
+        let note = sh.sh_offset as usize;
+        let namesz = writer.read_u32(out, note) as usize;
+        let descsz = writer.read_u32(out, note + 4) as usize;
+        let desc = note + 12 + namesz.div_ceil(4) * 4;
+        if desc + descsz <= note + sh.sh_size as usize {
+            rng.fill(&mut out[desc..desc + descsz]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::build_real_test_binary;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    fn run_binary(data: &[u8]) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stripped");
+        std::fs::write(&path, data).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let output = Command::new(&path).output().expect("Failed to run stripped binary");
+        String::from_utf8_lossy(&output.stdout).to_string()
+    }
+
+    fn section_names(data: &[u8]) -> Vec<String> {
+        let elf = Elf::parse(data).unwrap();
+        elf.section_headers
+            .iter()
+            .map(|sh| elf.shdr_strtab.get_at(sh.sh_name).unwrap_or("").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_strip_symbols_and_debug() {
+        let binary_data = match build_real_test_binary("gcc") {
+            Ok(data) => data,
+            Err(e) => {
+                println!("⚠️  Skipping test - failed to build binary: {}", e);
+                return;
+            }
+        };
+
+        let options = StripOptions { symbols: SymbolMode::Strip, strip_debug: true, ..Default::default() };
+        let stripped = strip_payload(&binary_data, &options).unwrap();
+
+        let names = section_names(&stripped);
+        assert!(!names.iter().any(|n| n == ".symtab" || n == ".strtab"), "Symbol table should be removed");
+        assert!(!names.iter().any(|n| n == ".comment" || n.starts_with(".debug")), "Debug sections should be removed");
+        assert!(Elf::parse(&stripped).unwrap().syms.is_empty());
+        assert!(run_binary(&stripped).contains("Test"), "Stripped binary should still run");
+    }
+
+    #[test]
+    fn test_obfuscate_symbols() {
+        let binary_data = match build_real_test_binary("gcc") {
+            Ok(data) => data,
+            Err(e) => {
+                println!("⚠️  Skipping test - failed to build binary: {}", e);
+                return;
+            }
+        };
+
+        let options = StripOptions { symbols: SymbolMode::Obfuscate, ..Default::default() };
+        let obfuscated = strip_payload(&binary_data, &options).unwrap();
+
+        let elf = Elf::parse(&obfuscated).unwrap();
+        assert!(!elf.syms.is_empty(), "Symbol table should be kept");
+        assert!(
+            !elf.syms.iter().any(|sym| elf.strtab.get_at(sym.st_name) == Some("main")),
+            "Symbol names should be obfuscated"
+        );
+        assert!(run_binary(&obfuscated).contains("Test"), "Obfuscated binary should still run");
+    }
+
+    #[test]
+    fn test_symbol_mode_parse() {
+        assert_eq!(SymbolMode::parse("strip").unwrap(), SymbolMode::Strip);
+        assert_eq!(SymbolMode::parse("").unwrap(), SymbolMode::Keep);
+        assert!(SymbolMode::parse("shred").is_err());
+    }
+}
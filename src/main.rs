@@ -18,6 +18,14 @@ async fn main() -> std::io::Result<()> {
     log::info!("🕸️  Starting Weaver Binary Weaving Service");
     log::info!("📍 Listening on {}:{}", config.host, config.port);
     log::info!("📁 Temp directory: {}", config.temp_dir);
+    match config.debug_signing_key.as_deref().map(core::merger::logging::parse_signing_key) {
+        Some(Ok(key)) => log::info!(
+            "🔑 Debug builds enabled, stubs need KILLCODE_DEBUG_PUBKEY={}",
+            core::merger::logging::public_key_hex(&key)
+        ),
+        Some(Err(e)) => log::warn!("⚠️  Ignoring WEAVER_DEBUG_SIGNING_KEY: {}", e),
+        None => {}
+    }
     
     let bind_addr = (config.host.clone(), config.port);
     
@@ -23,7 +23,13 @@ int main() {
     return 0;
 }
 "#;
-    let source = NamedTempFile::new().map_err(|e| e.to_string())?;
+    build_test_binary(compiler, code, true)
+}
+
+/// Build `code` with the specified compiler, linked statically or against the system's libc
+pub fn build_test_binary(compiler: &str, code: &str, static_link: bool) -> Result<Vec<u8>, String> {
+    // The compiler goes by the extension
+    let source = tempfile::Builder::new().suffix(".c").tempfile().map_err(|e| e.to_string())?;
     fs::write(source.path(), code).map_err(|e| e.to_string())?;
     
     let output = NamedTempFile::new().map_err(|e| e.to_string())?;
@@ -32,7 +38,7 @@ int main() {
         .arg(source.path())
         .arg("-o")
         .arg(output.path())
-        .arg("-static")
+        .args(static_link.then_some("-static"))
         .output()
         .map_err(|e| format!("Compiler '{}' not found: {}", compiler, e))?;
     